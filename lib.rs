@@ -0,0 +1,16579 @@
+/*
+ * Angolmois -- the simple BMS player
+ * Copyright (c) 2005, 2007, 2009, 2012, 2013, 2014, Kang Seonghoon.
+ * Project Angolmois is copyright (c) 2003-2007, Choi Kaya (CHKY).
+ * 
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ * 
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ * 
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 59 Temple Place - Suite 330, Boston, MA  02111-1307, USA.
+ */
+
+/*!
+ * This is a direct, one-to-one translation of Angolmois to Rust programming language.
+ * [Angolmois](http://mearie.org/projects/angolmois/) is
+ * a [BM98](http://bm98.yaneu.com/bm98/)-like minimalistic music video game which supports
+ * the [BMS format](http://en.wikipedia.org/wiki/Be-Music_Source) for playing.
+ * 
+ * Angolmois is a combination of string parsing, path manipulation, two-dimensional graphics and
+ * complex game play carefully packed into some thousand lines of code. This translation is intended
+ * to provide an example of translating a moderately-sized C code to Rust, and also to investigate
+ * additional library supports required for such moderately-sized programs.
+ * 
+ * Angolmois is distributed under GNU GPL version 2+, so is this translation. The portions of it is
+ * intended to be sent as a patch to Rust, so those portions are licensed under Apache License 2.0
+ * and MIT license. Unlike the original Angolmois code (which sacrifices most comments due to code
+ * size concerns), the Rust version has much more comments which can be beneficial for understanding
+ * Angolmois itself too.
+ *
+ * The crate is split into a library (this file) containing the parser, graphics and game play
+ * engine, and a thin executable (`main.rs`) which only parses the command line. Other programs
+ * can use the library to embed the BMS parser or the whole game play.
+ *
+ * Starting from Rust 0.9, Angolmois Rust edition tracks the most recent development version of
+ * Rust. Consequently it is now synchronized with the up-to-date version of rust-sdl.
+ *
+ * # Key
+ * 
+ * The following notations are used in the comments and documentations.
+ * 
+ * * (C: ...) - variable/function corresponds to given name in the C code.
+ * * Rust: ... - suboptimal translation with a room for improvement in Rust. often contains a Rust
+ *   issue number like #1234.
+ * * XXX - should be fixed as soon as Rust issue is gone.
+ * * TODO - other problems unrelated to Rust.
+ */
+
+#![crate_name = "angolmois"]
+#![crate_type = "lib"]
+
+#![feature(macro_rules)]
+
+// XXX temporarily cope with the nightly
+#![allow(unknown_features)]
+#![feature(slicing_syntax)]
+
+#![comment = "Angolmois"]
+#![license = "GPLv2+"]
+
+extern crate libc;
+extern crate serialize;
+extern crate flate;
+
+extern crate sdl;
+extern crate sdl_mixer;
+extern crate sdl_image;
+
+/// Returns a version string. (C: `VERSION`)
+pub fn version() -> String { "Angolmois 2.0.0 alpha 2 (rust edition)".to_string() }
+
+//==================================================================================================
+// utility declarations
+
+/// Returns an executable name used in the command line if any. (C: `argv0`)
+pub fn exename() -> String {
+    let args = std::os::args();
+    if args.is_empty() {"angolmois".to_string()} else {args[0].clone()}
+}
+
+//==================================================================================================
+// error handling
+
+/// An error that can occur while loading or playing the chart. Fallible functions return this
+/// instead of terminating the process, so the embedding program can recover from the failure.
+pub enum AngolmoisError {
+    /// Failed to read or write the file at given path.
+    IoFailure(String, std::io::IoError),
+    /// SDL or its extensions failed to initialize or to allocate a resource.
+    SdlFailure(String),
+    /// The key specification is invalid or cannot be determined.
+    InvalidKeySpec(String),
+    /// The input mapping (normally from environment variables) is invalid.
+    InvalidKeyMap(String),
+    /// The replay file does not match the chart being played.
+    InvalidReplay(String),
+    /// The result could not be submitted to the internet ranking.
+    IrFailure(String),
+    /// The configuration file is invalid.
+    InvalidConfig(String),
+    /// The chart has conflicting objects and the strict sanitizer policy is in effect.
+    InvalidChart(String),
+    /// The user requested to quit (e.g. with the escape key) before the game play.
+    Interrupted,
+}
+
+impl std::fmt::Show for AngolmoisError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            IoFailure(ref path, ref err) => write!(f, "{}: {}", path, err),
+            SdlFailure(ref msg) | InvalidKeySpec(ref msg) | InvalidKeyMap(ref msg) |
+            InvalidReplay(ref msg) | IrFailure(ref msg) | InvalidConfig(ref msg) |
+            InvalidChart(ref msg) => {
+                write!(f, "{}", msg)
+            }
+            Interrupted => write!(f, "Interrupted"),
+        }
+    }
+}
+
+/// A result type with `AngolmoisError`.
+pub type AngolmoisResult<T> = Result<T, AngolmoisError>;
+
+/// Utility functions.
+#[macro_escape]
+pub mod util {
+    use std;
+    use libc;
+    #[cfg(target_os = "windows")] use std::sync::atomic;
+
+    /// String utilities for Rust. Parallels to `std::str`.
+    pub mod str {
+        /// Extensions to `str`.
+        pub trait StrUtil<'r> {
+            /// Returns a slice of the given string starting from `begin` and up to the byte
+            /// position `end`. `end` doesn't have to point to valid characters.
+            ///
+            /// # Failure
+            ///
+            /// If `begin` does not point to valid characters or beyond the last character of
+            /// the string, or `end` points beyond the last character of the string
+            fn slice_upto(&self, begin: uint, end: uint) -> &'r str;
+
+            /// Counts the number of bytes in the complete UTF-8 sequences up to `limit` bytes
+            /// in `s` starting from `start`.
+            fn count_bytes_upto(&self, start: uint, limit: uint) -> uint;
+
+            /// Work with a null-terminated UTF-16 buffer of the string. Useful for calling
+            /// Win32 API.
+            fn as_utf16_c_str<T>(&self, f: |*const u16| -> T) -> T;
+        }
+
+        impl<'r> StrUtil<'r> for &'r str {
+            fn slice_upto(&self, begin: uint, end: uint) -> &'r str {
+                (*self)[begin..begin + self.count_bytes_upto(begin, end)]
+            }
+
+            fn count_bytes_upto(&self, start: uint, limit: uint) -> uint {
+                assert!(self.is_char_boundary(start));
+                let limit = start + limit;
+                let l = self.len();
+                assert!(limit < l);
+                let mut end = start;
+                loop {
+                    assert!(end < l);
+                    let next = self.char_range_at(end).next;
+                    if next > limit { break; }
+                    end = next;
+                }
+                end - start
+            }
+
+            fn as_utf16_c_str<T>(&self, f: |*const u16| -> T) -> T {
+                let mut s16: Vec<u16> = self.utf16_units().collect();
+                s16.push(0u16);
+                f(s16.as_ptr())
+            }
+        }
+
+        /// A version of `std::from_str::FromStr` which parses a prefix of the input and
+        /// returns a remaining portion of the input as well.
+        //
+        // Rust: `std::num::from_str_bytes_common` does not recognize a number followed
+        //        by garbage, so we need to parse it ourselves.
+        pub trait FromStrPrefix {
+            /// Returns a parsed value and remaining string slice if possible.
+            fn from_str_prefix<'a>(s: &'a str) -> Option<(Self, &'a str)>;
+        }
+
+        /// A convenience function that invokes `FromStrPrefix::from_str_prefix`.
+        pub fn from_str_prefix<'a, T:FromStrPrefix>(s: &'a str) -> Option<(T, &'a str)> {
+            FromStrPrefix::from_str_prefix(s)
+        }
+
+        /// Returns a length of the longest prefix of given string,
+        /// which `from_str::<uint>` would in general accept without a failure, if any.
+        fn scan_uint(s: &str) -> Option<uint> {
+            match s.find(|c| !('0' <= c && c <= '9')) {
+                Some(first) if first > 0u => Some(first),
+                None if s.len() > 0u => Some(s.len()),
+                _ => None
+            }
+        }
+
+        /// Returns a length of the longest prefix of given string,
+        /// which `from_str::<int>` would in general accept without a failure, if any.
+        fn scan_int(s: &str) -> Option<uint> {
+            match s.slice_shift_char() {
+                (Some('-'), s_) | (Some('+'), s_) => scan_uint(s_).map(|pos| pos + 1u),
+                _ => scan_uint(s)
+            }
+        }
+
+        /// Returns a length of the longest prefix of given string,
+        /// which `from_str::<f64>` (and so on) would in general accept without a failure, if any.
+        fn scan_float(s: &str) -> Option<uint> {
+            scan_int(s).and_then(|pos| {
+                // scan `.` followed by digits if any
+                match s[pos..].slice_shift_char() {
+                    (Some('.'), s_) => scan_uint(s_).map(|pos2| pos + 1u + pos2),
+                    _ => Some(pos)
+                }
+            }).and_then(|pos| {
+                // scan `e` or `E` followed by an optional sign and digits if any
+                match s[pos..].slice_shift_char() {
+                    (Some('e'), s_) | (Some('E'), s_) => scan_int(s_).map(|pos2| pos + 1u + pos2),
+                    _ => Some(pos)
+                }
+            })
+        }
+
+        macro_rules! from_str_prefix_impls(
+            ($($scan:ident then $t:ty;)*) => (
+                $(
+                    impl FromStrPrefix for $t {
+                        fn from_str_prefix<'a>(s: &'a str) -> Option<($t, &'a str)> {
+                            $scan(s).and_then(|pos| {
+                                from_str::<$t>(s[..pos]).map(|v| (v, s[pos..]))
+                            })
+                        }
+                    }
+                )*
+            )
+        )
+
+        from_str_prefix_impls! {
+            scan_int   then int;
+            scan_int   then i8;
+            scan_int   then i16;
+            scan_int   then i32;
+            scan_int   then i64;
+            scan_uint  then uint;
+            scan_uint  then u8;
+            scan_uint  then u16;
+            scan_uint  then u32;
+            scan_uint  then u64;
+            scan_float then f32;
+            scan_float then f64;
+        }
+
+        impl FromStrPrefix for char {
+            fn from_str_prefix<'a>(s: &'a str) -> Option<(char, &'a str)> {
+                match s.slice_shift_char() {
+                    (Some(c), s_) => Some((c, s_)),
+                    (None, _) => None,
+                }
+            }
+        }
+
+        /// A trait which provides `prefix_shifted` method. Similar to `str::starts_with`, but with
+        /// swapped `self` and argument.
+        pub trait ShiftablePrefix {
+            /// When given string starts with `self`, returns a slice of that string
+            /// excluding that prefix. Otherwise returns `None`.
+            fn prefix_shifted<'r>(&self, s: &'r str) -> Option<&'r str>;
+        }
+
+        /// A convenience function that invokes `ShiftablePrefix::prefix_shifted`.
+        pub fn prefix_shifted<'a, T:ShiftablePrefix>(s: &'a str, prefix: T) -> Option<&'a str> {
+            prefix.prefix_shifted(s)
+        }
+
+        impl ShiftablePrefix for char {
+            fn prefix_shifted<'r>(&self, s: &'r str) -> Option<&'r str> {
+                match s.slice_shift_char() {
+                    (Some(c), s_) if c == *self => Some(s_),
+                    (_, _) => None,
+                }
+            }
+        }
+
+        impl<'r> ShiftablePrefix for &'r str {
+            fn prefix_shifted<'r>(&self, s: &'r str) -> Option<&'r str> {
+                if s.starts_with(*self) {
+                    Some(s[self.len()..])
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// Option utilities for Rust. Parallels to `std::option`.
+    pub mod option {
+        /// An utility trait for an option of string or alikes.
+        pub trait StrOption {
+            /// Returns a string slice in the option if any.
+            fn as_ref_slice<'a>(&'a self) -> Option<&'a str>;
+
+            /// Returns a string slice in the option if any, or `default` otherwise.
+            fn as_ref_slice_or<'a>(&'a self, default: &'a str) -> &'a str {
+                self.as_ref_slice().unwrap_or(default)
+            }
+        }
+
+        impl<T:Str> StrOption for Option<T> {
+            fn as_ref_slice<'a>(&'a self) -> Option<&'a str> {
+                self.as_ref().map(|s| s.as_slice())
+            }
+        }
+    }
+
+    /// MD5 message digest. Used for identifying charts in the same way as other BMS players, as
+    /// the chart hash should be shared with them.
+    pub mod md5 {
+        /// The per-round shift amounts.
+        static SHIFTS: [uint, ..16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+
+        /// An incremental MD5 context, for calculating the digest of data read in pieces.
+        pub struct Md5 {
+            /// The per-round constants.
+            k: [u32, ..64],
+            /// The current state.
+            h: [u32, ..4],
+            /// Input bytes not yet processed, less than 64 bytes long.
+            pending: Vec<u8>,
+            /// The total number of input bytes.
+            length: u64,
+        }
+
+        impl Md5 {
+            /// Creates a new context.
+            pub fn new() -> Md5 {
+                // the constants are the integral part of `abs(sin(i+1)) * 2^32`, exact in `f64`
+                let mut k = [0u32, ..64];
+                for i in range(0u, 64) {
+                    k[i] = (((i + 1) as f64).sin().abs() * 4294967296.0) as u32;
+                }
+                Md5 { k: k, h: [0x67452301u32, 0xefcdab89, 0x98badcfe, 0x10325476],
+                      pending: Vec::with_capacity(64), length: 0 }
+            }
+
+            /// Processes a single 64-byte block.
+            fn process(&mut self, chunk: &[u8]) {
+
+                let mut m = [0u32, ..16];
+                for i in range(0u, 16) {
+                    m[i] = (chunk[i*4] as u32) | (chunk[i*4+1] as u32 << 8) |
+                           (chunk[i*4+2] as u32 << 16) | (chunk[i*4+3] as u32 << 24);
+                }
+
+                let k = &self.k;
+                let h = &mut self.h;
+                let (mut a, mut b, mut c, mut d) = (h[0], h[1], h[2], h[3]);
+                for i in range(0u, 64) {
+                    let (f, g) = match i / 16 {
+                        0 => ((b & c) | (!b & d), i),
+                        1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                        2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                        _ => (c ^ (b | !d), (7 * i) % 16),
+                    };
+                    let x = a + f + k[i] + m[g];
+                    let shift = SHIFTS[(i / 16) * 4 + i % 4];
+                    a = d;
+                    d = c;
+                    c = b;
+                    b = b + ((x << shift) | (x >> (32 - shift)));
+                }
+                h[0] += a;
+                h[1] += b;
+                h[2] += c;
+                h[3] += d;
+            }
+
+            /// Feeds given data to the context.
+            pub fn input(&mut self, data: &[u8]) {
+                self.length += data.len() as u64;
+                let mut data = data;
+                if !self.pending.is_empty() {
+                    let n = ::std::cmp::min(64 - self.pending.len(), data.len());
+                    self.pending.push_all(data[..n]);
+                    data = data[n..];
+                    if self.pending.len() < 64 { return; }
+                    let block = ::std::mem::replace(&mut self.pending, Vec::with_capacity(64));
+                    self.process(block[]);
+                }
+                while data.len() >= 64 {
+                    self.process(data[..64]);
+                    data = data[64..];
+                }
+                self.pending.push_all(data);
+            }
+
+            /// Finishes the calculation and returns the digest.
+            pub fn result(mut self) -> [u8, ..16] {
+                // pads the message to the multiple of 64 bytes, ending with the length in bits
+                let nbits = self.length * 8;
+                let mut padding = vec!(0x80u8);
+                while (self.pending.len() + padding.len()) % 64 != 56 { padding.push(0); }
+                for i in range(0u, 8) { padding.push((nbits >> (i * 8)) as u8); }
+                let length = self.length;
+                self.input(padding[]);
+                self.length = length;
+                assert!(self.pending.is_empty());
+
+                let mut out = [0u8, ..16];
+                for i in range(0u, 16) { out[i] = (self.h[i / 4] >> ((i % 4) * 8)) as u8; }
+                out
+            }
+        }
+
+        /// Calculates the MD5 digest of given data.
+        pub fn digest(data: &[u8]) -> [u8, ..16] {
+            let mut md5 = Md5::new();
+            md5.input(data);
+            md5.result()
+        }
+
+        /// Converts the MD5 digest to lowercase hexadecimal digits.
+        pub fn to_hex(digest: &[u8]) -> String {
+            let mut s = String::new();
+            for &b in digest.iter() { s.push_str(format!("{:02x}", b)[]); }
+            s
+        }
+
+        /// Calculates the MD5 digest of given data in lowercase hexadecimal digits.
+        pub fn hex_digest(data: &[u8]) -> String {
+            to_hex(digest(data)[])
+        }
+    }
+
+    /// Binary reader utilities for Rust. Used by the importers of binary chart formats.
+    pub mod binary {
+        use std::io::IoResult;
+
+        /// An utility trait for reading little-endian binary structures.
+        pub trait ReaderUtil: Reader {
+            /// Skips given number of bytes.
+            fn skip_bytes(&mut self, nbytes: uint) -> IoResult<()> {
+                let _ = try!(self.read_exact(nbytes));
+                Ok(())
+            }
+
+            /// Reads a fixed-size, NUL-padded string. Invalid UTF-8 sequences are replaced.
+            //
+            // XXX most binary formats use a legacy encoding (e.g. EUC-KR) which is not handled.
+            fn read_fixed_str(&mut self, nbytes: uint) -> IoResult<String> {
+                let buf = try!(self.read_exact(nbytes));
+                let len = buf.iter().position(|&c| c == 0).unwrap_or(nbytes);
+                Ok(String::from_utf8_lossy(buf[..len]).into_string())
+            }
+        }
+
+        impl<R:Reader> ReaderUtil for R {}
+    }
+
+    /**
+     * Decoding of texts in the legacy encoding. BMS files and file names from Japan are commonly
+     * in Shift_JIS (more precisely, Windows code page 932), which is decoded with iconv or
+     * the Win32 API so that no conversion table has to be embedded.
+     */
+    pub mod encoding {
+        use std::str;
+
+        #[cfg(not(target_os = "windows"))]
+        mod ll {
+            #![allow(non_camel_case_types)]
+            use libc::{c_char, c_int, c_void, size_t};
+            pub type iconv_t = *mut c_void;
+            // glibc has iconv built in, but other systems have a separate library
+            #[cfg(target_os = "macos")] #[link(name = "iconv")] extern {}
+            extern {
+                pub fn iconv_open(tocode: *const c_char, fromcode: *const c_char) -> iconv_t;
+                pub fn iconv(cd: iconv_t, inbuf: *mut *mut c_char, inbytesleft: *mut size_t,
+                             outbuf: *mut *mut c_char, outbytesleft: *mut size_t) -> size_t;
+                pub fn iconv_close(cd: iconv_t) -> c_int;
+            }
+        }
+
+        #[cfg(target_os = "windows")]
+        mod ll {
+            use libc::{c_char, c_int};
+            use libc::types::os::arch::extra::{DWORD, LPWSTR};
+            pub const CP932: c_int = 932;
+            pub const MB_ERR_INVALID_CHARS: DWORD = 8;
+            #[link(name = "kernel32")]
+            extern "system" {
+                pub fn MultiByteToWideChar(CodePage: c_int, dwFlags: DWORD,
+                                           lpMultiByteStr: *const c_char, cbMultiByte: c_int,
+                                           lpWideCharStr: LPWSTR, cchWideChar: c_int) -> c_int;
+            }
+        }
+
+        /// Decodes given bytes in CP932, or returns `None` if they contain invalid sequences.
+        #[cfg(not(target_os = "windows"))]
+        pub fn decode_cp932(bytes: &[u8]) -> Option<String> {
+            use libc::{c_char, size_t};
+
+            unsafe {
+                let cd = "UTF-8".with_c_str(|to| "CP932".with_c_str(|from| {
+                    ll::iconv_open(to, from)
+                }));
+                if cd as int == -1 { return None; }
+
+                // every CP932 character takes at most 3 bytes in UTF-8
+                let mut out = Vec::from_elem(bytes.len() * 3, 0u8);
+                let mut inbuf = bytes.as_ptr() as *mut c_char;
+                let mut inleft = bytes.len() as size_t;
+                let mut outbuf = out.as_mut_ptr() as *mut c_char;
+                let mut outleft = out.len() as size_t;
+                let ret = ll::iconv(cd, &mut inbuf, &mut inleft, &mut outbuf, &mut outleft);
+                ll::iconv_close(cd);
+                if ret == -1 as size_t || inleft > 0 { return None; }
+
+                let len = out.len() - outleft as uint;
+                out.truncate(len);
+                String::from_utf8(out).ok()
+            }
+        }
+
+        /// Decodes given bytes in CP932, or returns `None` if they contain invalid sequences.
+        #[cfg(target_os = "windows")]
+        pub fn decode_cp932(bytes: &[u8]) -> Option<String> {
+            use std::ptr;
+            use libc::{c_char, c_int};
+
+            if bytes.is_empty() { return Some(String::new()); }
+            unsafe {
+                let src = bytes.as_ptr() as *const c_char;
+                let len = ll::MultiByteToWideChar(ll::CP932, ll::MB_ERR_INVALID_CHARS, src,
+                                                  bytes.len() as c_int, ptr::null_mut(), 0);
+                if len <= 0 { return None; }
+                let mut buf = Vec::from_elem(len as uint, 0u16);
+                let len = ll::MultiByteToWideChar(ll::CP932, ll::MB_ERR_INVALID_CHARS, src,
+                                                  bytes.len() as c_int, buf.as_mut_ptr(), len);
+                if len <= 0 { return None; }
+                buf.truncate(len as uint);
+                String::from_utf16(buf[])
+            }
+        }
+
+        /// Decodes given bytes in UTF-8, or in CP932 if they are not valid UTF-8. Invalid
+        /// sequences are replaced with U+FFFD as a last resort. Valid UTF-8 is borrowed as is,
+        /// so that only the fallbacks allocate.
+        pub fn decode_text<'a>(bytes: &'a [u8]) -> str::MaybeOwned<'a> {
+            match str::from_utf8(bytes) {
+                Some(s) => str::Slice(s),
+                None => match decode_cp932(bytes) {
+                    Some(s) => str::Owned(s),
+                    None => String::from_utf8_lossy(bytes),
+                },
+            }
+        }
+    }
+
+    /**
+     * A minimal but functional binding for SMPEG.
+     *
+     * NOTE: Some of these additions will be eventually sent to rust-sdl and are not subject to
+     * the above copyright notice.
+     */
+    pub mod smpeg {
+        #![allow(non_camel_case_types)]
+
+        use std;
+        use libc::{c_int, c_float};
+        use std::ptr::null_mut;
+        use sdl::audio;
+        use sdl::audio::ll::SDL_AudioSpec;
+        use sdl::video::Surface;
+        use self::ll::SMPEGstatus;
+
+        pub mod ll {
+            use libc::{c_void, c_int, c_char, c_float, c_double};
+            use sdl::video::ll::{SDL_RWops, SDL_Surface};
+            use sdl::audio::ll::SDL_AudioSpec;
+            #[repr(C)]
+            pub struct SMPEG { _opaque: () }
+            #[repr(C)]
+            pub struct SMPEG_Info {
+                pub has_audio: c_int,
+                pub has_video: c_int,
+                pub width: c_int,
+                pub height: c_int,
+                pub current_frame: c_int,
+                pub current_fps: c_double,
+                pub audio_string: [c_char, ..80],
+                pub audio_current_frame: c_int,
+                pub current_offset: u32,
+                pub total_size: u32,
+                pub current_time: c_double,
+                pub total_time: c_double
+            }
+            #[deriving(PartialEq, Eq, Clone)]
+            #[repr(C)]
+            pub enum SMPEGstatus {
+                SMPEG_ERROR = -1,
+                SMPEG_STOPPED = 0,
+                SMPEG_PLAYING =1
+            }
+            #[link(name = "smpeg")]
+            extern {
+                pub fn SMPEG_new(file: *const c_char, info: *mut SMPEG_Info,
+                                 sdl_audio: c_int) -> *mut SMPEG;
+                pub fn SMPEG_new_descr(file: c_int, info: *mut SMPEG_Info,
+                                       sdl_audio: c_int) -> *mut SMPEG;
+                pub fn SMPEG_new_data(data: *mut c_void, size: c_int, info: *mut SMPEG_Info,
+                                      sdl_audio: c_int) -> *mut SMPEG;
+                pub fn SMPEG_new_rwops(src: *mut SDL_RWops, info: *mut SMPEG_Info,
+                                       sdl_audio: c_int) -> *mut SMPEG;
+                pub fn SMPEG_getinfo(mpeg: *mut SMPEG, info: *mut SMPEG_Info);
+                pub fn SMPEG_enableaudio(mpeg: *mut SMPEG, enable: c_int);
+                pub fn SMPEG_enablevideo(mpeg: *mut SMPEG, enable: c_int);
+                pub fn SMPEG_delete(mpeg: *mut SMPEG);
+                pub fn SMPEG_status(mpeg: *mut SMPEG) -> SMPEGstatus;
+                pub fn SMPEG_setvolume(mpeg: *mut SMPEG, volume: c_int);
+                // XXX SDL_Mutex and SMPEG_DisplayCallback unimplemented
+                pub fn SMPEG_setdisplay(mpeg: *mut SMPEG, dst: *mut SDL_Surface,
+                                        surfLock: *mut c_void, callback: *mut c_void);
+                pub fn SMPEG_loop(mpeg: *mut SMPEG, repeat: c_int);
+                pub fn SMPEG_scaleXY(mpeg: *mut SMPEG, width: c_int, height: c_int);
+                pub fn SMPEG_scale(mpeg: *mut SMPEG, scale: c_int);
+                pub fn SMPEG_move(mpeg: *mut SMPEG, x: c_int, y: c_int);
+                pub fn SMPEG_setdisplayregion(mpeg: *mut SMPEG, x: c_int, y: c_int,
+                                              w: c_int, h: c_int);
+                pub fn SMPEG_play(mpeg: *mut SMPEG);
+                pub fn SMPEG_pause(mpeg: *mut SMPEG);
+                pub fn SMPEG_stop(mpeg: *mut SMPEG);
+                pub fn SMPEG_rewind(mpeg: *mut SMPEG);
+                pub fn SMPEG_seek(mpeg: *mut SMPEG, bytes: c_int);
+                pub fn SMPEG_skip(mpeg: *mut SMPEG, seconds: c_float);
+                pub fn SMPEG_renderFrame(mpeg: *mut SMPEG, framenum: c_int);
+                pub fn SMPEG_renderFinal(mpeg: *mut SMPEG, dst: *mut SDL_Surface,
+                                         x: c_int, y: c_int);
+                // XXX SMPEG_Filter unimplemented
+                pub fn SMPEG_filter(mpeg: *mut SMPEG, filter: *mut c_void) -> *mut c_void;
+                pub fn SMPEG_error(mpeg: *mut SMPEG) -> *mut c_char;
+                pub fn SMPEG_playAudio(mpeg: *mut SMPEG, stream: *mut u8, len: c_int) -> c_int;
+                pub fn SMPEG_playAudioSDL(mpeg: *mut c_void, stream: *mut u8, len: c_int);
+                pub fn SMPEG_wantedSpec(mpeg: *mut SMPEG, wanted: *mut SDL_AudioSpec) -> c_int;
+                pub fn SMPEG_actualSpec(mpeg: *mut SMPEG, spec: *mut SDL_AudioSpec);
+            }
+        }
+
+        pub struct MPEG {
+            pub raw: *mut ll::SMPEG
+        }
+
+        fn wrap_mpeg(raw: *mut ll::SMPEG) -> MPEG {
+            MPEG { raw: raw }
+        }
+
+        impl Drop for MPEG {
+            fn drop(&mut self) {
+                unsafe { ll::SMPEG_delete(self.raw); }
+            }
+        }
+
+        impl MPEG {
+            pub fn from_path(path: &Path) -> Result<MPEG, String> {
+                let raw = unsafe {
+                    let path = path.to_c_str();
+                    ll::SMPEG_new(path.as_ptr(), null_mut(), 0)
+                };
+
+                if raw.is_null() { Err(::sdl::get_error()) }
+                else { Ok(wrap_mpeg(raw)) }
+            }
+
+            pub fn status(&self) -> SMPEGstatus {
+                unsafe { ll::SMPEG_status(self.raw) }
+            }
+
+            pub fn set_volume(&self, volume: int) {
+                unsafe { ll::SMPEG_setvolume(self.raw, volume as c_int); }
+            }
+
+            pub fn set_display(&self, surface: &Surface) {
+                unsafe {
+                    ll::SMPEG_setdisplay(self.raw, surface.raw, null_mut(), null_mut());
+                }
+            }
+
+            pub fn enable_video(&self, enable: bool) {
+                unsafe { ll::SMPEG_enablevideo(self.raw, enable as c_int); }
+            }
+
+            pub fn enable_audio(&self, enable: bool) {
+                unsafe { ll::SMPEG_enableaudio(self.raw, enable as c_int); }
+            }
+
+            /// Makes the audio track decoded to 16-bit stereo samples at given sampling rate,
+            /// which are then pulled with `ll::SMPEG_playAudio` instead of SMPEG's own audio
+            /// device. Returns false if the movie has no audio track.
+            pub fn set_audio_rate(&self, samplerate: uint) -> bool {
+                unsafe {
+                    let mut spec: SDL_AudioSpec = std::mem::zeroed();
+                    if ll::SMPEG_wantedSpec(self.raw, &mut spec) == 0 { return false; }
+                    spec.freq = samplerate as c_int;
+                    spec.format = audio::S16_AUDIO_FORMAT as u16;
+                    spec.channels = 2;
+                    ll::SMPEG_actualSpec(self.raw, &mut spec);
+                }
+                true
+            }
+
+            pub fn set_loop(&self, repeat: bool) {
+                unsafe { ll::SMPEG_loop(self.raw, repeat as c_int); }
+            }
+
+            pub fn resize(&self, width: int, height: int) {
+                unsafe { ll::SMPEG_scaleXY(self.raw, width as c_int, height as c_int); }
+            }
+
+            pub fn scale_by(&self, scale: int) {
+                unsafe { ll::SMPEG_scale(self.raw, scale as c_int); }
+            }
+
+            pub fn move_by(&self, x: int, y: int) {
+                unsafe { ll::SMPEG_move(self.raw, x as c_int, y as c_int); }
+            }
+
+            pub fn set_display_region(&self, x: int, y: int, w: int, h: int) {
+                unsafe {
+                    ll::SMPEG_setdisplayregion(self.raw, x as c_int, y as c_int,
+                                               w as c_int, h as c_int);
+                }
+            }
+
+            pub fn play(&self) {
+                unsafe { ll::SMPEG_play(self.raw); }
+            }
+
+            pub fn pause(&self) {
+                unsafe { ll::SMPEG_pause(self.raw); }
+            }
+
+            pub fn stop(&self) {
+                unsafe { ll::SMPEG_stop(self.raw); }
+            }
+
+            pub fn rewind(&self) {
+                unsafe { ll::SMPEG_rewind(self.raw); }
+            }
+
+            pub fn seek(&self, bytes: int) {
+                unsafe { ll::SMPEG_seek(self.raw, bytes as c_int); }
+            }
+
+            pub fn skip(&self, seconds: f64) {
+                unsafe { ll::SMPEG_skip(self.raw, seconds as c_float); }
+            }
+
+            pub fn get_error(&self) -> String {
+                unsafe {
+                    let cstr = ll::SMPEG_error(self.raw);
+                    std::string::raw::from_buf(std::mem::transmute(&cstr))
+                }
+            }
+        }
+    }
+
+    /**
+     * A minimal binding for libopusfile, used for Opus sound resources which SDL_mixer cannot
+     * decode. Decoded samples are always 48 kHz, which should be resampled to the mixer rate.
+     *
+     * libopusfile is only linked with the `opus` feature. Otherwise `decode` always fails, so
+     * that Opus sound resources are reported as missing.
+     */
+    pub mod opus {
+        #[cfg(feature = "opus")] use libc::{c_int, size_t};
+
+        /// The sample rate of decoded samples.
+        pub const OPUS_RATE: uint = 48000;
+
+        #[cfg(feature = "opus")]
+        pub mod ll {
+            #![allow(non_camel_case_types)]
+            use libc::{c_int, size_t};
+            pub enum OggOpusFile {}
+            #[link(name = "opusfile")]
+            extern {
+                pub fn op_open_memory(data: *const u8, size: size_t,
+                                      error: *mut c_int) -> *mut OggOpusFile;
+                pub fn op_read_stereo(of: *mut OggOpusFile, pcm: *mut i16,
+                                      buf_size: c_int) -> c_int;
+                pub fn op_free(of: *mut OggOpusFile);
+            }
+        }
+
+        /// Decodes the contents of the Opus file into 16-bit stereo samples at `OPUS_RATE`,
+        /// interleaved.
+        #[cfg(feature = "opus")]
+        pub fn decode(data: &[u8]) -> Result<Vec<i16>,String> {
+            let mut error = 0;
+            let of = unsafe {
+                ll::op_open_memory(data.as_ptr(), data.len() as size_t, &mut error)
+            };
+            if of.is_null() {
+                return Err(format!("cannot open the Opus stream (error {})", error));
+            }
+
+            let mut samples = Vec::new();
+            let mut buf = [0i16, ..11520]; // 120ms of stereo samples, the maximum packet size
+            let mut ret = Ok(());
+            loop {
+                let nread = unsafe { ll::op_read_stereo(of, buf.as_mut_ptr(),
+                                                        buf.len() as c_int) };
+                if nread < 0 {
+                    ret = Err(format!("corrupted Opus stream (error {})", nread));
+                    break;
+                }
+                if nread == 0 { break; }
+                samples.push_all(buf[..nread as uint * 2]);
+            }
+            unsafe { ll::op_free(of); }
+            ret.map(|()| samples)
+        }
+
+        /// Fails as libopusfile is not linked.
+        #[cfg(not(feature = "opus"))]
+        pub fn decode(_data: &[u8]) -> Result<Vec<i16>,String> {
+            Err("Opus support is not compiled in (the `opus` feature)".to_string())
+        }
+    }
+
+    /**
+     * A minimal binding for SDL_ttf, used for the text which the built-in bitmap font cannot show
+     * (e.g. Japanese and Korean metadata). The rendered text is only used as an alpha mask, so
+     * the caller can color it in its own way.
+     *
+     * SDL_ttf is only linked with the `ttf` feature. Otherwise fonts cannot be opened, so that
+     * such text is printed with the bitmap font.
+     */
+    pub mod ttf {
+        #[cfg(feature = "ttf")] use libc::c_int;
+        #[cfg(feature = "ttf")] use sdl::video::ll::SDL_FreeSurface;
+
+        #[cfg(feature = "ttf")]
+        pub mod ll {
+            #![allow(non_camel_case_types)]
+            use libc::{c_int, c_char};
+            use sdl::video::ll::SDL_Surface;
+            pub enum TTF_Font {}
+            #[repr(C)]
+            pub struct SDL_Color { pub r: u8, pub g: u8, pub b: u8, pub unused: u8 }
+            #[link(name = "SDL_ttf")]
+            extern {
+                pub fn TTF_WasInit() -> c_int;
+                pub fn TTF_Init() -> c_int;
+                pub fn TTF_OpenFont(file: *const c_char, ptsize: c_int) -> *mut TTF_Font;
+                pub fn TTF_CloseFont(font: *mut TTF_Font);
+                pub fn TTF_RenderUTF8_Blended(font: *mut TTF_Font, text: *const c_char,
+                                              fg: SDL_Color) -> *mut SDL_Surface;
+            }
+        }
+
+        /// An alpha mask of the rendered text: the width, the height and one byte per pixel.
+        pub type TextMask = (uint, uint, Vec<u8>);
+
+        /// A TrueType font opened at the particular size.
+        #[cfg(feature = "ttf")]
+        pub struct Font {
+            raw: *mut ll::TTF_Font
+        }
+
+        /// A TrueType font, which cannot be opened as SDL_ttf is not linked.
+        #[cfg(not(feature = "ttf"))]
+        pub struct Font;
+
+        #[cfg(feature = "ttf")]
+        impl Drop for Font {
+            fn drop(&mut self) {
+                unsafe { ll::TTF_CloseFont(self.raw); }
+            }
+        }
+
+        #[cfg(feature = "ttf")]
+        impl Font {
+            /// Opens a font file at given point size. SDL_ttf is initialized as needed.
+            pub fn open(path: &Path, ptsize: uint) -> Result<Font,String> {
+                unsafe {
+                    if ll::TTF_WasInit() == 0 && ll::TTF_Init() < 0 {
+                        return Err(::sdl::get_error());
+                    }
+                    let path = path.to_c_str();
+                    let raw = ll::TTF_OpenFont(path.as_ptr(), ptsize as c_int);
+                    if raw.is_null() { Err(::sdl::get_error()) } else { Ok(Font { raw: raw }) }
+                }
+            }
+
+            /// Renders a string with anti-aliasing, and returns its alpha mask.
+            pub fn render(&self, s: &str) -> Option<TextMask> {
+                let white = ll::SDL_Color { r: 255, g: 255, b: 255, unused: 0 };
+                let surface = s.with_c_str(|s| unsafe {
+                    ll::TTF_RenderUTF8_Blended(self.raw, s, white)
+                });
+                if surface.is_null() { return None; }
+
+                // the blended text is always in the 32-bit ARGB format
+                unsafe {
+                    let (w, h) = ((*surface).w as uint, (*surface).h as uint);
+                    let pitch = (*surface).pitch as uint / 4;
+                    let pixels = (*surface).pixels as *const u32;
+                    let mut mask = Vec::with_capacity(w * h);
+                    for y in range(0, h) {
+                        for x in range(0, w) {
+                            mask.push((*pixels.offset((x + y * pitch) as int) >> 24) as u8);
+                        }
+                    }
+                    SDL_FreeSurface(surface);
+                    Some((w, h, mask))
+                }
+            }
+        }
+
+        #[cfg(not(feature = "ttf"))]
+        impl Font {
+            /// Fails as SDL_ttf is not linked.
+            pub fn open(_path: &Path, _ptsize: uint) -> Result<Font,String> {
+                Err("TrueType fonts are not compiled in (the `ttf` feature)".to_string())
+            }
+
+            /// Never called as no font can be opened.
+            pub fn render(&self, _s: &str) -> Option<TextMask> {
+                None
+            }
+        }
+    }
+
+    /**
+     * A minimal binding for OpenGL 1.2, and the presenter built on it. Pixels are uploaded as
+     * `GL_BGRA` and `GL_UNSIGNED_INT_8_8_8_8_REV` which require OpenGL 1.2, so the version is
+     * checked when the presenter is created.
+     *
+     * Most screens are entirely composed in the software surface as usual, and then uploaded to
+     * a texture which is drawn to the window only for the vertical synchronization. The game play
+     * instead keeps the sprites and BGA images as textures and draws lanes, notes and BGAs as
+     * textured quads (see `gfx::Canvas`), so that only the panels and texts are composed in
+     * the software surface and overlaid.
+     */
+    pub mod gl {
+        #![allow(non_camel_case_types)]
+
+        use libc::c_void;
+        use std::cmp;
+        use std::cell::RefCell;
+        use std::collections::HashMap;
+        use std::ptr::{null, null_mut};
+        use sdl::video;
+        use sdl::video::{Surface, Color, RGB, RGBA};
+        use self::ll::*;
+
+        pub mod ll {
+            use libc::{c_int, c_uint, c_uchar, c_float, c_double, c_void};
+            pub type GLenum = c_uint;
+            pub type GLuint = c_uint;
+            pub type GLint = c_int;
+            pub type GLsizei = c_int;
+            pub type GLfloat = c_float;
+            pub type GLdouble = c_double;
+            pub type GLbitfield = c_uint;
+            pub type GLubyte = c_uchar;
+            pub const GL_TEXTURE_2D: GLenum = 0x0DE1;
+            pub const GL_TEXTURE_MAG_FILTER: GLenum = 0x2800;
+            pub const GL_TEXTURE_MIN_FILTER: GLenum = 0x2801;
+            pub const GL_TEXTURE_WRAP_S: GLenum = 0x2802;
+            pub const GL_TEXTURE_WRAP_T: GLenum = 0x2803;
+            pub const GL_NEAREST: GLint = 0x2600;
+            pub const GL_LINEAR: GLint = 0x2601;
+            pub const GL_CLAMP: GLint = 0x2900;
+            pub const GL_RGB: GLint = 0x1907;
+            pub const GL_RGBA: GLint = 0x1908;
+            pub const GL_BGRA: GLenum = 0x80E1;
+            pub const GL_UNSIGNED_BYTE: GLenum = 0x1401;
+            pub const GL_UNSIGNED_INT_8_8_8_8_REV: GLenum = 0x8367;
+            pub const GL_QUADS: GLenum = 0x0007;
+            pub const GL_COLOR_BUFFER_BIT: GLbitfield = 0x4000;
+            pub const GL_MODELVIEW: GLenum = 0x1700;
+            pub const GL_PROJECTION: GLenum = 0x1701;
+            pub const GL_BLEND: GLenum = 0x0BE2;
+            pub const GL_SRC_ALPHA: GLenum = 0x0302;
+            pub const GL_ONE_MINUS_SRC_ALPHA: GLenum = 0x0303;
+            pub const GL_SCISSOR_TEST: GLenum = 0x0C11;
+            pub const GL_VERSION: GLenum = 0x1F02;
+            pub const GL_UNPACK_ROW_LENGTH: GLenum = 0x0CF2;
+            pub const GL_PACK_ROW_LENGTH: GLenum = 0x0D02;
+            pub const SDL_GL_DOUBLEBUFFER: c_int = 5;
+            pub const SDL_GL_SWAP_CONTROL: c_int = 16;
+            #[cfg_attr(target_os = "windows", link(name = "opengl32"))]
+            #[cfg_attr(target_os = "macos", link(name = "OpenGL", kind = "framework"))]
+            #[cfg_attr(not(any(target_os = "windows", target_os = "macos")), link(name = "GL"))]
+            extern "system" {
+                pub fn glGetString(name: GLenum) -> *const GLubyte;
+                pub fn glViewport(x: GLint, y: GLint, width: GLsizei, height: GLsizei);
+                pub fn glMatrixMode(mode: GLenum);
+                pub fn glLoadIdentity();
+                pub fn glOrtho(left: GLdouble, right: GLdouble, bottom: GLdouble, top: GLdouble,
+                               near: GLdouble, far: GLdouble);
+                pub fn glEnable(cap: GLenum);
+                pub fn glDisable(cap: GLenum);
+                pub fn glBlendFunc(sfactor: GLenum, dfactor: GLenum);
+                pub fn glScissor(x: GLint, y: GLint, width: GLsizei, height: GLsizei);
+                pub fn glClear(mask: GLbitfield);
+                pub fn glGenTextures(n: GLsizei, textures: *mut GLuint);
+                pub fn glDeleteTextures(n: GLsizei, textures: *const GLuint);
+                pub fn glBindTexture(target: GLenum, texture: GLuint);
+                pub fn glTexParameteri(target: GLenum, pname: GLenum, param: GLint);
+                pub fn glPixelStorei(pname: GLenum, param: GLint);
+                pub fn glTexImage2D(target: GLenum, level: GLint, internalformat: GLint,
+                                    width: GLsizei, height: GLsizei, border: GLint,
+                                    format: GLenum, kind: GLenum, pixels: *const c_void);
+                pub fn glTexSubImage2D(target: GLenum, level: GLint, xoffset: GLint,
+                                       yoffset: GLint, width: GLsizei, height: GLsizei,
+                                       format: GLenum, kind: GLenum, pixels: *const c_void);
+                pub fn glReadPixels(x: GLint, y: GLint, width: GLsizei, height: GLsizei,
+                                    format: GLenum, kind: GLenum, pixels: *mut c_void);
+                pub fn glBegin(mode: GLenum);
+                pub fn glEnd();
+                pub fn glColor4ub(r: GLubyte, g: GLubyte, b: GLubyte, a: GLubyte);
+                pub fn glTexCoord2f(s: GLfloat, t: GLfloat);
+                pub fn glVertex2f(x: GLfloat, y: GLfloat);
+            }
+            extern {
+                pub fn SDL_GL_SetAttribute(attr: c_int, value: c_int) -> c_int;
+                pub fn SDL_GL_SwapBuffers();
+                pub fn SDL_FillRect(dst: *mut c_void, dstrect: *mut c_void, color: u32) -> c_int;
+            }
+        }
+
+        /// The width and height of the texture for the whole frame. Textures should have
+        /// the power of two sizes before OpenGL 2.0.
+        const TEXTURE_SIZE: uint = 1024;
+
+        /// The width and height of tiles for other surfaces. The sprites can be wider than
+        /// the maximum texture size, so they are split into tiles small enough for every
+        /// implementation in practice.
+        const TILE_SIZE: uint = 512;
+
+        /// Requests the double buffering and the vertical synchronization. Should be called
+        /// before setting the OpenGL video mode.
+        pub fn set_attributes() {
+            unsafe {
+                SDL_GL_SetAttribute(SDL_GL_DOUBLEBUFFER, 1);
+                SDL_GL_SetAttribute(SDL_GL_SWAP_CONTROL, 1);
+            }
+        }
+
+        /// Returns the major and minor version of the current OpenGL context, or `(0, 0)` if
+        /// unknown.
+        fn version() -> (uint, uint) {
+            let version = unsafe {
+                let version = glGetString(GL_VERSION);
+                if version.is_null() { return (0, 0); }
+                ::std::c_str::CString::new(version as *const ::libc::c_char, false)
+            };
+            // the version string starts with `major.minor`, optionally followed by others
+            let version = version.as_str().unwrap_or("");
+            let mut parts = version.split('.').map(|part| {
+                let digits: String = part.chars().take_while(|&c| '0' <= c && c <= '9').collect();
+                from_str::<uint>(digits[]).unwrap_or(0)
+            });
+            let major = parts.next().unwrap_or(0);
+            let minor = parts.next().unwrap_or(0);
+            (major, minor)
+        }
+
+        /// Sets the parameters of the bound texture, and allocates it with given size.
+        unsafe fn init_texture(size: uint, filter: GLint) {
+            glTexParameteri(GL_TEXTURE_2D, GL_TEXTURE_MIN_FILTER, filter);
+            glTexParameteri(GL_TEXTURE_2D, GL_TEXTURE_MAG_FILTER, filter);
+            glTexParameteri(GL_TEXTURE_2D, GL_TEXTURE_WRAP_S, GL_CLAMP);
+            glTexParameteri(GL_TEXTURE_2D, GL_TEXTURE_WRAP_T, GL_CLAMP);
+            glTexImage2D(GL_TEXTURE_2D, 0, GL_RGBA, size as GLsizei, size as GLsizei, 0, GL_BGRA,
+                         GL_UNSIGNED_INT_8_8_8_8_REV, null());
+        }
+
+        /// Draws the area of the bound texture (with given size) at `(sx,sy)` as a quad at
+        /// `(dx,dy)`. The projection maps a unit to a pixel, so the texture is not scaled.
+        unsafe fn draw_quad((sx, sy): (uint, uint), (dx, dy): (uint, uint), (w, h): (uint, uint),
+                            size: uint) {
+            let size = size as GLfloat;
+            let (u1, v1) = (sx as GLfloat / size, sy as GLfloat / size);
+            let (u2, v2) = ((sx + w) as GLfloat / size, (sy + h) as GLfloat / size);
+            let (x1, y1) = (dx as GLfloat, dy as GLfloat);
+            let (x2, y2) = ((dx + w) as GLfloat, (dy + h) as GLfloat);
+            glBegin(GL_QUADS);
+            glTexCoord2f(u1, v1); glVertex2f(x1, y1);
+            glTexCoord2f(u2, v1); glVertex2f(x2, y1);
+            glTexCoord2f(u2, v2); glVertex2f(x2, y2);
+            glTexCoord2f(u1, v2); glVertex2f(x1, y2);
+            glEnd();
+        }
+
+        /// Converts pixels of given 32-bit surface to `0xAARRGGBB` integers. The alpha comes from
+        /// the alpha channel if any, or the per-surface alpha, and the color key is transparent.
+        fn argb_pixels(surface: &Surface) -> Vec<u32> {
+            let width = surface.get_width() as uint;
+            let height = surface.get_height() as uint;
+            let (fmt, flags, pitch) = unsafe {
+                ((*surface.raw).format, (*surface.raw).flags, (*surface.raw).pitch as uint / 4)
+            };
+            let (hasalpha, colorkey, surfacealpha) = unsafe {
+                ((*fmt).Amask != 0,
+                 if flags & video::SrcColorKey as u32 != 0 {Some((*fmt).colorkey)} else {None},
+                 if flags & video::SrcAlpha as u32 != 0 {(*fmt).alpha} else {255})
+            };
+
+            let mut argb = Vec::with_capacity(width * height);
+            surface.with_lock(|pixels| {
+                let pixels: &mut [u32] = unsafe { ::std::mem::transmute(pixels) };
+                for y in range(0, height) {
+                    for x in range(0, width) {
+                        let pixel = pixels[y * pitch + x];
+                        let (r, g, b, a) = match Color::from_mapped(pixel, fmt as *const _) {
+                            RGB(r, g, b) => (r, g, b, surfacealpha),
+                            RGBA(r, g, b, a) => (r, g, b, if hasalpha {a} else {surfacealpha}),
+                        };
+                        let a = if colorkey == Some(pixel) {0} else {a};
+                        argb.push((a as u32 << 24) | (r as u32 << 16) | (g as u32 << 8) | b as u32);
+                    }
+                }
+            });
+            argb
+        }
+
+        /// A surface uploaded as textures, split into tiles of `TILE_SIZE` by `TILE_SIZE` pixels
+        /// in the row-major order.
+        struct Texture {
+            /// The texture names of tiles.
+            tiles: Vec<GLuint>,
+            /// The width of the surface.
+            width: uint,
+            /// The height of the surface.
+            height: uint,
+        }
+
+        impl Texture {
+            /// Creates textures for given surface and uploads its pixels.
+            fn new(surface: &Surface) -> Texture {
+                let width = surface.get_width() as uint;
+                let height = surface.get_height() as uint;
+                let ntiles = ((width + TILE_SIZE - 1) / TILE_SIZE) *
+                             ((height + TILE_SIZE - 1) / TILE_SIZE);
+                let mut tiles = Vec::from_elem(ntiles, 0 as GLuint);
+                unsafe {
+                    glGenTextures(ntiles as GLsizei, tiles.as_mut_ptr());
+                    for &tile in tiles.iter() {
+                        glBindTexture(GL_TEXTURE_2D, tile);
+                        init_texture(TILE_SIZE, GL_NEAREST);
+                    }
+                }
+                let texture = Texture { tiles: tiles, width: width, height: height };
+                texture.upload(surface);
+                texture
+            }
+
+            /// Returns the position of given tile in the surface.
+            fn tile_pos(&self, i: uint) -> (uint, uint) {
+                let ncols = (self.width + TILE_SIZE - 1) / TILE_SIZE;
+                ((i % ncols) * TILE_SIZE, (i / ncols) * TILE_SIZE)
+            }
+
+            /// Uploads the current pixels of given surface, which should have the same size.
+            fn upload(&self, surface: &Surface) {
+                let pixels = argb_pixels(surface);
+                unsafe { glPixelStorei(GL_UNPACK_ROW_LENGTH, self.width as GLint); }
+                for (i, &tile) in self.tiles.iter().enumerate() {
+                    let (x, y) = self.tile_pos(i);
+                    let w = cmp::min(TILE_SIZE, self.width - x);
+                    let h = cmp::min(TILE_SIZE, self.height - y);
+                    unsafe {
+                        glBindTexture(GL_TEXTURE_2D, tile);
+                        glTexSubImage2D(GL_TEXTURE_2D, 0, 0, 0, w as GLsizei, h as GLsizei,
+                                        GL_BGRA, GL_UNSIGNED_INT_8_8_8_8_REV,
+                                        pixels[y * self.width + x..].as_ptr() as *const c_void);
+                    }
+                }
+            }
+
+            /// Draws the area of the surface at `(sx,sy)` to `(dx,dy)` with given size. The area
+            /// is clipped to the surface as in `SDL_BlitSurface`.
+            fn draw(&self, (sx, sy): (uint, uint), (dx, dy): (uint, uint), (w, h): (uint, uint)) {
+                for (i, &tile) in self.tiles.iter().enumerate() {
+                    // the intersection of the area and the tile
+                    let (tx, ty) = self.tile_pos(i);
+                    let (x1, y1) = (cmp::max(sx, tx), cmp::max(sy, ty));
+                    let x2 = cmp::min(cmp::min(sx + w, tx + TILE_SIZE), self.width);
+                    let y2 = cmp::min(cmp::min(sy + h, ty + TILE_SIZE), self.height);
+                    if x1 >= x2 || y1 >= y2 { continue; }
+                    unsafe {
+                        glBindTexture(GL_TEXTURE_2D, tile);
+                        draw_quad((x1 - tx, y1 - ty), (dx + x1 - sx, dy + y1 - sy),
+                                  (x2 - x1, y2 - y1), TILE_SIZE);
+                    }
+                }
+            }
+        }
+
+        impl Drop for Texture {
+            fn drop(&mut self) {
+                unsafe { glDeleteTextures(self.tiles.len() as GLsizei, self.tiles.as_ptr()); }
+            }
+        }
+
+        /// Clears the overlay to be presented by `Presenter::present_overlay`, so that pixels not
+        /// drawn afterwards are transparent. The overlay should be created by `gfx::new_surface`,
+        /// which has no alpha channel; SDL leaves the unused upper byte zero when drawing,
+        /// so the clear value sets it instead to mark untouched pixels.
+        pub fn clear_overlay(overlay: &Surface) {
+            unsafe { SDL_FillRect(overlay.raw as *mut c_void, null_mut(), 0xff000000); }
+        }
+
+        /// The OpenGL state for presenting frames. Textures are deleted when dropped, so
+        /// the presenter should be dropped before the video mode changes.
+        pub struct Presenter {
+            /// The texture where the frame or overlay is uploaded.
+            texture: GLuint,
+            /// The width of the frame, up to `TEXTURE_SIZE`.
+            width: uint,
+            /// The height of the frame, up to `TEXTURE_SIZE`.
+            height: uint,
+            /// Textures for surfaces drawn by `blit_area`, keyed by the address of the surface.
+            /// The surfaces should outlive the presenter, as the address can be reused.
+            textures: RefCell<HashMap<uint,Texture>>,
+        }
+
+        impl Presenter {
+            /// Creates the texture and sets the projection for the frame of given size. Should be
+            /// called after setting the OpenGL video mode. Fails if OpenGL 1.2 is not available.
+            pub fn new(width: uint, height: uint) -> Result<Presenter,String> {
+                let (major, minor) = version();
+                if (major, minor) < (1, 2) {
+                    return Err(format!("OpenGL 1.2 or later is required but {}.{} is found",
+                                       major, minor));
+                }
+
+                let mut texture = 0;
+                unsafe {
+                    glGenTextures(1, &mut texture);
+                    glBindTexture(GL_TEXTURE_2D, texture);
+                    init_texture(TEXTURE_SIZE, GL_LINEAR);
+                    glEnable(GL_TEXTURE_2D);
+
+                    glViewport(0, 0, width as GLsizei, height as GLsizei);
+                    glMatrixMode(GL_PROJECTION);
+                    glLoadIdentity();
+                    glOrtho(0.0, width as GLdouble, height as GLdouble, 0.0, -1.0, 1.0);
+                    glMatrixMode(GL_MODELVIEW);
+                    glLoadIdentity();
+                }
+                Ok(Presenter { texture: texture, width: width, height: height,
+                               textures: RefCell::new(HashMap::new()) })
+            }
+
+            /// Uploads the frame (a 32-bit surface with the size given to `new`, as created by
+            /// `gfx::new_surface`) and draws it. Pixels are read as native-endian 32-bit
+            /// integers, so that the byte order of the host does not matter.
+            fn draw_frame(&self, frame: &Surface) {
+                let pitch = unsafe { (*frame.raw).pitch / 4 } as GLint;
+                frame.with_lock(|pixels| unsafe {
+                    glBindTexture(GL_TEXTURE_2D, self.texture);
+                    glPixelStorei(GL_UNPACK_ROW_LENGTH, pitch);
+                    glTexSubImage2D(GL_TEXTURE_2D, 0, 0, 0, self.width as GLsizei,
+                                    self.height as GLsizei, GL_BGRA, GL_UNSIGNED_INT_8_8_8_8_REV,
+                                    pixels.as_ptr() as *const c_void);
+                    draw_quad((0, 0), (0, 0), (self.width, self.height), TEXTURE_SIZE);
+                });
+            }
+
+            /// Uploads the whole frame composed in the software surface and swaps buffers.
+            pub fn present(&self, frame: &Surface) {
+                unsafe {
+                    glDisable(GL_SCISSOR_TEST);
+                    glDisable(GL_BLEND);
+                    glClear(GL_COLOR_BUFFER_BIT);
+                }
+                self.draw_frame(frame);
+                unsafe { SDL_GL_SwapBuffers(); }
+            }
+
+            /// Starts a new frame drawn by `blit_area` and `fill_area`, which should be finished
+            /// by `present_overlay`.
+            pub fn begin_frame(&self) {
+                unsafe {
+                    glDisable(GL_SCISSOR_TEST);
+                    glClear(GL_COLOR_BUFFER_BIT);
+                    glEnable(GL_BLEND);
+                    glBlendFunc(GL_SRC_ALPHA, GL_ONE_MINUS_SRC_ALPHA);
+                }
+            }
+
+            /// Draws the area of `src` at `srcxy` to `dstxy` with given size. The surface is
+            /// uploaded at the first use with its transparency, and kept as textures until
+            /// `refresh` is called.
+            pub fn blit_area(&self, src: &Surface, srcxy: (uint, uint), dstxy: (uint, uint),
+                             wh: (uint, uint)) {
+                let key = src.raw as uint;
+                let mut textures = self.textures.borrow_mut();
+                if !textures.contains_key(&key) {
+                    textures.insert(key, Texture::new(src));
+                }
+                textures.find(&key).unwrap().draw(srcxy, dstxy, wh);
+            }
+
+            /// Uploads the current pixels of `src` again if it has been drawn, for surfaces
+            /// updated after the first `blit_area` such as movies.
+            pub fn refresh(&self, src: &Surface) {
+                match self.textures.borrow().find(&(src.raw as uint)) {
+                    Some(texture) => texture.upload(src),
+                    None => {}
+                }
+            }
+
+            /// Fills the area with given color. `RGBA` colors keep the underlying pixels by
+            /// the alpha, same as `gfx::SurfacePixels::put_blended_pixel`.
+            pub fn fill_area(&self, (x, y): (uint, uint), (w, h): (uint, uint), color: Color) {
+                let (r, g, b, a) = match color {
+                    RGB(r, g, b) => (r, g, b, 255),
+                    RGBA(r, g, b, a) => (r, g, b, 255 - a),
+                };
+                let (x1, y1) = (x as GLfloat, y as GLfloat);
+                let (x2, y2) = ((x + w) as GLfloat, (y + h) as GLfloat);
+                unsafe {
+                    glDisable(GL_TEXTURE_2D);
+                    glColor4ub(r, g, b, a);
+                    glBegin(GL_QUADS);
+                    glVertex2f(x1, y1);
+                    glVertex2f(x2, y1);
+                    glVertex2f(x2, y2);
+                    glVertex2f(x1, y2);
+                    glEnd();
+                    glColor4ub(255, 255, 255, 255);
+                    glEnable(GL_TEXTURE_2D);
+                }
+            }
+
+            /// Restricts the further drawing to given area until `reset_clip_area` is called.
+            pub fn set_clip_area(&self, (x, y): (uint, uint), (w, h): (uint, uint)) {
+                // the origin of the window coordinates is at the bottom left
+                let bottom = self.height - cmp::min(y + h, self.height);
+                unsafe {
+                    glEnable(GL_SCISSOR_TEST);
+                    glScissor(x as GLint, bottom as GLint, w as GLsizei, h as GLsizei);
+                }
+            }
+
+            /// Removes the restriction by `set_clip_area`.
+            pub fn reset_clip_area(&self) {
+                unsafe { glDisable(GL_SCISSOR_TEST); }
+            }
+
+            /// Draws the overlay (cleared by `clear_overlay` beforehand) over the frame started
+            /// by `begin_frame` and swaps buffers. The composed frame is also read back to
+            /// `capture` (a 32-bit surface with the same size) if given.
+            pub fn present_overlay(&self, overlay: &Surface, capture: Option<&Surface>) {
+                // the upper byte of untouched pixels is 0xff, and that of others is zero
+                unsafe {
+                    glDisable(GL_SCISSOR_TEST);
+                    glBlendFunc(GL_ONE_MINUS_SRC_ALPHA, GL_SRC_ALPHA);
+                }
+                self.draw_frame(overlay);
+                for &capture in capture.iter() {
+                    self.read_frame(capture);
+                }
+                unsafe {
+                    glBlendFunc(GL_SRC_ALPHA, GL_ONE_MINUS_SRC_ALPHA);
+                    SDL_GL_SwapBuffers();
+                }
+            }
+
+            /// Reads the frame being drawn to given surface.
+            fn read_frame(&self, dst: &Surface) {
+                let pitch = unsafe { (*dst.raw).pitch } as uint;
+                let rowlen = self.width * 4;
+                dst.with_lock(|pixels| {
+                    unsafe {
+                        glPixelStorei(GL_PACK_ROW_LENGTH, (pitch / 4) as GLint);
+                        glReadPixels(0, 0, self.width as GLsizei, self.height as GLsizei, GL_BGRA,
+                                     GL_UNSIGNED_INT_8_8_8_8_REV,
+                                     pixels.as_mut_ptr() as *mut c_void);
+                    }
+                    // the rows are read from the bottom
+                    for y in range(0, self.height / 2) {
+                        let (top, bottom) = (y * pitch, (self.height - 1 - y) * pitch);
+                        for i in range(0, rowlen) { pixels.swap(top + i, bottom + i); }
+                    }
+                });
+            }
+        }
+
+        impl Drop for Presenter {
+            fn drop(&mut self) {
+                self.textures.borrow_mut().clear();
+                unsafe { glDeleteTextures(1, &self.texture); }
+            }
+        }
+    }
+
+    /// Win32 API wrappers.
+    #[cfg(target_os = "windows")]
+    pub mod win32 {
+        pub mod ll {
+            #![allow(non_camel_case_types)]
+
+            use libc::{c_int, c_uint, c_void};
+            use libc::types::os::arch::extra::{BOOL, WORD, DWORD, HANDLE, LPWSTR, LPCWSTR};
+
+            pub type HWND = HANDLE;
+            pub type HINSTANCE = HANDLE;
+            pub type HDROP = HANDLE;
+            pub type WPARAM = uint;
+            pub type LPARAM = int;
+
+            pub const OFN_HIDEREADONLY: DWORD = 4;
+            pub const WM_DROPFILES: c_uint = 0x0233;
+
+            #[allow(non_snake_case)]
+            #[repr(C)]
+            pub struct OPENFILENAMEW {
+                pub lStructSize: DWORD,
+                pub hwndOwner: HWND,
+                pub hInstance: HINSTANCE,
+                pub lpstrFilter: LPCWSTR,
+                pub lpstrCustomFilter: LPWSTR,
+                pub nMaxCustFilter: DWORD,
+                pub nFilterIndex: DWORD,
+                pub lpstrFile: LPWSTR,
+                pub nMaxFile: DWORD,
+                pub lpstrFileTitle: LPWSTR,
+                pub nMaxFileTitle: DWORD,
+                pub lpstrInitialDir: LPCWSTR,
+                pub lpstrTitle: LPCWSTR,
+                pub Flags: DWORD,
+                pub nFileOffset: WORD,
+                pub nFileExtension: WORD,
+                pub lpstrDefExt: LPCWSTR,
+                pub lCustData: DWORD,
+                pub lpfnHook: *mut (), // XXX LPOFNHOOKPROC = fn(HWND,c_uint,WPARAM,LPARAM)->c_uint
+                pub lpTemplateName: LPCWSTR,
+                pub pvReserved: *mut c_void,
+                pub dwReserved: DWORD,
+                pub FlagsEx: DWORD,
+            }
+
+            #[link(name = "user32")]
+            extern "system" {
+                pub fn MessageBoxW(hWnd: HWND, lpText: LPCWSTR, lpCaption: LPCWSTR,
+                                   uType: c_uint) -> c_int;
+            }
+
+            #[link(name = "comdlg32")]
+            extern "system" {
+                pub fn GetOpenFileNameW(lpofn: *mut OPENFILENAMEW) -> BOOL;
+            }
+
+            #[link(name = "shell32")]
+            extern "system" {
+                pub fn DragAcceptFiles(hWnd: HWND, fAccept: BOOL);
+                pub fn DragQueryFileW(hDrop: HDROP, iFile: c_uint, lpszFile: LPWSTR,
+                                      cch: c_uint) -> c_uint;
+                pub fn DragFinish(hDrop: HDROP);
+            }
+
+            // SDL 1.2 bindings for the system window manager events, which are missing from
+            // rust-sdl. (C: `SDL_syswm.h`)
+
+            pub const SDL_SYSWMEVENT: u8 = 13;
+            pub const SDL_ENABLE: c_int = 1;
+
+            #[repr(C)]
+            pub struct SDL_version {
+                pub major: u8,
+                pub minor: u8,
+                pub patch: u8,
+            }
+
+            #[repr(C)]
+            pub struct SDL_SysWMinfo {
+                pub version: SDL_version,
+                pub window: HWND,
+                pub hglrc: HANDLE,
+            }
+
+            #[allow(non_snake_case)]
+            #[repr(C)]
+            pub struct SDL_SysWMmsg {
+                pub version: SDL_version,
+                pub hwnd: HWND,
+                pub msg: c_uint,
+                pub wParam: WPARAM,
+                pub lParam: LPARAM,
+            }
+
+            /// A prefix of `SDL_Event` shared by every event type, followed by the fields for
+            /// `SDL_SYSWMEVENT`.
+            #[repr(C)]
+            pub struct SDL_SysWMEvent {
+                pub type_: u8,
+                pub msg: *const SDL_SysWMmsg,
+            }
+
+            pub type SDL_EventFilter = extern "C" fn(event: *const SDL_SysWMEvent) -> c_int;
+
+            #[link(name = "SDL")]
+            extern "C" {
+                pub fn SDL_GetWMInfo(info: *mut SDL_SysWMinfo) -> c_int;
+                pub fn SDL_EventState(type_: u8, state: c_int) -> u8;
+                pub fn SDL_SetEventFilter(filter: Option<SDL_EventFilter>);
+                pub fn SDL_GetEventFilter() -> Option<SDL_EventFilter>;
+            }
+        }
+    }
+
+    /// Immediately terminates the program with given exit code.
+    pub fn exit(exitcode: int) -> ! {
+        // Rust: `std::os::set_exit_status` doesn't immediately terminate the program.
+        unsafe { libc::exit(exitcode as libc::c_int); }
+    }
+
+    /// Returns the current time in seconds since the Unix epoch.
+    pub fn unix_time() -> u64 {
+        extern { fn time(t: *mut libc::time_t) -> libc::time_t; }
+        unsafe { time(std::ptr::null_mut()) as u64 }
+    }
+
+    /// Sleeps for given milliseconds.
+    pub fn delay(msec: uint) {
+        extern { fn SDL_Delay(ms: u32); }
+        unsafe { SDL_Delay(msec as u32); }
+    }
+
+    /// Returns the peak resident set size of the process in bytes if available.
+    #[cfg(target_os = "windows")]
+    pub fn peak_rss() -> Option<u64> {
+        use libc::types::os::arch::extra::{BOOL, DWORD, HANDLE};
+
+        #[allow(non_snake_case)]
+        #[repr(C)]
+        struct PROCESS_MEMORY_COUNTERS {
+            cb: DWORD,
+            PageFaultCount: DWORD,
+            PeakWorkingSetSize: libc::size_t,
+            _rest: [libc::size_t, ..7],
+        }
+        #[link(name = "kernel32")]
+        extern "system" { fn GetCurrentProcess() -> HANDLE; }
+        #[link(name = "psapi")]
+        extern "system" {
+            fn GetProcessMemoryInfo(process: HANDLE, counters: *mut PROCESS_MEMORY_COUNTERS,
+                                    cb: DWORD) -> BOOL;
+        }
+
+        unsafe {
+            let mut counters: PROCESS_MEMORY_COUNTERS = std::mem::zeroed();
+            let cb = std::mem::size_of::<PROCESS_MEMORY_COUNTERS>() as DWORD;
+            counters.cb = cb;
+            if GetProcessMemoryInfo(GetCurrentProcess(), &mut counters, cb) == 0 { return None; }
+            Some(counters.PeakWorkingSetSize as u64)
+        }
+    }
+
+    /// Returns the peak resident set size of the process in bytes if available.
+    #[cfg(not(target_os = "windows"))]
+    pub fn peak_rss() -> Option<u64> {
+        // two `struct timeval`s precede `ru_maxrss`, which is followed by 13 more fields
+        #[repr(C)]
+        struct rusage {
+            ru_times: [libc::c_long, ..4],
+            ru_maxrss: libc::c_long,
+            _rest: [libc::c_long, ..13],
+        }
+        extern { fn getrusage(who: libc::c_int, usage: *mut rusage) -> libc::c_int; }
+        const RUSAGE_SELF: libc::c_int = 0;
+
+        let mut usage: rusage = unsafe { std::mem::zeroed() };
+        if unsafe { getrusage(RUSAGE_SELF, &mut usage) } != 0 { return None; }
+        // Linux reports in kilobytes, while Mac OS X reports in bytes
+        let scale = if cfg!(target_os = "macos") {1} else {1024};
+        Some(usage.ru_maxrss as u64 * scale)
+    }
+
+    /// Allocator statistics of the process. All counts are cumulative since the start.
+    pub struct HeapStats {
+        /// The number of bytes currently allocated.
+        pub allocated: u64,
+        /// The size and number of allocations for each small size class, in the increasing order.
+        pub small: Vec<(u64, u64)>,
+        /// The number of large allocations, which get their own pages.
+        pub large: u64,
+        /// The number of huge allocations, which get their own chunks.
+        pub huge: u64,
+    }
+
+    /// Returns the allocator statistics of the process if available. They are read from jemalloc,
+    /// the allocator of the Rust runtime, which has to be built with the statistics enabled.
+    pub fn heap_stats() -> Option<HeapStats> {
+        use libc::{c_char, c_int, c_uint, c_void, size_t};
+
+        extern {
+            fn je_mallctl(name: *const c_char, oldp: *mut c_void, oldlenp: *mut size_t,
+                          newp: *mut c_void, newlen: size_t) -> c_int;
+        }
+
+        // reads the value of given name, whose type should match `T` exactly
+        fn read<T>(name: &str) -> Option<T> {
+            let mut value: T = unsafe { std::mem::zeroed() };
+            let mut len = std::mem::size_of::<T>() as size_t;
+            let ret = name.with_c_str(|name| unsafe {
+                je_mallctl(name, &mut value as *mut T as *mut c_void, &mut len,
+                           std::ptr::null_mut(), 0)
+            });
+            if ret == 0 {Some(value)} else {None}
+        }
+
+        // Returns from the function if the value is not available.
+        macro_rules! try_opt(
+            ($e:expr) => (match $e { Some(v) => v, None => { return None; } })
+        )
+
+        // the statistics are cached until the epoch is advanced
+        let mut epoch = 1u64;
+        let len = std::mem::size_of::<u64>() as size_t;
+        let mut oldlen = len;
+        let epochptr = &mut epoch as *mut u64 as *mut c_void;
+        let ret = "epoch".with_c_str(|name| unsafe {
+            je_mallctl(name, epochptr, &mut oldlen, epochptr, len)
+        });
+        if ret != 0 { return None; }
+
+        // the arena right after the last one has the statistics merged from every arena
+        let allocated = try_opt!(read::<size_t>("stats.allocated")) as u64;
+        let narenas = try_opt!(read::<c_uint>("arenas.narenas"));
+        let nbins = try_opt!(read::<c_uint>("arenas.nbins"));
+        let mut small = Vec::new();
+        for i in range(0, nbins) {
+            let size = try_opt!(read::<size_t>(format!("arenas.bin.{}.size", i)[])) as u64;
+            let count = try_opt!(read::<u64>(format!("stats.arenas.{}.bins.{}.nmalloc",
+                                                     narenas, i)[]));
+            small.push((size, count));
+        }
+        let large = try_opt!(read::<u64>(format!("stats.arenas.{}.large.nmalloc", narenas)[]));
+        let huge = try_opt!(read::<u64>("stats.huge.nmalloc"));
+        Some(HeapStats { allocated: allocated, small: small, large: large, huge: huge })
+    }
+
+    /// Exits with an error message. Internally used in the `die!` macro below.
+    #[cfg(target_os = "windows")]
+    pub fn die(s: &str) -> ! {
+        use util::str::StrUtil;
+        ::exename()[].as_utf16_c_str(|caption| {
+            s[].as_utf16_c_str(|text| {
+                unsafe { win32::ll::MessageBoxW(std::ptr::null_mut(), text, caption, 0); }
+            })
+        });
+        exit(1)
+    }
+
+    /// Exits with an error message. Internally used in the `die!` macro below.
+    #[cfg(not(target_os = "windows"))]
+    pub fn die(s: &str) -> ! {
+        let mut stderr = std::io::stderr();
+        let _ = writeln!(&mut stderr, "{}: {}", ::exename(), s);
+        exit(1)
+    }
+
+    /// Prints an warning message. Internally used in the `warn!` macro below.
+    pub fn warn(s: &str) {
+        let mut stderr = std::io::stderr();
+        let _ = writeln!(&mut stderr, "*** Warning: {}", s);
+    }
+
+    /// Exits with a formatted error message. (C: `die`)
+    #[macro_export]
+    macro_rules! die(
+        ($($e:expr),+) => (::util::die(format!($($e),+)[]))
+    )
+
+    /// Prints a formatted warning message. (C: `warn`)
+    #[macro_export]
+    macro_rules! warn(
+        ($($e:expr),+) => (::util::warn(format!($($e),+)[]))
+    )
+
+    /// Reads a path string from the user in the platform-dependent way. Returns `None` if the user
+    /// refused to do so or the platform is unsupported. (C: `filedialog`)
+    #[cfg(target_os = "windows")]
+    pub fn get_path_from_dialog() -> Option<String> {
+        use std::ptr::{null, null_mut};
+        use util::str::StrUtil;
+
+        let filter =
+            "All Be-Music Source File (*.bms;*.bme;*.bml;*.pms;*.bmson;*.zip)\x00\
+             *.bms;*.bme;*.bml;*.pms;*.bmson;*.zip\x00\
+             Be-Music Source File (*.bms)\x00*.bms\x00\
+             Extended Be-Music Source File (*.bme)\x00*.bme\x00\
+             Longnote Be-Music Source File (*.bml)\x00*.bml\x00\
+             Po-Mu Source File (*.pms)\x00*.pms\x00\
+             bmson File (*.bmson)\x00*.bmson\x00\
+             ZIP Archive (*.zip)\x00*.zip\x00\
+             All Files (*.*)\x00*.*\x00";
+        filter.as_utf16_c_str(|filter| {
+            "Choose a file to play".as_utf16_c_str(|title| {
+                let mut buf = [0u16, ..512];
+                let ofnsz = std::mem::size_of::<win32::ll::OPENFILENAMEW>();
+                let mut ofn = win32::ll::OPENFILENAMEW {
+                    lStructSize: ofnsz as libc::DWORD,
+                    lpstrFilter: filter,
+                    lpstrFile: buf.as_mut_ptr(),
+                    nMaxFile: buf.len() as libc::DWORD,
+                    lpstrTitle: title,
+                    Flags: win32::ll::OFN_HIDEREADONLY,
+
+                    // zero-initialized fields
+                    hwndOwner: null_mut(), hInstance: null_mut(),
+                    lpstrCustomFilter: null_mut(), nMaxCustFilter: 0, nFilterIndex: 0,
+                    lpstrFileTitle: null_mut(), nMaxFileTitle: 0,
+                    lpstrInitialDir: null(), nFileOffset: 0, nFileExtension: 0,
+                    lpstrDefExt: null(), lCustData: 0, lpfnHook: null_mut(),
+                    lpTemplateName: null(), pvReserved: null_mut(),
+                    dwReserved: 0, FlagsEx: 0,
+                };
+                let ret = unsafe {win32::ll::GetOpenFileNameW(&mut ofn)};
+                if ret != 0 {
+                    let path: &[u16] = match buf.position_elem(&0) {
+                        Some(idx) => buf[..idx],
+                        None => buf[]
+                    };
+                    String::from_utf16(path)
+                } else {
+                    None
+                }
+            })
+        })
+    }
+
+    /// Runs an external dialog program and returns the first line of its output, or `None` if
+    /// the program is missing, failed or was cancelled.
+    #[cfg(not(target_os = "windows"))]
+    fn run_dialog(program: &str, args: &[&str]) -> Option<String> {
+        use std::io::process::Command;
+        let output = match Command::new(program).args(args).output() {
+            Ok(output) => output,
+            Err(_) => { return None; }
+        };
+        if !output.status.success() { return None; }
+        let path = String::from_utf8(output.output).ok();
+        path.and_then(|path| {
+            let path = path[].lines().next().unwrap_or("").to_string();
+            if path.is_empty() {None} else {Some(path)}
+        })
+    }
+
+    /// Reads a path string from the user in the platform-dependent way. Returns `None` if the user
+    /// refused to do so or the platform is unsupported. (C: `filedialog`)
+    ///
+    /// This uses `osascript` in Mac OS X, and either `zenity` or `kdialog` (whichever is found
+    /// first) in other systems.
+    #[cfg(target_os = "macos")]
+    pub fn get_path_from_dialog() -> Option<String> {
+        run_dialog("osascript", ["-e", "POSIX path of (choose file with prompt \
+                                        \"Choose a file to play\")"][])
+    }
+
+    /// Reads a path string from the user in the platform-dependent way. Returns `None` if the user
+    /// refused to do so or the platform is unsupported. (C: `filedialog`)
+    ///
+    /// This uses `osascript` in Mac OS X, and either `zenity` or `kdialog` (whichever is found
+    /// first) in other systems.
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    pub fn get_path_from_dialog() -> Option<String> {
+        use std::io::process::Command;
+
+        // dialog programs need the X display, and fail slowly without it
+        if std::os::getenv("DISPLAY").is_none() { return None; }
+
+        let patterns = "*.bms *.bme *.bml *.pms *.bmson *.osu *.ojn *.zip \
+                        *.BMS *.BME *.BML *.PMS *.BMSON *.OSU *.OJN *.ZIP";
+        let zenity_filter = format!("--file-filter=Be-Music Source File | {}", patterns);
+        let kdialog_filter = format!("{}|Be-Music Source File", patterns);
+
+        // `zenity` exits with 1 when cancelled, so the missing program should be checked first
+        let has_zenity = Command::new("zenity").arg("--version").output().is_ok();
+        if has_zenity {
+            run_dialog("zenity", ["--file-selection", "--title=Choose a file to play",
+                                  zenity_filter[]][])
+        } else {
+            run_dialog("kdialog", ["--title", "Choose a file to play",
+                                   "--getopenfilename", ".", kdialog_filter[]][])
+        }
+    }
+
+    /// The files (`HDROP`) dropped onto the window and not yet retrieved by `poll_dropped_file`,
+    /// or 0. SDL may call the event filter from the event thread, so this is atomically swapped.
+    #[cfg(target_os = "windows")]
+    static DROPPED_FILES: atomic::AtomicUint = atomic::INIT_ATOMIC_UINT;
+
+    /// The event filter which was set before `enable_drop_files` if any. Only written before
+    /// the filter is set, and called for events other than the window manager events.
+    #[cfg(target_os = "windows")]
+    static mut PREV_EVENT_FILTER: Option<win32::ll::SDL_EventFilter> = None;
+
+    /// Lets the user drop files onto the current SDL window, which can be retrieved later with
+    /// `poll_dropped_file`. Should be called after the video mode has been set.
+    ///
+    /// SDL 1.2 has no drop event, so this intercepts the raw `WM_DROPFILES` message from the
+    /// system window manager events with the event filter, which is chained to the existing
+    /// one. This is only supported in Windows, where the other functions are no-ops.
+    #[cfg(target_os = "windows")]
+    pub fn enable_drop_files() {
+        use self::win32::ll;
+
+        extern "C" fn filter(event: *const ll::SDL_SysWMEvent) -> libc::c_int {
+            unsafe {
+                if (*event).type_ != ll::SDL_SYSWMEVENT {
+                    return PREV_EVENT_FILTER.map_or(1, |prev| prev(event));
+                }
+                // other window manager events are not used, and would only fill the queue
+                if (*(*event).msg).msg != ll::WM_DROPFILES { return 0; }
+                // only the most recent drop is kept
+                let hdrop = (*(*event).msg).wParam as uint;
+                let old = DROPPED_FILES.swap(hdrop, atomic::SeqCst);
+                if old != 0 { ll::DragFinish(old as ll::HDROP); }
+                1 // wakes up `event::wait_event` and friends
+            }
+        }
+
+        let mut info = ll::SDL_SysWMinfo {
+            version: ll::SDL_version { major: 1, minor: 2, patch: 15 },
+            window: std::ptr::null_mut(), hglrc: std::ptr::null_mut(),
+        };
+        unsafe {
+            if ll::SDL_GetWMInfo(&mut info) <= 0 { return; }
+            ll::DragAcceptFiles(info.window, 1);
+            ll::SDL_EventState(ll::SDL_SYSWMEVENT, ll::SDL_ENABLE);
+            // the filter may have been set by the earlier call, e.g. in the course mode
+            let prev = ll::SDL_GetEventFilter();
+            if prev.map_or(true, |prev| prev as uint != filter as uint) {
+                PREV_EVENT_FILTER = prev;
+                ll::SDL_SetEventFilter(Some(filter));
+            }
+        }
+    }
+
+    /// Lets the user drop files onto the current SDL window. This is not supported in this
+    /// platform.
+    #[cfg(not(target_os = "windows"))]
+    pub fn enable_drop_files() {}
+
+    /// Returns true if some files have been dropped onto the window and not yet retrieved.
+    #[cfg(target_os = "windows")]
+    pub fn has_dropped_file() -> bool {
+        DROPPED_FILES.load(atomic::SeqCst) != 0
+    }
+
+    /// Returns true if some files have been dropped onto the window and not yet retrieved.
+    #[cfg(not(target_os = "windows"))]
+    pub fn has_dropped_file() -> bool { false }
+
+    /// Retrieves a path to the file dropped onto the window since the last call if any. Only the
+    /// first file is returned when multiple files have been dropped at once.
+    #[cfg(target_os = "windows")]
+    pub fn poll_dropped_file() -> Option<String> {
+        use std::ptr::null_mut;
+        use self::win32::ll;
+
+        let hdrop = DROPPED_FILES.swap(0, atomic::SeqCst) as ll::HDROP;
+        if hdrop.is_null() { return None; }
+        unsafe {
+            let len = ll::DragQueryFileW(hdrop, 0, null_mut(), 0) as uint;
+            let mut buf = Vec::from_elem(len + 1, 0u16);
+            let ret = ll::DragQueryFileW(hdrop, 0, buf.as_mut_ptr(), buf.len() as libc::c_uint);
+            ll::DragFinish(hdrop);
+            if ret == 0 { return None; }
+            String::from_utf16(buf[..ret as uint])
+        }
+    }
+
+    /// Retrieves a path to the file dropped onto the window since the last call if any. This is
+    /// not supported in this platform and always returns `None`.
+    #[cfg(not(target_os = "windows"))]
+    pub fn poll_dropped_file() -> Option<String> { None }
+
+    /**
+     * A lexer barely powerful enough to parse BMS format. Comparable to C's `sscanf`.
+     *
+     * `lex!(e; fmt1, fmt2, ..., fmtN)` returns an expression that evaluates to true if and only if
+     * all format specification is consumed. The format specification (analogous to `sscanf`'s
+     * `%`-string) is as follows:
+     *
+     * - `ws`: Consumes one or more whitespace. (C: `%*[ \t\r\n]` or similar)
+     * - `ws*`: Consumes zero or more whitespace. (C: ` `)
+     * - `int [-> e2]` and so on: Any type implementing the `FromStrPrefix` trait can be used.
+     *   Optionally saves the parsed value to `e2`. The default implementations includes
+     *   all integers, floating point types and `char`. (C: `%d`/`%*d`, `%f`/`%*f`, and `%1c`
+     *   with slight lexical differences)
+     * - `str [-> e2]`: Consumes a remaining input as a string and optionally saves it to `e2`.
+     *   The string is at least one character long. (C: not really maps to `sscanf`, similar to
+     *   `fgets`) Implies `!`. It can be followed by `ws*` which makes the string right-trimmed.
+     * - `str* [-> e2]`: Same as above but the string can be empty.
+     * - `!`: Ensures that the entire string has been consumed. Should be the last format
+     *   specification.
+     * - `lit "foo"`, `lit '.'` etc.: A literal string or literal character.
+     *
+     * Whitespaces are only trimmed when `ws` or `ws*` specification is used.
+     * Therefore `char`, for example, can read a whitespace when not prepended with `ws` or `ws*`.
+     */
+    // Rust: - multiple statements do not expand correctly. (#4375)
+    //       - it is desirable to have a matcher only accepts an integer literal or string literal,
+    //         not a generic expression.
+    //       - it would be more useful to generate bindings for parsed result. this is related to
+    //         many issues in general.
+    //       - could we elide a `lit` prefix somehow?
+    macro_rules! lex(
+        ($e:expr; ) => (true);
+        ($e:expr; !) => ($e.is_empty());
+
+        ($e:expr; str -> $dst:expr, ws*, $($tail:tt)*) => ({
+            let _line: &str = $e;
+            if !_line.is_empty() {
+                $dst = _line.trim_right();
+                lex!(""; $($tail)*) // optimization!
+            } else {
+                false
+            }
+        });
+        ($e:expr; str -> $dst:expr, $($tail:tt)*) => ({
+            let _line: &str = $e;
+            if !_line.is_empty() {
+                $dst = _line;
+                lex!(""; $($tail)*) // optimization!
+            } else {
+                false
+            }
+        });
+        ($e:expr; str* -> $dst:expr, ws*, $($tail:tt)*) => ({
+            let _line: &str = $e;
+            $dst = _line.trim_right();
+            lex!(""; $($tail)*) // optimization!
+        });
+        ($e:expr; str* -> $dst:expr, $($tail:tt)*) => ({
+            let _line: &str = $e;
+            $dst = _line;
+            lex!(""; $($tail)*) // optimization!
+        });
+        ($e:expr; $t:ty -> $dst:expr, $($tail:tt)*) => ({
+            let _line: &str = $e;
+            ::util::str::from_str_prefix::<$t>(_line).map_or(false, |(_value, _line)| {
+                $dst = _value;
+                lex!(_line; $($tail)*)
+            })
+        });
+
+        ($e:expr; ws, $($tail:tt)*) => ({
+            let _line: &str = $e;
+            if !_line.is_empty() && _line.char_at(0).is_whitespace() {
+                lex!(_line.trim_left(); $($tail)*)
+            } else {
+                false
+            }
+        });
+        ($e:expr; ws*, $($tail:tt)*) => ({
+            let _line: &str = $e;
+            lex!(_line.trim_left(); $($tail)*)
+        });
+        ($e:expr; str, $($tail:tt)*) => ({
+            !$e.is_empty() && lex!(""; $($tail)*) // optimization!
+        });
+        ($e:expr; str*, $($tail:tt)*) => ({
+            lex!(""; $($tail)*) // optimization!
+        });
+        ($e:expr; $t:ty, $($tail:tt)*) => ({
+            let mut _dummy: $t; // unused
+            lex!($e; $t -> _dummy, $($tail)*)
+        });
+        ($e:expr; lit $lit:expr, $($tail:tt)*) => ({
+            ::util::str::prefix_shifted($e, $lit).map_or(false, |_line| {
+                lex!(_line; $($tail)*)
+            })
+        });
+
+        ($e:expr; str -> $dst:expr) => (lex!($e; str -> $dst, ));
+        ($e:expr; str -> $dst:expr, ws*) => (lex!($e; str -> $dst, ws*, ));
+        ($e:expr; str* -> $dst:expr) => (lex!($e; str* -> $dst, ));
+        ($e:expr; str* -> $dst:expr, ws*) => (lex!($e; str* -> $dst, ws*, ));
+        ($e:expr; $t:ty -> $dst:expr) => (lex!($e; $t -> $dst, ));
+
+        ($e:expr; ws) => (lex!($e; ws, ));
+        ($e:expr; ws*) => (lex!($e; ws*, ));
+        ($e:expr; str) => (lex!($e; str, ));
+        ($e:expr; str*) => (lex!($e; str*, ));
+        ($e:expr; $t:ty) => (lex!($e; $t, ));
+        ($e:expr; lit $lit:expr) => (lex!($e; lit $lit, ))
+    )
+
+}
+
+//==================================================================================================
+// ZIP archives
+
+/**
+ * ZIP archive reader. Many BMS packages are distributed as ZIP archives, and Angolmois can read
+ * them without extracting.
+ *
+ * A file inside the archive is referred by a path continuing from the archive path, e.g.
+ * `foo.zip/bar/baz.bms` refers to `bar/baz.bms` in `foo.zip`. The archive path is detected by
+ * its extension (case-insensitively) and such path can be used wherever a plain path to the chart
+ * or resource is expected.
+ *
+ * Only stored and deflated entries are supported. Encryption, ZIP64 and multi-disk archives are not
+ * supported. Entry names are decoded as in `::util::encoding::decode_text`.
+ */
+pub mod zip {
+    use std::io;
+    use std::ascii::AsciiExt;
+    use std::collections::HashMap;
+    use std::io::fs::PathExtensions;
+
+    /// A file entry in the ZIP archive.
+    #[deriving(Clone)]
+    pub struct ZipEntry {
+        /// The name of the entry, with `/` as a directory separator.
+        pub name: String,
+        /// The compression method. 0 is stored and 8 is deflated.
+        pub method: u16,
+        /// The offset to the local file header.
+        pub offset: u64,
+        /// The compressed size.
+        pub compsize: uint,
+        /// The uncompressed size.
+        pub size: uint,
+    }
+
+    /// A ZIP archive with the list of file entries. The entries are read on demand.
+    #[deriving(Clone)]
+    pub struct ZipArchive {
+        /// A path to the archive.
+        pub path: Path,
+        /// File entries in the archive. Directory entries are excluded.
+        pub entries: Vec<ZipEntry>,
+    }
+
+    /// Returns an I/O error for the malformed or unsupported archive.
+    fn invalid_archive(desc: &'static str, detail: Option<String>) -> io::IoError {
+        io::IoError { kind: io::InvalidInput, desc: desc, detail: detail }
+    }
+
+    impl ZipArchive {
+        /// Reads the central directory of the archive.
+        pub fn open(path: &Path) -> io::IoResult<ZipArchive> {
+            let mut f = try!(io::File::open(path));
+            let filesize = try!(f.stat()).size;
+
+            // the end of central directory record is at least 22 bytes long, and may be followed
+            // by the archive comment up to 65535 bytes.
+            let tailsize = if filesize < 65557 {filesize} else {65557};
+            try!(f.seek(-(tailsize as i64), io::SeekEnd));
+            let tail = try!(f.read_exact(tailsize as uint));
+            let mut eocd = None;
+            if tail.len() >= 22 {
+                let mut i = tail.len() - 22;
+                loop {
+                    if tail[i..i+4] == b"PK\x05\x06" { eocd = Some(i); break; }
+                    if i == 0 { break; }
+                    i -= 1;
+                }
+            }
+            let eocd = match eocd {
+                Some(eocd) => eocd,
+                None => { return Err(invalid_archive("not a ZIP archive", None)); }
+            };
+
+            let mut r = io::BufReader::new(tail[eocd+10..eocd+22]);
+            let nentries = try!(r.read_le_u16()) as uint;
+            let cdsize = try!(r.read_le_u32()) as uint;
+            let cdoffset = try!(r.read_le_u32()) as u64;
+            if cdoffset == 0xffffffff {
+                return Err(invalid_archive("ZIP64 archives are not supported", None));
+            }
+
+            try!(f.seek(cdoffset as i64, io::SeekSet));
+            let cd = try!(f.read_exact(cdsize));
+            let mut r = io::BufReader::new(cd[]);
+            let mut entries = Vec::new();
+            for _ in range(0, nentries) {
+                let header = try!(r.read_exact(46));
+                if header[..4] != b"PK\x01\x02" {
+                    return Err(invalid_archive("corrupted central directory", None));
+                }
+                let mut h = io::BufReader::new(header[]);
+                let field = |h: &mut io::BufReader, pos: uint, size: uint| -> io::IoResult<u64> {
+                    try!(h.seek(pos as i64, io::SeekSet));
+                    h.read_le_uint_n(size)
+                };
+                let flags = try!(field(&mut h, 8, 2));
+                let method = try!(field(&mut h, 10, 2)) as u16;
+                let compsize = try!(field(&mut h, 20, 4)) as uint;
+                let size = try!(field(&mut h, 24, 4)) as uint;
+                let namelen = try!(field(&mut h, 28, 2)) as uint;
+                let extralen = try!(field(&mut h, 30, 2)) as uint;
+                let commentlen = try!(field(&mut h, 32, 2)) as uint;
+                let offset = try!(field(&mut h, 42, 4));
+
+                let name = try!(r.read_exact(namelen));
+                try!(r.read_exact(extralen + commentlen));
+                // the name is decoded first, as the CP932 trail byte can be 0x5C (`\`)
+                let name = ::util::encoding::decode_text(name[]).as_slice().replace("\\", "/");
+                if name[].ends_with("/") { continue; } // directory
+                if flags & 1 != 0 {
+                    return Err(invalid_archive("encrypted entries are not supported", Some(name)));
+                }
+                entries.push(ZipEntry { name: name, method: method, offset: offset,
+                                        compsize: compsize, size: size });
+            }
+
+            Ok(ZipArchive { path: path.clone(), entries: entries })
+        }
+
+        /**
+         * Finds the entry with given name. The name is matched with the same rules as
+         * `player::resolve_relative_path`: both `/` and `\` are accepted as a directory separator,
+         * names are matched case-insensitively and the alternative extensions are tried if
+         * the initial match fails.
+         */
+        pub fn find<'r>(&'r self, name: &str, exts: &[&str]) -> Option<&'r ZipEntry> {
+            let mut parts = Vec::new();
+            for part in name.split(|c: char| c == '/' || c == '\\') {
+                if part.is_empty() { continue; }
+                parts.push(part.to_ascii_upper());
+            }
+            if parts.is_empty() { return None; }
+            let name = parts.connect("/");
+            let (dir, namenoext) = match name[].rfind('/') {
+                Some(idx) => (name[..idx+1], name[idx+1..]),
+                None => ("", name[]),
+            };
+            let namenoext = match namenoext.rfind('.') {
+                Some(idx) => Some(name[..dir.len()+idx].to_string()),
+                None => None, // does not try alternative extensions if there was no extension
+            };
+
+            let mut found = None;
+            for entry in self.entries.iter() {
+                let entryname = entry.name.to_ascii_upper();
+                if entryname == name { return Some(entry); }
+                if found.is_none() {
+                    for namenoext in namenoext.iter() {
+                        if exts.iter().any(|ext| namenoext.to_string() + *ext == entryname) {
+                            found = Some(entry);
+                        }
+                    }
+                }
+            }
+            found
+        }
+
+        /// Reads and uncompresses the contents of given entry.
+        pub fn read(&self, entry: &ZipEntry) -> io::IoResult<Vec<u8>> {
+            let mut f = try!(io::File::open(&self.path));
+            try!(f.seek(entry.offset as i64, io::SeekSet));
+            let header = try!(f.read_exact(30));
+            if header[..4] != b"PK\x03\x04" {
+                return Err(invalid_archive("corrupted local file header",
+                                           Some(entry.name.clone())));
+            }
+            let mut h = io::BufReader::new(header[26..30]);
+            let namelen = try!(h.read_le_u16()) as i64;
+            let extralen = try!(h.read_le_u16()) as i64;
+            try!(f.seek(namelen + extralen, io::SeekCur));
+            let data = try!(f.read_exact(entry.compsize));
+
+            match entry.method {
+                0 => Ok(data),
+                8 => match ::flate::inflate_bytes(data[]) {
+                    Some(data) => Ok(data.as_slice().to_vec()),
+                    None => Err(invalid_archive("corrupted deflate stream",
+                                                Some(entry.name.clone()))),
+                },
+                _ => Err(invalid_archive("unsupported compression method",
+                                         Some(entry.name.clone()))),
+            }
+        }
+    }
+
+    /**
+     * Splits a path into the archive path and the path inside the archive, if the path refers to
+     * the archive or the file in the archive. The latter may be empty.
+     */
+    pub fn split_archive_path(path: &str) -> Option<(Path, String)> {
+        let lowerpath = path.to_ascii_lower();
+        let mut start = 0;
+        loop {
+            let idx = match lowerpath[start..].find_str(".zip") {
+                Some(idx) => start + idx + 4,
+                None => { return None; }
+            };
+            let rest = path[idx..];
+            if rest.is_empty() || rest.starts_with("/") || rest.starts_with("\\") {
+                let archive = Path::new(path[..idx]);
+                if archive.is_file() {
+                    let inner = rest.trim_left_chars(['/', '\\'][]).replace("\\", "/");
+                    return Some((archive, inner));
+                }
+            }
+            start = idx;
+        }
+    }
+
+    /// Calls the closure with the archive at given path. The central directory is cached, since
+    /// it is used for every resource in the archive.
+    pub fn with_archive<T>(path: &Path, cb: |&ZipArchive| -> T) -> io::IoResult<T> {
+        local_data_key!(key_archive_cache: HashMap<Path,ZipArchive>);
+
+        let mut cache = match key_archive_cache.replace(None) {
+            Some(cache) => cache,
+            None => HashMap::new()
+        };
+        if !cache.contains_key(path) {
+            match ZipArchive::open(path) {
+                Ok(archive) => { cache.insert(path.clone(), archive); }
+                Err(err) => {
+                    key_archive_cache.replace(Some(cache));
+                    return Err(err);
+                }
+            }
+        }
+        let ret = cb(&cache[*path]);
+        key_archive_cache.replace(Some(cache));
+        Ok(ret)
+    }
+
+    /// Reads the whole contents of the file, which may be inside the archive.
+    pub fn read_file(path: &str) -> io::IoResult<Vec<u8>> {
+        match split_archive_path(path) {
+            Some((archive, ref inner)) if !inner.is_empty() => {
+                let ret = with_archive(&archive, |archive| {
+                    match archive.find(inner[], []) {
+                        Some(entry) => archive.read(entry),
+                        None => Err(io::standard_error(io::FileNotFound)),
+                    }
+                });
+                ret.and_then(|ret| ret)
+            }
+            _ => io::File::open(&Path::new(path)).and_then(|mut f| f.read_to_end()),
+        }
+    }
+
+    /// Opens the file for reading, which may be inside the archive.
+    pub fn open_file(path: &str) -> io::IoResult<Box<Reader+'static>> {
+        match split_archive_path(path) {
+            Some((_, ref inner)) if !inner.is_empty() => {
+                let data = try!(read_file(path));
+                Ok(box io::MemReader::new(data) as Box<Reader+'static>)
+            }
+            _ => {
+                let f = try!(io::File::open(&Path::new(path)));
+                Ok(box f as Box<Reader+'static>)
+            }
+        }
+    }
+
+    /// Returns the first chart (in the order of names) in the archive with given file extensions.
+    pub fn find_chart(archive: &Path, exts: &[&str]) -> io::IoResult<Option<String>> {
+        with_archive(archive, |archive| {
+            let mut names: Vec<&str> = archive.entries.iter().map(|e| e.name[]).filter(|name| {
+                let name = name.to_ascii_upper();
+                exts.iter().any(|ext| name[].ends_with(*ext))
+            }).collect();
+            names.sort();
+            names.head().map(|name| archive.path.join(*name).display().to_string())
+        })
+    }
+}
+
+//==================================================================================================
+// bms parser
+
+/**
+ * BMS parser module.
+ *
+ * # Structure
+ *
+ * The BMS format is a plain text format with most directives start with optional whitespace
+ * followed by `#`. Besides the metadata (title, artist etc.), a BMS file is a map from the time
+ * position to various game play elements (henceforth "objects") and other object-like effects
+ * including BGM and BGA changes. It also contains preprocessor directives used to randomize some or
+ * all parts of the BMS file, which would only make sense in the loading time.
+ *
+ * The time position is a virtual time divided by an unit of (musical) measure. It is related to
+ * the actual time by the current Beats Per Minute (BPM) value which can, well, also change during
+ * the game play. Consequently it is convenient to refer the position in terms of measures, which
+ * the BMS format does: the lines `#xxxyy:AABBCC...` indicates that the measure number `xxx`
+ * contains objects or object-like effects (of the type specified by `yy`, henceforth "channels"),
+ * evenly spaced throughout the measure and which data values are `AA`, `BB`, `CC` respectively.
+ *
+ * An alphanumeric identifier (henceforth "alphanumeric key") like `AA` or `BB` may mean that
+ * the actual numeric value interpreted as base 16 or 36 (depending on the channel), or a reference
+ * to other assets (e.g. `#BMPAA foo.png`) or complex values specified by other commands (e.g.
+ * `#BPMBB 192.0`). In most cases, an identifier `00` indicates an absence of objects or object-like
+ * effects at that position.
+ *
+ * More detailed information about BMS format, including surveys about how different implementations
+ * (so called BMS players) react to underspecified features or edge cases, can be found at [BMS
+ * command memo](http://hitkey.nekokan.dyndns.info/cmds.htm).
+ */
+pub mod parser {
+    use std::{f64, str, iter, io, fmt, cmp};
+    use std::rand::Rng;
+    use util::str::FromStrPrefix;
+    use {AngolmoisResult, IoFailure};
+
+    //----------------------------------------------------------------------------------------------
+    // alphanumeric key
+
+    /// Two-letter alphanumeric identifier used for virtually everything, including resource
+    /// management, variable BPM and chart specification.
+    #[deriving(PartialEq,PartialOrd,Eq,Ord,Clone)]
+    pub struct Key(pub int);
+
+    /// The number of all possible alphanumeric keys. (C: `MAXKEY`)
+    pub const MAXKEY: int = 36*36;
+
+    impl Deref<int> for Key {
+        fn deref<'a>(&'a self) -> &'a int {
+            let Key(ref v) = *self;
+            v
+        }
+    }
+
+    impl Key {
+        /// Returns if the alphanumeric key is in the proper range. Angolmois supports the full
+        /// range of 00-ZZ (0-1295) for every case.
+        pub fn is_valid(&self) -> bool {
+            0 <= **self && **self < MAXKEY
+        }
+
+        /// Re-reads the alphanumeric key as a hexadecimal number if possible. This is required
+        /// due to handling of channel #03 (BPM is expected to be in hexadecimal).
+        pub fn to_hex(&self) -> Option<int> {
+            let sixteens = **self / 36;
+            let ones = **self % 36;
+            if sixteens < 16 && ones < 16 {Some(sixteens * 16 + ones)} else {None}
+        }
+    }
+
+    impl fmt::Show for Key {
+        /// Returns a two-letter representation of alphanumeric key. (C: `TO_KEY`)
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            assert!(self.is_valid());
+            let sixteens = **self / 36;
+            let ones = **self % 36;
+            let map = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+            write!(f, "{}{}", map[sixteens as uint] as char, map[ones as uint] as char)
+        }
+    }
+
+    //----------------------------------------------------------------------------------------------
+    // lane and key kinds
+
+    /// A game play element mapped to the single input element (for example, button) and the screen
+    /// area (henceforth "lane").
+    #[deriving(PartialEq,Eq,Clone)]
+    pub struct Lane(pub uint);
+
+    /// The maximum number of lanes. (C: `NNOTECHANS`)
+    pub const NLANES: uint = 72;
+
+    impl Deref<uint> for Lane {
+        fn deref<'a>(&'a self) -> &'a uint {
+            let Lane(ref v) = *self;
+            v
+        }
+    }
+
+    impl Lane {
+        /// Converts the channel number to the lane number.
+        pub fn from_channel(chan: Key) -> Lane {
+            let player = match *chan / 36 {
+                1 | 3 | 5 | 0xD => 0,
+                2 | 4 | 6 | 0xE => 1,
+                _ => panic!("non-object channel")
+            };
+            Lane(player * 36 + *chan as uint % 36)
+        }
+    }
+
+    /**
+     * Key kinds. They define an appearance of particular lane, but otherwise ignored for the game
+     * play. Angolmois supports several key kinds in order to cover many potential uses.
+     * (C: `KEYKIND_MNEMONICS`)
+     *
+     * # Defaults
+     *
+     * For BMS/BME, channels #11/13/15/19 and #21/23/25/29 use `WhiteKey`, #12/14/18 and #22/24/28
+     * use `BlackKey`, #16 and #26 use `Scratch`, #17 and #27 use `FootPedal`.
+     *
+     * For PMS, channels #11/17/25 use `Button1`, #12/16/24 use `Button2`, #13/19/23 use `Button3`,
+     * #14/18/22 use `Button4`, #15 uses `Button5`.
+     */
+    #[deriving(PartialEq,Eq,Clone)]
+    pub enum KeyKind {
+        /// White key, which mimics a real white key in the musical keyboard.
+        WhiteKey,
+        /// White key, but rendered yellow. This is used for simulating the O2Jam interface which
+        /// has one yellow lane (mapped to spacebar) in middle of six other lanes (mapped to normal
+        /// keys).
+        WhiteKeyAlt,
+        /// Black key, which mimics a real black key in the keyboard but rendered light blue as in
+        /// Beatmania and other games.
+        BlackKey,
+        /// Scratch, rendered red. Scratch lane is wider than other "keys" and normally doesn't
+        /// count as a key.
+        Scratch,
+        /// Foot pedal, rendered green. Otherwise has the same properties as scratch. The choice of
+        /// color follows that of EZ2DJ, one of the first games that used this game element.
+        FootPedal,
+        /// White button. This and following "buttons" come from Pop'n Music, which has nine colored
+        /// buttons. (White buttons constitute 1st and 9th of Pop'n Music buttons.) The "buttons"
+        /// are wider than aforementioned "keys" but narrower than scratch and foot pedal.
+        Button1,
+        /// Yellow button (2nd and 8th of Pop'n Music buttons).
+        Button2,
+        /// Green button (3rd and 7th of Pop'n Music buttons).
+        Button3,
+        /// Navy button (4th and 6th of Pop'n Music buttons).
+        Button4,
+        /// Red button (5th of Pop'n Music buttons).
+        Button5,
+    }
+
+    impl KeyKind {
+        /// Returns a list of all supported key kinds.
+        //
+        // Rust: can this method be generated on the fly?
+        pub fn all() -> &'static [KeyKind] {
+            static ALL: [KeyKind, ..10] = [WhiteKey, WhiteKeyAlt, BlackKey, Scratch, FootPedal,
+                                           Button1, Button2, Button3, Button4, Button5];
+            ALL
+        }
+
+        /// Converts a mnemonic character to an appropriate key kind. Used for parsing a key
+        /// specification (see also `KeySpec`).
+        pub fn from_char(c: char) -> Option<KeyKind> {
+            match c {
+                'a' => Some(WhiteKey),
+                'y' => Some(WhiteKeyAlt),
+                'b' => Some(BlackKey),
+                's' => Some(Scratch),
+                'p' => Some(FootPedal),
+                'q' => Some(Button1),
+                'w' => Some(Button2),
+                'e' => Some(Button3),
+                'r' => Some(Button4),
+                't' => Some(Button5),
+                _   => None
+            }
+        }
+
+        /// Converts an appropriate key kind to a mnemonic character. Used for environment variables
+        /// (see also `read_keymap`).
+        pub fn to_char(&self) -> char {
+            match *self {
+                WhiteKey    => 'a',
+                WhiteKeyAlt => 'y',
+                BlackKey    => 'b',
+                Scratch     => 's',
+                FootPedal   => 'p',
+                Button1     => 'w',
+                Button2     => 'e',
+                Button3     => 'r',
+                Button4     => 't',
+                Button5     => 's'
+            }
+        }
+
+        /**
+         * Returns true if a kind counts as a "key". (C: `KEYKIND_IS_KEY`)
+         *
+         * This affects the number of keys displayed in the loading screen, and reflects a common
+         * practice of counting "keys" in many games (e.g. Beatmania IIDX has 8 lanes including one
+         * scratch but commonly said to have 7 "keys").
+         */
+        pub fn counts_as_key(&self) -> bool {
+            *self != Scratch && *self != FootPedal
+        }
+    }
+
+    //----------------------------------------------------------------------------------------------
+    // object parameters
+
+    /// Sound reference.
+    #[deriving(PartialEq,Eq,Clone)]
+    pub struct SoundRef(pub Key);
+
+    impl Deref<Key> for SoundRef {
+        fn deref<'a>(&'a self) -> &'a Key {
+            let SoundRef(ref key) = *self;
+            key
+        }
+    }
+
+    /// Image reference.
+    #[deriving(PartialEq,Eq,Clone)]
+    pub struct ImageRef(pub Key);
+
+    impl Deref<Key> for ImageRef {
+        fn deref<'a>(&'a self) -> &'a Key {
+            let ImageRef(ref key) = *self;
+            key
+        }
+    }
+
+    /// BGA layers. (C: `enum BGA_type`)
+    #[deriving(PartialEq,Eq,Clone)]
+    pub enum BGALayer {
+        /// The lowest layer. BMS channel #04. (C: `BGA_LAYER`)
+        Layer1 = 0,
+        /// The middle layer. BMS channel #07. (C: `BGA2_LAYER`)
+        Layer2 = 1,
+        /// The highest layer. BMS channel #0A. (C: `BGA3_LAYER`)
+        Layer3 = 2,
+        /// The layer only displayed shortly after the MISS grade. It is technically not over
+        /// `Layer3`, but several extensions to BMS assumes it. BMS channel #06.
+        /// (C: `POORBGA_LAYER`)
+        PoorBGA = 3
+    }
+
+    /// The number of BGA layers.
+    pub const NLAYERS: uint = 4;
+
+    /// Beats per minute. Used as a conversion factor between the time position and actual time
+    /// in BMS.
+    #[deriving(PartialEq,Clone)]
+    pub struct BPM(pub f64);
+
+    impl Deref<f64> for BPM {
+        fn deref<'a>(&'a self) -> &'a f64 {
+            let BPM(ref v) = *self;
+            v
+        }
+    }
+
+    impl BPM {
+        /// Converts a measure to a millisecond. (C: `MEASURE_TO_MSEC`)
+        pub fn measure_to_msec(&self, measure: f64) -> f64 { measure * 240000.0 / **self }
+
+        /// Converts a millisecond to a measure. (C: `MSEC_TO_MEASURE`)
+        pub fn msec_to_measure(&self, msec: f64) -> f64 { msec * **self / 240000.0 }
+    }
+
+    /// A duration from the particular point. It may be specified in measures or seconds. Used in
+    /// the `Stop` object.
+    #[deriving(PartialEq,Clone)]
+    pub enum Duration { Seconds(f64), Measures(f64) }
+
+    impl Duration {
+        /// Calculates the actual milliseconds from the current BPM.
+        pub fn to_msec(&self, bpm: BPM) -> f64 {
+            match *self {
+                Seconds(secs) => secs * 1000.0,
+                Measures(measures) => bpm.measure_to_msec(measures)
+            }
+        }
+    }
+
+    /// A damage value upon the MISS grade. Normally it is specified in percents of the full gauge
+    /// (as in `MAXGAUGE`), but sometimes it may cause an instant death. Used in the `Bomb` object
+    /// (normal note objects have a fixed value).
+    #[deriving(PartialEq,Clone)]
+    pub enum Damage { GaugeDamage(f64), InstantDeath }
+
+    //----------------------------------------------------------------------------------------------
+    // object
+
+    /// A data for objects (or object-like effects). Does not include the time information.
+    #[deriving(PartialEq,Clone)]
+    pub enum ObjData {
+        /// Deleted object. Only used during various processing.
+        Deleted,
+        /// Visible object. Sound is played when the key is input inside the associated grading
+        /// area. (C: `NOTE`)
+        Visible(Lane, Option<SoundRef>),
+        /// Invisible object. Sound is played when the key is input inside the associated grading
+        /// area. No render nor grading performed. (C: `INVNOTE`)
+        Invisible(Lane, Option<SoundRef>),
+        /// Start of long note (LN). Sound is played when the key is down inside the associated
+        /// grading area. (C: `LNSTART`)
+        LNStart(Lane, Option<SoundRef>),
+        /// End of LN. Sound is played when the start of LN is graded, the key was down and now up
+        /// inside the associated grading area. (C: `LNDONE`)
+        LNDone(Lane, Option<SoundRef>),
+        /// Bomb. Pressing the key down at the moment that the object is on time causes
+        /// the specified damage; sound is played in this case. No associated grading area.
+        /// (C: `BOMB`)
+        Bomb(Lane, Option<SoundRef>, Damage),
+        /// Plays associated sound. (C: `BGM_CHANNEL`)
+        BGM(SoundRef),
+        /**
+         * Sets the virtual BGA layer to given image. The layer itself may not be displayed
+         * depending on the current game status. (C: `BGA_CHANNEL`)
+         *
+         * If the reference points to a movie, the movie starts playing; if the other layer had
+         * the same movie started, it rewinds to the beginning. The resulting image from the movie
+         * can be shared among multiple layers.
+         */
+        SetBGA(BGALayer, Option<ImageRef>),
+        /// Sets the BPM. Negative BPM causes the chart scrolls backwards (and implicitly signals
+        /// the end of the chart). (C: `BPM_CHANNEL`)
+        SetBPM(BPM),
+        /// Stops the scroll of the chart for given duration ("scroll stopper" hereafter).
+        /// (C: `STOP_CHANNEL`)
+        Stop(Duration)
+    }
+
+    /// Query operations for objects.
+    pub trait ObjQueryOps {
+        /// Returns true if the object is a visible object (`Visible`). (C: `obj->type == NOTE`)
+        fn is_visible(&self) -> bool;
+        /// Returns true if the object is an invisible object (`Invisible`).
+        /// (C: `obj->type == INVNOTE`)
+        fn is_invisible(&self) -> bool;
+        /// Returns true if the object is a start of LN object (`LNStart`).
+        /// (C: `obj->type == LNSTART`)
+        fn is_lnstart(&self) -> bool;
+        /// Returns true if the object is an end of LN object (`LNEnd`). (C: `obj->type == LNDONE`)
+        fn is_lndone(&self) -> bool;
+        /// Returns true if the object is either a start or an end of LN object.
+        /// (C: `obj->type < NOTE`)
+        fn is_ln(&self) -> bool;
+        /// Returns true if the object is a bomb (`Bomb`). (C: `obj->type == BOMB`)
+        fn is_bomb(&self) -> bool;
+        /// Returns true if the object is soundable when it is the closest soundable object from
+        /// the current position and the player pressed the key. Named "soundable" since it may
+        /// choose not to play the associated sound. Note that not every object with sound is
+        /// soundable. (C: `obj->type <= INVNOTE`)
+        fn is_soundable(&self) -> bool;
+        /// Returns true if the object is subject to grading. (C: `obj->type < INVNOTE`)
+        fn is_gradable(&self) -> bool;
+        /// Returns true if the object has a visible representation. (C: `obj->type != INVNOTE`)
+        fn is_renderable(&self) -> bool;
+        /// Returns true if the data is an object. (C: `IS_NOTE_CHANNEL(obj->chan)`)
+        fn is_object(&self) -> bool;
+        /// Returns true if the data is a BGM. (C: `obj->chan == BGM_CHANNEL`)
+        fn is_bgm(&self) -> bool;
+        /// Returns true if the data is a BGA. (C: `obj->chan == BGA_CHANNEL`)
+        fn is_setbga(&self) -> bool;
+        /// Returns true if the data is a BPM change. (C: `obj->chan == BPM_CHANNEL`)
+        fn is_setbpm(&self) -> bool;
+        /// Returns true if the data is a scroll stopper. (C: `obj->chan == STOP_CHANNEL`)
+        fn is_stop(&self) -> bool;
+
+        /// Returns an associated lane if the data is an object.
+        fn object_lane(&self) -> Option<Lane>;
+        /// Returns all sounds associated to the data.
+        fn sounds(&self) -> Vec<SoundRef>;
+        /// Returns all sounds played when key is pressed.
+        fn keydown_sound(&self) -> Option<SoundRef>;
+        /// Returns all sounds played when key is unpressed.
+        fn keyup_sound(&self) -> Option<SoundRef>;
+        /// Returns all sounds played when the object is activated while the corresponding key is
+        /// currently pressed. Bombs are the only instance of this kind of sounds.
+        fn through_sound(&self) -> Option<SoundRef>;
+        /// Returns all images associated to the data.
+        fn images(&self) -> Vec<ImageRef>;
+        /// Returns an associated damage value when the object is activated.
+        fn through_damage(&self) -> Option<Damage>;
+    }
+
+    /// Conversion operations for objects.
+    pub trait ObjConvOps: ObjQueryOps {
+        /// Returns a visible object with the same time, lane and sound as given object.
+        fn to_visible(&self) -> Self;
+        /// Returns an invisible object with the same time, lane and sound as given object.
+        fn to_invisible(&self) -> Self;
+        /// Returns a start of LN object with the same time, lane and sound as given object.
+        fn to_lnstart(&self) -> Self;
+        /// Returns an end of LN object with the same time, lane and sound as given object.
+        fn to_lndone(&self) -> Self;
+    }
+
+    impl ObjQueryOps for ObjData {
+        fn is_visible(&self) -> bool {
+            match *self { Visible(..) => true, _ => false }
+        }
+
+        fn is_invisible(&self) -> bool {
+            match *self { Invisible(..) => true, _ => false }
+        }
+
+        fn is_lnstart(&self) -> bool {
+            match *self { LNStart(..) => true, _ => false }
+        }
+
+        fn is_lndone(&self) -> bool {
+            match *self { LNDone(..) => true, _ => false }
+        }
+
+        fn is_ln(&self) -> bool {
+            match *self { LNStart(..) | LNDone(..) => true, _ => false }
+        }
+
+        fn is_bomb(&self) -> bool {
+            match *self { Bomb(..) => true, _ => false }
+        }
+
+        fn is_soundable(&self) -> bool {
+            match *self { Visible(..) | Invisible(..) | LNStart(..) | LNDone(..) => true,
+                         _ => false }
+        }
+
+        fn is_gradable(&self) -> bool {
+            match *self { Visible(..) | LNStart(..) | LNDone(..) => true, _ => false }
+        }
+
+        fn is_renderable(&self) -> bool {
+            match *self { Visible(..) | LNStart(..) | LNDone(..) | Bomb(..) => true, _ => false }
+        }
+
+        fn is_object(&self) -> bool {
+            match *self { Visible(..) | Invisible(..) | LNStart(..) | LNDone(..) | Bomb(..) => true,
+                         _ => false }
+        }
+
+        fn is_bgm(&self) -> bool {
+            match *self { BGM(..) => true, _ => false }
+        }
+
+        fn is_setbga(&self) -> bool {
+            match *self { SetBGA(..) => true, _ => false }
+        }
+
+        fn is_setbpm(&self) -> bool {
+            match *self { SetBPM(..) => true, _ => false }
+        }
+
+        fn is_stop(&self) -> bool {
+            match *self { Stop(..) => true, _ => false }
+        }
+
+        fn object_lane(&self) -> Option<Lane> {
+            match *self {
+                Visible(lane,_) | Invisible(lane,_) | LNStart(lane,_) |
+                LNDone(lane,_) | Bomb(lane,_,_) => Some(lane),
+                _ => None
+            }
+        }
+
+        fn sounds(&self) -> Vec<SoundRef> {
+            match *self {
+                Visible(_,Some(sref)) => vec!(sref),
+                Invisible(_,Some(sref)) => vec!(sref),
+                LNStart(_,Some(sref)) => vec!(sref),
+                LNDone(_,Some(sref)) => vec!(sref),
+                Bomb(_,Some(sref),_) => vec!(sref),
+                BGM(sref) => vec!(sref),
+                _ => Vec::new()
+            }
+        }
+
+        fn keydown_sound(&self) -> Option<SoundRef> {
+            match *self { Visible(_,sref) | Invisible(_,sref) | LNStart(_,sref) => sref, _ => None }
+        }
+
+        fn keyup_sound(&self) -> Option<SoundRef> {
+            match *self { LNDone(_,sref) => sref, _ => None }
+        }
+
+        fn through_sound(&self) -> Option<SoundRef> {
+            match *self { Bomb(_,sref,_) => sref, _ => None }
+        }
+
+        fn images(&self) -> Vec<ImageRef> {
+            match *self { SetBGA(_,Some(iref)) => vec!(iref), _ => Vec::new() }
+        }
+
+        fn through_damage(&self) -> Option<Damage> {
+            match *self { Bomb(_,_,damage) => Some(damage), _ => None }
+        }
+    }
+
+    impl ObjConvOps for ObjData {
+        fn to_visible(&self) -> ObjData {
+            match *self {
+                Visible(lane,snd) | Invisible(lane,snd) |
+                LNStart(lane,snd) | LNDone(lane,snd) => Visible(lane,snd),
+                _ => panic!("to_visible for non-object")
+            }
+        }
+
+        fn to_invisible(&self) -> ObjData {
+            match *self {
+                Visible(lane,snd) | Invisible(lane,snd) |
+                LNStart(lane,snd) | LNDone(lane,snd) => Invisible(lane,snd),
+                _ => panic!("to_invisible for non-object")
+            }
+        }
+
+        fn to_lnstart(&self) -> ObjData {
+            match *self {
+                Visible(lane,snd) | Invisible(lane,snd) |
+                LNStart(lane,snd) | LNDone(lane,snd) => LNStart(lane,snd),
+                _ => panic!("to_lnstart for non-object")
+            }
+        }
+
+        fn to_lndone(&self) -> ObjData {
+            match *self {
+                Visible(lane,snd) | Invisible(lane,snd) |
+                LNStart(lane,snd) | LNDone(lane,snd) => LNDone(lane,snd),
+                _ => panic!("to_lndone for non-object")
+            }
+        }
+    }
+
+    /// Game play data associated to the time axis. It contains both objects (which are also
+    /// associated to lanes) and object-like effects.
+    #[deriving(PartialEq,Clone)]
+    pub struct Obj {
+        /// Time position in measures.
+        pub time: f64,
+        /// Actual data.
+        pub data: ObjData
+    }
+
+    #[allow(non_snake_case)]
+    impl Obj {
+        /// Creates a `Visible` object.
+        pub fn Visible(time: f64, lane: Lane, sref: Option<Key>) -> Obj {
+            Obj { time: time, data: Visible(lane, sref.map(SoundRef)) }
+        }
+
+        /// Creates an `Invisible` object.
+        pub fn Invisible(time: f64, lane: Lane, sref: Option<Key>) -> Obj {
+            Obj { time: time, data: Invisible(lane, sref.map(SoundRef)) }
+        }
+
+        /// Creates an `LNStart` object.
+        pub fn LNStart(time: f64, lane: Lane, sref: Option<Key>) -> Obj {
+            Obj { time: time, data: LNStart(lane, sref.map(SoundRef)) }
+        }
+
+        /// Creates an `LNDone` object.
+        pub fn LNDone(time: f64, lane: Lane, sref: Option<Key>) -> Obj {
+            Obj { time: time, data: LNDone(lane, sref.map(SoundRef)) }
+        }
+
+        /// Creates a `Bomb` object.
+        pub fn Bomb(time: f64, lane: Lane, sref: Option<Key>, damage: Damage) -> Obj {
+            Obj { time: time, data: Bomb(lane, sref.map(SoundRef), damage) }
+        }
+
+        /// Creates a `BGM` object.
+        pub fn BGM(time: f64, sref: Key) -> Obj {
+            Obj { time: time, data: BGM(SoundRef(sref)) }
+        }
+
+        /// Creates a `SetBGA` object.
+        pub fn SetBGA(time: f64, layer: BGALayer, iref: Option<Key>) -> Obj {
+            Obj { time: time, data: SetBGA(layer, iref.map(ImageRef)) }
+        }
+
+        /// Creates a `SetBPM` object.
+        pub fn SetBPM(time: f64, bpm: BPM) -> Obj {
+            Obj { time: time, data: SetBPM(bpm) }
+        }
+
+        /// Creates a `Stop` object.
+        pub fn Stop(time: f64, duration: Duration) -> Obj {
+            Obj { time: time, data: Stop(duration) }
+        }
+
+        /// Returns the number of a measure containing this object.
+        pub fn measure(&self) -> int { self.time.floor() as int }
+    }
+
+    impl ObjQueryOps for Obj {
+        fn is_visible(&self) -> bool { self.data.is_visible() }
+        fn is_invisible(&self) -> bool { self.data.is_invisible() }
+        fn is_lnstart(&self) -> bool { self.data.is_lnstart() }
+        fn is_lndone(&self) -> bool { self.data.is_lndone() }
+        fn is_ln(&self) -> bool { self.data.is_ln() }
+        fn is_bomb(&self) -> bool { self.data.is_bomb() }
+        fn is_soundable(&self) -> bool { self.data.is_soundable() }
+        fn is_gradable(&self) -> bool { self.data.is_gradable() }
+        fn is_renderable(&self) -> bool { self.data.is_renderable() }
+        fn is_object(&self) -> bool { self.data.is_object() }
+        fn is_bgm(&self) -> bool { self.data.is_bgm() }
+        fn is_setbga(&self) -> bool { self.data.is_setbga() }
+        fn is_setbpm(&self) -> bool { self.data.is_setbpm() }
+        fn is_stop(&self) -> bool { self.data.is_stop() }
+
+        fn object_lane(&self) -> Option<Lane> { self.data.object_lane() }
+        fn sounds(&self) -> Vec<SoundRef> { self.data.sounds() }
+        fn keydown_sound(&self) -> Option<SoundRef> { self.data.keydown_sound() }
+        fn keyup_sound(&self) -> Option<SoundRef> { self.data.keyup_sound() }
+        fn through_sound(&self) -> Option<SoundRef> { self.data.through_sound() }
+        fn images(&self) -> Vec<ImageRef> { self.data.images() }
+        fn through_damage(&self) -> Option<Damage> { self.data.through_damage() }
+    }
+
+    impl ObjConvOps for Obj {
+        fn to_visible(&self) -> Obj { Obj { time: self.time, data: self.data.to_visible() } }
+        fn to_invisible(&self) -> Obj { Obj { time: self.time, data: self.data.to_invisible() } }
+        fn to_lnstart(&self) -> Obj { Obj { time: self.time, data: self.data.to_lnstart() } }
+        fn to_lndone(&self) -> Obj { Obj { time: self.time, data: self.data.to_lndone() } }
+    }
+
+    //----------------------------------------------------------------------------------------------
+    // diagnostics
+
+    /// The severity of a diagnostic, in the increasing order.
+    #[deriving(PartialEq,Eq,PartialOrd,Ord,Clone,Show)]
+    pub enum Severity {
+        /// The line is valid but not supported by Angolmois, e.g. unknown commands.
+        Note,
+        /// The line is processed but likely to be a mistake, e.g. out-of-range values.
+        Warning,
+        /// The line is malformed and ignored in part or as a whole.
+        Error,
+    }
+
+    /// A problem found while parsing or sanitizing the BMS file.
+    #[deriving(Clone)]
+    pub struct Diagnostic {
+        /// The line number, starting from 1. 0 if the problem is not tied to a particular line,
+        /// e.g. the conflicting objects found by `sanitize_bms`.
+        pub line: uint,
+        /// The column number in characters, starting from 1.
+        pub column: uint,
+        /// The severity.
+        pub severity: Severity,
+        /// A human-readable message.
+        pub message: String,
+    }
+
+    impl fmt::Show for Diagnostic {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            let severity = match self.severity { Note => "note", Warning => "warning",
+                                                 Error => "error" };
+            if self.line == 0 {
+                write!(f, "{}: {}", severity, self.message)
+            } else {
+                write!(f, "line {}:{}: {}: {}", self.line, self.column, severity, self.message)
+            }
+        }
+    }
+
+    //----------------------------------------------------------------------------------------------
+    // BMS data
+
+    /// Default BPM. This value comes from the original BMS specification.
+    pub const DEFAULT_BPM: BPM = BPM(130.0);
+
+    /**
+     * Blit commands, which manipulate the image after the image had been loaded. This maps to BMS
+     * #BGA command. (C: `struct blitcmd`)
+     *
+     * Blitting occurs from the region `(x1,y1)-(x2,y2)` in the source surface to the region
+     * `(dx,dy)-(dx+(x2-x1),dy+(y2-y1))` in the destination surface. The rectangular region contains
+     * the upper-left corner but not the lower-right corner. The region is clipped to make
+     * the upper-left corner has non-negative coordinates and the size of the region doesn't exceed
+     * 256 by 256 pixels.
+     */
+    pub struct BlitCmd {
+        pub dst: ImageRef, pub src: ImageRef,
+        pub x1: int, pub y1: int, pub x2: int, pub y2: int, pub dx: int, pub dy: int
+    }
+
+    /// A value of BMS #PLAYER command signifying Single Play (SP), where only channels #1x are used
+    /// for the game play.
+    pub const SINGLE_PLAY: int = 1;
+    /// A value of BMS #PLAYER command signifying Couple Play, where channels #1x and #2x renders to
+    /// the different panels. They are meant to be played by different players with separate
+    /// gauges and scores, which Angolmois keeps for each side (see `player::SideState`) and shows
+    /// side by side during the game play and in the result screen. The game play continues until
+    /// both sides have failed.
+    pub const COUPLE_PLAY: int = 2;
+    /// A value of BMS #PLAYER command signifying Double Play (DP), where both channels #1x and #2x
+    /// renders to a single wide panel. The chart is still meant to be played by one person.
+    pub const DOUBLE_PLAY: int = 3;
+
+    /// A rhythmic division of the time position, determined by the finest subdivision of beats
+    /// which the position falls on. See also `Bms::beat_division`.
+    #[deriving(PartialEq,Eq,Clone)]
+    pub enum BeatDivision {
+        /// The position is on a beat (a quarter note).
+        QuarterBeat,
+        /// The position is on an eighth note but not on a beat.
+        EighthBeat,
+        /// The position is on a sixteenth note but not on an eighth note.
+        SixteenthBeat,
+        /// The position is not on any of above, e.g. on triplets.
+        OtherBeat,
+    }
+
+    /// The number of `BeatDivision` values.
+    pub const NBEATDIVISIONS: uint = 4;
+
+    impl BeatDivision {
+        /// Returns an index of the division from 0 to `NBEATDIVISIONS - 1`.
+        pub fn index(&self) -> uint {
+            match *self {
+                QuarterBeat => 0,
+                EighthBeat => 1,
+                SixteenthBeat => 2,
+                OtherBeat => 3,
+            }
+        }
+    }
+
+    /// Loaded BMS data. It is not a global state unlike C.
+    pub struct Bms {
+        /// Title. Maps to BMS #TITLE command. (C: `string[S_TITLE]`)
+        pub title: Option<String>,
+        /// Genre. Maps to BMS #GENRE command. (C: `string[S_GENRE]`)
+        pub genre: Option<String>,
+        /// Artist. Maps to BMS #ARTIST command. (C: `string[S_ARTIST]`)
+        pub artist: Option<String>,
+        /// Path to an image for loading screen. Maps to BMS #STAGEFILE command.
+        /// (C: `string[S_STAGEFILE]`)
+        pub stagefile: Option<String>,
+        /// Path to a banner image, normally 300 by 80 pixels. Maps to BMS #BANNER command.
+        pub banner: Option<String>,
+        /// Path to a background image for loading screen, displayed behind #STAGEFILE image.
+        /// Maps to BMS #BACKBMP command.
+        pub backbmp: Option<String>,
+        /// A base path used for loading all other resources. Maps to BMS #PATH_WAV command.
+        /// (C: `string[S_BASEPATH]`)
+        pub basepath: Option<String>,
+        /// Path to a short audio clip used for previewing the song. Maps to BMS #PREVIEW command.
+        pub preview: Option<String>,
+
+        /// Game mode. One of `SINGLE_PLAY`(1), `COUPLE_PLAY`(2) or `DOUBLE_PLAY`(3). Maps to BMS
+        /// #PLAYER command. (C: `value[V_PLAYER]`)
+        pub player: int,
+        /// Game level. Does not affect the actual game play. Maps to BMS #PLAYLEVEL command.
+        /// (C: `value[V_PLAYLEVEL]`)
+        pub playlevel: int,
+        /// Gauge difficulty. Higher is easier. Maps to BMS #RANK command. (C: `value[V_RANK]`)
+        pub rank: int,
+
+        /// Initial BPM. (C: `initbpm`)
+        pub initbpm: BPM,
+        /// Paths to sound file relative to `basepath` or BMS file. (C: `sndpath`)
+        pub sndpath: Vec<Option<String>>,
+        /// Paths to image/movie file relative to `basepath` or BMS file. (C: `imgpath`)
+        pub imgpath: Vec<Option<String>>,
+        /// List of blit commands to be executed after `imgpath` is loaded. (C: `blitcmd`)
+        pub blitcmd: Vec<BlitCmd>,
+
+        /// List of objects sorted by the position. (C: `objs`)
+        pub objs: Vec<Obj>,
+        /// The scaling factor of measures. Defaults to 1.0. (C: `shortens`)
+        pub shortens: Vec<f64>,
+        /// The number of measures after the origin, i.e. the length of the BMS file. The play stops
+        /// after the last measure. (C: `length`)
+        pub nmeasures: uint,
+        /// The MD5 digest of the raw chart file in hexadecimal digits, if read from the file.
+        pub md5: Option<String>,
+
+        /// Problems found while parsing, in the order of appearance, followed by the changes made
+        /// by `sanitize_bms`.
+        pub diagnostics: Vec<Diagnostic>
+    }
+
+    impl Bms {
+        /// Creates a default value of BMS data.
+        pub fn new() -> Bms {
+            Bms { title: None, genre: None, artist: None, stagefile: None, banner: None,
+                  backbmp: None, basepath: None, preview: None,
+                  player: SINGLE_PLAY, playlevel: 0, rank: 2, initbpm: DEFAULT_BPM,
+                  sndpath: Vec::from_elem(MAXKEY as uint, None),
+                  imgpath: Vec::from_elem(MAXKEY as uint, None), blitcmd: Vec::new(),
+                  objs: Vec::new(), shortens: Vec::new(), nmeasures: 0, md5: None,
+                  diagnostics: Vec::new() }
+        }
+
+        /// Returns a scaling factor of given measure number. The default scaling factor is 1.0, and
+        /// that value applies to any out-of-bound measures. (C: `shorten`)
+        pub fn shorten(&self, measure: int) -> f64 {
+            if measure < 0 || measure as uint >= self.shortens.len() {
+                1.0
+            } else {
+                self.shortens[measure as uint]
+            }
+        }
+
+        /// Calculates the virtual time that is `offset` measures away from the virtual time `base`.
+        /// This takes account of the scaling factor, so if first four measures are scaled by 1/4,
+        /// then `adjust_object_time(0.0, 2.0)` results in `5.0`. (C: `adjust_object_time`)
+        pub fn adjust_object_time(&self, base: f64, offset: f64) -> f64 {
+            let basemeasure = base.floor() as int;
+            let baseshorten = self.shorten(basemeasure);
+            let basefrac = base - basemeasure as f64;
+            let tonextmeasure = (1.0 - basefrac) * baseshorten;
+            if offset < tonextmeasure {
+                base + offset / baseshorten
+            } else {
+                let mut offset = offset - tonextmeasure;
+                let mut i = basemeasure + 1;
+                let mut curshorten = self.shorten(i);
+                while offset >= curshorten {
+                    offset -= curshorten;
+                    i += 1;
+                    curshorten = self.shorten(i);
+                }
+                i as f64 + offset / curshorten
+            }
+        }
+
+        /// Returns the first beat at or after the virtual time `time`. A beat is a quarter of
+        /// the unscaled measure and every measure starts with a beat, so for example, the measure
+        /// scaled by 0.75x has three beats and the measure scaled by 1.2x has five beats with
+        /// the last one shortened.
+        pub fn beat_at_or_after(&self, time: f64) -> f64 {
+            let measure = time.floor();
+            let beat = 0.25 / self.shorten(measure as int);
+            let pos = measure + ((time - measure) / beat).ceil() * beat;
+            if pos < measure + 1.0 {pos} else {measure + 1.0}
+        }
+
+        /// Returns the rhythmic division of the virtual time `time`. Beats are counted from
+        /// the start of each measure as in `beat_at_or_after`.
+        pub fn beat_division(&self, time: f64) -> BeatDivision {
+            let measure = time.floor();
+            let beats = (time - measure) * self.shorten(measure as int) * 4.0;
+            let near_integer = |x: f64| (x - x.round()).abs() < 1e-4;
+            if near_integer(beats) {
+                QuarterBeat
+            } else if near_integer(beats * 2.0) {
+                EighthBeat
+            } else if near_integer(beats * 4.0) {
+                SixteenthBeat
+            } else {
+                OtherBeat
+            }
+        }
+
+        /// Calculates an adjusted offset between the virtual time `base` and `base + offset`.
+        /// This takes account of the measure scaling factor, so for example, the adjusted offset
+        /// between the virtual time 0.0 and 2.0 is, if the measure #000 is scaled by 1.2x,
+        /// 2.2 measures instead of 2.0 measures. (C: `adjust_object_position`)
+        pub fn adjust_object_position(&self, base: f64, time: f64) -> f64 {
+            let basemeasure = base.floor() as int;
+            let timemeasure = time.floor() as int;
+            let basefrac = base - basemeasure as f64;
+            let timefrac = time - timemeasure as f64;
+            let mut pos = timefrac * self.shorten(timemeasure) -
+                          basefrac * self.shorten(basemeasure);
+            for i in range(basemeasure, timemeasure) {
+                pos += self.shorten(i);
+            }
+            pos
+        }
+    }
+
+    //----------------------------------------------------------------------------------------------
+    // parsing
+
+    /// Converts a single alphanumeric (base-36) letter to an integer. (C: `getdigit`)
+    fn getdigit(n: char) -> Option<int> {
+        match n {
+            '0'...'9' => Some((n as int) - ('0' as int)),
+            'a'...'z' => Some((n as int) - ('a' as int) + 10),
+            'A'...'Z' => Some((n as int) - ('A' as int) + 10),
+            _ => None
+        }
+    }
+
+    /// Converts the first two letters of `s` to a `Key`. (C: `key2index`)
+    pub fn key2index(s: &[char]) -> Option<int> {
+        if s.len() < 2 { return None; }
+        getdigit(s[0]).and_then(|a| {
+            getdigit(s[1]).map(|b| { a * 36 + b })
+        })
+    }
+
+    impl FromStrPrefix for Key {
+        fn from_str_prefix<'a>(s: &'a str) -> Option<(Key, &'a str)> {
+            if s.len() < 2 { return None; }
+            let str::CharRange {ch:c1, next:p1} = s.char_range_at(0);
+            getdigit(c1).and_then(|a| {
+                let str::CharRange {ch:c2, next:p2} = s.char_range_at(p1);
+                getdigit(c2).map(|b| {
+                    assert!(p2 == 2); // both characters should be in ASCII
+                    (Key(a * 36 + b), s[p2..])
+                })
+            })
+        }
+    }
+
+    /// A wrapper type for a measure number. Only used for parsing.
+    struct Measure(uint);
+
+    impl FromStrPrefix for Measure {
+        fn from_str_prefix<'a>(s: &'a str) -> Option<(Measure, &'a str)> {
+            let isdigit = |c| '0' <= c && c <= '9';
+            if s.len() >= 3 && isdigit(s.char_at(0)) && isdigit(s.char_at(1))
+                            && isdigit(s.char_at(2)) {
+                let measure = from_str::<uint>(s[..3]).unwrap();
+                Some((Measure(measure), s[3..]))
+            } else {
+                None
+            }
+        }
+    }
+
+    /// The size of chunks read by `LineReader`.
+    const LINE_READER_CHUNK: uint = 8192;
+
+    /**
+     * Splits the input into lines as it reads, without buffering the whole input. The line
+     * terminator (`\n`) is stripped but any preceding `\r` is kept. The MD5 digest of the input
+     * is calculated along the way.
+     */
+    struct LineReader<'r> {
+        /// The underlying reader.
+        reader: &'r mut (Reader + 'r),
+        /// The chunk read but not yet split.
+        buf: Vec<u8>,
+        /// The start of unconsumed bytes in `buf`.
+        pos: uint,
+        /// True if the reader has reached the end of input.
+        eof: bool,
+        /// The MD5 digest of bytes read so far.
+        md5: ::util::md5::Md5,
+    }
+
+    impl<'r> LineReader<'r> {
+        /// Creates a new line reader.
+        fn new(reader: &'r mut (Reader + 'r)) -> LineReader<'r> {
+            LineReader { reader: reader, buf: Vec::with_capacity(LINE_READER_CHUNK), pos: 0,
+                         eof: false, md5: ::util::md5::Md5::new() }
+        }
+
+        /// Reads the next line into `line`, replacing its contents. Returns false at the end of
+        /// input, in which case `line` is empty.
+        fn read_line(&mut self, line: &mut Vec<u8>) -> io::IoResult<bool> {
+            line.clear();
+            loop {
+                let rest = self.buf[self.pos..];
+                match rest.iter().position(|&ch| ch == b'\n') {
+                    Some(i) => {
+                        line.push_all(rest[..i]);
+                        self.pos += i + 1;
+                        return Ok(true);
+                    }
+                    None => { line.push_all(rest); }
+                }
+
+                if self.eof { return Ok(!line.is_empty()); }
+                self.buf.clear();
+                self.pos = 0;
+                match self.reader.push(LINE_READER_CHUNK, &mut self.buf) {
+                    Ok(_) => { self.md5.input(self.buf[]); }
+                    Err(ref err) if err.kind == io::EndOfFile => { self.eof = true; }
+                    Err(err) => { return Err(err); }
+                }
+            }
+        }
+
+        /// Returns the MD5 digest of the whole input in lowercase hexadecimal digits.
+        fn hex_digest(self) -> String {
+            ::util::md5::to_hex(self.md5.result()[])
+        }
+    }
+
+    /**
+     * Determines how the parser treats the known divergences between BMS players, so that charts
+     * authored against a particular player behave identically. Every other behavior is shared.
+     *
+     * - Odd-length data lines: the last character is ignored, except for `Lr2Like` which ignores
+     *   the whole line.
+     * - #IF outside of #RANDOM blocks: never taken, except for `Lr2Like` which always takes it.
+     * - Duplicate #WAVxx: the last definition wins, except for `NanasiLike` which keeps the first.
+     * - The default #LNTYPE: 1, except for `NanasiLike` which defaults to 2.
+     *
+     * The profile used for the game play is recorded in replays, and the local records for
+     * the chart are kept separately for each profile (see `CompatProfile::name`).
+     */
+    #[deriving(PartialEq,Eq,Clone,Show)]
+    pub enum CompatProfile {
+        /// The behavior of Angolmois, which follows the original specification where possible.
+        Canonical,
+        /// Mimics Lunatic Rave 2.
+        Lr2Like,
+        /// Mimics nanasi and its descendants.
+        NanasiLike,
+    }
+
+    impl CompatProfile {
+        /// Returns the compatibility profile with given name (case-insensitive) if any.
+        pub fn from_name(name: &str) -> Option<CompatProfile> {
+            use std::ascii::AsciiExt;
+            match name.to_ascii_lower()[] {
+                "canonical" => Some(Canonical),
+                "lr2" => Some(Lr2Like),
+                "nanasi" => Some(NanasiLike),
+                _ => None
+            }
+        }
+
+        /// Returns true if a data line with an odd number of characters is ignored as a whole.
+        pub fn drops_odd_data_lines(&self) -> bool { *self == Lr2Like }
+
+        /// Returns true if #IF outside of #RANDOM blocks is always taken.
+        pub fn takes_if_without_random(&self) -> bool { *self == Lr2Like }
+
+        /// Returns true if the first one of duplicate #WAVxx commands is used.
+        pub fn keeps_first_wav(&self) -> bool { *self == NanasiLike }
+
+        /// Returns the #LNTYPE value used when the chart does not have one.
+        pub fn default_lntype(&self) -> int { if *self == NanasiLike {2} else {1} }
+
+        /// Returns the code of the compatibility profile used in replay files.
+        pub fn code(&self) -> u8 {
+            match *self { Canonical => 0, Lr2Like => 1, NanasiLike => 2 }
+        }
+
+        /// Returns the compatibility profile with given code used in replay files if any.
+        pub fn from_code(code: u8) -> Option<CompatProfile> {
+            match code {
+                0 => Some(Canonical),
+                1 => Some(Lr2Like),
+                2 => Some(NanasiLike),
+                _ => None
+            }
+        }
+
+        /// Returns the name of the compatibility profile, as accepted by `from_name`.
+        pub fn name(&self) -> &'static str {
+            match *self { Canonical => "canonical", Lr2Like => "lr2", NanasiLike => "nanasi" }
+        }
+    }
+
+    /// Reads and parses the BMS file with given RNG from given reader.
+    pub fn parse_bms_from_reader<R:Rng>(f: &mut Reader, profile: CompatProfile,
+                                        r: &mut R) -> io::IoResult<Bms> {
+        parse_bms_from_reader_with(f, profile, |max| r.gen_range(1, max + 1))
+    }
+
+    /// Reads and parses the BMS file from given reader. Lines not in UTF-8 are decoded as
+    /// Shift_JIS (see `::util::encoding::decode_text`). `gen(max)` should return a value between
+    /// 1 and `max` (inclusive) for each #RANDOM command, which allows the caller to choose
+    /// a particular branch.
+    pub fn parse_bms_from_reader_with(f: &mut Reader, profile: CompatProfile,
+                                      gen: |int| -> int) -> io::IoResult<Bms> {
+        /// The list of recognized prefixes of directives. The longest prefix should come first.
+        /// Also note that not all recognized prefixes are processed (counterexample being `ENDSW`).
+        /// (C: `bmsheader`)
+        static BMS_HEADER: &'static [&'static str] = &[
+            "TITLE", "GENRE", "ARTIST", "STAGEFILE", "BANNER", "BACKBMP", "PATH_WAV", "BPM",
+            "PREVIEW", "PLAYER", "PLAYLEVEL", "RANK", "LNTYPE", "LNOBJ", "WAV", "BMP",
+            "BGA", "STOP", "STP", "RANDOM", "SETRANDOM", "ENDRANDOM", "IF",
+            "ELSEIF", "ELSE", "ENDSW", "END"];
+
+        let mut bms = Bms::new();
+
+        /// Records a diagnostic at given line and column.
+        fn diagnose(bms: &mut Bms, (line, column): (uint, uint), severity: Severity,
+                    message: String) {
+            bms.diagnostics.push(Diagnostic { line: line, column: column, severity: severity,
+                                              message: message });
+        }
+
+        /// The state of the block, for determining which lines should be processed.
+        #[deriving(PartialEq)]
+        enum BlockState {
+            /// Not contained in the #IF block. (C: `state == -1`)
+            Outside,
+            /// Active. (C: `state == 0`)
+            Process,
+            /// Inactive, but (for the purpose of #IF/#ELSEIF/#ELSE/#ENDIF structure) can move to
+            /// `Process` state when matching clause appears. (C: `state == 1`)
+            Ignore,
+            /// Inactive and won't be processed until the end of block. (C: `state == 2`)
+            NoFurther
+        }
+
+        impl BlockState {
+            /// Returns true if lines should be ignored in the current block given that the parent
+            /// block was active. (C: `state > 0`)
+            fn inactive(&self) -> bool {
+                match *self { Outside | Process => false, Ignore | NoFurther => true }
+            }
+        }
+
+        /**
+         * Block information. The parser keeps a list of nested blocks and determines if
+         * a particular line should be processed or not. (C: `struct rnd`)
+         *
+         * Angomlois actually recognizes only one kind of blocks, starting with #RANDOM or
+         * #SETRANDOM and ending with #ENDRANDOM or #END(IF) outside an #IF block. An #IF block is
+         * a state within #RANDOM, so it follows that #RANDOM/#SETRANDOM blocks can nest but #IF
+         * can't nest unless its direct parent is #RANDOM/#SETRANDOM.
+         */
+        #[deriving(PartialEq)]
+        struct Block {
+            /// A generated value if any. It can be `None` if this block is the topmost one (which
+            /// is actually not a block but rather a sentinel) or the last `#RANDOM` or `#SETRANDOM`
+            /// command was invalid, and #IF in that case will always evaluates to false. (C: `val`
+            /// field)
+            val: Option<int>,
+            /// The state of the block. (C: `state` field)
+            state: BlockState,
+            /// True if the parent block is already ignored so that this block should be ignored
+            /// no matter what `state` is. (C: `skip` field)
+            skip: bool
+        }
+
+        impl Block {
+            /// Returns true if lines should be ignored in the current block.
+            fn inactive(&self) -> bool { self.skip || self.state.inactive() }
+        }
+
+        // A list of nested blocks. (C: `rnd`)
+        let mut blk = vec!(Block { val: None, state: Outside, skip: false });
+
+        /// An unprocessed data line of BMS file. `at` is the line and column of `data`.
+        #[deriving(Clone)]
+        struct BmsLine { measure: uint, chan: Key, data: String, at: (uint, uint) }
+
+        // A list of unprocessed data lines. They have to be sorted with a stable algorithm and
+        // processed in the order of measure number. (C: `bmsline`)
+        let mut bmsline = Vec::new();
+        // A table of BPMs. Maps to BMS #BPMxx command. (C: `bpmtab`)
+        let mut bpmtab: Vec<Option<BPM>> = Vec::from_elem(MAXKEY as uint, None);
+        // A table of the length of scroll stoppers. Maps to BMS #STOP/#STP commands. (C: `stoptab`)
+        let mut stoptab: Vec<Option<Duration>> = Vec::from_elem(MAXKEY as uint, None);
+
+        // Allows LNs to be specified as a consecutive row of same or non-00 alphanumeric keys (MGQ
+        // type, #LNTYPE 2). The default is to specify LNs as two endpoints (RDM type, #LNTYPE 1),
+        // unless the compatibility profile says otherwise. (C: `value[V_LNTYPE]`)
+        let mut consecutiveln = profile.default_lntype() == 2;
+
+        // A table of end-of-LN markers used in LN specification for channels #1x/2x. Maps to BMS
+        // #LNOBJ command, which can be given multiple times. (C: `value[V_LNOBJ]`)
+        let mut lnobjs: Vec<bool> = Vec::from_elem(MAXKEY as uint, false);
+
+        // the input is read line by line into the reusable buffer
+        let mut reader = LineReader::new(f);
+        let mut linebuf = Vec::new();
+        let mut lineno = 0u;
+        while try!(reader.read_line(&mut linebuf)) {
+            lineno += 1;
+            let line0 = ::util::encoding::decode_text(linebuf[]);
+            let line0 = line0.as_slice();
+            let line = line0;
+
+            // skip non-command lines
+            let line = line.trim_left();
+            if !line.starts_with("#") { continue; }
+            let cmdat = (lineno, line0.char_len() - line.char_len() + 1);
+            let line = line[1..];
+
+            // search for header prefix. the header list (`BMS_HEADER`) is in the decreasing order
+            // of prefix length.
+            let mut prefix = "";
+            for &header in BMS_HEADER.iter() {
+                use std::ascii::AsciiExt;
+                if line.len() >= header.len() && line[..header.len()].to_ascii_upper()[] == header {
+                    prefix = header;
+                    break;
+                }
+            }
+            let line = line[prefix.len()..];
+            let at = (lineno, line0.char_len() - line.char_len() + 1);
+
+            // Records a diagnostic pointing to the argument of the command.
+            macro_rules! diag(
+                ($severity:expr, $($e:expr),+) => (
+                    diagnose(&mut bms, at, $severity, format!($($e),+))
+                )
+            )
+            macro_rules! malformed(
+                () => (diag!(Error, "malformed #{} command", prefix))
+            )
+
+            // Common readers.
+            macro_rules! read(
+                (string $string:ident) => ({
+                    let mut text = "";
+                    if lex!(line; ws, str* -> text, ws*, !) {
+                        bms.$string = Some(text.to_string());
+                    } else {
+                        malformed!();
+                    }
+                });
+                (value $value:ident) => ({
+                    if !lex!(line; ws, int -> bms.$value) { malformed!(); }
+                });
+                (path $paths:ident, $keepfirst:expr) => ({
+                    let mut key = Key(-1);
+                    let mut path = "";
+                    if lex!(line; Key -> key, ws, str -> path, ws*, !) {
+                        let Key(key) = key;
+                        if !$keepfirst || bms.$paths[key as uint].is_none() {
+                            bms.$paths[mut][key as uint] = Some(path.to_string());
+                        }
+                    } else {
+                        malformed!();
+                    }
+                })
+            )
+
+            assert!(!blk.is_empty());
+            match (prefix, blk.last().unwrap().inactive()) {
+                // #TITLE|#GENRE|#ARTIST|#STAGEFILE|#BANNER|#BACKBMP|#PATH_WAV|#PREVIEW <string>
+                ("TITLE", false) => read!(string title),
+                ("GENRE", false) => read!(string genre),
+                ("ARTIST", false) => read!(string artist),
+                ("STAGEFILE", false) => read!(string stagefile),
+                ("BANNER", false) => read!(string banner),
+                ("BACKBMP", false) => read!(string backbmp),
+                ("PATH_WAV", false) => read!(string basepath),
+                ("PREVIEW", false) => read!(string preview),
+
+                // #BPM <float> or #BPMxx <float>
+                ("BPM", false) => {
+                    let mut key = Key(-1);
+                    let mut bpm = 0.0;
+                    let parsed = if lex!(line; Key -> key, ws, f64 -> bpm) {
+                        let Key(key) = key;
+                        bpmtab[mut][key as uint] = Some(BPM(bpm));
+                        true
+                    } else if lex!(line; ws, f64 -> bpm) {
+                        bms.initbpm = BPM(bpm);
+                        true
+                    } else {
+                        malformed!();
+                        false
+                    };
+                    if parsed && bpm <= 0.0 {
+                        diag!(Warning, "BPM should be positive, not {}", bpm);
+                    }
+                }
+
+                // #PLAYER|#PLAYLEVEL|#RANK <int>
+                ("PLAYER", false) => {
+                    read!(value player);
+                    if bms.player < SINGLE_PLAY || bms.player > DOUBLE_PLAY {
+                        diag!(Warning, "#PLAYER should be between 1 and 3, not {}", bms.player);
+                    }
+                }
+                ("PLAYLEVEL", false) => read!(value playlevel),
+                ("RANK", false) => {
+                    read!(value rank);
+                    if bms.rank < 0 || bms.rank > 4 {
+                        diag!(Warning, "#RANK should be between 0 and 4, not {}", bms.rank);
+                    }
+                }
+
+                // #LNTYPE <int>
+                ("LNTYPE", false) => {
+                    let mut lntype = 1;
+                    if lex!(line; ws, int -> lntype) {
+                        consecutiveln = lntype == 2;
+                        if lntype != 1 && lntype != 2 {
+                            diag!(Warning, "#LNTYPE should be 1 or 2, not {}", lntype);
+                        }
+                    } else {
+                        malformed!();
+                    }
+                }
+                // #LNOBJ <key>
+                ("LNOBJ", false) => {
+                    let mut key = Key(-1);
+                    if lex!(line; ws, Key -> key) {
+                        let Key(key) = key;
+                        lnobjs[mut][key as uint] = true;
+                    } else {
+                        malformed!();
+                    }
+                }
+
+                // #WAVxx|#BMPxx <path>
+                ("WAV", false) => read!(path sndpath, profile.keeps_first_wav()),
+                ("BMP", false) => read!(path imgpath, false),
+
+                // #BGAxx yy <int> <int> <int> <int> <int> <int>
+                ("BGA", false) => {
+                    let mut dst = Key(0);
+                    let mut src = Key(0);
+                    let mut bc = BlitCmd { dst: ImageRef(Key(0)), src: ImageRef(Key(0)),
+                                           x1: 0, y1: 0, x2: 0, y2: 0, dx: 0, dy: 0 };
+                    if lex!(line; Key -> dst, ws, Key -> src, ws,
+                                  int -> bc.x1, ws, int -> bc.y1, ws,
+                                  int -> bc.x2, ws, int -> bc.y2, ws,
+                                  int -> bc.dx, ws, int -> bc.dy) {
+                        bc.src = ImageRef(src);
+                        bc.dst = ImageRef(dst);
+                        bms.blitcmd.push(bc);
+                    } else {
+                        malformed!();
+                    }
+                }
+
+                // #STOPxx <int>
+                ("STOP", false) => {
+                    let mut key = Key(-1);
+                    let mut duration = 0;
+                    if lex!(line; Key -> key, ws, int -> duration) {
+                        let Key(key) = key;
+                        stoptab[mut][key as uint] = Some(Measures(duration as f64 / 192.0));
+                        if duration < 0 { diag!(Warning, "negative #STOP duration {}", duration); }
+                    } else {
+                        malformed!();
+                    }
+                }
+
+                // #STP<int>.<int> <int>
+                ("STP", false) => {
+                    let mut measure = Measure(0);
+                    let mut frac = 0;
+                    let mut duration = 0;
+                    if lex!(line; Measure -> measure, lit '.', uint -> frac, ws,
+                                  int -> duration) {
+                        if duration > 0 {
+                            let Measure(measure) = measure;
+                            let pos = measure as f64 + frac as f64 * 0.001;
+                            let dur = Seconds(duration as f64 * 0.001);
+                            bms.objs.push(Obj::Stop(pos, dur));
+                        } else {
+                            diag!(Warning, "#STP duration should be positive, not {}", duration);
+                        }
+                    } else {
+                        malformed!();
+                    }
+                }
+
+                // #RANDOM|#SETRANDOM <int>
+                ("RANDOM", _) |
+                ("SETRANDOM", _) => {
+                    let mut val = 0;
+                    if lex!(line; ws, int -> val) {
+                        if val <= 0 { diag!(Warning, "#{} value should be positive", prefix); }
+                        let val = if val <= 0 {None} else {Some(val)};
+
+                        // do not generate a random value if the entire block is skipped (but it
+                        // still marks the start of block)
+                        let inactive = blk.last().unwrap().inactive();
+                        let generated = match val {
+                            Some(val) if prefix == "SETRANDOM" => Some(val),
+                            Some(val) if !inactive => Some(gen(val)),
+                            _ => None
+                        };
+                        blk.push(Block { val: generated, state: Outside, skip: inactive });
+                    } else {
+                        malformed!();
+                    }
+                }
+
+                // #ENDRANDOM
+                ("ENDRANDOM", _) => {
+                    if blk.len() > 1 { blk.pop(); }
+                }
+
+                // #IF|#ELSEIF <int>
+                ("IF", _) |
+                ("ELSEIF", _) => {
+                    let mut val = 0;
+                    if lex!(line; ws, int -> val) {
+                        let val = if val <= 0 {None} else {Some(val)};
+
+                        // the topmost block is a sentinel, so #IF there is outside of #RANDOM
+                        let toplevel = blk.len() == 1;
+                        let last = blk.last_mut().unwrap();
+                        last.state =
+                            if (prefix == "IF" && !last.state.inactive()) || last.state == Ignore {
+                                if toplevel && profile.takes_if_without_random() {
+                                    Process
+                                } else if val.is_none() || val != last.val {
+                                    Ignore
+                                } else {
+                                    Process
+                                }
+                            } else {
+                                NoFurther
+                            };
+                    } else {
+                        malformed!();
+                    }
+                }
+
+                // #ELSE
+                ("ELSE", _) => {
+                    let last = blk.last_mut().unwrap();
+                    last.state = if last.state == Ignore {Process} else {NoFurther};
+                }
+
+                // #END(IF)
+                ("END", _) => {
+                    for &idx in blk.iter().rposition(|&i| i.state != Outside).iter() {
+                        if idx > 0 { blk.truncate(idx + 1); }
+                    }
+
+                    blk.last_mut().unwrap().state = Outside;
+                }
+
+                // #nnnmm:...
+                ("", false) => {
+                    let mut measure = Measure(0);
+                    let mut chan = Key(0);
+                    let mut data = "";
+                    if lex!(line; Measure -> measure, Key -> chan, lit ':', ws*,
+                                  str -> data, ws*, !) {
+                        let Measure(measure) = measure;
+                        let offset = data.as_ptr() as uint - line0.as_ptr() as uint;
+                        let dataat = (lineno, line0[..offset].char_len() + 1);
+                        bmsline.push(BmsLine { measure: measure, chan: chan,
+                                               data: data.to_string(), at: dataat })
+                    } else if line.len() >= 3 && line[..3].chars().all(|c| c.is_digit()) {
+                        diag!(Error, "malformed data line");
+                    } else {
+                        use std::ascii::AsciiExt;
+                        let name = line.split(|c: char| c.is_whitespace()).next().unwrap_or("");
+                        if !name.is_empty() {
+                            diagnose(&mut bms, cmdat, Note,
+                                     format!("unsupported command #{}", name.to_ascii_upper()));
+                        }
+                    }
+                }
+
+                (_, _) => {}
+            }
+        }
+        bms.md5 = Some(reader.hex_digest());
+
+        // Poor BGA defined by #BMP00 wouldn't be played if it is a movie. We can't just let it
+        // played at the beginning of the chart as the "beginning" is not always 0.0 (actually,
+        // `originoffset`). Thus we add an artificial BGA object at time 0.0 only when the other
+        // poor BGA does not exist at this position. (C: `poorbgafix`)
+        let mut poorbgafix = true;
+
+        // Indices to last visible object per channels. A marker specified by #LNOBJ will turn
+        // this last object to the start of LN. (C: `prev12`)
+        let mut lastvis: [Option<uint>, ..NLANES] = [None, ..NLANES];
+
+        // Indices to last LN start or end inserted (and not finalized yet) per channels.
+        // If `consecutiveln` is on (#LNTYPE 2), the position of referenced object gets updated
+        // during parsing; if off (#LNTYPE 1), it is solely used for checking if we are inside
+        // the LN or not. (C: `prev56`)
+        let mut lastln: [Option<uint>, ..NLANES] = [None, ..NLANES];
+
+        // The position of the last LN start in the BMS file per channels, for diagnostics.
+        let mut lastlnat: [(uint, uint), ..NLANES] = [(0, 0), ..NLANES];
+
+        {
+            // Adds an object. Objects are sorted by its position later.
+            let add = |bms: &mut Bms, obj: Obj| { bms.objs.push(obj); };
+
+            // Adds an object and returns its position. LN parsing generally mutates the existing
+            // object for simplicity.
+            let mark = |bms: &mut Bms, obj: Obj| -> Option<uint> {
+                let marked = bms.objs.len();
+                bms.objs.push(obj);
+                Some(marked)
+            };
+
+            // Handles a non-00 alphanumeric key `v` positioned at the particular channel `chan` and
+            // particular position `t`. The position `t2` next to `t` is used for some cases that
+            // an alphanumeric key designates an area rather than a point.
+            // `at` is the position of `v` in the BMS file, used for diagnostics.
+            let handle_key = |bms: &mut Bms, chan: Key, t: f64, t2: f64, v: Key,
+                              at: (uint, uint)| {
+                match *chan {
+                    // channel #01: BGM
+                    1 => { add(bms, Obj::BGM(t, v)); }
+
+                    // channel #03: BPM as an hexadecimal key
+                    3 => {
+                        match v.to_hex() {
+                            Some(v) => add(bms, Obj::SetBPM(t, BPM(v as f64))),
+                            None => diagnose(bms, at, Warning,
+                                             format!("invalid hexadecimal BPM {}", v)),
+                        }
+                    }
+
+                    // channel #04: BGA layer 1
+                    4 => { add(bms, Obj::SetBGA(t, Layer1, Some(v))); }
+
+                    // channel #06: POOR BGA
+                    6 => {
+                        add(bms, Obj::SetBGA(t, PoorBGA, Some(v)));
+                        poorbgafix = false; // we don't add artificial BGA
+                    }
+
+                    // channel #07: BGA layer 2
+                    7 => { add(bms, Obj::SetBGA(t, Layer2, Some(v))); }
+
+                    // channel #08: BPM defined by #BPMxx
+                    8 => {
+                        let bpm = match bpmtab[*v as uint] {
+                            Some(bpm) => bpm,
+                            None => {
+                                diagnose(bms, at, Warning, format!("undefined #BPM{}", v));
+                                DEFAULT_BPM
+                            }
+                        };
+                        add(bms, Obj::SetBPM(t, bpm));
+                    }
+
+                    // channel #09: scroll stopper defined by #STOPxx
+                    9 => {
+                        match stoptab[*v as uint] {
+                            Some(duration) => add(bms, Obj::Stop(t, duration)),
+                            None => diagnose(bms, at, Warning, format!("undefined #STOP{}", v)),
+                        }
+                    }
+
+                    // channel #0A: BGA layer 3
+                    10 => { add(bms, Obj::SetBGA(t, Layer3, Some(v))); }
+
+                    // channels #1x/2x: visible object, possibly LNs when #LNOBJ is in active
+                    36/*1*36*/...107/*3*36-1*/ => {
+                        let lane = Lane::from_channel(chan);
+                        if lnobjs[*v as uint] {
+                            // change the last inserted visible object to the start of LN if any.
+                            let lastvispos = lastvis[*lane];
+                            for &pos in lastvispos.iter() {
+                                assert!(bms.objs[pos].is_visible());
+                                bms.objs[mut][pos] = bms.objs[pos].to_lnstart();
+                                add(bms, Obj::LNDone(t, lane, Some(v)));
+                                lastvis[*lane] = None;
+                            }
+                        } else {
+                            lastvis[*lane] = mark(bms, Obj::Visible(t, lane, Some(v)));
+                        }
+                    }
+
+                    // channels #3x/4x: invisible object
+                    108/*3*36*/...179/*5*36-1*/ => {
+                        let lane = Lane::from_channel(chan);
+                        add(bms, Obj::Invisible(t, lane, Some(v)));
+                    }
+
+                    // channels #5x/6x, #LNTYPE 1: LN endpoints
+                    180/*5*36*/...251/*7*36-1*/ if !consecutiveln => {
+                        let lane = Lane::from_channel(chan);
+
+                        // a pair of non-00 alphanumeric keys designate one LN. if there are an odd
+                        // number of them, the last LN is implicitly closed later.
+                        if lastln[*lane].is_some() {
+                            lastln[*lane] = None;
+                            add(bms, Obj::LNDone(t, lane, Some(v)));
+                        } else {
+                            lastln[*lane] = mark(bms, Obj::LNStart(t, lane, Some(v)));
+                            lastlnat[*lane] = at;
+                        }
+                    }
+
+                    // channels #5x/6x, #LNTYPE 2: LN areas
+                    180/*5*36*/...251/*7*36-1*/ if consecutiveln => {
+                        let lane = Lane::from_channel(chan);
+
+                        // one non-00 alphanumeric key, in the absence of other information,
+                        // inserts one complete LN starting at `t` and ending at `t2`.
+                        //
+                        // the next non-00 alphanumeric key also inserts one complete LN
+                        // from `t` to `t2`, unless there is already an end of LN at `t`
+                        // in which case the end of LN is simply moved from `t` to `t2`
+                        // (effectively increasing the length of previous LN).
+                        match lastln[*lane] {
+                            Some(pos) if bms.objs[pos].time == t => {
+                                assert!(bms.objs[pos].is_lndone());
+                                bms.objs[mut][pos].time = t2;
+                            }
+                            _ => {
+                                add(bms, Obj::LNStart(t, lane, Some(v)));
+                                lastln[mut][*lane] = mark(bms, Obj::LNDone(t2, lane, Some(v)));
+                            }
+                        }
+                    }
+
+                    // channels #Dx/Ex: bombs, base-36 damage value (unit of 0.5% of the full gauge)
+                    // or instant death (ZZ)
+                    468/*0xD*36*/...539/*0xF*36-1*/ => {
+                        let lane = Lane::from_channel(chan);
+                        let damage = match v {
+                            Key(v @ 1...200) => Some(GaugeDamage(v as f64 / 200.0)),
+                            Key(1295) => Some(InstantDeath), // XXX 1295=MAXKEY-1
+                            _ => None
+                        };
+                        for &damage in damage.iter() {
+                            add(bms, Obj::Bomb(t, lane, Some(Key(0)), damage));
+                        }
+                    }
+
+                    // unsupported: channels #0B/0C/0D/0E (BGA opacity), #97/98 (sound volume),
+                    // #99 (text), #A0 (dynamic #RANK), #A1/A2/A3/A4 (BGA color key update),
+                    // #A5 (BGA on keypress), #A6 (player-specific option)
+                    _ => {}
+                }
+            };
+
+            // loops over the sorted bmslines
+            bmsline.sort_by(|a, b| (a.measure, b.chan).cmp(&(a.measure, b.chan)));
+            for line in bmsline.iter() {
+                let (lineno, column) = line.at;
+                if line.chan == Key(2) {
+                    let mut shorten = 0.0;
+                    if lex!(line.data[]; ws*, f64 -> shorten) {
+                        if shorten > 0.001 {
+                            if bms.shortens.len() <= line.measure {
+                                let ncopies = line.measure - bms.shortens.len() + 1;
+                                bms.shortens.grow(ncopies, 1.0);
+                            }
+                            bms.shortens[mut][line.measure] = shorten;
+                        } else {
+                            diagnose(&mut bms, line.at, Warning,
+                                     format!("measure length {} is too small", shorten));
+                        }
+                    } else {
+                        diagnose(&mut bms, line.at, Error,
+                                 format!("malformed measure length {}", line.data));
+                    }
+                } else {
+                    let measure = line.measure as f64;
+                    let nchars = line.data[].char_len();
+                    let max = nchars / 2 * 2;
+                    let count = max as f64;
+                    if max != nchars {
+                        if profile.drops_odd_data_lines() {
+                            diagnose(&mut bms, (lineno, column + max), Error,
+                                     "odd number of characters in the data line, \
+                                      ignored".to_string());
+                            continue;
+                        }
+                        diagnose(&mut bms, (lineno, column + max), Warning,
+                                 "odd number of characters in the data line".to_string());
+                    }
+                    let mut chars = line.data[].chars();
+                    for i in iter::range_step(0, max, 2) {
+                        let at = (lineno, column + i);
+                        let pair = [chars.next().unwrap(), chars.next().unwrap()];
+                        match key2index(pair[]) {
+                            Some(0) => {} // ignores 00
+                            Some(v) => {
+                                let t = measure + i as f64 / count;
+                                let t2 = measure + (i + 2) as f64 / count;
+                                handle_key(&mut bms, line.chan, t, t2, Key(v), at);
+                            }
+                            None => {
+                                let key = String::from_chars(pair[]);
+                                diagnose(&mut bms, at, Error, format!("invalid key {}", key));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        if poorbgafix {
+            bms.objs.push(Obj::SetBGA(0.0, PoorBGA, Some(Key(0))));
+        }
+
+        // fix the unterminated longnote
+        bms.nmeasures = bmsline.last().map_or(0, |l| l.measure) + 1;
+        let endt = bms.nmeasures as f64;
+        for i in range(0, NLANES) {
+            if lastvis[i].is_some() || (!consecutiveln && lastln[i].is_some()) {
+                bms.objs.push(Obj::LNDone(endt, Lane(i), None));
+            }
+            if !consecutiveln && lastln[i].is_some() {
+                diagnose(&mut bms, lastlnat[i], Warning, "unterminated long note".to_string());
+            }
+        }
+
+        Ok(bms)
+    }
+
+    /// Reads and parses the BMS file with given RNG. (C: `parse_bms`)
+    pub fn parse_bms<R:Rng>(bmspath: &str, profile: CompatProfile,
+                            r: &mut R) -> AngolmoisResult<Bms> {
+        parse_bms_with(bmspath, profile, |max| r.gen_range(1, max + 1))
+    }
+
+    /// Reads and parses the BMS file, where `gen` chooses the value for each #RANDOM command.
+    /// See `parse_bms_from_reader_with`.
+    pub fn parse_bms_with(bmspath: &str, profile: CompatProfile,
+                          gen: |int| -> int) -> AngolmoisResult<Bms> {
+        let ret = ::zip::open_file(bmspath).and_then(|mut f| {
+            parse_bms_from_reader_with(&mut *f, profile, |max| gen(max))
+        });
+        ret.map_err(|err| IoFailure(bmspath.to_string(), err))
+    }
+
+    //----------------------------------------------------------------------------------------------
+    // writing
+
+    /// The maximum number of subdivisions in a measure tried by `write_bms`. Positions that do not
+    /// fit are rounded to this resolution.
+    const MAX_SUBDIVISIONS: uint = 960;
+
+    /// Returns the smallest number of subdivisions which can represent all given positions (in
+    /// the range of 0 to 1) exactly.
+    fn find_subdivisions(positions: &[f64]) -> uint {
+        for ndivs in range(1, MAX_SUBDIVISIONS) {
+            let exact = positions.iter().all(|&pos| {
+                let slot = pos * ndivs as f64;
+                (slot - slot.round()).abs() < 1e-4
+            });
+            if exact { return ndivs; }
+        }
+        MAX_SUBDIVISIONS
+    }
+
+    /**
+     * Writes the BMS data as a textual BMS file. Any #RANDOM block is already resolved in `Bms`,
+     * so the resulting file has no control flow. `#WAVxx`, `#BMPxx` and `#BGAxx` definitions are
+     * written as is, while `#BPMxx` and `#STOPxx` definitions are regenerated from the objects.
+     *
+     * Long notes are always written as #LNTYPE 1 in channels #5x/6x. Stops given in seconds are
+     * converted to measures with the BPM at that point, and objects before the measure #000 are
+     * dropped. The resulting file is meant to be parsed to the identical `Bms` modulo these.
+     */
+    pub fn write_bms(bms: &Bms, f: &mut Writer) -> io::IoResult<()> {
+        use std::collections::HashMap;
+        use std::collections::hashmap::{Occupied, Vacant};
+
+        macro_rules! write_header(
+            ($name:expr, $value:expr) => (
+                for value in $value.iter() { try!(writeln!(f, "#{} {}", $name, *value)); }
+            )
+        )
+
+        write_header!("PLAYER", Some(bms.player));
+        write_header!("GENRE", bms.genre);
+        write_header!("TITLE", bms.title);
+        write_header!("ARTIST", bms.artist);
+        write_header!("BPM", Some(*bms.initbpm));
+        write_header!("PLAYLEVEL", Some(bms.playlevel));
+        write_header!("RANK", Some(bms.rank));
+        write_header!("STAGEFILE", bms.stagefile);
+        write_header!("BANNER", bms.banner);
+        write_header!("BACKBMP", bms.backbmp);
+        write_header!("PREVIEW", bms.preview);
+        write_header!("PATH_WAV", bms.basepath);
+        try!(writeln!(f, ""));
+
+        for (i, path) in bms.sndpath.iter().enumerate() {
+            for path in path.iter() { try!(writeln!(f, "#WAV{} {}", Key(i as int), *path)); }
+        }
+        for (i, path) in bms.imgpath.iter().enumerate() {
+            for path in path.iter() { try!(writeln!(f, "#BMP{} {}", Key(i as int), *path)); }
+        }
+        for bc in bms.blitcmd.iter() {
+            try!(writeln!(f, "#BGA{} {} {} {} {} {} {} {}", *bc.dst, *bc.src,
+                          bc.x1, bc.y1, bc.x2, bc.y2, bc.dx, bc.dy));
+        }
+
+        // the list of (measure, channel, position in the measure, value) to be written
+        let mut data = Vec::new();
+        let mut bpmkeys = HashMap::new(); // f64 is not hashable, so its bit pattern is used
+        let mut bpmdefs = Vec::new();
+        let mut stopkeys = HashMap::new();
+        let mut stopdefs = Vec::new();
+        let mut ndropped = 0u;
+
+        // Allocates a new key from the definition table, if the value is not yet defined.
+        let allockey = |keys: &mut HashMap<u64,Key>, defs: &mut Vec<f64>, value: f64| {
+            let nkeys = keys.len();
+            match keys.entry(unsafe { ::std::mem::transmute(value) }) {
+                Occupied(e) => Some(*e.get()),
+                Vacant(e) if nkeys + 1 < MAXKEY as uint => {
+                    defs.push(value);
+                    Some(*e.set(Key(nkeys as int + 1)))
+                }
+                Vacant(_) => None
+            }
+        };
+        // Returns a channel for lane-based objects. `base` is the channel for the first player.
+        let lanechan = |Lane(lane): Lane, base: int| -> Key {
+            Key((base + (lane / 36) as int) * 36 + (lane % 36) as int)
+        };
+        let nosound = Key(MAXKEY - 1);
+
+        let mut bpm = bms.initbpm;
+        for obj in bms.objs.iter() {
+            if obj.time < 0.0 { ndropped += 1; continue; }
+            let chanvalue = match obj.data {
+                Deleted => None,
+                Visible(lane, sref) => Some((lanechan(lane, 1), sref.map_or(nosound, |s| *s))),
+                Invisible(lane, sref) => Some((lanechan(lane, 3), sref.map_or(nosound, |s| *s))),
+                LNStart(lane, sref) | LNDone(lane, sref) => {
+                    Some((lanechan(lane, 5), sref.map_or(nosound, |s| *s)))
+                }
+                Bomb(lane, _, damage) => {
+                    let value = match damage {
+                        GaugeDamage(ratio) => Key((ratio * 200.0).round() as int),
+                        InstantDeath => Key(MAXKEY - 1)
+                    };
+                    Some((lanechan(lane, 0xD), value))
+                }
+                BGM(sref) => Some((Key(1), *sref)),
+                SetBGA(layer, iref) => {
+                    let chan = match layer { Layer1 => 4, Layer2 => 7, Layer3 => 10, PoorBGA => 6 };
+                    Some((Key(chan), iref.map_or(Key(0), |i| *i)))
+                }
+                SetBPM(newbpm) => {
+                    bpm = newbpm;
+                    let v = *newbpm;
+                    if v >= 1.0 && v <= 255.0 && v == v.floor() {
+                        let v = v as int;
+                        Some((Key(3), Key(v / 16 * 36 + v % 16)))
+                    } else {
+                        allockey(&mut bpmkeys, &mut bpmdefs, v).map(|key| (Key(8), key))
+                    }
+                }
+                Stop(duration) => {
+                    let measures = match duration {
+                        Seconds(secs) => bpm.msec_to_measure(secs * 1000.0),
+                        Measures(measures) => measures
+                    };
+                    let v = (measures * 192.0).round();
+                    allockey(&mut stopkeys, &mut stopdefs, v).map(|key| (Key(9), key))
+                }
+            };
+            match chanvalue {
+                Some((chan, value)) if *value > 0 => {
+                    let measure = obj.time.floor();
+                    data.push((measure as uint, chan, obj.time - measure, value));
+                }
+                Some(_) => { ndropped += 1; }
+                None => {}
+            }
+        }
+
+        for (i, &v) in bpmdefs.iter().enumerate() {
+            try!(writeln!(f, "#BPM{} {}", Key(i as int + 1), v));
+        }
+        for (i, &v) in stopdefs.iter().enumerate() {
+            try!(writeln!(f, "#STOP{} {}", Key(i as int + 1), v as int));
+        }
+        try!(writeln!(f, ""));
+
+        for (measure, &shorten) in bms.shortens.iter().enumerate() {
+            if shorten != 1.0 { try!(writeln!(f, "#{:03}02:{}", measure, shorten)); }
+        }
+
+        // groups the data by the measure and channel, keeping the original order within groups
+        data.sort_by(|&(m1, c1, _, _), &(m2, c2, _, _)| (m1, *c1).cmp(&(m2, *c2)));
+        let mut start = 0;
+        while start < data.len() {
+            let (measure, chan, _, _) = data[start];
+            let mut end = start;
+            while end < data.len() {
+                let (measure2, chan2, _, _) = data[end];
+                if measure2 != measure || chan2 != chan { break; }
+                end += 1;
+            }
+            let group = data[start..end];
+            start = end;
+
+            if measure > 999 { ndropped += group.len(); continue; }
+            let positions: Vec<f64> = group.iter().map(|&(_, _, pos, _)| pos).collect();
+            let ndivs = find_subdivisions(positions[]);
+
+            // objects at the same position are split to multiple lines
+            let mut rows: Vec<Vec<Key>> = Vec::new();
+            for &(_, _, pos, value) in group.iter() {
+                let slot = cmp::min((pos * ndivs as f64).round() as uint, ndivs - 1);
+                match rows.iter().position(|row| *row[slot] == 0) {
+                    Some(i) => { rows[mut][i][mut][slot] = value; }
+                    None => {
+                        let mut row = Vec::from_elem(ndivs, Key(0));
+                        row[mut][slot] = value;
+                        rows.push(row);
+                    }
+                }
+            }
+            for row in rows.iter() {
+                try!(write!(f, "#{:03}{}:", measure, chan));
+                for &key in row.iter() { try!(write!(f, "{}", key)); }
+                try!(writeln!(f, ""));
+            }
+        }
+
+        if ndropped > 0 {
+            warn!("{} objects cannot be represented in BMS and were dropped", ndropped);
+        }
+        Ok(())
+    }
+
+    //----------------------------------------------------------------------------------------------
+    // key specification
+
+    /// The key specification. Specifies the order and apperance of lanes. Once determined from
+    /// the options and BMS file, the key specification is fixed and independent of other data
+    /// (e.g. `#PLAYER` value).
+    pub struct KeySpec {
+        /// The number of lanes on the left side. This number is significant only when Couple Play
+        /// is used. (C: `nleftkeys`)
+        pub split: uint,
+        /// The order of significant lanes. The first `nleftkeys` lanes go to the left side and
+        /// the remaining lanes (C: `nrightkeys`) go to the right side. (C: `keyorder`)
+        pub order: Vec<Lane>,
+        /// The type of lanes. (C: `keykind`)
+        pub kinds: Vec<Option<KeyKind>>
+    }
+
+    impl KeySpec {
+        /// Returns a number of lanes that count towards "keys". Notably scratches and pedals do not
+        /// count as keys. (C: `nkeys`)
+        pub fn nkeys(&self) -> uint {
+            let mut nkeys = 0;
+            for kind in self.kinds.iter().filter_map(|kind| *kind) {
+                if kind.counts_as_key() { nkeys += 1; }
+            }
+            nkeys
+        }
+
+        /// Returns a list of lanes on the left side, from left to right.
+        pub fn left_lanes<'r>(&'r self) -> &'r [Lane] {
+            assert!(self.split <= self.order.len());
+            self.order[..self.split]
+        }
+
+        /// Returns a list of lanes on the right side if any, from left to right.
+        pub fn right_lanes<'r>(&'r self) -> &'r [Lane] {
+            assert!(self.split <= self.order.len());
+            self.order[self.split..]
+        }
+    }
+
+    /// Parses the key specification from the string. (C: `parse_key_spec`)
+    pub fn parse_key_spec(s: &str) -> Option<Vec<(Lane, KeyKind)>> {
+        let mut specs = Vec::new();
+        let mut s = s.trim_left();
+        while !s.is_empty() {
+            let mut chan = Key(0);
+            let mut kind = '\x00';
+            if !lex!(s; Key -> chan, char -> kind, ws*, str* -> s, !) {
+                return None;
+            }
+            match (chan, KeyKind::from_char(kind)) {
+                (Key(chan @ 36/*1*36*/...107/*3*36-1*/), Some(kind)) => {
+                    specs.push((Lane(chan as uint - 1*36), kind));
+                }
+                (_, _) => { return None; }
+            }
+        }
+        Some(specs)
+    }
+
+    /// A list of well-known key specifications. (C: `presets`)
+    static PRESETS: &'static [(&'static str, &'static str, &'static str)] = &[
+        // 5-key BMS, SP/DP
+        ("5",     "16s 11a 12b 13a 14b 15a", ""),
+        ("10",    "16s 11a 12b 13a 14b 15a", "21a 22b 23a 24b 25a 26s"),
+        // 5-key BMS with a foot pedal, SP/DP
+        ("5/fp",  "16s 11a 12b 13a 14b 15a 17p", ""),
+        ("10/fp", "16s 11a 12b 13a 14b 15a 17p", "27p 21a 22b 23a 24b 25a 26s"),
+        // 7-key BME, SP/DP
+        ("7",     "16s 11a 12b 13a 14b 15a 18b 19a", ""),
+        ("14",    "16s 11a 12b 13a 14b 15a 18b 19a", "21a 22b 23a 24b 25a 28b 29a 26s"),
+        // 7-key BME with a foot pedal, SP/DP
+        ("7/fp",  "16s 11a 12b 13a 14b 15a 18b 19a 17p", ""),
+        ("14/fp", "16s 11a 12b 13a 14b 15a 18b 19a 17p", "27p 21a 22b 23a 24b 25a 28b 29a 26s"),
+        // 9-key PMS (#PLAYER 3)
+        ("9",     "11q 12w 13e 14r 15t 22r 23e 24w 25q", ""),
+        // 9-key PMS (BME-compatible)
+        ("9-bme", "11q 12w 13e 14r 15t 18r 19e 16w 17q", ""),
+        // 7-key O2Jam
+        ("o2jam", "11a 12b 13a 14y 15a 18b 19a", ""),
+    ];
+
+    /**
+     * Determines the key specification from the preset name, in the absence of explicit key
+     * specification with `-K` option. (C: `detect_preset`)
+     *
+     * Besides from presets specified in `PRESETS`, this function also allows the following
+     * pseudo-presets inferred from the BMS file:
+     *
+     * - `bms`, `bme`, `bml` or no preset: Selects one of eight presets `{5,7,10,14}[/fp]`.
+     * - `pms`: Selects one of two presets `9` and `9-bme`.
+     */
+    pub fn preset_to_key_spec(bms: &Bms, preset: Option<String>) -> Option<(String, String)> {
+        use std::ascii::OwnedAsciiExt;
+        use util::option::StrOption;
+
+        let mut present = [false, ..NLANES];
+        for &obj in bms.objs.iter() {
+            for &Lane(lane) in obj.object_lane().iter() {
+                present[lane] = true;
+            }
+        }
+
+        let preset = preset.map(|s| s.into_ascii_lower());
+        let preset = match preset.as_ref_slice() {
+            None | Some("bms") | Some("bme") | Some("bml") => {
+                let isbme = present[8] || present[9] || present[36+8] || present[36+9];
+                let haspedal = present[7] || present[36+7];
+                let nkeys = match bms.player {
+                    COUPLE_PLAY | DOUBLE_PLAY => if isbme {"14"} else {"10"},
+                    _                         => if isbme {"7" } else {"5" }
+                };
+                if haspedal {nkeys.to_string() + "/fp"} else {nkeys.to_string()}
+            },
+            Some("pms") => {
+                let isbme = present[6] || present[7] || present[8] || present[9];
+                let nkeys = if isbme {"9-bme"} else {"9"};
+                nkeys.to_string()
+            },
+            Some(_) => preset.unwrap()
+        };
+
+        for &(name, leftkeys, rightkeys) in PRESETS.iter() {
+            if name == preset[] {
+                return Some((leftkeys.to_string(), rightkeys.to_string()));
+            }
+        }
+        None
+    }
+
+    //----------------------------------------------------------------------------------------------
+    // post-processing
+
+    /// Updates the object in place to BGM or placeholder. (C: `remove_or_replace_note`)
+    fn remove_or_replace_note(obj: &mut Obj) {
+        obj.data = match obj.data {
+            Visible(_,Some(sref)) | Invisible(_,Some(sref)) |
+            LNStart(_,Some(sref)) | LNDone(_,Some(sref)) => BGM(sref),
+            _ => Deleted
+        };
+    }
+
+    /// Determines how `sanitize_bms` treats conflicting objects.
+    #[deriving(PartialEq,Eq,Clone,Show)]
+    pub enum SanitizePolicy {
+        /// Removes or replaces conflicting objects, and reports them as notes. This is the default.
+        FixConflicts,
+        /// Same as `FixConflicts`, but keeps objects at the same position with the same kind
+        /// (e.g. two visible notes in the same lane). Duplicate long note endpoints are still
+        /// removed, as they cannot be paired otherwise.
+        KeepDuplicates,
+        /// Reports conflicting objects as warnings without changing them. The resulting chart
+        /// is only sorted and cannot be played.
+        ReportOnly,
+        /// Same as `FixConflicts`, but reports conflicting objects as errors. The caller is
+        /// expected to reject the chart if anything is changed.
+        Strict,
+    }
+
+    impl SanitizePolicy {
+        /// Returns the sanitizer policy with given name (case-insensitive) if any.
+        pub fn from_name(name: &str) -> Option<SanitizePolicy> {
+            use std::ascii::AsciiExt;
+            match name.to_ascii_lower()[] {
+                "fix" => Some(FixConflicts),
+                "keep-duplicates" => Some(KeepDuplicates),
+                "report" => Some(ReportOnly),
+                "strict" => Some(Strict),
+                _ => None
+            }
+        }
+    }
+
+    /// Returns a human-readable description of the object, used for conflicting objects.
+    pub fn describe_conflict(obj: &Obj) -> String {
+        match obj.object_lane() {
+            Some(lane) => format!("overlapping object in lane {}", *lane),
+            None if obj.is_setbpm() => "overlapping BPM change".to_string(),
+            None => "overlapping BGA change or scroll stopper".to_string(),
+        }
+    }
+
+    /**
+     * Fixes a problematic data according to `policy`, and records every removed or replaced object
+     * to `bms.diagnostics`. Returns the number of such objects, which is the number of objects
+     * that would be changed for `ReportOnly`. (C: `sanitize_bms`)
+     */
+    pub fn sanitize_bms(bms: &mut Bms, policy: SanitizePolicy) -> uint {
+        sort_objs(bms.objs[mut]);
+        let orig = bms.objs.clone();
+        fix_conflicts(bms.objs[mut], policy == KeepDuplicates);
+
+        let severity = match policy {
+            FixConflicts | KeepDuplicates => Note,
+            ReportOnly => Warning,
+            Strict => Error,
+        };
+        let mut nchanged = 0;
+        for (before, after) in orig.iter().zip(bms.objs.iter()) {
+            if before.data != after.data && before.data != Deleted {
+                let action = if policy == ReportOnly {""} else {" removed"};
+                let message = format!("measure {:.3}: {}{}", before.time,
+                                      describe_conflict(before), action);
+                bms.diagnostics.push(Diagnostic { line: 0, column: 0, severity: severity,
+                                                  message: message });
+                nchanged += 1;
+            }
+        }
+
+        if policy == ReportOnly { bms.objs = orig; }
+        nchanged
+    }
+
+    /// Sorts the list of objects by the position. Objects at the same position keep their order.
+    fn sort_objs(objs: &mut [Obj]) {
+        objs.sort_by(|a, b| {
+            if a.time < b.time {Less} else if a.time > b.time {Greater} else {Equal}
+        });
+    }
+
+    /// Sorts and fixes a problematic list of objects. Removed objects are replaced with `BGM` or
+    /// `Deleted` in place, so the caller can compare the result with the sorted original.
+    pub fn sanitize_objs(objs: &mut [Obj]) {
+        sort_objs(objs);
+        fix_conflicts(objs, false);
+    }
+
+    /// Fixes a problematic list of objects sorted by `sort_objs`. Objects at the same position
+    /// with the same kind are kept if `keepdups` is true, except for long note endpoints.
+    fn fix_conflicts(objs: &mut [Obj], keepdups: bool) {
+        // `dups` is a bit mask of types allowed to be duplicated
+        fn sanitize(objs: &mut [Obj], dups: int, to_type: |&Obj| -> Option<uint>,
+                    merge_types: |int| -> int) {
+            let len = objs.len();
+            let mut i = 0;
+            while i < len {
+                let cur = objs[i].time;
+                let mut types = 0;
+                let mut j = i;
+                while j < len && objs[j].time <= cur {
+                    let obj = &mut objs[j];
+                    for &t in to_type(obj).iter() {
+                        if (types & (1 << t)) != 0 && (dups & (1 << t)) == 0 {
+                            // duplicate type
+                            remove_or_replace_note(obj);
+                        } else {
+                            types |= 1 << t;
+                        }
+                    }
+                    j += 1;
+                }
+
+                types = merge_types(types);
+
+                while i < j {
+                    let obj = &mut objs[i];
+                    for &t in to_type(obj).iter() {
+                        if (types & (1 << t)) == 0 {
+                            remove_or_replace_note(obj);
+                        }
+                    }
+                    i += 1;
+                }
+            }
+        }
+
+        for lane in range(0, NLANES) {
+            let lane0 = Lane(lane);
+
+            const LNDONE: uint = 0;
+            const LNSTART: uint = 1;
+            const VISIBLE: uint = 2;
+            const INVISIBLE: uint = 3;
+            const BOMB: uint = 4;
+            let to_type = |obj: &Obj| -> Option<uint> {
+                match obj.data {
+                    Visible(lane,_) if lane == lane0 => Some(VISIBLE),
+                    Invisible(lane,_) if lane == lane0 => Some(INVISIBLE),
+                    LNStart(lane,_) if lane == lane0 => Some(LNSTART),
+                    LNDone(lane,_) if lane == lane0 => Some(LNDONE),
+                    Bomb(lane,_,_) if lane == lane0 => Some(BOMB),
+                    _ => None,
+                }
+            };
+
+            let dups = if keepdups {(1 << VISIBLE) | (1 << INVISIBLE) | (1 << BOMB)} else {0};
+            let mut inside = false;
+            sanitize(objs[mut], dups, |obj| to_type(obj), |mut types| {
+                const LNMASK: int = (1 << LNSTART) | (1 << LNDONE);
+
+                // remove overlapping LN endpoints altogether
+                if (types & LNMASK) == LNMASK { types &= !LNMASK; }
+
+                // remove prohibited types according to inside
+                if inside {
+                    types &= !((1 << LNSTART) | (1 << VISIBLE) | (1 << BOMB));
+                } else {
+                    types &= !(1 << LNDONE);
+                }
+
+                // invisible note cannot overlap with long note endpoints
+                if (types & LNMASK) != 0 { types &= !(1 << INVISIBLE); }
+
+                // keep the most important (lowest) type, except for
+                // BOMB/INVISIBLE combination
+                let lowest = types & -types;
+                if lowest == (1 << INVISIBLE) {
+                    types = lowest | (types & (1 << BOMB));
+                } else {
+                    types = lowest;
+                }
+
+                if (types & (1 << LNSTART)) != 0 {
+                    inside = true;
+                } else if (types & (1 << LNDONE)) != 0 {
+                    inside = false;
+                }
+
+                types
+            });
+
+            if inside {
+                // remove last starting longnote which is unfinished
+                match objs.iter().rposition(|obj| to_type(obj).is_some()) {
+                    Some(pos) if objs[pos].is_lnstart() =>
+                        remove_or_replace_note(&mut objs[mut][pos]),
+                    _ => {}
+                }
+            }
+        }
+
+        sanitize(objs[mut], if keepdups {-1} else {0},
+                 |&obj| match obj.data {
+                            SetBGA(Layer1,_) => Some(0),
+                            SetBGA(Layer2,_) => Some(1),
+                            SetBGA(Layer3,_) => Some(2),
+                            SetBGA(PoorBGA,_) => Some(3),
+                            SetBPM(..) => Some(4),
+                            Stop(..) => Some(5),
+                            _ => None,
+                        },
+                 |types| types);
+    }
+
+    /// Removes insignificant objects (i.e. not in visible lanes) and ensures that there is no
+    /// `Deleted` object. (C: `analyze_and_compact_bms`)
+    pub fn compact_bms(bms: &mut Bms, keyspec: &KeySpec) {
+        for obj in bms.objs.iter_mut() {
+            for &Lane(lane) in obj.object_lane().iter() {
+                if keyspec.kinds[lane].is_none() {
+                    remove_or_replace_note(obj)
+                }
+            }
+        }
+
+        bms.objs.retain(|obj| obj.data != Deleted);
+    }
+
+    //----------------------------------------------------------------------------------------------
+    // analysis
+
+    /// Derived BMS information. Again, this is not a global state.
+    pub struct BmsInfo {
+        /// The start position of the BMS file. This is either -1.0 or 0.0 depending on the first
+        /// measure has any visible objects or not. (C: `originoffset`)
+        pub originoffset: f64,
+        /// Set to true if the BMS file has a BPM change. (C: `hasbpmchange`)
+        pub hasbpmchange: bool,
+        /// Set to true if the BMS file has long note objects. (C: `haslongnote`)
+        pub haslongnote: bool,
+        /// The number of visible objects in the BMS file. A long note object counts as one object.
+        /// (C: `nnotes`)
+        pub nnotes: int,
+        /// The maximum possible score. (C: `maxscore`)
+        pub maxscore: int,
+        /// The MD5 digest of the raw chart file in hexadecimal digits, if known. This is
+        /// a canonical hash shared with other BMS players, and same as `replay::ChartHash::md5`.
+        pub md5: Option<String>,
+        /// The number of visible objects in each measure, counted as in `nnotes`. Used to
+        /// render the note density graph.
+        pub density: Vec<uint>,
+        /// The minimum and maximum BPM in the BMS file. Negative BPMs, which end the chart, are
+        /// not counted.
+        pub minbpm: f64,
+        /// See `minbpm`.
+        pub maxbpm: f64,
+        /// The total duration of scroll stoppers in seconds.
+        pub stoptime: f64,
+        /// The length of the BMS file in seconds as calculated by `bms_duration`, without
+        /// accounting for the length of sounds.
+        pub duration: f64,
+    }
+
+    /// Analyzes the loaded BMS file. (C: `analyze_and_compact_bms`)
+    pub fn analyze_bms(bms: &Bms) -> BmsInfo {
+        let mut infos = BmsInfo { originoffset: 0.0, hasbpmchange: false, haslongnote: false,
+                                  nnotes: 0, maxscore: 0, md5: bms.md5.clone(),
+                                  density: Vec::new(), minbpm: *bms.initbpm,
+                                  maxbpm: *bms.initbpm, stoptime: 0.0, duration: 0.0 };
+
+        for &obj in bms.objs.iter() {
+            infos.haslongnote |= obj.is_lnstart();
+            infos.hasbpmchange |= obj.is_setbpm();
+
+            if obj.is_lnstart() || obj.is_visible() {
+                infos.nnotes += 1;
+                if obj.time < 1.0 { infos.originoffset = -1.0; }
+
+                let measure = obj.time.floor();
+                if measure >= 0.0 {
+                    let measure = measure as uint;
+                    if infos.density.len() <= measure {
+                        let ngrow = measure + 1 - infos.density.len();
+                        infos.density.grow(ngrow, 0);
+                    }
+                    infos.density[mut][measure] += 1;
+                }
+            }
+        }
+
+        for i in range(0, infos.nnotes) {
+            let ratio = (i as f64) / (infos.nnotes as f64);
+            infos.maxscore += (300.0 * (1.0 + ratio)) as int;
+        }
+
+        // XXX stops at the first negative BPM, as in `bms_duration`.
+        let mut bpm = bms.initbpm;
+        for &obj in bms.objs.iter() {
+            match obj.data {
+                SetBPM(BPM(newbpm)) if newbpm > 0.0 => {
+                    bpm = BPM(newbpm);
+                    infos.minbpm = infos.minbpm.min(newbpm);
+                    infos.maxbpm = infos.maxbpm.max(newbpm);
+                }
+                SetBPM(BPM(newbpm)) if newbpm < 0.0 => { break; }
+                Stop(duration) => { infos.stoptime += duration.to_msec(bpm) / 1000.0; }
+                _ => {}
+            }
+        }
+        infos.duration = bms_duration(bms, infos.originoffset, |_| 0.0);
+
+        infos
+    }
+
+    /// Returns indices to objects in each lane, in the order of `Bms::objs`. The index is only
+    /// valid as long as objects are not changed, so this should be built after `sanitize_bms`
+    /// and `compact_bms`.
+    pub fn lane_objects(bms: &Bms) -> Vec<Vec<uint>> {
+        let mut lanes = Vec::from_fn(NLANES, |_| Vec::new());
+        for (i, obj) in bms.objs.iter().enumerate() {
+            for &Lane(lane) in obj.object_lane().iter() {
+                lanes[mut][lane].push(i);
+            }
+        }
+        lanes
+    }
+
+    /// Returns the first position in the sorted list of object indices `index` which index is
+    /// not less than `pos`, or the length of `index` if none.
+    pub fn lower_bound(index: &[uint], pos: uint) -> uint {
+        let (mut lo, mut hi) = (0, index.len());
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if index[mid] < pos { lo = mid + 1; } else { hi = mid; }
+        }
+        lo
+    }
+
+    /// Calculates the duration of the loaded BMS file in seconds. `sound_length` should return
+    /// the length of sound resources in seconds or 0.0. (C: `get_bms_duration`)
+    pub fn bms_duration(bms: &Bms, originoffset: f64,
+                        sound_length: |SoundRef| -> f64) -> f64 {
+        let mut pos = originoffset;
+        let mut bpm = bms.initbpm;
+        let mut time = 0.0;
+        let mut sndtime = 0.0;
+
+        for &obj in bms.objs.iter() {
+            let delta = bms.adjust_object_position(pos, obj.time);
+            time += bpm.measure_to_msec(delta);
+            match obj.data {
+                Visible(_,Some(sref)) | LNStart(_,Some(sref)) | BGM(sref) => {
+                    let sndend = time + sound_length(sref) * 1000.0;
+                    if sndtime > sndend { sndtime = sndend; }
+                }
+                SetBPM(BPM(newbpm)) => {
+                    if newbpm > 0.0 {
+                        bpm = BPM(newbpm);
+                    } else if newbpm < 0.0 {
+                        bpm = BPM(newbpm);
+                        let delta = bms.adjust_object_position(originoffset, pos);
+                        time += BPM(-newbpm).measure_to_msec(delta);
+                        break;
+                    }
+                }
+                Stop(duration) => {
+                    time += duration.to_msec(bpm);
+                }
+                _ => {}
+            }
+            pos = obj.time;
+        }
+
+        if *bpm > 0.0 { // the chart scrolls backwards to `originoffset` for negative BPM
+            let delta = bms.adjust_object_position(pos, (bms.nmeasures + 1) as f64);
+            time += bpm.measure_to_msec(delta);
+        }
+        (if time > sndtime {time} else {sndtime}) / 1000.0
+     }
+
+    /// A point in `Timeline` where the relationship between the virtual time and actual time
+    /// changes.
+    struct TimelineEntry {
+        /// The virtual time of the change.
+        time: f64,
+        /// The actual time of the change in milliseconds, relative to the origin.
+        msec: f64,
+        /// The BPM after the change. It is zero during the scroll stopper.
+        bpm: BPM,
+    }
+
+    /**
+     * A mapping between the virtual time and the actual time in milliseconds. This is used to
+     * render objects at a constant scroll rate regardless of BPM changes and scroll stoppers.
+     *
+     * The table is built once, so that conversions take logarithmic time to the number of changes
+     * instead of scanning every change and every measure between two virtual times.
+     */
+    pub struct Timeline {
+        /// Changes in the virtual time order. There is always at least one entry.
+        entries: Vec<TimelineEntry>,
+        /// The position of the start of each measure from the start of the measure #0, taking
+        /// account of the scaling factor. The last entry is for the first measure which scaling
+        /// factor is not given, so every later measure is assumed to be unscaled.
+        measurepos: Vec<f64>,
+        /// The BPM which lasts for the longest time in the chart. Also known as "main BPM".
+        pub mainbpm: BPM,
+    }
+
+    impl Timeline {
+        /// Builds a timeline from the loaded BMS file, starting from the virtual time `origin`.
+        ///
+        /// XXX the timeline stops at the first zero or negative BPM, as in `bms_duration`. Later
+        /// objects are never reached, and only the BPM is recorded for `bpm_at`.
+        pub fn new(bms: &Bms, origin: f64) -> Timeline {
+            let mut entries = vec!(TimelineEntry { time: origin, msec: 0.0, bpm: bms.initbpm });
+            let mut pos = origin;
+            let mut bpm = bms.initbpm;
+            let mut msec = 0.0;
+
+            // the total duration for each BPM, in order to find the main BPM
+            fn add_duration(durations: &mut Vec<(f64,f64)>, bpm: BPM, duration: f64) {
+                for entry in durations.iter_mut() {
+                    let (bpm0, total) = *entry;
+                    if bpm0 == *bpm { *entry = (bpm0, total + duration); return; }
+                }
+                durations.push((*bpm, duration));
+            }
+            let mut durations = Vec::new();
+
+            for &obj in bms.objs.iter() {
+                let delta = bms.adjust_object_position(pos, obj.time);
+                let duration = bpm.measure_to_msec(delta);
+                match obj.data {
+                    SetBPM(newbpm) if *newbpm > 0.0 => {
+                        add_duration(&mut durations, bpm, duration);
+                        msec += duration;
+                        pos = obj.time;
+                        bpm = newbpm;
+                        entries.push(TimelineEntry { time: pos, msec: msec, bpm: bpm });
+                    }
+                    SetBPM(newbpm) => {
+                        add_duration(&mut durations, bpm, duration);
+                        msec += duration;
+                        pos = obj.time;
+                        entries.push(TimelineEntry { time: pos, msec: msec, bpm: newbpm });
+                        break;
+                    }
+                    Stop(duration0) => {
+                        add_duration(&mut durations, bpm, duration);
+                        msec += duration;
+                        pos = obj.time;
+                        entries.push(TimelineEntry { time: pos, msec: msec, bpm: BPM(0.0) });
+                        msec += duration0.to_msec(bpm);
+                        entries.push(TimelineEntry { time: pos, msec: msec, bpm: bpm });
+                    }
+                    _ => {}
+                }
+            }
+            let delta = bms.adjust_object_position(pos, (bms.nmeasures + 1) as f64);
+            add_duration(&mut durations, bpm, bpm.measure_to_msec(delta));
+
+            let mut mainbpm = bms.initbpm;
+            let mut longest = 0.0;
+            for &(bpm, total) in durations.iter() {
+                if total > longest { mainbpm = BPM(bpm); longest = total; }
+            }
+
+            let mut measurepos = Vec::with_capacity(bms.shortens.len() + 1);
+            let mut total = 0.0;
+            measurepos.push(total);
+            for &shorten in bms.shortens.iter() {
+                total += shorten;
+                measurepos.push(total);
+            }
+
+            Timeline { entries: entries, measurepos: measurepos, mainbpm: mainbpm }
+        }
+
+        /// Returns the position of the virtual time `time` from the start of the measure #0,
+        /// taking account of the scaling factor.
+        fn position(&self, bms: &Bms, time: f64) -> f64 {
+            let measure = time.floor() as int;
+            let frac = time - measure as f64;
+            let last = self.measurepos.len() as int - 1;
+            let start = if measure < 0 {
+                measure as f64 // negative measures are never scaled
+            } else if measure <= last {
+                self.measurepos[measure as uint]
+            } else {
+                self.measurepos[last as uint] + (measure - last) as f64
+            };
+            start + frac * bms.shorten(measure)
+        }
+
+        /// Same as `Bms::adjust_object_position` but takes constant time.
+        pub fn adjust_object_position(&self, bms: &Bms, base: f64, time: f64) -> f64 {
+            self.position(bms, time) - self.position(bms, base)
+        }
+
+        /// Same as `Bms::adjust_object_time` but takes logarithmic time to the number of
+        /// measures.
+        pub fn adjust_object_time(&self, bms: &Bms, base: f64, offset: f64) -> f64 {
+            let pos = self.position(bms, base) + offset;
+            if pos < 0.0 { return pos; } // negative measures are never scaled
+
+            // finds the last measure starting at or before `pos`
+            let (mut lo, mut hi) = (0, self.measurepos.len());
+            while lo + 1 < hi {
+                let mid = lo + (hi - lo) / 2;
+                if self.measurepos[mid] <= pos { lo = mid; } else { hi = mid; }
+            }
+            let measure = lo;
+            let frac = pos - self.measurepos[measure];
+            if measure + 1 == self.measurepos.len() {
+                measure as f64 + frac
+            } else {
+                measure as f64 + frac / bms.shorten(measure as int)
+            }
+        }
+
+        /// Returns the number of entries after the first entry satisfying `pred`. `pred` should
+        /// be monotonic, i.e. once false it remains false for later entries.
+        fn count_entries(&self, pred: |&TimelineEntry| -> bool) -> uint {
+            let (mut lo, mut hi) = (1, self.entries.len());
+            while lo < hi {
+                let mid = lo + (hi - lo) / 2;
+                if pred(&self.entries[mid]) { lo = mid + 1; } else { hi = mid; }
+            }
+            lo - 1
+        }
+
+        /// Converts the virtual time to the actual time in milliseconds. An object at the scroll
+        /// stopper is mapped to the start of the stopper. An object never reached, i.e. after
+        /// the zero or negative BPM, is infinitely far.
+        pub fn time_to_msec(&self, bms: &Bms, time: f64) -> f64 {
+            let i = self.count_entries(|entry| entry.time < time);
+            self.entry_to_msec(bms, i, time)
+        }
+
+        /// Same as `time_to_msec`, but an object at the scroll stopper is mapped to the end of
+        /// the stopper.
+        pub fn time_to_msec_after_stop(&self, bms: &Bms, time: f64) -> f64 {
+            let i = self.count_entries(|entry| entry.time <= time);
+            self.entry_to_msec(bms, i, time)
+        }
+
+        /// Converts the virtual time to the actual time in milliseconds from the `i`-th entry.
+        fn entry_to_msec(&self, bms: &Bms, i: uint, time: f64) -> f64 {
+            let entry = &self.entries[i];
+            if *entry.bpm <= 0.0 {
+                return if time > entry.time {f64::INFINITY} else {entry.msec};
+            }
+            let delta = self.adjust_object_position(bms, entry.time, time);
+            entry.msec + entry.bpm.measure_to_msec(delta)
+        }
+
+        /// Returns the BPM at the virtual time `time`, after every BPM change and scroll stopper
+        /// at that time.
+        pub fn bpm_at(&self, time: f64) -> BPM {
+            self.entries[self.count_entries(|entry| entry.time <= time)].bpm
+        }
+
+        /// Converts the actual time in milliseconds to the virtual time. This is an inverse of
+        /// `time_to_msec` except for the scroll stoppers.
+        pub fn msec_to_time(&self, bms: &Bms, msec: f64) -> f64 {
+            let i = self.count_entries(|entry| entry.msec <= msec);
+            let entry = &self.entries[i];
+            if *entry.bpm <= 0.0 { return entry.time; }
+            self.adjust_object_time(bms, entry.time, entry.bpm.msec_to_measure(msec - entry.msec))
+        }
+    }
+
+    //----------------------------------------------------------------------------------------------
+    // modifiers
+
+    /// Applies a function to the object lane if any. This is used to shuffle the lanes without
+    /// modifying the relative time position.
+    fn update_object_lane(obj: &mut Obj, f: |Lane| -> Lane) {
+        obj.data = match obj.data {
+            Visible(lane,sref) => Visible(f(lane),sref),
+            Invisible(lane,sref) => Invisible(f(lane),sref),
+            LNStart(lane,sref) => LNStart(f(lane),sref),
+            LNDone(lane,sref) => LNDone(f(lane),sref),
+            Bomb(lane,sref,damage) => Bomb(f(lane),sref,damage),
+            objdata => objdata
+        };
+    }
+
+    /// Swaps given lanes in the reverse order. (C: `shuffle_bms` with `MIRROR_MODF`)
+    pub fn apply_mirror_modf(bms: &mut Bms, lanes: &[Lane]) {
+        let mut map = Vec::from_fn(NLANES, |lane| Lane(lane));
+        for (&Lane(from), &to) in lanes.iter().zip(lanes.iter().rev()) {
+            map[mut][from] = to;
+        }
+
+        for obj in bms.objs.iter_mut() {
+            update_object_lane(obj, |Lane(lane)| map[lane]);
+        }
+    }
+
+    /// Swaps the left side lanes and the right side lanes of the double play chart, as given by
+    /// the key specification. The n-th lane of each key kind in one side is paired with the n-th
+    /// lane of the same kind in the other side (e.g. the scratches, or the first white keys), so
+    /// that both sides retain their own layout. Returns false and does nothing if some lane
+    /// doesn't have its counterpart.
+    pub fn apply_flip_modf(bms: &mut Bms, keyspec: &KeySpec) -> bool {
+        let (left, right) = (keyspec.left_lanes(), keyspec.right_lanes());
+        if left.len() != right.len() { return false; }
+        let mut map = Vec::from_fn(NLANES, |lane| Lane(lane));
+        let mut paired = Vec::from_elem(right.len(), false);
+        for &Lane(from) in left.iter() {
+            let kind = &keyspec.kinds[from];
+            let found = right.iter().enumerate().position(|(i, &Lane(to))| {
+                !paired[i] && keyspec.kinds[to] == *kind
+            });
+            let i = match found {
+                Some(i) => i,
+                None => { return false; }
+            };
+            paired[mut][i] = true;
+            let Lane(to) = right[i];
+            map[mut][from] = Lane(to);
+            map[mut][to] = Lane(from);
+        }
+
+        for obj in bms.objs.iter_mut() {
+            update_object_lane(obj, |Lane(lane)| map[lane]);
+        }
+        true
+    }
+
+    /// Duplicates objects in the left side lanes (channels `1x`) of the single play chart to
+    /// the corresponding right side lanes (channels `2x`), and turns the chart into the Couple
+    /// Play so that two players can compete with the same chart. `bms` should be sanitized
+    /// afterwards, since the duplicated objects are appended at the end. Returns false and does
+    /// nothing if the chart is not for the single play.
+    pub fn apply_battle_modf(bms: &mut Bms) -> bool {
+        if bms.player != SINGLE_PLAY { return false; }
+        let mut copies = Vec::new();
+        for &obj in bms.objs.iter() {
+            match obj.object_lane() {
+                Some(Lane(lane)) if lane < 36 => {
+                    let mut copy = obj;
+                    update_object_lane(&mut copy, |Lane(lane)| Lane(lane + 36));
+                    copies.push(copy);
+                }
+                _ => {}
+            }
+        }
+        bms.objs.extend(copies.into_iter());
+        bms.player = COUPLE_PLAY;
+        true
+    }
+
+    /// Scales the tempo of the chart by `rate`, so that the chart is played `rate` times as fast.
+    /// BPMs are multiplied and scroll stoppers given in seconds are divided by the rate; other
+    /// scroll stoppers are given in measures and follow BPMs.
+    pub fn apply_rate(bms: &mut Bms, rate: f64) {
+        assert!(rate > 0.0);
+        bms.initbpm = BPM(*bms.initbpm * rate);
+        for obj in bms.objs.iter_mut() {
+            match obj.data {
+                SetBPM(bpm) => { obj.data = SetBPM(BPM(*bpm * rate)); }
+                Stop(Seconds(secs)) => { obj.data = Stop(Seconds(secs / rate)); }
+                _ => {}
+            }
+        }
+    }
+
+    /// Swaps given lanes in the random order. (C: `shuffle_bms` with
+    /// `SHUFFLE_MODF`/`SHUFFLEEX_MODF`)
+    pub fn apply_shuffle_modf<R:Rng>(bms: &mut Bms, r: &mut R, lanes: &[Lane]) {
+        let mut shuffled = lanes.to_vec();
+        r.shuffle(shuffled[mut]);
+        let mut map = Vec::from_fn(NLANES, |lane| Lane(lane));
+        for (&Lane(from), &to) in lanes.iter().zip(shuffled.iter()) {
+            map[mut][from] = to;
+        }
+
+        for obj in bms.objs.iter_mut() {
+            update_object_lane(obj, |Lane(lane)| map[lane]);
+        }
+    }
+
+    /// Swaps given lanes in the random order, where the order is determined per object.
+    /// `bms` should be first sanitized by `sanitize_bms`. It does not cause objects to move within
+    /// another LN object, or place two objects in the same or very close time position to the same
+    /// lane. (C: `shuffle_bms` with `RANDOM_MODF`/`RANDOMEX_MODF`)
+    pub fn apply_random_modf<R:Rng>(bms: &mut Bms, r: &mut R, lanes: &[Lane]) {
+        let mut movable = lanes.to_vec();
+        let mut map = Vec::from_fn(NLANES, |lane| Lane(lane));
+
+        let mut lasttime = f64::NEG_INFINITY;
+        for obj in bms.objs.iter_mut() {
+            if obj.is_lnstart() {
+                let lane = obj.object_lane().unwrap();
+                match movable.iter().position(|&i| i == lane) {
+                    Some(i) => { movable.swap_remove(i); }
+                    None => panic!("non-sanitized BMS detected")
+                }
+            }
+            if lasttime < obj.time { // reshuffle required
+                lasttime = obj.time + 1e-4;
+                let mut shuffled = movable.clone();
+                r.shuffle(shuffled[mut]);
+                for (&Lane(from), &to) in movable.iter().zip(shuffled.iter()) {
+                    map[mut][from] = to;
+                }
+            }
+            if obj.is_lnstart() {
+                let lane = obj.object_lane().unwrap();
+                movable.push(lane);
+            }
+            update_object_lane(obj, |Lane(lane)| map[lane]);
+        }
+    }
+
+    /// The minimum interval in milliseconds between two consecutive notes in the same lane, which
+    /// `apply_hrandom_modf` tries to keep.
+    pub const HRANDOM_MIN_INTERVAL: f64 = 150.0;
+
+    /// Moves each object in given lanes to a random lane independently. `bms` should be first
+    /// sanitized by `sanitize_bms`. Objects are never placed within another LN object or to
+    /// the lane already occupied in the same time position, and an LN object is moved as a whole.
+    /// If possible, two consecutive notes in the same lane are placed at least `mininterval`
+    /// milliseconds apart.
+    fn apply_per_note_random_modf<R:Rng>(bms: &mut Bms, r: &mut R, lanes: &[Lane],
+                                         mininterval: f64) {
+        let msecs: Vec<f64> = {
+            let bms = &*bms;
+            let timeline = Timeline::new(bms, 0.0);
+            bms.objs.iter().map(|obj| timeline.time_to_msec(bms, obj.time)).collect()
+        };
+
+        // the virtual time until which the lane is occupied, the actual time of the last note in
+        // the lane, and the lane where the ongoing LN object (by the original lane) has moved to
+        let mut occupied = Vec::from_elem(NLANES, f64::NEG_INFINITY);
+        let mut lastnote = Vec::from_elem(NLANES, f64::NEG_INFINITY);
+        let mut lnlanes = Vec::from_elem(NLANES, None);
+
+        for (obj, &msec) in bms.objs.iter_mut().zip(msecs.iter()) {
+            let lane = match obj.object_lane() {
+                Some(lane) if lanes.contains(&lane) => lane,
+                _ => continue
+            };
+            let to = if obj.is_lndone() {
+                lnlanes[mut][*lane].take().unwrap_or(lane)
+            } else {
+                let time = obj.time;
+                let free: Vec<Lane> =
+                    lanes.iter().map(|&lane| lane).filter(|&lane| occupied[*lane] < time).collect();
+                let spaced: Vec<Lane> =
+                    free.iter().map(|&lane| lane)
+                        .filter(|&lane| msec - lastnote[*lane] >= mininterval).collect();
+                let candidates = if spaced.is_empty() {free} else {spaced};
+                r.choose(candidates[]).map_or(lane, |&to| to)
+            };
+
+            if obj.is_lnstart() {
+                lnlanes[mut][*lane] = Some(to);
+                occupied[mut][*to] = f64::INFINITY;
+            } else {
+                occupied[mut][*to] = obj.time + 1e-4;
+            }
+            if obj.is_visible() || obj.is_lnstart() {
+                lastnote[mut][*to] = msec;
+            }
+            update_object_lane(obj, |_| to);
+        }
+    }
+
+    /// Moves each object in given lanes to a random lane independently. See
+    /// `apply_per_note_random_modf` for the detailed algorithm. Also known as "S-RANDOM".
+    pub fn apply_srandom_modf<R:Rng>(bms: &mut Bms, r: &mut R, lanes: &[Lane]) {
+        apply_per_note_random_modf(bms, r, lanes, f64::NEG_INFINITY)
+    }
+
+    /// Same as `apply_srandom_modf` but avoids placing consecutive notes in the same lane within
+    /// `HRANDOM_MIN_INTERVAL` milliseconds whenever possible. Also known as "H-RANDOM".
+    pub fn apply_hrandom_modf<R:Rng>(bms: &mut Bms, r: &mut R, lanes: &[Lane]) {
+        apply_per_note_random_modf(bms, r, lanes, HRANDOM_MIN_INTERVAL)
+    }
+
+    //----------------------------------------------------------------------------------------------
+
+}
+
+//==================================================================================================
+// osu!mania importer
+
+/**
+ * osu! beatmap importer. Only the osu!mania mode (`Mode: 3`) is supported.
+ *
+ * The beatmap is a line-oriented text divided into sections like `[General]` or `[HitObjects]`.
+ * Unlike BMS every position is given in milliseconds, so the importer reconstructs the virtual
+ * time from timing points. Angolmois does not distinguish the scroll speed from the BPM, thus
+ * slider velocity changes (inherited timing points) are emulated by scaling the BPM and the
+ * distance between objects by the same factor; the actual time of every object is retained.
+ */
+pub mod osu {
+    use std::io;
+    use std::collections::HashMap;
+    use parser::{Key, Lane, MAXKEY, BPM, Obj, Bms, SINGLE_PLAY, DOUBLE_PLAY};
+    use {AngolmoisResult, IoFailure};
+
+    /// A hit object in the beatmap.
+    struct HitObject {
+        /// The horizontal position in the range of 0 to 512, which determines the column.
+        x: f64,
+        /// The start time in milliseconds.
+        start: f64,
+        /// The end time in milliseconds, if the object is a hold note.
+        end: Option<f64>,
+        /// The custom sample file, if any.
+        sample: Option<String>,
+    }
+
+    /// A timing point converted to the continuous segment of the virtual time.
+    struct Segment {
+        /// The start of the segment in milliseconds.
+        msec: f64,
+        /// The start of the segment in measures.
+        pos: f64,
+        /// The effective BPM, i.e. the actual BPM multiplied by the slider velocity.
+        bpm: BPM,
+    }
+
+    /// Returns an I/O error for the malformed or unsupported beatmap.
+    fn invalid_beatmap(desc: &'static str, detail: Option<String>) -> io::IoError {
+        io::IoError { kind: io::InvalidInput, desc: desc, detail: detail }
+    }
+
+    /// Returns a list of lanes for given number of columns. The columns are mapped to the 7-key
+    /// (BME) layout where possible; 8 columns get an additional scratch lane at the left, and
+    /// 9 to 14 columns are mapped to the double play layout.
+    fn lanes_for_columns(ncolumns: uint) -> Option<Vec<Lane>> {
+        static LEFT: &'static [uint] = &[1, 2, 3, 4, 5, 8, 9];
+        static RIGHT: &'static [uint] = &[36+1, 36+2, 36+3, 36+4, 36+5, 36+8, 36+9];
+        match ncolumns {
+            1...7 => Some(LEFT[..ncolumns].iter().map(|&lane| Lane(lane)).collect()),
+            8 => Some([6u].iter().chain(LEFT.iter()).map(|&lane| Lane(lane)).collect()),
+            9...14 => Some(LEFT.iter().chain(RIGHT[..ncolumns-7].iter())
+                               .map(|&lane| Lane(lane)).collect()),
+            _ => None
+        }
+    }
+
+    /// Returns a key for given sample file, allocating a new `#WAVxx` slot if needed.
+    fn sound_key(bms: &mut Bms, keys: &mut HashMap<String,Key>, path: &str) -> Option<Key> {
+        let path = path.to_string();
+        match keys.find(&path) {
+            Some(&key) => { return Some(key); }
+            None => {}
+        }
+        let key = Key(keys.len() as int + 1);
+        if *key >= MAXKEY { return None; }
+        bms.sndpath[mut][*key as uint] = Some(path.clone());
+        keys.insert(path, key);
+        Some(key)
+    }
+
+    /// Strips the surrounding quotes from the file name in the `[Events]` section.
+    fn unquote(s: &str) -> &str {
+        let s = s.trim();
+        if s.len() >= 2 && s.starts_with("\"") && s.ends_with("\"") {
+            s[1..s.len()-1]
+        } else {
+            s
+        }
+    }
+
+    /// Reads and converts the osu!mania beatmap from given reader.
+    pub fn parse_osu_from_reader(f: &mut Reader) -> io::IoResult<Bms> {
+        let file = try!(f.read_to_end());
+        let md5 = ::util::md5::hex_digest(file[]);
+        let file = String::from_utf8_lossy(file[]).into_string();
+
+        let mut bms = Bms::new();
+        bms.md5 = Some(md5);
+        let mut section = String::new();
+        let mut mode = 0i;
+        let mut ncolumns = 0u;
+        let mut od = 5.0f64;
+        let mut audio = None;
+        let mut version = None;
+        let mut bgms = Vec::new(); // (msec, path)
+        let mut timings = Vec::new(); // (msec, beat length, uninherited)
+        let mut hitobjs = Vec::new();
+
+        for line in file[].lines() {
+            let line = line.trim_left_chars('\uFEFF').trim();
+            if line.is_empty() || line.starts_with("//") { continue; }
+            if line.starts_with("[") && line.ends_with("]") {
+                section = line[1..line.len()-1].to_string();
+                continue;
+            }
+
+            match section[] {
+                "General" | "Metadata" | "Difficulty" => {
+                    let (key, value) = match line.find(':') {
+                        Some(idx) => (line[..idx].trim(), line[idx+1..].trim()),
+                        None => { continue; }
+                    };
+                    match key {
+                        "AudioFilename" => { audio = Some(value.to_string()); }
+                        "Mode" => { mode = from_str(value).unwrap_or(0); }
+                        "Title" => { bms.title = Some(value.to_string()); }
+                        "Artist" => { bms.artist = Some(value.to_string()); }
+                        "Version" => { version = Some(value.to_string()); }
+                        "CircleSize" => {
+                            ncolumns = from_str::<f64>(value).map_or(0, |v| v as uint);
+                        }
+                        "OverallDifficulty" => { od = from_str(value).unwrap_or(5.0); }
+                        _ => {}
+                    }
+                }
+
+                "Events" => {
+                    let fields: Vec<&str> = line.split(',').collect();
+                    if fields.len() >= 3 && fields[0].trim() == "0" {
+                        // background image: `0,0,"file",x,y`
+                        bms.stagefile = Some(unquote(fields[2]).to_string());
+                    } else if fields.len() >= 4 &&
+                              (fields[0].trim() == "Sample" || fields[0].trim() == "5") {
+                        // storyboard sample: `Sample,time,layer,"file",volume`
+                        for &time in from_str::<f64>(fields[1].trim()).iter() {
+                            bgms.push((time, unquote(fields[3]).to_string()));
+                        }
+                    }
+                }
+
+                // `time,beatLength,meter,sampleSet,sampleIndex,volume,uninherited,effects`
+                "TimingPoints" => {
+                    let fields: Vec<&str> = line.split(',').map(|s| s.trim()).collect();
+                    if fields.len() < 2 { continue; }
+                    let time = from_str::<f64>(fields[0]);
+                    let beatlen = from_str::<f64>(fields[1]);
+                    match (time, beatlen) {
+                        (Some(time), Some(beatlen)) => {
+                            let uninherited =
+                                if fields.len() > 6 {fields[6] == "1"} else {beatlen > 0.0};
+                            timings.push((time, beatlen, uninherited && beatlen > 0.0));
+                        }
+                        (_, _) => {}
+                    }
+                }
+
+                // `x,y,time,type,hitSound,objectParams,hitSample`; hold notes have
+                // `endTime:hitSample` in place of the last two fields.
+                "HitObjects" => {
+                    let fields: Vec<&str> = line.split(',').map(|s| s.trim()).collect();
+                    if fields.len() < 5 { continue; }
+                    let x = from_str::<f64>(fields[0]);
+                    let start = from_str::<f64>(fields[2]);
+                    let kind = from_str::<uint>(fields[3]).unwrap_or(0);
+                    let (x, start) = match (x, start) {
+                        (Some(x), Some(start)) => (x, start),
+                        (_, _) => { continue; }
+                    };
+
+                    let extra: Vec<&str> = match fields[].last() {
+                        Some(last) if fields.len() > 5 => last.split(':').collect(),
+                        _ => Vec::new()
+                    };
+                    let (end, sample) =
+                        if kind & 128 != 0 && !extra.is_empty() {
+                            (from_str::<f64>(extra[0]), extra[].get(5))
+                        } else {
+                            (None, extra[].get(4))
+                        };
+                    let sample = match sample {
+                        Some(s) if !s.is_empty() => Some(s.to_string()),
+                        _ => None
+                    };
+                    hitobjs.push(HitObject { x: x, start: start, end: end, sample: sample });
+                }
+
+                _ => {}
+            }
+        }
+
+        if mode != 3 {
+            return Err(invalid_beatmap("not an osu!mania beatmap", None));
+        }
+        let lanes = match lanes_for_columns(ncolumns) {
+            Some(lanes) => lanes,
+            None => {
+                return Err(invalid_beatmap("unsupported number of columns",
+                                           Some(ncolumns.to_string())));
+            }
+        };
+        if ncolumns > 8 { bms.player = DOUBLE_PLAY; } else { bms.player = SINGLE_PLAY; }
+
+        match version {
+            Some(version) => {
+                let title = bms.title.take().unwrap_or(String::new());
+                bms.title = Some(format!("{} [{}]", title, version));
+            }
+            None => {}
+        }
+        bms.rank = if od >= 8.0 {0} else if od >= 6.0 {1} else if od >= 4.0 {2} else {3};
+
+        // builds the virtual time. the first uninherited timing point is at the origin, and
+        // anything before it is extrapolated with the initial BPM.
+        let mut segments: Vec<Segment> = Vec::new();
+        let mut basebpm = 0.0;
+        for &(time, beatlen, uninherited) in timings.iter() {
+            let sv =
+                if uninherited {
+                    basebpm = 60000.0 / beatlen;
+                    1.0
+                } else if segments.is_empty() {
+                    continue; // slider velocity without any BPM is meaningless
+                } else {
+                    (-100.0 / beatlen).max(0.01).min(10.0)
+                };
+            let pos = match segments[].last() {
+                Some(seg) => seg.pos + seg.bpm.msec_to_measure(time - seg.msec),
+                None => 0.0
+            };
+            // the later timing point at the same time overrides the former
+            let overridden = segments[].last().map_or(false, |seg| seg.msec == time);
+            if overridden { segments.pop(); }
+            segments.push(Segment { msec: time, pos: pos, bpm: BPM(basebpm * sv) });
+        }
+        if segments.is_empty() {
+            return Err(invalid_beatmap("no timing points", None));
+        }
+        let topos = |msec: f64| -> f64 {
+            let seg = segments.iter().rev().find(|seg| seg.msec <= msec).unwrap_or(&segments[0]);
+            seg.pos + seg.bpm.msec_to_measure(msec - seg.msec)
+        };
+
+        // the earliest object (or the start of the audio) goes to the measure #001.
+        let mut earliest = 0.0f64;
+        for obj in hitobjs.iter() { earliest = earliest.min(obj.start); }
+        for &(time, _) in bgms.iter() { earliest = earliest.min(time); }
+        let origin = topos(earliest).floor() - 1.0;
+
+        bms.initbpm = segments[0].bpm;
+        for seg in segments[1..].iter() {
+            bms.objs.push(Obj::SetBPM(seg.pos - origin, seg.bpm));
+        }
+
+        let mut keys = HashMap::new();
+        let mut lastpos = 0.0f64;
+        for path in audio.iter() {
+            for &key in sound_key(&mut bms, &mut keys, path[]).iter() {
+                bms.objs.push(Obj::BGM(topos(0.0) - origin, key));
+            }
+        }
+        for &(time, ref path) in bgms.iter() {
+            for &key in sound_key(&mut bms, &mut keys, path[]).iter() {
+                let pos = topos(time) - origin;
+                bms.objs.push(Obj::BGM(pos, key));
+                lastpos = lastpos.max(pos);
+            }
+        }
+
+        for obj in hitobjs.iter() {
+            let column = (obj.x * ncolumns as f64 / 512.0).floor().max(0.0) as uint;
+            let lane = lanes[column.min(ncolumns - 1)];
+            let sref = match obj.sample {
+                Some(ref path) => sound_key(&mut bms, &mut keys, path[]),
+                None => None
+            };
+            let start = topos(obj.start) - origin;
+            match obj.end {
+                Some(end) if end > obj.start => {
+                    let end = topos(end) - origin;
+                    bms.objs.push(Obj::LNStart(start, lane, sref));
+                    bms.objs.push(Obj::LNDone(end, lane, None));
+                    lastpos = lastpos.max(end);
+                }
+                _ => {
+                    bms.objs.push(Obj::Visible(start, lane, sref));
+                    lastpos = lastpos.max(start);
+                }
+            }
+        }
+
+        bms.nmeasures = lastpos.floor() as uint + 1;
+        Ok(bms)
+    }
+
+    /// Reads and converts the osu!mania beatmap with given path.
+    pub fn parse_osu(path: &str) -> AngolmoisResult<Bms> {
+        let ret = ::zip::open_file(path).and_then(|mut f| parse_osu_from_reader(&mut *f));
+        ret.map_err(|err| IoFailure(path.to_string(), err))
+    }
+}
+
+//==================================================================================================
+// O2Jam importer
+
+/**
+ * O2Jam chart importer. O2Jam uses two binary files: OJN contains the metadata, the cover image and
+ * the notes for three difficulties, and OJM contains the sound samples referenced by OJN.
+ *
+ * OJN is converted to `Bms` directly. OJM is unpacked into a cache directory under the system
+ * temporary directory, which is then used as `#PATH_WAV` so that the sound samples are loaded
+ * by the ordinary resource loader.
+ */
+pub mod o2jam {
+    use std::{io, os, cmp};
+    use std::io::BufReader;
+    use std::io::fs::PathExtensions;
+    use std::collections::HashMap;
+    use util::binary::ReaderUtil;
+    use parser::{Key, Lane, MAXKEY, BPM, Obj, Bms, SINGLE_PLAY};
+    use {AngolmoisResult, IoFailure};
+
+    /// The difficulty used when loading OJN. 0, 1 and 2 correspond to Easy, Normal and Hard.
+    pub const DEFAULT_DIFFICULTY: uint = 2;
+
+    /// Genre names indexed by the genre code in OJN.
+    static GENRES: &'static [&'static str] = &[
+        "Ballad", "Rock", "Dance", "Techno", "Hip-hop", "Soul/R&B", "Jazz", "Funk", "Classical",
+        "Traditional", "Etc",
+    ];
+
+    /// Lanes for seven note channels (#2 to #8) in OJN. Matches the `o2jam` key preset.
+    static LANES: [Lane, ..7] = [Lane(1), Lane(2), Lane(3), Lane(4), Lane(5), Lane(8), Lane(9)];
+
+    /// Returns an I/O error for the malformed or unsupported file.
+    fn invalid_file(desc: &'static str, detail: Option<String>) -> io::IoError {
+        io::IoError { kind: io::InvalidInput, desc: desc, detail: detail }
+    }
+
+    //----------------------------------------------------------------------------------------------
+    // OJN
+
+    /// The metadata of OJN not directly stored in `Bms`.
+    pub struct OjnInfo {
+        /// The name of associated OJM file.
+        pub ojmfile: String,
+        /// The cover image (normally JPEG) if any.
+        pub cover: Option<Vec<u8>>,
+    }
+
+    /// Reads and converts the given difficulty of OJN from given reader. Sound samples are named
+    /// after their reference numbers (e.g. `0012.wav` or `1003.wav`), which `unpack_ojm` uses.
+    pub fn parse_ojn_from_reader(f: &mut Reader,
+                                 difficulty: uint) -> io::IoResult<(Bms, OjnInfo)> {
+        let data = try!(f.read_to_end());
+        let mut r = BufReader::new(data[]);
+
+        let _songid = try!(r.read_le_i32());
+        let signature = try!(r.read_exact(4));
+        if signature[] != b"ojn\0" {
+            return Err(invalid_file("not an OJN file", None));
+        }
+        let _encodever = try!(r.read_le_f32());
+        let genre = try!(r.read_le_i32());
+        let bpm = try!(r.read_le_f32());
+        let mut levels = [0i16, ..4];
+        for level in levels.iter_mut() { *level = try!(r.read_le_i16()); }
+        try!(r.skip_bytes(4 * 12)); // event, note, measure and package counts
+        try!(r.skip_bytes(2 + 2 + 20 + 4 + 4)); // legacy fields and the thumbnail size
+        let title = try!(r.read_fixed_str(64));
+        let artist = try!(r.read_fixed_str(32));
+        let _noter = try!(r.read_fixed_str(32));
+        let ojmfile = try!(r.read_fixed_str(32));
+        let coversize = try!(r.read_le_i32()) as uint;
+        try!(r.skip_bytes(4 * 3)); // durations
+        let mut offsets = [0u, ..4]; // three note sections and the cover image
+        for offset in offsets.iter_mut() { *offset = try!(r.read_le_i32()) as uint; }
+
+        if difficulty >= 3 {
+            return Err(invalid_file("invalid difficulty", Some(difficulty.to_string())));
+        }
+        let (start, end) = (offsets[difficulty], offsets[difficulty+1]);
+        if start > end || end > data.len() {
+            return Err(invalid_file("corrupted OJN file", None));
+        }
+
+        let mut bms = Bms::new();
+        bms.title = Some(title);
+        bms.artist = Some(artist);
+        bms.md5 = Some(::util::md5::hex_digest(data[]));
+        bms.genre = GENRES.get(genre as uint).map(|s| s.to_string());
+        bms.player = SINGLE_PLAY;
+        bms.playlevel = levels[difficulty] as int;
+        bms.initbpm = BPM(bpm as f64);
+
+        let mut keys = HashMap::new();
+        let mut nmeasures = 0;
+        let mut r = BufReader::new(data[start..end]);
+        while !r.eof() {
+            let measure = try!(r.read_le_i32());
+            let channel = try!(r.read_le_i16());
+            let nevents = try!(r.read_le_i16()) as uint;
+            if measure < 0 {
+                return Err(invalid_file("corrupted OJN file", None));
+            }
+            let measure = measure as uint;
+            nmeasures = cmp::max(nmeasures, measure + 1);
+
+            for i in range(0, nevents) {
+                let pos = measure as f64 + i as f64 / nevents as f64;
+                match channel {
+                    // measure fraction
+                    0 => {
+                        let shorten = try!(r.read_le_f32()) as f64;
+                        if shorten <= 0.0 { continue; }
+                        if bms.shortens.len() <= measure {
+                            let ncopies = measure - bms.shortens.len() + 1;
+                            bms.shortens.grow(ncopies, 1.0);
+                        }
+                        bms.shortens[mut][measure] = shorten;
+                    }
+
+                    // BPM change
+                    1 => {
+                        let bpm = try!(r.read_le_f32()) as f64;
+                        if bpm > 0.0 { bms.objs.push(Obj::SetBPM(pos, BPM(bpm))); }
+                    }
+
+                    // notes (#2 to #8) and background sounds (#9 and later)
+                    _ => {
+                        let value = try!(r.read_le_i16());
+                        let _volpan = try!(r.read_u8());
+                        let kind = try!(r.read_u8());
+                        if value <= 0 { continue; }
+
+                        // the third bit of `kind` indicates a sample from the OGG section
+                        let sample = value as int - 1 + if kind & 4 != 0 {1000} else {0};
+                        let sref = match keys.find(&sample) {
+                            Some(&key) => Some(key),
+                            None if keys.len() as int + 1 < MAXKEY => {
+                                let key = Key(keys.len() as int + 1);
+                                bms.sndpath[mut][*key as uint] =
+                                    Some(format!("{:04}.wav", sample));
+                                keys.insert(sample, key);
+                                Some(key)
+                            }
+                            None => None
+                        };
+
+                        match (channel, kind & 3) {
+                            (2...8, 2) => {
+                                bms.objs.push(Obj::LNStart(pos, LANES[channel as uint - 2], sref));
+                            }
+                            (2...8, 3) => {
+                                bms.objs.push(Obj::LNDone(pos, LANES[channel as uint - 2], None));
+                            }
+                            (2...8, _) => {
+                                bms.objs.push(Obj::Visible(pos, LANES[channel as uint - 2], sref));
+                            }
+                            (_, _) => {
+                                for &sref in sref.iter() { bms.objs.push(Obj::BGM(pos, sref)); }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        bms.nmeasures = nmeasures;
+
+        let coverstart = offsets[3];
+        let cover =
+            if coversize > 0 && coverstart + coversize <= data.len() {
+                Some(data[coverstart..coverstart+coversize].to_vec())
+            } else {
+                None
+            };
+        Ok((bms, OjnInfo { ojmfile: ojmfile, cover: cover }))
+    }
+
+    //----------------------------------------------------------------------------------------------
+    // OJM
+
+    /// A sound sample unpacked from OJM.
+    pub struct Sample {
+        /// The reference number used by OJN. OGG samples in OMC/OJM files start from 1000.
+        pub id: int,
+        /// The file extension without a leading dot, either `wav` or `ogg`.
+        pub ext: &'static str,
+        /// The contents of sound file.
+        pub data: Vec<u8>,
+    }
+
+    /// Undoes a simple XOR encryption applied to each 4-byte block. The trailing bytes are left
+    /// intact.
+    fn xor_blocks(data: &mut [u8], mask: &[u8]) {
+        let len = data.len() - data.len() % 4;
+        for (i, b) in data[mut ..len].iter_mut().enumerate() {
+            *b ^= mask[i % 4];
+        }
+    }
+
+    /// Builds a RIFF WAVE file from the raw PCM data and its format.
+    fn make_wav(format: u16, nchannels: u16, rate: u32, byterate: u32, blockalign: u16,
+                bits: u16, data: &[u8]) -> io::IoResult<Vec<u8>> {
+        let mut w = io::MemWriter::new();
+        try!(w.write(b"RIFF"));
+        try!(w.write_le_u32(36 + data.len() as u32));
+        try!(w.write(b"WAVEfmt "));
+        try!(w.write_le_u32(16));
+        try!(w.write_le_u16(format));
+        try!(w.write_le_u16(nchannels));
+        try!(w.write_le_u32(rate));
+        try!(w.write_le_u32(byterate));
+        try!(w.write_le_u16(blockalign));
+        try!(w.write_le_u16(bits));
+        try!(w.write(b"data"));
+        try!(w.write_le_u32(data.len() as u32));
+        try!(w.write(data));
+        Ok(w.unwrap())
+    }
+
+    /// Reads the M30 container, which has OGG samples optionally encrypted with XOR masks.
+    fn read_m30(r: &mut BufReader) -> io::IoResult<Vec<Sample>> {
+        let _version = try!(r.read_le_i32());
+        let encryption = try!(r.read_le_i32());
+        let nsamples = try!(r.read_le_i32());
+        let offset = try!(r.read_le_i32());
+        try!(r.skip_bytes(4 + 4)); // payload size and padding
+        try!(r.seek(offset as i64, io::SeekSet));
+
+        let mask: Option<&[u8]> = match encryption {
+            0 => None,
+            16 => Some(b"nami"),
+            32 => Some(b"0412"),
+            _ => {
+                return Err(invalid_file("unsupported M30 encryption",
+                                        Some(encryption.to_string())));
+            }
+        };
+
+        let mut samples = Vec::new();
+        for _ in range(0, nsamples) {
+            try!(r.skip_bytes(32)); // name
+            let size = try!(r.read_le_i32()) as uint;
+            let codec = try!(r.read_le_i16());
+            try!(r.skip_bytes(2 + 4)); // secondary codec and music flag
+            let refno = try!(r.read_le_i16()) as int;
+            try!(r.skip_bytes(2 + 4)); // unknown and the number of PCM samples
+            let mut data = try!(r.read_exact(size));
+            for &mask in mask.iter() { xor_blocks(data[mut], mask); }
+            let id = match codec {
+                0 => 1000 + refno,
+                5 => refno,
+                _ => { continue; }
+            };
+            samples.push(Sample { id: id, ext: "ogg", data: data });
+        }
+        Ok(samples)
+    }
+
+    /// Reads the OMC or OJM container, which has a WAV section followed by an OGG section.
+    /// Returns the samples and the number of encrypted WAV samples skipped.
+    //
+    // TODO WAV samples in OMC files are encrypted with a byte rearrangement and an accumulating
+    // XOR, which is not supported yet. they are skipped and reported by `unpack_ojm`.
+    fn read_omc(r: &mut BufReader, encrypted: bool) -> io::IoResult<(Vec<Sample>, uint)> {
+        let _nwavs = try!(r.read_le_i16());
+        let _noggs = try!(r.read_le_i16());
+        let wavstart = try!(r.read_le_i32()) as u64;
+        let oggstart = try!(r.read_le_i32()) as u64;
+        let filesize = try!(r.read_le_i32()) as u64;
+
+        let mut samples = Vec::new();
+        let mut skipped = 0u;
+        try!(r.seek(wavstart as i64, io::SeekSet));
+        let mut id = 0;
+        while try!(r.tell()) < oggstart {
+            try!(r.skip_bytes(32)); // name
+            let format = try!(r.read_le_u16());
+            let nchannels = try!(r.read_le_u16());
+            let rate = try!(r.read_le_u32());
+            let byterate = try!(r.read_le_u32());
+            let blockalign = try!(r.read_le_u16());
+            let bits = try!(r.read_le_u16());
+            try!(r.skip_bytes(4)); // unknown
+            let size = try!(r.read_le_i32()) as uint;
+            if size > 0 {
+                let data = try!(r.read_exact(size));
+                if encrypted {
+                    skipped += 1;
+                } else {
+                    let data = try!(make_wav(format, nchannels, rate, byterate, blockalign,
+                                             bits, data[]));
+                    samples.push(Sample { id: id, ext: "wav", data: data });
+                }
+            }
+            id += 1;
+        }
+
+        try!(r.seek(oggstart as i64, io::SeekSet));
+        let mut id = 1000;
+        while try!(r.tell()) < filesize && !r.eof() {
+            try!(r.skip_bytes(32)); // name
+            let size = try!(r.read_le_i32()) as uint;
+            if size > 0 {
+                let data = try!(r.read_exact(size));
+                samples.push(Sample { id: id, ext: "ogg", data: data });
+            }
+            id += 1;
+        }
+        Ok((samples, skipped))
+    }
+
+    /// Reads all sound samples in OJM from given reader. Supports M30, OJM and OMC containers.
+    /// Returns the samples and the number of samples which could not be decrypted.
+    pub fn read_ojm_from_reader(f: &mut Reader) -> io::IoResult<(Vec<Sample>, uint)> {
+        let data = try!(f.read_to_end());
+        let mut r = BufReader::new(data[]);
+        let signature = try!(r.read_exact(4));
+        if signature[] == b"M30\0" {
+            read_m30(&mut r).map(|samples| (samples, 0))
+        } else if signature[] == b"OJM\0" {
+            read_omc(&mut r, false)
+        } else if signature[] == b"OMC\0" {
+            read_omc(&mut r, true)
+        } else {
+            Err(invalid_file("not an OJM file", None))
+        }
+    }
+
+    /// The file in the OJM cache directory recording the number of skipped encrypted samples.
+    const SKIPPED_FILE: &'static str = "skipped.txt";
+
+    /// Warns about the samples in given OJM which could not be decrypted, as they will be missing
+    /// from the game play.
+    fn warn_skipped_samples(ojmpath: &Path, skipped: uint) {
+        if skipped > 0 {
+            warn!("{} encrypted WAV samples in {} are not supported and will be silent",
+                  skipped, ojmpath.display());
+        }
+    }
+
+    /**
+     * Unpacks OJM into the cache directory and returns the directory. The cache directory is
+     * determined from the name, size and modification time of OJM, and is reused if it already
+     * exists.
+     *
+     * Samples are named after their reference numbers, so that `foo.ojm` containing the sample
+     * #1003 would be unpacked to `<tmpdir>/angolmois-ojm-foo-<size>-<mtime>/1003.ogg`.
+     * The number of samples skipped during the unpacking is kept in the cache and reported
+     * every time the cache is used.
+     */
+    pub fn unpack_ojm(ojmpath: &Path, cover: Option<&[u8]>) -> io::IoResult<Path> {
+        let stat = try!(ojmpath.stat());
+        let stem = ojmpath.filestem_str().unwrap_or("unknown");
+        let key = format!("angolmois-ojm-{}-{}-{}", stem, stat.size, stat.modified);
+        let cachedir = os::tmpdir().join(key[]);
+        if cachedir.is_dir() {
+            let skipped = io::File::open(&cachedir.join(SKIPPED_FILE)).read_to_string().ok()
+                                   .and_then(|s| from_str::<uint>(s[].trim())).unwrap_or(0);
+            warn_skipped_samples(ojmpath, skipped);
+            return Ok(cachedir);
+        }
+
+        // unpacks into the separate directory first, so that the interrupted unpacking does not
+        // leave the incomplete cache.
+        let tmpdir = os::tmpdir().join(format!("{}.tmp", key));
+        if tmpdir.exists() { try!(io::fs::rmdir_recursive(&tmpdir)); }
+        try!(io::fs::mkdir_recursive(&tmpdir, io::USER_RWX));
+
+        let mut f = try!(io::File::open(ojmpath));
+        let (samples, skipped) = try!(read_ojm_from_reader(&mut f));
+        for sample in samples.iter() {
+            let path = tmpdir.join(format!("{:04}.{}", sample.id, sample.ext));
+            try!(io::File::create(&path).write(sample.data[]));
+        }
+        for cover in cover.iter() {
+            try!(io::File::create(&tmpdir.join("cover.jpg")).write(*cover));
+        }
+        if skipped > 0 {
+            try!(io::File::create(&tmpdir.join(SKIPPED_FILE)).write_str(skipped.to_string()[]));
+        }
+        warn_skipped_samples(ojmpath, skipped);
+
+        try!(io::fs::rename(&tmpdir, &cachedir));
+        Ok(cachedir)
+    }
+
+    /// Reads and converts OJN with given path, unpacking the associated OJM file.
+    //
+    // XXX OJN inside the ZIP archive is not supported, as `unpack_ojm` reads OJM from the file.
+    pub fn parse_ojn(ojnpath: &str, difficulty: uint) -> AngolmoisResult<Bms> {
+        let path = Path::new(ojnpath);
+        let ret = io::File::open(&path).and_then(|mut f| {
+            parse_ojn_from_reader(&mut f, difficulty)
+        });
+        let (mut bms, info) = try!(ret.map_err(|err| IoFailure(ojnpath.to_string(), err)));
+
+        let ojmpath = path.dir_path().join(info.ojmfile[]);
+        let cachedir = try!(unpack_ojm(&ojmpath, info.cover.as_ref().map(|v| v[])).map_err(|err| {
+            IoFailure(ojmpath.display().to_string(), err)
+        }));
+        bms.basepath = cachedir.as_str().map(|s| s.to_string());
+        if info.cover.is_some() { bms.stagefile = Some("cover.jpg".to_string()); }
+        Ok(bms)
+    }
+}
+
+//==================================================================================================
+// bmson exporter
+
+/**
+ * bmson exporter. bmson is a JSON-based successor of BMS, which has a sound channel per sound
+ * file and expresses the position in pulses instead of measures.
+ *
+ * The conversion is mostly straightforward, but some BMS features have no bmson counterparts:
+ * invisible notes, bombs, the third BGA layer and blit commands are dropped with a warning.
+ */
+pub mod bmson {
+    use std::{io, cmp};
+    use serialize::json;
+    use parser::{Lane, MAXKEY, NLANES, Seconds, Measures, ObjQueryOps, Bms, SINGLE_PLAY};
+    use parser::{Visible, Invisible, LNStart, LNDone, Bomb, BGM, SetBGA, SetBPM, Stop, Deleted};
+    use parser::{Layer1, Layer2, Layer3, PoorBGA};
+    use {AngolmoisResult, IoFailure};
+
+    /// The number of pulses in a quarter note.
+    pub const RESOLUTION: uint = 240;
+
+    #[deriving(Encodable)]
+    struct BmsonInfo {
+        title: String, subtitle: String, artist: String, subartists: Vec<String>,
+        genre: String, mode_hint: String, chart_name: String, level: int, init_bpm: f64,
+        judge_rank: f64, total: f64, back_image: String, eyecatch_image: String,
+        banner_image: String, preview_music: String, resolution: uint,
+    }
+
+    #[deriving(Encodable)]
+    struct BarLine { y: uint }
+
+    #[deriving(Encodable)]
+    struct BpmEvent { y: uint, bpm: f64 }
+
+    #[deriving(Encodable)]
+    struct StopEvent { y: uint, duration: uint }
+
+    #[deriving(Encodable)]
+    struct Note { x: uint, y: uint, l: uint, c: bool }
+
+    #[deriving(Encodable)]
+    struct SoundChannel { name: String, notes: Vec<Note> }
+
+    #[deriving(Encodable)]
+    struct BgaHeader { id: uint, name: String }
+
+    #[deriving(Encodable)]
+    struct BgaEvent { y: uint, id: uint }
+
+    #[deriving(Encodable)]
+    struct BgaInfo {
+        bga_header: Vec<BgaHeader>, bga_events: Vec<BgaEvent>,
+        layer_events: Vec<BgaEvent>, poor_events: Vec<BgaEvent>,
+    }
+
+    #[deriving(Encodable)]
+    struct Bmson {
+        version: String, info: BmsonInfo, lines: Vec<BarLine>, bpm_events: Vec<BpmEvent>,
+        stop_events: Vec<StopEvent>, sound_channels: Vec<SoundChannel>, bga: BgaInfo,
+    }
+
+    /// Converts a lane to the bmson lane number. `pms` selects the `popn-9k` mode instead of
+    /// `beat-*k` modes. The foot pedal has no counterpart and returns `None`.
+    fn lane_to_x(Lane(lane): Lane, pms: bool) -> Option<uint> {
+        if pms {
+            match lane {
+                1...5 => Some(lane),
+                38...41 => Some(lane - 36 + 4), // #22 to #25 ("9" preset)
+                8 => Some(6), 9 => Some(7), 6 => Some(8), 7 => Some(9), // "9-bme" preset
+                _ => None
+            }
+        } else {
+            let (base, lane) = if lane >= 36 {(8, lane - 36)} else {(0, lane)};
+            match lane {
+                1...5 => Some(base + lane),
+                8 => Some(base + 6),
+                9 => Some(base + 7),
+                6 => Some(base + 8),
+                _ => None
+            }
+        }
+    }
+
+    /// Converts the BMS data to bmson and writes it to given writer. `pms` should be set when
+    /// the data came from PMS file, as the lane numbering differs.
+    pub fn write_bmson(bms: &Bms, pms: bool, f: &mut Writer) -> io::IoResult<()> {
+        use util::option::StrOption;
+
+        let mut objs: Vec<_> = bms.objs.iter().collect();
+        objs.sort_by(|a, b| if a.time < b.time {Less} else if a.time > b.time {Greater}
+                            else {Equal});
+
+        // the position of every measure in pulses
+        let pulsespermeasure = (RESOLUTION * 4) as f64;
+        let lastmeasure = objs.iter().fold(bms.nmeasures, |m, obj| {
+            cmp::max(m, obj.time.max(0.0) as uint)
+        });
+        let mut measurey = Vec::new();
+        let mut y = 0.0;
+        for measure in range(0, lastmeasure + 2) {
+            measurey.push(y);
+            y += bms.shorten(measure as int) * pulsespermeasure;
+        }
+        let topulse = |time: f64| -> uint {
+            if time <= 0.0 { return 0; }
+            let measure = time.floor() as uint;
+            let y = measurey[measure] +
+                    (time - measure as f64) * bms.shorten(measure as int) * pulsespermeasure;
+            (y + 0.5) as uint
+        };
+
+        let mut ndropped = 0u;
+        let mut bpm = bms.initbpm;
+        let mut bpmevents = Vec::new();
+        let mut stopevents = Vec::new();
+        let mut bgaevents = Vec::new();
+        let mut layerevents = Vec::new();
+        let mut poorevents = Vec::new();
+        // notes per sound; the last entry is for notes without sounds
+        let nosound = MAXKEY as uint;
+        let mut notes: Vec<Vec<Note>> = Vec::from_fn(nosound + 1, |_| Vec::new());
+        let mut pending: Vec<Option<(uint, uint, uint)>> = Vec::from_elem(NLANES, None);
+
+        for &obj in objs.iter() {
+            let y = topulse(obj.time);
+            match obj.data {
+                Visible(lane, sref) | LNStart(lane, sref) | LNDone(lane, sref) => {
+                    let x = match lane_to_x(lane, pms) {
+                        Some(x) => x,
+                        None => { ndropped += 1; continue; }
+                    };
+                    let sound = sref.map_or(nosound, |sref| **sref as uint);
+                    let Lane(lane) = lane;
+                    match obj.data {
+                        Visible(..) => {
+                            notes[mut][sound].push(Note { x: x, y: y, l: 0, c: false });
+                        }
+                        LNStart(..) => {
+                            // an unpaired start (only when unsanitized) becomes a normal note
+                            for &(x, y, sound) in pending[lane].iter() {
+                                notes[mut][sound].push(Note { x: x, y: y, l: 0, c: false });
+                            }
+                            pending[mut][lane] = Some((x, y, sound));
+                        }
+                        _ => {
+                            for &(x, starty, sound) in pending[lane].iter() {
+                                let note = Note { x: x, y: starty, l: y - starty, c: false };
+                                notes[mut][sound].push(note);
+                            }
+                            pending[mut][lane] = None;
+                        }
+                    }
+                }
+                BGM(sref) => {
+                    notes[mut][**sref as uint].push(Note { x: 0, y: y, l: 0, c: false });
+                }
+                SetBGA(layer, iref) => {
+                    let iref = match iref {
+                        Some(iref) => **iref as uint,
+                        None => { ndropped += 1; continue; }
+                    };
+                    let event = BgaEvent { y: y, id: iref };
+                    match layer {
+                        Layer1 => { bgaevents.push(event); }
+                        Layer2 => { layerevents.push(event); }
+                        PoorBGA => { poorevents.push(event); }
+                        Layer3 => { ndropped += 1; }
+                    }
+                }
+                SetBPM(newbpm) => {
+                    bpm = newbpm;
+                    if *newbpm > 0.0 {
+                        bpmevents.push(BpmEvent { y: y, bpm: *newbpm });
+                    } else {
+                        ndropped += 1;
+                    }
+                }
+                Stop(duration) => {
+                    let measures = match duration {
+                        Seconds(secs) => bpm.msec_to_measure(secs * 1000.0),
+                        Measures(measures) => measures
+                    };
+                    let duration = (measures * pulsespermeasure + 0.5) as uint;
+                    stopevents.push(StopEvent { y: y, duration: duration });
+                }
+                Invisible(..) | Bomb(..) => { ndropped += 1; }
+                Deleted => {}
+            }
+        }
+        for pending in pending.iter() {
+            for &(x, y, sound) in pending.iter() {
+                notes[mut][sound].push(Note { x: x, y: y, l: 0, c: false });
+            }
+        }
+        if !bms.blitcmd.is_empty() { ndropped += bms.blitcmd.len(); }
+        if ndropped > 0 {
+            warn!("{} objects have no bmson counterparts and were dropped", ndropped);
+        }
+
+        let mut channels = Vec::new();
+        for (i, notes) in notes.into_iter().enumerate() {
+            let name = if i < nosound {bms.sndpath[i].clone()} else {None};
+            if notes.is_empty() && name.is_none() { continue; }
+            channels.push(SoundChannel { name: name.unwrap_or(String::new()), notes: notes });
+        }
+
+        let bgaheader = bms.imgpath.iter().enumerate().filter_map(|(i, path)| {
+            path.as_ref().map(|path| BgaHeader { id: i, name: path.clone() })
+        }).collect();
+
+        let modehint =
+            if pms {
+                "popn-9k"
+            } else {
+                let isbme = bms.objs.iter().any(|obj| match obj.object_lane() {
+                    Some(Lane(8)) | Some(Lane(9)) | Some(Lane(44)) | Some(Lane(45)) => true,
+                    _ => false
+                });
+                match (bms.player == SINGLE_PLAY, isbme) {
+                    (true, false) => "beat-5k",
+                    (true, true) => "beat-7k",
+                    (false, false) => "beat-10k",
+                    (false, true) => "beat-14k",
+                }
+            };
+
+        // #RANK 2 (NORMAL) corresponds to 100, and each step widens or narrows it by 25
+        let judgerank = 100.0 + (bms.rank as f64 - 2.0) * 25.0;
+        let info = BmsonInfo {
+            title: bms.title.as_ref_slice_or("").to_string(),
+            subtitle: String::new(),
+            artist: bms.artist.as_ref_slice_or("").to_string(),
+            subartists: Vec::new(),
+            genre: bms.genre.as_ref_slice_or("").to_string(),
+            mode_hint: modehint.to_string(),
+            chart_name: String::new(),
+            level: bms.playlevel,
+            init_bpm: *bms.initbpm,
+            judge_rank: judgerank.max(25.0),
+            total: 100.0,
+            back_image: bms.backbmp.as_ref_slice_or("").to_string(),
+            eyecatch_image: bms.stagefile.as_ref_slice_or("").to_string(),
+            banner_image: bms.banner.as_ref_slice_or("").to_string(),
+            preview_music: bms.preview.as_ref_slice_or("").to_string(),
+            resolution: RESOLUTION,
+        };
+        let lines = range(0, bms.nmeasures + 1).map(|measure| {
+            BarLine { y: (measurey[measure] + 0.5) as uint }
+        }).collect();
+
+        let bmson = Bmson {
+            version: "1.0.0".to_string(), info: info, lines: lines,
+            bpm_events: bpmevents, stop_events: stopevents, sound_channels: channels,
+            bga: BgaInfo { bga_header: bgaheader, bga_events: bgaevents,
+                           layer_events: layerevents, poor_events: poorevents },
+        };
+        f.write_str(json::encode(&bmson)[])
+    }
+
+    /// Converts the BMS data to bmson and writes it to given path.
+    pub fn write_bmson_file(bms: &Bms, pms: bool, path: &str) -> AngolmoisResult<()> {
+        let ret = io::File::create(&Path::new(path)).and_then(|mut f| {
+            write_bmson(bms, pms, &mut f)
+        });
+        ret.map_err(|err| IoFailure(path.to_string(), err))
+    }
+}
+
+//==================================================================================================
+// chart linter
+
+/**
+ * Chart linter. It reports problems in the chart which the player would silently fix or ignore,
+ * without initializing SDL. Problems found while parsing (including undefined #BPMxx/#STOPxx
+ * references and unterminated long notes) are already in `Bms::diagnostics`; this module adds
+ * the problems only visible from the whole chart.
+ */
+pub mod lint {
+    use std::fmt;
+    use std::ascii::AsciiExt;
+    use parser::{Key, MAXKEY, ObjQueryOps, Bms, Severity, Note, Warning, Error};
+    use parser::{Deleted, sanitize_objs, describe_conflict};
+    use player::{Options, get_basedirs, resolve_relative_path, SOUND_EXTS, IMAGE_EXTS};
+
+    /// A problem found by `lint_bms`.
+    pub struct Issue {
+        /// The position of the problem in measures, if the problem is tied to objects.
+        pub time: Option<f64>,
+        /// The severity.
+        pub severity: Severity,
+        /// A human-readable message.
+        pub message: String,
+    }
+
+    impl fmt::Show for Issue {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            let severity = match self.severity { Note => "note", Warning => "warning",
+                                                 Error => "error" };
+            match self.time {
+                Some(time) => write!(f, "measure {:.3}: {}: {}", time, severity, self.message),
+                None => write!(f, "{}: {}", severity, self.message),
+            }
+        }
+    }
+
+    /// Checks the unsanitized BMS data and returns a list of problems. `opts` is used to locate
+    /// the resource files.
+    pub fn lint_bms(bms: &Bms, opts: &Options) -> Vec<Issue> {
+        let mut issues = Vec::new();
+        macro_rules! issue(
+            ($time:expr, $severity:expr, $($e:expr),+) => (
+                issues.push(Issue { time: $time, severity: $severity, message: format!($($e),+) })
+            )
+        )
+
+        // runs the sanitizer on the copy and reports every object it has removed
+        let mut objs = bms.objs.clone();
+        objs.sort_by(|a, b| {
+            if a.time < b.time {Less} else if a.time > b.time {Greater} else {Equal}
+        });
+        let orig = objs.clone();
+        sanitize_objs(objs[mut]);
+        for (before, after) in orig.iter().zip(objs.iter()) {
+            if before.data != after.data && before.data != Deleted {
+                issue!(Some(before.time), Warning, "{}", describe_conflict(before));
+            }
+        }
+
+        // references to #WAVxx and #BMPxx (both in objects and #BGA commands)
+        let mut sndused = Vec::from_elem(MAXKEY as uint, false);
+        let mut imgused = Vec::from_elem(MAXKEY as uint, false);
+        for obj in bms.objs.iter() {
+            for &sref in obj.sounds().iter() {
+                let Key(key) = *sref;
+                if key != 0 && bms.sndpath[key as uint].is_none() {
+                    issue!(Some(obj.time), Warning, "undefined #WAV{}", *sref);
+                }
+                sndused[mut][key as uint] = true;
+            }
+            for &iref in obj.images().iter() {
+                let Key(key) = *iref;
+                if key != 0 && bms.imgpath[key as uint].is_none() {
+                    issue!(Some(obj.time), Warning, "undefined #BMP{}", *iref);
+                }
+                imgused[mut][key as uint] = true;
+            }
+        }
+        for bc in bms.blitcmd.iter() {
+            let Key(src) = *bc.src;
+            let Key(dst) = *bc.dst;
+            imgused[mut][src as uint] = true;
+            imgused[mut][dst as uint] = true;
+        }
+        for i in range(1, MAXKEY as uint) {
+            if bms.sndpath[i].is_some() && !sndused[i] {
+                issue!(None, Note, "unused #WAV{}", Key(i as int));
+            }
+            if bms.imgpath[i].is_some() && !imgused[i] {
+                issue!(None, Note, "unused #BMP{}", Key(i as int));
+            }
+        }
+
+        // resource files
+        let basedirs = get_basedirs(bms, opts);
+        for (i, path) in bms.sndpath.iter().enumerate() {
+            for path in path.iter() {
+                if resolve_relative_path(basedirs[], path[], SOUND_EXTS).is_none() {
+                    issue!(None, Error, "missing file {} for #WAV{}", path, Key(i as int));
+                }
+            }
+        }
+        for (i, path) in bms.imgpath.iter().enumerate() {
+            for path in path.iter() {
+                if resolve_relative_path(basedirs[], path[], IMAGE_EXTS).is_none() {
+                    issue!(None, Error, "missing file {} for #BMP{}", path, Key(i as int));
+                }
+            }
+        }
+        for &(cmd, ref path) in [("#STAGEFILE", &bms.stagefile),
+                                 ("#BANNER", &bms.banner),
+                                 ("#BACKBMP", &bms.backbmp)].iter() {
+            for path in path.iter() {
+                if resolve_relative_path(basedirs[], path[], IMAGE_EXTS).is_none() {
+                    issue!(None, Error, "missing file {} for {}", path, cmd);
+                }
+                let ext = path[].rfind('.').map_or("", |i| path[i..]).to_ascii_upper();
+                if !IMAGE_EXTS.iter().any(|&e| e == ext[]) {
+                    issue!(None, Warning, "unsupported image format {} for {}", path, cmd);
+                }
+            }
+        }
+
+        issues
+    }
+}
+
+//==================================================================================================
+// replays
+
+/**
+ * Replay files. A replay records every input to the lanes during the game play, along with
+ * the random seed and modifier needed to reconstruct the identical chart. The file is bound to
+ * the chart by its hash, and ends with the final score so that it can be verified.
+ *
+ * The file is a big-endian binary consisting of:
+ *
+ * - The magic `ANGREPL2`.
+ * - The chart hash (`ChartHash::md5`, 32 ASCII characters), the random seed (u64) and
+ *   the modifier code (u8, 0 for none).
+ * - The option flags (u8): bit 0 is set when both sides of the chart have been flipped, and
+ *   bits 1 and 2 are the code of the compatibility profile (`CompatProfile::code`).
+ * - The number of events (u32), followed by each event: the timestamp in milliseconds since
+ *   the start of play (u32), the lane (u8), the input state (i8), 1 if the input is continuous
+ *   (u8), the grade issued by the input (u8, 0xff for none), and the score (u32) and combo number
+ *   (u32) after the input.
+ * - The final score (u32) and the numbers of each grade (`NGRADES` times u32).
+ *
+ * The older `ANGREPL1` files are also read. They lack PGREAT, so their COOL grades are read as
+ * PGREAT which had the same EX score, and only `NGRADES - 1` grade counts are stored. They also
+ * lack the option flags, so they are never flipped and always use the canonical profile. Their
+ * chart hash is the legacy hash (`ChartHash::legacy`, u64) instead.
+ */
+pub mod replay {
+    use std::{io, hash};
+    use parser::{Lane, NLANES, CompatProfile};
+    use player::{Modf, MirrorModf, ShuffleModf, ShuffleExModf, RandomModf, RandomExModf};
+    use player::{SRandomModf, HRandomModf};
+    use player::{InputState, Positive, Neutral, Negative};
+    use player::{Grade, MISS, BAD, GOOD, GREAT, COOL, PGREAT, NGRADES};
+    use {AngolmoisResult, IoFailure};
+
+    /// The magic at the beginning of replay files.
+    static MAGIC: &'static [u8] = b"ANGREPL2";
+    /// The magic at the beginning of replay files without PGREAT.
+    static MAGIC_V1: &'static [u8] = b"ANGREPL1";
+
+    /// An input to the lane recorded during the game play.
+    #[deriving(Clone)]
+    pub struct ReplayEvent {
+        /// The time of input in milliseconds since the start of play.
+        pub time: uint,
+        /// The lane affected by the input.
+        pub lane: Lane,
+        /// The new input state.
+        pub state: InputState,
+        /// True if the input is not discrete, i.e. from joystick axes.
+        pub continuous: bool,
+        /// The grade issued by this input if any.
+        pub grade: Option<Grade>,
+        /// The score after this input. Used for the ghost.
+        pub score: uint,
+        /// The combo number after this input. Used for the ghost.
+        pub combo: uint,
+    }
+
+    /// A recorded game play.
+    pub struct Replay {
+        /// The hash of the chart file, either `ChartHash::md5` or `ChartHash::legacy` in 16
+        /// hexadecimal digits (for older files). Use `ChartHash::matches` for comparison.
+        pub hash: String,
+        /// The random seed used for parsing the chart and applying the modifier.
+        pub seed: u64,
+        /// The modifier applied if any.
+        pub modf: Option<Modf>,
+        /// True if both sides of the chart have been flipped.
+        pub flip: bool,
+        /// The compatibility profile used for parsing the chart.
+        pub compat: CompatProfile,
+        /// Recorded inputs in the order of time.
+        pub events: Vec<ReplayEvent>,
+        /// The final score.
+        pub score: uint,
+        /// The final numbers of each grade.
+        pub gradecounts: [uint, ..NGRADES],
+    }
+
+    /// The hash of the chart file, which identifies the chart in replays and local records.
+    #[deriving(Clone)]
+    pub struct ChartHash {
+        /// The MD5 digest in lowercase hexadecimal digits, shared with other BMS players (as in
+        /// `BmsInfo::md5`).
+        pub md5: String,
+        /// The hash used by older versions, which depends on the Rust version. It is only used
+        /// for reading older replays and records, which are migrated to `md5` when written.
+        pub legacy: u64,
+    }
+
+    impl ChartHash {
+        /// Returns true if given hash from replays or records identifies this chart.
+        pub fn matches(&self, hash: &str) -> bool {
+            hash == self.md5[] || hash == format!("{:016x}", self.legacy)[]
+        }
+    }
+
+    /// Calculates the hash of the chart file, which identifies the chart in replays.
+    pub fn chart_hash(path: &str) -> AngolmoisResult<ChartHash> {
+        let ret = ::zip::read_file(path);
+        match ret {
+            Ok(data) => Ok(ChartHash { md5: ::util::md5::hex_digest(data[]),
+                                       legacy: hash::hash(&data) }),
+            Err(err) => Err(IoFailure(path.to_string(), err)),
+        }
+    }
+
+    /// Converts the modifier to the code used in replay files.
+    fn modf_to_code(modf: Option<Modf>) -> u8 {
+        match modf {
+            None => 0,
+            Some(MirrorModf) => 1,
+            Some(ShuffleModf) => 2,
+            Some(ShuffleExModf) => 3,
+            Some(RandomModf) => 4,
+            Some(RandomExModf) => 5,
+            Some(SRandomModf) => 6,
+            Some(HRandomModf) => 7,
+        }
+    }
+
+    /// Converts the code used in replay files to the modifier.
+    fn code_to_modf(code: u8) -> Option<Option<Modf>> {
+        match code {
+            0 => Some(None),
+            1 => Some(Some(MirrorModf)),
+            2 => Some(Some(ShuffleModf)),
+            3 => Some(Some(ShuffleExModf)),
+            4 => Some(Some(RandomModf)),
+            5 => Some(Some(RandomExModf)),
+            6 => Some(Some(SRandomModf)),
+            7 => Some(Some(HRandomModf)),
+            _ => None,
+        }
+    }
+
+    /// Returns an `IoError` for the malformed replay file.
+    fn invalid_file(desc: &'static str) -> io::IoError {
+        io::IoError { kind: io::InvalidInput, desc: desc, detail: None }
+    }
+
+    /// Reads the replay in the binary format.
+    pub fn read_replay(f: &mut Reader) -> io::IoResult<Replay> {
+        let magic = try!(f.read_exact(MAGIC.len()));
+        let v1 = magic[] == MAGIC_V1;
+        if magic[] != MAGIC && !v1 { return Err(invalid_file("not a replay file")); }
+
+        let hash = if v1 {
+            format!("{:016x}", try!(f.read_be_u64()))
+        } else {
+            let hash = try!(f.read_exact(32));
+            if !hash.iter().all(|&c| (c as char).is_digit_radix(16)) {
+                return Err(invalid_file("invalid chart hash"));
+            }
+            String::from_utf8(hash).unwrap()
+        };
+        let seed = try!(f.read_be_u64());
+        let modf = match code_to_modf(try!(f.read_u8())) {
+            Some(modf) => modf,
+            None => { return Err(invalid_file("invalid modifier")); }
+        };
+        let flags = if v1 {0} else {try!(f.read_u8())};
+        if flags & !7 != 0 { return Err(invalid_file("invalid option flags")); }
+        let flip = flags & 1 != 0;
+        let compat = match CompatProfile::from_code(flags >> 1) {
+            Some(compat) => compat,
+            None => { return Err(invalid_file("invalid compatibility profile")); }
+        };
+
+        let nevents = try!(f.read_be_u32()) as uint;
+        let mut events = Vec::with_capacity(nevents);
+        for _ in range(0, nevents) {
+            let time = try!(f.read_be_u32()) as uint;
+            let lane = try!(f.read_u8()) as uint;
+            if lane >= NLANES { return Err(invalid_file("invalid lane")); }
+            let state = match try!(f.read_i8()) {
+                1 => Positive,
+                0 => Neutral,
+                -1 => Negative,
+                _ => { return Err(invalid_file("invalid input state")); }
+            };
+            let continuous = try!(f.read_u8()) != 0;
+            let grade = match try!(f.read_u8()) {
+                0 => Some(MISS),
+                1 => Some(BAD),
+                2 => Some(GOOD),
+                3 => Some(GREAT),
+                4 if v1 => Some(PGREAT),
+                4 => Some(COOL),
+                5 if !v1 => Some(PGREAT),
+                0xff => None,
+                _ => { return Err(invalid_file("invalid grade")); }
+            };
+            let score = try!(f.read_be_u32()) as uint;
+            let combo = try!(f.read_be_u32()) as uint;
+            events.push(ReplayEvent { time: time, lane: Lane(lane), state: state,
+                                      continuous: continuous, grade: grade,
+                                      score: score, combo: combo });
+        }
+
+        let score = try!(f.read_be_u32()) as uint;
+        let mut gradecounts = [0, ..NGRADES];
+        for count in gradecounts[mut ..COOL as uint].iter_mut() {
+            *count = try!(f.read_be_u32()) as uint;
+        }
+        if v1 {
+            gradecounts[PGREAT as uint] = try!(f.read_be_u32()) as uint;
+        } else {
+            for count in gradecounts[mut COOL as uint..].iter_mut() {
+                *count = try!(f.read_be_u32()) as uint;
+            }
+        }
+        Ok(Replay { hash: hash, seed: seed, modf: modf, flip: flip, compat: compat,
+                    events: events, score: score, gradecounts: gradecounts })
+    }
+
+    /// Reads the replay from given path.
+    pub fn read_replay_file(path: &str) -> AngolmoisResult<Replay> {
+        let ret = io::File::open(&Path::new(path)).and_then(|mut f| read_replay(&mut f));
+        ret.map_err(|err| IoFailure(path.to_string(), err))
+    }
+
+    /// Writes the replay in the binary format.
+    pub fn write_replay(replay: &Replay, f: &mut Writer) -> io::IoResult<()> {
+        try!(f.write(MAGIC));
+        try!(f.write_str(replay.hash[]));
+        try!(f.write_be_u64(replay.seed));
+        try!(f.write_u8(modf_to_code(replay.modf)));
+        try!(f.write_u8((if replay.flip {1} else {0}) | replay.compat.code() << 1));
+        try!(f.write_be_u32(replay.events.len() as u32));
+        for ev in replay.events.iter() {
+            try!(f.write_be_u32(ev.time as u32));
+            try!(f.write_u8(*ev.lane as u8));
+            try!(f.write_i8(ev.state as i8));
+            try!(f.write_u8(if ev.continuous {1} else {0}));
+            try!(f.write_u8(ev.grade.map_or(0xff, |grade| grade as u8)));
+            try!(f.write_be_u32(ev.score as u32));
+            try!(f.write_be_u32(ev.combo as u32));
+        }
+        try!(f.write_be_u32(replay.score as u32));
+        for &count in replay.gradecounts.iter() {
+            try!(f.write_be_u32(count as u32));
+        }
+        Ok(())
+    }
+
+    /// Writes the replay to given path.
+    pub fn write_replay_file(replay: &Replay, path: &str) -> AngolmoisResult<()> {
+        let ret = io::File::create(&Path::new(path)).and_then(|mut f| write_replay(replay, &mut f));
+        ret.map_err(|err| IoFailure(path.to_string(), err))
+    }
+}
+
+//==================================================================================================
+// song library
+
+/**
+ * Song library. It walks the directory tree and collects the metadata of every BMS file, which
+ * is kept in the cache file so that later scans only have to read new or modified files.
+ *
+ * Only the headers are read, so any header inside #RANDOM blocks is read as if every block is
+ * active (and the last one wins). osu!mania and O2Jam charts are not scanned.
+ *
+ * The cache is a UTF-8 text file with one chart per line, consisting of the path, the modified
+ * time, the file size, the hash (as in `replay::ChartHash::md5`), the level, the title,
+ * the artist and the genre separated by tabs. Lines from older versions, which had a different
+ * hash, are considered malformed so that their charts are read again.
+ */
+pub mod library {
+    use std::{io, str};
+    use std::ascii::AsciiExt;
+    use std::io::fs::PathExtensions;
+    use {AngolmoisResult, IoFailure};
+
+    /// File extensions scanned as BMS files.
+    static BMS_EXTS: &'static [&'static str] = &[".BMS", ".BME", ".BML", ".PMS"];
+
+    /// The name of the cache file placed in the root directory.
+    pub static CACHE_NAME: &'static str = ".angolmois-library";
+
+    /// The metadata of a chart in the library.
+    #[deriving(Clone)]
+    pub struct LibraryEntry {
+        /// A path to the chart.
+        pub path: String,
+        /// The last modified time of the chart file, used for invalidating the cache.
+        pub modified: u64,
+        /// The size of the chart file, used for invalidating the cache.
+        pub size: u64,
+        /// The MD5 digest of the chart file in lowercase hexadecimal digits.
+        pub hash: String,
+        /// The level. Maps to BMS #PLAYLEVEL command.
+        pub level: int,
+        /// The title. Maps to BMS #TITLE command.
+        pub title: Option<String>,
+        /// The artist. Maps to BMS #ARTIST command.
+        pub artist: Option<String>,
+        /// The genre. Maps to BMS #GENRE command.
+        pub genre: Option<String>,
+    }
+
+    /// Reads the metadata from the contents of the BMS file, without parsing the objects.
+    fn read_headers(path: String, modified: u64, size: u64, data: &[u8]) -> LibraryEntry {
+        let mut entry = LibraryEntry { path: path, modified: modified, size: size,
+                                       hash: ::util::md5::hex_digest(data), level: 0,
+                                       title: None, artist: None, genre: None };
+        let text = ::util::encoding::decode_text(data);
+        for line in text.as_slice().lines() {
+            let line = line.trim();
+            if !line.starts_with("#") { continue; }
+            let line = line[1..];
+            let cmdlen = line.find(|c: char| c.is_whitespace()).unwrap_or(line.len());
+            let arg = line[cmdlen..].trim();
+            match line[..cmdlen].to_ascii_upper()[] {
+                "TITLE" => { entry.title = Some(arg.to_string()); }
+                "ARTIST" => { entry.artist = Some(arg.to_string()); }
+                "GENRE" => { entry.genre = Some(arg.to_string()); }
+                "PLAYLEVEL" => { entry.level = from_str(arg).unwrap_or(0); }
+                _ => {}
+            }
+        }
+        entry
+    }
+
+    /// Reads the cache file. Malformed lines are ignored.
+    pub fn read_cache(f: &mut Buffer) -> io::IoResult<Vec<LibraryEntry>> {
+        fn field(s: &str) -> Option<String> {
+            if s.is_empty() {None} else {Some(s.to_string())}
+        }
+
+        let mut entries = Vec::new();
+        for line in f.lines() {
+            let line = try!(line);
+            let fields: Vec<&str> = line[].trim_right_chars(['\r', '\n'][]).split('\t').collect();
+            if fields.len() != 8 { continue; }
+            let modified = from_str::<u64>(fields[1]);
+            let size = from_str::<u64>(fields[2]);
+            let hash = fields[3];
+            let level = from_str::<int>(fields[4]);
+            let validhash = hash.len() == 32 && hash.chars().all(|c| c.is_digit_radix(16));
+            if modified.is_none() || size.is_none() || !validhash || level.is_none() {
+                continue;
+            }
+            entries.push(LibraryEntry { path: fields[0].to_string(), modified: modified.unwrap(),
+                                        size: size.unwrap(), hash: hash.to_string(),
+                                        level: level.unwrap(), title: field(fields[5]),
+                                        artist: field(fields[6]), genre: field(fields[7]) });
+        }
+        Ok(entries)
+    }
+
+    /// Writes the cache file.
+    pub fn write_cache(entries: &[LibraryEntry], f: &mut Writer) -> io::IoResult<()> {
+        fn field(s: &Option<String>) -> String {
+            let s = s.as_ref().map_or("", |s| s[]);
+            s.chars().map(|c| if c == '\t' || c == '\r' || c == '\n' {' '} else {c}).collect()
+        }
+
+        for entry in entries.iter() {
+            try!(writeln!(f, "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}", entry.path, entry.modified,
+                          entry.size, entry.hash, entry.level, field(&entry.title),
+                          field(&entry.artist), field(&entry.genre)));
+        }
+        Ok(())
+    }
+
+    /**
+     * Scans every BMS file under the root directory and returns their metadata sorted by
+     * the path. The cache file (`CACHE_NAME`) in the root directory is used for unmodified files
+     * and updated afterwards. Unreadable files and directories are skipped.
+     */
+    pub fn scan_library(root: &Path) -> AngolmoisResult<Vec<LibraryEntry>> {
+        use std::collections::HashMap;
+
+        let cachepath = root.join(CACHE_NAME);
+        let mut cached = HashMap::new();
+        if cachepath.exists() {
+            let ret = io::File::open(&cachepath).and_then(|f| {
+                read_cache(&mut io::BufferedReader::new(f))
+            });
+            for entry in ret.unwrap_or(Vec::new()).into_iter() {
+                cached.insert(entry.path.clone(), entry);
+            }
+        }
+
+        let mut entries = Vec::new();
+        let walker = match io::fs::walk_dir(root) {
+            Ok(walker) => walker,
+            Err(err) => { return Err(IoFailure(root.display().to_string(), err)); }
+        };
+        for path in walker {
+            let name = path.filename().and_then(str::from_utf8).map(|v| v.to_ascii_upper());
+            let isbms = name.map_or(false, |name| {
+                BMS_EXTS.iter().any(|ext| name[].ends_with(*ext))
+            });
+            if !isbms || !path.is_file() { continue; }
+            let pathstr = match path.as_str() {
+                Some(pathstr) => pathstr.to_string(),
+                None => { continue; }
+            };
+            let stat = match path.stat() {
+                Ok(stat) => stat,
+                Err(_) => { continue; }
+            };
+
+            match cached.pop(&pathstr) {
+                Some(ref entry) if entry.modified == stat.modified && entry.size == stat.size => {
+                    entries.push(entry.clone());
+                }
+                _ => {
+                    match io::File::open(&path).and_then(|mut f| f.read_to_end()) {
+                        Ok(data) => {
+                            entries.push(read_headers(pathstr, stat.modified, stat.size, data[]));
+                        }
+                        Err(_) => {}
+                    }
+                }
+            }
+        }
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let ret = io::File::create(&cachepath).and_then(|mut f| write_cache(entries[], &mut f));
+        match ret {
+            Ok(()) => Ok(entries),
+            Err(err) => Err(IoFailure(cachepath.display().to_string(), err)),
+        }
+    }
+}
+
+//==================================================================================================
+// internet ranking
+
+/**
+ * Internet ranking (IR) client. The result of the finished game play is posted as a JSON object
+ * to the configured endpoint with a plain HTTP/1.0 request; only `http://` URLs are supported.
+ * The endpoint is expected to answer with any 2xx status, and the response body is ignored.
+ * The request is made in a background task, and gives up after `TIMEOUT` for each of
+ * connecting, sending and receiving.
+ *
+ * The JSON object has the following fields (see `Submission`):
+ *
+ * - `md5`: the MD5 digest of the chart file in lowercase hexadecimal digits.
+ * - `exscore`, `score` and `maxcombo`: the EX score, the score and the best combo number.
+ * - `lamp`: the clear lamp, one of `player::CLEAR_LAMPS` (e.g. `"HARD CLEAR"`).
+ * - `pgreat`, `cool`, `great`, `good`, `bad` and `miss`: the number of each grade.
+ * - `client`: the client version as returned by `version`.
+ */
+pub mod ir {
+    use std::io;
+    use std::io::net::addrinfo::get_host_addresses;
+    use std::io::net::ip::SocketAddr;
+    use std::io::net::tcp::TcpStream;
+    use std::time::Duration;
+    use serialize::json;
+    use player::{Player, MISS, BAD, GOOD, GREAT, COOL, PGREAT};
+    use replay::ChartHash;
+    use {version, AngolmoisResult, IoFailure, IrFailure};
+
+    /// The timeout for connecting, sending and receiving, in milliseconds.
+    pub const TIMEOUT: u64 = 5000;
+
+    /// The result of the game play submitted to the IR.
+    #[deriving(Encodable)]
+    pub struct Submission {
+        /// The MD5 digest of the chart file (as in `replay::ChartHash::md5`).
+        pub md5: String,
+        /// The EX score.
+        pub exscore: uint,
+        /// The score.
+        pub score: uint,
+        /// The best combo number.
+        pub maxcombo: uint,
+        /// The clear lamp as returned by `Player::clear_lamp`.
+        pub lamp: String,
+        /// The number of each grade.
+        pub pgreat: uint,
+        pub cool: uint,
+        pub great: uint,
+        pub good: uint,
+        pub bad: uint,
+        pub miss: uint,
+        /// The client version.
+        pub client: String,
+    }
+
+    impl Submission {
+        /// Creates a submission from the result of the finished game play.
+        pub fn new(hash: &ChartHash, player: &Player) -> Submission {
+            Submission {
+                md5: hash.md5.clone(),
+                exscore: player.exscore, score: player.score, maxcombo: player.bestcombo,
+                lamp: player.clear_lamp().to_string(), pgreat: player.gradecounts[PGREAT as uint],
+                cool: player.gradecounts[COOL as uint], great: player.gradecounts[GREAT as uint],
+                good: player.gradecounts[GOOD as uint], bad: player.gradecounts[BAD as uint],
+                miss: player.gradecounts[MISS as uint], client: version(),
+            }
+        }
+    }
+
+    /// Splits the `http://` URL into the host, the port and the path.
+    fn split_url(url: &str) -> Option<(String, u16, String)> {
+        if !url.starts_with("http://") { return None; }
+        let rest = url["http://".len()..];
+        let (hostport, path) = match rest.find('/') {
+            Some(i) => (rest[..i], rest[i..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match hostport.rfind(':') {
+            Some(i) => match from_str::<u16>(hostport[i+1..]) {
+                Some(port) => (hostport[..i], port),
+                None => { return None; }
+            },
+            None => (hostport, 80),
+        };
+        if host.is_empty() { return None; }
+        Some((host.to_string(), port, path.to_string()))
+    }
+
+    /// Posts the submission to the IR endpoint at given URL.
+    pub fn submit(url: &str, submission: &Submission) -> AngolmoisResult<()> {
+        let (host, port, path) = match split_url(url) {
+            Some(parts) => parts,
+            None => { return Err(IrFailure(format!("Invalid IR endpoint: {}", url))); }
+        };
+        let body = json::encode(submission);
+        let request = format!("POST {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: {}\r\n\
+                               Content-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                              path, host, version(), body.len(), body);
+
+        let ret = get_host_addresses(host[]).and_then(|addrs| {
+            let ip = match addrs.into_iter().next() {
+                Some(ip) => ip,
+                None => { return Err(io::standard_error(io::ConnectionFailed)); }
+            };
+            let timeout = Duration::milliseconds(TIMEOUT as i64);
+            TcpStream::connect_timeout(SocketAddr { ip: ip, port: port }, timeout)
+        }).and_then(|mut stream| {
+            stream.set_timeout(Some(TIMEOUT));
+            try!(stream.write_str(request[]));
+            io::BufferedReader::new(stream).read_line()
+        });
+        let status = match ret {
+            Ok(status) => status,
+            Err(err) => { return Err(IoFailure(url.to_string(), err)); }
+        };
+
+        // the status line looks like `HTTP/1.1 200 OK`
+        match status[].words().nth(1).and_then(from_str::<uint>) {
+            Some(code) if 200 <= code && code < 300 => Ok(()),
+            _ => Err(IrFailure(format!("{}: IR responded with {}", url, status[].trim()))),
+        }
+    }
+
+    /// Same as `submit` but in a background task. The result is sent to the returned channel.
+    pub fn submit_in_background(url: String,
+                                submission: Submission) -> Receiver<AngolmoisResult<()>> {
+        let (tx, rx) = channel();
+        spawn(proc() {
+            let _ = tx.send_opt(submit(url[], &submission));
+        });
+        rx
+    }
+}
+
+//==================================================================================================
+// configuration file
+
+/**
+ * The configuration file at `~/.config/angolmois/config.toml`. It is a small subset of TOML:
+ * `[section]` headers, `key = value` lines and `#` comments, where a value is a basic
+ * (`"..."`) or literal (`'...'`) string, a number or a boolean. Arrays, tables inside values and
+ * multi-line strings are not supported.
+ *
+ * The `[options]` section gives default command line options: `key = value` is same as
+ * `--key value`, and a boolean `key = true` or `key = false` is same as `--key` or `--no-key`
+ * respectively. Options given in the command line are processed later, so they override
+ * the configuration. The `[keys]` section gives the input mapping, where the key is the name of
+ * the corresponding environment variable without `ANGOLMOIS_` (e.g. `1p_keys`); the environment
+ * variable is used if the key is missing.
+ */
+pub mod config {
+    use std::io;
+    use std::ascii::AsciiExt;
+    use std::io::fs::PathExtensions;
+    use {AngolmoisResult, IoFailure, InvalidConfig};
+
+    /// The name of the configuration file in `player::config_dir`.
+    pub static CONFIG_NAME: &'static str = "config.toml";
+
+    /// A value in the configuration file.
+    #[deriving(PartialEq,Clone,Show)]
+    pub enum Value {
+        /// A string, with escape sequences processed.
+        StrValue(String),
+        /// A number, kept as written so that it can be parsed as an integer later.
+        NumValue(String),
+        /// A boolean.
+        BoolValue(bool),
+    }
+
+    /// The parsed configuration file.
+    pub struct Config {
+        /// A list of the section (empty for the top level), the key and the value in order.
+        pub entries: Vec<(String, String, Value)>,
+    }
+
+    /// Parses a value after `=`. Returns `None` if the value is invalid.
+    fn parse_value(s: &str) -> Option<Value> {
+        let s = s.trim();
+        if s.starts_with("'") {
+            if s.len() < 2 || !s.ends_with("'") { return None; }
+            let inner = s[1..s.len()-1];
+            if inner.contains_char('\'') { return None; }
+            return Some(StrValue(inner.to_string()));
+        }
+        if s.starts_with("\"") {
+            let mut ret = String::new();
+            let mut chars = s[1..].chars();
+            loop {
+                match chars.next() {
+                    None => { return None; }
+                    Some('"') => { break; }
+                    Some('\\') => match chars.next() {
+                        Some('"') => { ret.push('"'); }
+                        Some('\\') => { ret.push('\\'); }
+                        Some('n') => { ret.push('\n'); }
+                        Some('t') => { ret.push('\t'); }
+                        _ => { return None; }
+                    },
+                    Some(c) => { ret.push(c); }
+                }
+            }
+            if !chars.collect::<String>()[].trim().is_empty() { return None; }
+            return Some(StrValue(ret));
+        }
+        match s {
+            "true" => Some(BoolValue(true)),
+            "false" => Some(BoolValue(false)),
+            _ => from_str::<f64>(s).map(|_| NumValue(s.to_string())),
+        }
+    }
+
+    /// Strips a comment outside of strings from the line.
+    fn strip_comment<'r>(line: &'r str) -> &'r str {
+        let mut quote = None;
+        let mut escaped = false;
+        for (i, c) in line.char_indices() {
+            match quote {
+                Some(q) => {
+                    if escaped { escaped = false; }
+                    else if c == '\\' && q == '"' { escaped = true; }
+                    else if c == q { quote = None; }
+                }
+                None if c == '#' => { return line[..i]; }
+                None if c == '"' || c == '\'' => { quote = Some(c); }
+                None => {}
+            }
+        }
+        line
+    }
+
+    impl Config {
+        /// Returns an empty configuration.
+        pub fn new() -> Config {
+            Config { entries: Vec::new() }
+        }
+
+        /// Parses the contents of the configuration file. `path` is only used for errors.
+        pub fn parse(path: &str, contents: &str) -> AngolmoisResult<Config> {
+            let mut entries = Vec::new();
+            let mut section = String::new();
+            for (lineno, line) in contents.lines().enumerate() {
+                let error = |msg: &str| {
+                    Err(InvalidConfig(format!("{}:{}: {}", path, lineno + 1, msg)))
+                };
+                let line = strip_comment(line).trim();
+                if line.is_empty() { continue; }
+                if line.starts_with("[") {
+                    if !line.ends_with("]") { return error("invalid section header"); }
+                    section = line[1..line.len()-1].trim().to_string();
+                    continue;
+                }
+                let (key, value) = match line.find('=') {
+                    Some(idx) => (line[..idx].trim(), line[idx+1..]),
+                    None => { return error("expected `key = value`"); }
+                };
+                let key = key.trim_chars('"');
+                if key.is_empty() { return error("empty key"); }
+                match parse_value(value) {
+                    Some(value) => { entries.push((section.clone(), key.to_string(), value)); }
+                    None => { return error("invalid value"); }
+                }
+            }
+            Ok(Config { entries: entries })
+        }
+
+        /// Returns the last value for given section and key if any.
+        pub fn get<'r>(&'r self, section: &str, key: &str) -> Option<&'r Value> {
+            self.entries.iter().rev().find(|&&(ref s, ref k, _)| s[] == section && k[] == key)
+                               .map(|&(_, _, ref value)| value)
+        }
+
+        /**
+         * Converts the `[options]` section to the command line arguments. `has_option` should
+         * return true if given long option (e.g. `--speed`) exists. Returns an error if some
+         * key does not correspond to any option.
+         */
+        pub fn to_args(&self, has_option: |&str| -> bool) -> AngolmoisResult<Vec<String>> {
+            let mut args = Vec::new();
+            for &(ref section, ref key, ref value) in self.entries.iter() {
+                if section[] != "options" { continue; }
+                let option = format!("--{}", key);
+                match *value {
+                    BoolValue(b) => {
+                        let option = if b {option} else {format!("--no-{}", key)};
+                        if !has_option(option[]) {
+                            return Err(InvalidConfig(format!("Invalid option in the configuration \
+                                                              file: {} = {}", key, b)));
+                        }
+                        args.push(option);
+                    }
+                    StrValue(ref s) | NumValue(ref s) => {
+                        if !has_option(option[]) {
+                            return Err(InvalidConfig(format!("Invalid option in the configuration \
+                                                              file: {}", key)));
+                        }
+                        args.push(option);
+                        args.push(s.clone());
+                    }
+                }
+            }
+            Ok(args)
+        }
+
+        /// Returns the input mapping for given environment variable from the `[keys]` section,
+        /// or the environment variable itself if the configuration doesn't have it.
+        pub fn getenv(&self, name: &str) -> Option<String> {
+            if name.starts_with("ANGOLMOIS_") {
+                let key = name["ANGOLMOIS_".len()..].to_ascii_lower();
+                let found = self.entries.iter().rev().find(|&&(ref s, ref k, _)| {
+                    s[] == "keys" && k[].to_ascii_lower().replace("-", "_") == key
+                });
+                match found {
+                    Some(&(_, _, StrValue(ref s))) => { return Some(s.clone()); }
+                    Some(&(_, _, ref value)) => {
+                        warn!("ignored non-string value for {} in the configuration file: {}",
+                              name, value);
+                    }
+                    None => {}
+                }
+            }
+            ::std::os::getenv(name)
+        }
+    }
+
+    /// Returns a path to the configuration file if the home directory is known.
+    pub fn config_path() -> Option<Path> {
+        ::player::config_dir().map(|dir| dir.join(CONFIG_NAME))
+    }
+
+    /// Reads the configuration file. A missing configuration file is same as an empty one.
+    pub fn read_config() -> AngolmoisResult<Config> {
+        let path = match config_path() {
+            Some(path) => path,
+            None => { return Ok(Config::new()); }
+        };
+        if !path.exists() { return Ok(Config::new()); }
+        let pathstr = path.display().to_string();
+        let contents = match io::File::open(&path).and_then(|mut f| f.read_to_string()) {
+            Ok(contents) => contents,
+            Err(err) => { return Err(IoFailure(pathstr, err)); }
+        };
+        Config::parse(pathstr[], contents[])
+    }
+}
+
+//==================================================================================================
+// graphics
+
+/// Graphic utilities.
+pub mod gfx {
+    use std;
+    use std::{io, num, cmp};
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use util::ttf;
+    use sdl::Rect;
+    use sdl::video;
+    use sdl::video::{Color, RGB, RGBA, Surface};
+
+    //----------------------------------------------------------------------------------------------
+    // `Rect` additions
+
+    /// A trait that can be translated to point coordinates (`x` and `y` fields in `sdl::Rect`,
+    /// hence the name). Also contains `()`.
+    pub trait XyOpt {
+        /// Returns point coordinates if any.
+        fn xy_opt(&self) -> Option<(i16,i16)>;
+    }
+
+    /// Same as `XyOpt` but does not contain `()`.
+    pub trait Xy: XyOpt {
+        /// Returns point coordinates.
+        fn xy(&self) -> (i16,i16);
+    }
+
+    /// A trait that can be translated to a rectangular area (`w` and `h` fields in `sdl::Rect`,
+    /// hence the name). Also contains `()`.
+    pub trait WhOpt {
+        /// Returns a rectangular area if any.
+        fn wh_opt(&self) -> Option<(u16,u16)>;
+    }
+
+    /// Same as `WhOpt` but does not contain `()`.
+    pub trait Wh {
+        /// Returns a rectangular area.
+        fn wh(&self) -> (u16,u16);
+    }
+
+    impl XyOpt for () {
+        #[inline(always)]
+        fn xy_opt(&self) -> Option<(i16,i16)> { None }
+    }
+
+    // Rust: we can't define these with `impl<T:Xy> XyOpt for T` due to the ambiguity.
+    impl XyOpt for Rect {
+        #[inline(always)]
+        fn xy_opt(&self) -> Option<(i16,i16)> { Some((self.x, self.y)) }
+    }
+
+    impl<'r,T:XyOpt> XyOpt for &'r T {
+        #[inline(always)]
+        fn xy_opt(&self) -> Option<(i16,i16)> { (*self).xy_opt() }
+    }
+
+    impl Xy for Rect {
+        #[inline(always)]
+        fn xy(&self) -> (i16,i16) { (self.x, self.y) }
+    }
+
+    impl<'r,T:Xy> Xy for &'r T {
+        #[inline(always)]
+        fn xy(&self) -> (i16,i16) { (*self).xy() }
+    }
+
+    impl WhOpt for () {
+        #[inline(always)]
+        fn wh_opt(&self) -> Option<(u16,u16)> { None }
+    }
+
+    impl WhOpt for Rect {
+        #[inline(always)]
+        fn wh_opt(&self) -> Option<(u16,u16)> { Some((self.w, self.h)) }
+    }
+
+    impl WhOpt for Surface {
+        #[inline(always)]
+        fn wh_opt(&self) -> Option<(u16,u16)> { Some(self.get_size()) }
+    }
+
+    impl<'r,T:WhOpt> WhOpt for &'r T {
+        #[inline(always)]
+        fn wh_opt(&self) -> Option<(u16,u16)> { (*self).wh_opt() }
+    }
+
+    impl Wh for Rect {
+        #[inline(always)]
+        fn wh(&self) -> (u16,u16) { (self.w, self.h) }
+    }
+
+    impl Wh for Surface {
+        #[inline(always)]
+        fn wh(&self) -> (u16,u16) { self.get_size() }
+    }
+
+    impl<'r,T:Wh> Wh for &'r T {
+        #[inline(always)]
+        fn wh(&self) -> (u16,u16) { (*self).wh() }
+    }
+
+    /// A helper trait for defining every implementations for types `(T1,T2)` where `T1` and `T2` is
+    /// convertible to an integer.
+    trait ToInt16 {
+        /// Converts to `i16`.
+        fn to_i16(&self) -> i16;
+        /// Converts to `u16`.
+        fn to_u16(&self) -> u16;
+    }
+
+    macro_rules! define_ToInt16(
+        ($t:ty) => (impl ToInt16 for $t {
+                        #[inline(always)]
+                        fn to_i16(&self) -> i16 { *self as i16 }
+                        #[inline(always)]
+                        fn to_u16(&self) -> u16 { *self as u16 }
+                    })
+    )
+
+    define_ToInt16!(int)
+    define_ToInt16!(uint)
+    define_ToInt16!(i8)
+    define_ToInt16!(i16)
+    define_ToInt16!(i32)
+    define_ToInt16!(i64)
+    define_ToInt16!(u8)
+    define_ToInt16!(u16)
+    define_ToInt16!(u32)
+    define_ToInt16!(u64)
+
+    impl<X:ToInt16+Clone,Y:ToInt16+Clone> XyOpt for (X,Y) {
+        #[inline(always)]
+        fn xy_opt(&self) -> Option<(i16,i16)> {
+            let (x, y) = self.clone();
+            Some((x.to_i16(), y.to_i16()))
+        }
+    }
+
+    impl<X:ToInt16+Clone,Y:ToInt16+Clone> Xy for (X,Y) {
+        #[inline(always)]
+        fn xy(&self) -> (i16,i16) {
+            let (x, y) = self.clone();
+            (x.to_i16(), y.to_i16())
+        }
+    }
+
+    impl<W:ToInt16+Clone,H:ToInt16+Clone> WhOpt for (W,H) {
+        #[inline(always)]
+        fn wh_opt(&self) -> Option<(u16,u16)> {
+            let (w, h) = self.clone();
+            Some((w.to_u16(), h.to_u16()))
+        }
+    }
+
+    impl<W:ToInt16+Clone,H:ToInt16+Clone> Wh for (W,H) {
+        #[inline(always)]
+        fn wh(&self) -> (u16,u16) {
+            let (w, h) = self.clone();
+            (w.to_u16(), h.to_u16())
+        }
+    }
+
+    /// Constructs an `sdl::Rect` from given point coordinates. Fills `w` and `h` fields to 0
+    /// as expected by the second `sdl::Rect` argument from `SDL_BlitSurface`.
+    #[inline(always)]
+    pub fn rect_from_xy<XY:Xy>(xy: XY) -> Rect {
+        let (x, y) = xy.xy();
+        Rect { x: x, y: y, w: 0, h: 0 }
+    }
+
+    /// Constructs an `sdl::Rect` from given point coordinates and optional rectangular area.
+    /// `rect_from_xywh(xy, ())` equals to `rect_from_xy(xy)`.
+    #[inline(always)]
+    pub fn rect_from_xywh<XY:Xy,WH:WhOpt>(xy: XY, wh: WH) -> Rect {
+        let (x, y) = xy.xy();
+        let (w, h) = wh.wh_opt().unwrap_or((0, 0));
+        Rect { x: x, y: y, w: w, h: h }
+    }
+
+    /// Additions to `sdl::video::Surface`. They replace their `_rect` suffixed counterparts,
+    /// which are generally annoying to work with.
+    pub trait SurfaceAreaUtil {
+        /// An alternative interface to `set_clip_rect`.
+        fn set_clip_area<XY:Xy,WH:WhOpt>(&self, xy: XY, wh: WH);
+        /// An alternative interface to `blit_rect`.
+        fn blit_area<SrcXY:Xy,DstXY:XyOpt,WH:WhOpt>(&self, src: &Surface,
+                                                    srcxy: SrcXY, dstxy: DstXY, wh: WH) -> bool;
+        /// An alternative interface to `fill_rect`.
+        fn fill_area<XY:Xy,WH:WhOpt>(&self, xy: XY, wh: WH, color: Color) -> bool;
+    }
+
+    impl SurfaceAreaUtil for Surface {
+        #[inline(always)]
+        fn set_clip_area<XY:Xy,WH:WhOpt>(&self, xy: XY, wh: WH) {
+            let rect = rect_from_xywh(xy, wh);
+            self.set_clip_rect(&rect)
+        }
+
+        #[inline(always)]
+        fn blit_area<SrcXY:Xy,DstXY:XyOpt,WH:WhOpt>(&self, src: &Surface,
+                                                    srcxy: SrcXY, dstxy: DstXY, wh: WH) -> bool {
+            let srcrect = rect_from_xywh(srcxy, wh);
+            let dstrect = dstxy.xy_opt().map(|xy| rect_from_xywh(xy, &srcrect));
+            self.blit_rect(src, Some(srcrect), dstrect)
+        }
+
+        #[inline(always)]
+        fn fill_area<XY:Xy,WH:WhOpt>(&self, xy: XY, wh: WH, color: Color) -> bool {
+            let rect = rect_from_xywh(xy, wh);
+            self.fill_rect(Some(rect), color)
+        }
+    }
+
+    /// A target of the sprite-based rendering in the game play. The software surface blits
+    /// surfaces as usual, while the OpenGL presenter keeps them as textures and draws them with
+    /// the GPU.
+    pub enum Canvas<'r> {
+        /// Renders to the software surface.
+        SoftwareCanvas(&'r Surface),
+        /// Renders through the OpenGL presenter. See `::util::gl`.
+        GLCanvas(&'r ::util::gl::Presenter),
+    }
+
+    impl<'r> Canvas<'r> {
+        /// Copies the area of `src` at `srcxy` to `dstxy` with given size.
+        pub fn blit_area(&self, src: &Surface, srcxy: (uint, uint), dstxy: (uint, uint),
+                         wh: (uint, uint)) {
+            match *self {
+                SoftwareCanvas(screen) => { screen.blit_area(src, srcxy, dstxy, wh); }
+                GLCanvas(presenter) => { presenter.blit_area(src, srcxy, dstxy, wh); }
+            }
+        }
+
+        /// Fills the area with given color. `RGBA` colors are blended as in
+        /// `SurfacePixels::put_blended_pixel`, and the area should be inside the screen.
+        pub fn fill_area(&self, (x, y): (uint, uint), (w, h): (uint, uint), color: Color) {
+            match *self {
+                SoftwareCanvas(screen) => match color {
+                    RGB(..) => { screen.fill_area((x, y), (w, h), color); }
+                    RGBA(..) => {
+                        screen.with_pixels(|pixels| {
+                            for j in range(y, y + h) {
+                                for i in range(x, x + w) {
+                                    pixels.put_blended_pixel(i, j, color);
+                                }
+                            }
+                        });
+                    }
+                },
+                GLCanvas(presenter) => { presenter.fill_area((x, y), (w, h), color); }
+            }
+        }
+
+        /// Notifies that the pixels of `src` have been changed since it was last blitted.
+        pub fn refresh(&self, src: &Surface) {
+            match *self {
+                SoftwareCanvas(_) => {}
+                GLCanvas(presenter) => { presenter.refresh(src); }
+            }
+        }
+
+        /// Restricts the further rendering to given area until `reset_clip_area` is called.
+        pub fn set_clip_area(&self, xy: (uint, uint), wh: (uint, uint)) {
+            match *self {
+                SoftwareCanvas(screen) => { screen.set_clip_area(xy, wh); }
+                GLCanvas(presenter) => { presenter.set_clip_area(xy, wh); }
+            }
+        }
+
+        /// Removes the restriction by `set_clip_area`.
+        pub fn reset_clip_area(&self) {
+            match *self {
+                SoftwareCanvas(screen) => { screen.set_clip_rect(&screen.get_rect()); }
+                GLCanvas(presenter) => { presenter.reset_clip_area(); }
+            }
+        }
+    }
+
+    //----------------------------------------------------------------------------------------------
+    // color
+
+    /// Extracts red, green, blue components from given color.
+    fn to_rgb(c: Color) -> (u8, u8, u8) {
+        match c { RGB(r, g, b) | RGBA(r, g, b, _) => (r, g, b) }
+    }
+
+    /// Linear color gradient.
+    #[deriving(PartialEq)]
+    pub struct Gradient {
+        /// A color at the position 0.0. Normally used as a topmost value.
+        pub zero: Color,
+        /// A color at the position 1.0. Normally used as a bottommost value.
+        pub one: Color
+    }
+
+    impl Gradient {
+        /// Creates a new color gradient (for text printing).
+        pub fn new(top: Color, bottom: Color) -> Gradient {
+            Gradient { zero: top, one: bottom }
+        }
+    }
+
+    /// A trait for color or color gradient. The color at the particular position can be calculated
+    /// with `blend` method.
+    pub trait Blend {
+        /// Returns itself. This is same as `Clone::clone` but redefined here due to the inability
+        /// of implementing `Clone` for `Color`.
+        fn clone(&self) -> Self;
+        /// Calculates the color at the position `num/denom`. (C: `blend`)
+        fn blend(&self, num: int, denom: int) -> Color;
+    }
+
+    impl Blend for Color {
+        fn clone(&self) -> Color { *self }
+        fn blend(&self, _num: int, _denom: int) -> Color { *self }
+    }
+
+    impl Blend for Gradient {
+        fn clone(&self) -> Gradient { *self }
+        fn blend(&self, num: int, denom: int) -> Color {
+            fn mix(x: u8, y: u8, num: int, denom: int) -> u8 {
+                let x = x as int;
+                let y = y as int;
+                (y + ((x - y) * num / denom)) as u8
+            }
+
+            let (r0, g0, b0) = to_rgb(self.zero);
+            let (r1, g1, b1) = to_rgb(self.one);
+            RGB(mix(r1, r0, num, denom), mix(g1, g0, num, denom), mix(b1, b0, num, denom))
+        }
+    }
+
+    /// A color or color gradient made translucent by given alpha value.
+    pub struct Translucent<ColorT> {
+        /// The original color or color gradient.
+        pub color: ColorT,
+        /// The alpha value, from 0 (transparent) to 255 (opaque).
+        pub alpha: u8
+    }
+
+    impl<ColorT:Blend> Blend for Translucent<ColorT> {
+        fn clone(&self) -> Translucent<ColorT> {
+            Translucent { color: self.color.clone(), alpha: self.alpha }
+        }
+        fn blend(&self, num: int, denom: int) -> Color {
+            // `RGBA` keeps more of the original pixel as the alpha increases, unlike `alpha`
+            let (r, g, b) = to_rgb(self.color.blend(num, denom));
+            RGBA(r, g, b, 255 - self.alpha)
+        }
+    }
+
+    //----------------------------------------------------------------------------------------------
+    // surface utilities
+
+    /// Creates a new RAM-backed surface. By design, Angolmois does not use a VRAM-backed surface
+    /// except for the screen. (C: `newsurface`)
+    pub fn new_surface(w: uint, h: uint) -> Result<Surface,String> {
+        Surface::new([video::SWSurface], w as int, h as int, 32, 0xff0000, 0xff00, 0xff, 0)
+    }
+
+    /// A proxy to `sdl::video::Surface` for the direct access to pixels. For now, it is for 32 bits
+    /// per pixel only.
+    pub struct SurfacePixels<'r> {
+        fmt: *mut video::ll::SDL_PixelFormat,
+        width: uint,
+        height: uint,
+        pitch: uint,
+        pixels: &'r mut [u32]
+    }
+
+    /// A trait for the direct access to pixels.
+    pub trait SurfacePixelsUtil {
+        /// Grants the direct access to pixels. Also locks the surface as needed, so you can't blit
+        /// during working with pixels.
+        fn with_pixels<R>(&self, f: |pixels: &mut SurfacePixels| -> R) -> R;
+    }
+
+    impl SurfacePixelsUtil for Surface {
+        fn with_pixels<R>(&self, f: |pixels: &mut SurfacePixels| -> R) -> R {
+            self.with_lock(|pixels| {
+                let fmt = unsafe {(*self.raw).format};
+                let pitch = unsafe {((*self.raw).pitch / 4) as uint};
+                let pixels = unsafe {std::mem::transmute(pixels)};
+                let mut proxy = SurfacePixels { fmt: fmt, width: self.get_width() as uint,
+                                                height: self.get_height() as uint,
+                                                pitch: pitch, pixels: pixels };
+                f(&mut proxy)
+            })
+        }
+    }
+
+    impl<'r> SurfacePixels<'r> {
+        /// Returns a pixel at given position. (C: `getpixel`)
+        pub fn get_pixel(&self, x: uint, y: uint) -> Color {
+            Color::from_mapped(self.pixels[x + y * self.pitch], self.fmt as *const _)
+        }
+
+        /// Sets a pixel to given position. (C: `putpixel`)
+        pub fn put_pixel(&mut self, x: uint, y: uint, c: Color) {
+            self.pixels[x + y * self.pitch] = c.to_mapped(self.fmt as *const _);
+        }
+
+        /// Sets or blends (if `c` is `RGBA`) a pixel to given position. (C: `putblendedpixel`)
+        pub fn put_blended_pixel(&mut self, x: uint, y: uint, c: Color) {
+            match c {
+                RGB(..) => self.put_pixel(x, y, c),
+                RGBA(r,g,b,a) => match self.get_pixel(x, y) {
+                    RGB(r2,g2,b2) | RGBA(r2,g2,b2,_) => {
+                        let grad = Gradient { zero: RGB(r,g,b), one: RGB(r2,g2,b2) };
+                        self.put_pixel(x, y, grad.blend(a as int, 255));
+                    }
+                }
+            }
+        }
+    }
+
+    /// A scaling factor for the calculation of convolution kernel.
+    const FP_SHIFT1: uint = 11;
+    /// A scaling factor for the summation of weighted pixels.
+    const FP_SHIFT2: uint = 16;
+
+    /// Returns `2^FP_SHIFT * W(x/y)` where `W(x)` is a bicubic kernel function. `y` should be
+    /// positive. (C: `bicubic_kernel`)
+    fn bicubic_kernel(x: int, y: int) -> int {
+        let x = num::abs(x);
+        if x < y {
+            // W(x/y) = 1/2 (2 - 5(x/y)^2 + 3(x/y)^3)
+            ((2*y*y - 5*x*x + 3*x*x/y*x) << (FP_SHIFT1-1)) / (y*y)
+        } else if x < y * 2 {
+            // W(x/y) = 1/2 (4 - 8(x/y) + 5(x/y)^2 - (x/y)^3)
+            ((4*y*y - 8*x*y + 5*x*x - x*x/y*x) << (FP_SHIFT1-1)) / (y*y)
+        } else {
+            0
+        }
+    }
+
+    /**
+     * Performs the bicubic interpolation. `dest` should be initialized to the target dimension
+     * before calling this function. This function should be used only for the upscaling; it can do
+     * the downscaling somehow but technically its result is incorrect. (C: `bicubic_interpolation`)
+     *
+     * Well, this function is one of the ugliest functions in Angolmois, especially since it is
+     * a complicated (in terms of code complexity) and still poor (we normally use the matrix form
+     * instead) implementation of the algorithm. In fact, the original version of `bicubic_kernel`
+     * had even a slightly incorrect curve (`1/2 - x^2 + 1/2 x^3` instead of `1 - 5/2 x^2 +
+     * 3/2 x^3`). This function still remains here only because we don't use OpenGL...
+     */
+    pub fn bicubic_interpolation(src: &SurfacePixels, dest: &mut SurfacePixels) {
+        let w = dest.width as int - 1;
+        let h = dest.height as int - 1;
+        let ww = src.width as int - 1;
+        let hh = src.height as int - 1;
+
+        let mut dx = 0;
+        let mut x = 0;
+        for i in range(0, w + 1) {
+            let mut dy = 0;
+            let mut y = 0;
+            for j in range(0, h + 1) {
+                let mut r = 0;
+                let mut g = 0;
+                let mut b = 0;
+                let a0 = [bicubic_kernel((x-1) * w - i * ww, w),
+                          bicubic_kernel( x    * w - i * ww, w),
+                          bicubic_kernel((x+1) * w - i * ww, w),
+                          bicubic_kernel((x+2) * w - i * ww, w)];
+                let a1 = [bicubic_kernel((y-1) * h - j * hh, h),
+                          bicubic_kernel( y    * h - j * hh, h),
+                          bicubic_kernel((y+1) * h - j * hh, h),
+                          bicubic_kernel((y+2) * h - j * hh, h)];
+                for k0 in range(0u, 4) {
+                    for k1 in range(0u, 4) {
+                        let xx = x + k0 as int - 1;
+                        let yy = y + k1 as int - 1;
+                        if 0 <= xx && xx <= ww && 0 <= yy && yy <= hh {
+                            let (r2,g2,b2) = to_rgb(src.get_pixel(xx as uint, yy as uint));
+                            let d = (a0[k0] * a1[k1]) >> (FP_SHIFT1*2 - FP_SHIFT2);
+                            r += r2 as int * d;
+                            g += g2 as int * d;
+                            b += b2 as int * d;
+                        }
+                    }
+                }
+
+                let r = cmp::min(cmp::max(r >> FP_SHIFT2, 0), 255) as u8;
+                let g = cmp::min(cmp::max(g >> FP_SHIFT2, 0), 255) as u8;
+                let b = cmp::min(cmp::max(b >> FP_SHIFT2, 0), 255) as u8;
+                dest.put_pixel(i as uint, j as uint, RGB(r, g, b));
+
+                dy += hh;
+                if dy > h {
+                    y += 1;
+                    dy -= h;
+                }
+            }
+
+            dx += ww;
+            if dx > w {
+                x += 1;
+                dx -= w;
+            }
+        }
+    }
+
+    //----------------------------------------------------------------------------------------------
+    // image encoding
+
+    /// Calculates the CRC-32 checksum of given data, as used in PNG chunks.
+    fn crc32(data: &[u8]) -> u32 {
+        let mut crc = 0xffffffffu32;
+        for &b in data.iter() {
+            crc ^= b as u32;
+            for _ in range(0u, 8) {
+                crc = if crc & 1 != 0 {(crc >> 1) ^ 0xedb88320} else {crc >> 1};
+            }
+        }
+        !crc
+    }
+
+    /// Writes pixels to given writer as a 24-bit PNG image. The image is not filtered, which is
+    /// fine for occasional screenshots.
+    pub fn write_png(f: &mut Writer, pixels: &SurfacePixels) -> io::IoResult<()> {
+        fn write_chunk(f: &mut Writer, kind: &[u8], data: &[u8]) -> io::IoResult<()> {
+            let mut chunk = kind.to_vec();
+            chunk.push_all(data);
+            try!(f.write_be_u32(data.len() as u32));
+            try!(f.write(chunk[]));
+            f.write_be_u32(crc32(chunk[]))
+        }
+
+        let mut header = io::MemWriter::new();
+        try!(header.write_be_u32(pixels.width as u32));
+        try!(header.write_be_u32(pixels.height as u32));
+        try!(header.write([8u8, 2, 0, 0, 0][])); // 8 bits per channel, RGB, not interlaced
+
+        let mut raw = Vec::with_capacity((pixels.width * 3 + 1) * pixels.height);
+        for y in range(0, pixels.height) {
+            raw.push(0u8); // no filter
+            for x in range(0, pixels.width) {
+                let (r, g, b) = to_rgb(pixels.get_pixel(x, y));
+                raw.push(r);
+                raw.push(g);
+                raw.push(b);
+            }
+        }
+        let data = match ::flate::deflate_bytes_zlib(raw[]) {
+            Some(data) => data,
+            None => { return Err(io::standard_error(io::OtherIoError)); }
+        };
+
+        try!(f.write(b"\x89PNG\r\n\x1a\n"));
+        try!(write_chunk(f, b"IHDR", header.get_ref()));
+        try!(write_chunk(f, b"IDAT", data.as_slice()));
+        write_chunk(f, b"IEND", &[])
+    }
+
+    //----------------------------------------------------------------------------------------------
+    // bitmap font
+
+    /// Bit vector which represents one row of zoomed font.
+    type ZoomedFontRow = u32;
+
+    /// 8x16 resizable bitmap font.
+    pub struct Font {
+        /**
+         * Font data used for zoomed font reconstruction. This is actually an array of `u32`
+         * elements, where the first `u16` element forms upper 16 bits and the second forms lower
+         * 16 bits. It is reinterpreted for better compression. (C: `fontdata`)
+         *
+         * One glyph has 16 `u32` elements for each row from the top to the bottom. One `u32`
+         * element contains eight four-bit groups for each column from the left (lowermost group)
+         * to the right (uppermost group). Each group is a bitwise OR of following bits:
+         *
+         * - 1: the lower right triangle of the zoomed pixel should be drawn.
+         * - 2: the lower left triangle of the zoomed pixel should be drawn.
+         * - 4: the upper left triangle of the zoomed pixel should be drawn.
+         * - 8: the upper right triangle of the zoomed pixel should be drawn.
+         *
+         * So for example, if the group bits read 3 (1+2), the zoomed pixel would be drawn
+         * as follows (in the zoom factor 5):
+         *
+         *     .....
+         *     #...#
+         *     ##.##
+         *     #####
+         *     #####
+         *
+         * The group bits 15 (1+2+4+8) always draw the whole square, so in the zoom factor 1 only
+         * pixels with group bits 15 will be drawn.
+         */
+        glyphs: Vec<u16>,
+
+        /// Precalculated zoomed font per zoom factor. It is three-dimensional array which indices
+        /// are zoom factor, glyph number and row respectively. Assumes that each element has
+        /// at least zoom factor times 8 (columns per row) bits. (C: `zoomfont`)
+        pixels: Vec<Vec<Vec<ZoomedFontRow>>>,
+
+        /// A path to the TrueType font used for non-ASCII strings, if any.
+        ttfpath: Option<Path>,
+        /// TrueType fonts opened per zoom factor. `None` means that the font failed to open.
+        ttffonts: RefCell<HashMap<uint,Option<ttf::Font>>>,
+        /// Alpha masks of non-ASCII strings rendered so far, per string and zoom factor.
+        ttfcache: RefCell<HashMap<(String,uint),ttf::TextMask>>,
+    }
+
+    /// TrueType fonts covering Japanese and Korean (as far as possible), tried in order when
+    /// `ANGOLMOIS_FONT` is not set.
+    #[cfg(target_os = "windows")]
+    static UNICODE_FONTS: &'static [&'static str] = &[
+        r"C:\Windows\Fonts\arialuni.ttf", r"C:\Windows\Fonts\msgothic.ttc",
+        r"C:\Windows\Fonts\malgun.ttf", r"C:\Windows\Fonts\gulim.ttc"];
+
+    /// TrueType fonts covering Japanese and Korean (as far as possible), tried in order when
+    /// `ANGOLMOIS_FONT` is not set.
+    #[cfg(target_os = "macos")]
+    static UNICODE_FONTS: &'static [&'static str] = &[
+        "/Library/Fonts/Arial Unicode.ttf", "/System/Library/Fonts/AppleSDGothicNeo.ttc",
+        "/System/Library/Fonts/Hiragino Sans GB.ttc"];
+
+    /// TrueType fonts covering Japanese and Korean (as far as possible), tried in order when
+    /// `ANGOLMOIS_FONT` is not set.
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    static UNICODE_FONTS: &'static [&'static str] = &[
+        "/usr/share/fonts/opentype/noto/NotoSansCJK-Regular.ttc",
+        "/usr/share/fonts/noto-cjk/NotoSansCJK-Regular.ttc",
+        "/usr/share/fonts/google-noto-cjk/NotoSansCJK-Regular.ttc",
+        "/usr/share/fonts/truetype/droid/DroidSansFallbackFull.ttf",
+        "/usr/share/fonts/truetype/unfonts-core/UnDotum.ttf"];
+
+    /// Returns a path to the TrueType font for non-ASCII strings, either from `ANGOLMOIS_FONT`
+    /// or from the list of well-known system fonts. Always `None` without the `ttf` feature.
+    fn find_unicode_font() -> Option<Path> {
+        if !cfg!(feature = "ttf") { return None; }
+        match std::os::getenv("ANGOLMOIS_FONT") {
+            Some(path) => Some(Path::new(path)),
+            None => UNICODE_FONTS.iter().map(|&path| Path::new(path)).find(|path| path.exists())
+        }
+    }
+
+    /// An alignment mode of `Font::print_string`.
+    pub enum Alignment {
+        /// Coordinates specify the top-left corner of the bounding box.
+        LeftAligned,
+        /// Coordinates specify the top-center point of the bounding box.
+        Centered,
+        /// Coordinates specify the top-right corner of the bounding box.
+        RightAligned
+    }
+
+    // Delta-coded code words. (C: `words`)
+    static FONT_DWORDS: &'static [u16] = &[
+        0, 2, 6, 2, 5, 32, 96, 97, 15, 497, 15, 1521, 15, 1537,
+        16, 48, 176, 1, 3, 1, 3, 7, 1, 4080, 4096, 3, 1, 8, 3, 4097, 4080,
+        16, 16128, 240, 1, 2, 9, 3, 8177, 15, 16385, 240, 15, 1, 47, 721,
+        143, 2673, 2, 6, 7, 1, 31, 17, 16, 63, 64, 33, 0, 1, 2, 1, 8, 3];
+
+    // LZ77-compressed indices to code words:
+    // - Byte 33..97 encodes a literal code word 0..64;
+    // - Byte 98..126 encodes an LZ77 length distance pair with length 3..31;
+    //   the following byte 33..126 encodes a distance 1..94.
+    // (C: `indices`)
+    static FONT_INDICES: &'static [u8] =
+        b"!!7a/&/&s$7a!f!'M*Q*Qc$(O&J!!&J&Jc(e!2Q2Qc$-Bg2m!2bB[Q7Q2[e&2Q!Qi>&!&!>UT2T2&2>WT!c*\
+          T2GWc8icM2U2D!.8(M$UQCQ-jab!'U*2*2*2TXbZ252>9ZWk@*!*!*8(J$JlWi@cxQ!Q!d$#Q'O*?k@e2dfe\
+          jcNl!&JTLTLG_&J>]c*&Jm@cB&J&J7[e(o>pJM$Qs<7[{Zj`Jm40!3!.8(M$U!C!-oR>UQ2U2]2a9Y[S[QCQ\
+          2GWk@*M*Q*B*!*!g$aQs`G8.M(U$[!Ca[o@Q2Q!IJQ!Q!c,GWk@787M6U2C2d!a[2!2k?!bnc32>[u`>Uc4d\
+          @b(q@abXU!D!.8(J&J&d$q`Q2IXu`g@Q2aWQ!q@!!ktk,x@M$Qk@3!.8(M$U!H#W'O,?4m_f!7[i&n!:eX5g\
+          hCk=>UQ2Q2U2Dc>J!!&J&b&k@J)LKg!GK!)7Wk@'8,M=UWCcfa[c&Q2l`f4If(Q2G[l@MSUQC!2!2c$Q:RWG\
+          Ok@,[<2WfZQ2U2D2.l`a[eZ7f(!2b2|@b$j!>MSUQCc6[2W2Q:RWGOk@Q2Q2c$a[g*Ql`7[&J&Jk$7[l`!Qi\
+          $d^GWk@U2D2.9([$[#['[,@<2W2k@!2!2m$a[l`:^[a[a[T2Td~c$k@d2:R[V[a@_b|o@,M=UWCgZU:EW.Ok\
+          @>[g<G[!2!2d$k@Ug@Q2V2a2IW_!Wt`Ih*q`!2>WQ!Q!c,Gk_!7[&J&Jm$k@gti$m`k:U:EW.O(?s@T2Tb$a\
+          [CW2Qk@M+U:^[GbX,M>U`[WCO-l@'U,D<.W(O&J&Je$k@a[Q!U!]!G8.M(U$[!Ca[k@*Q!Q!l$b2m!+!:#W'\
+          O,?4!1n;c`*!*!l$h`'8,M=UWCO-pWz!a[i,#Q'O,?4~R>QQ!Q!aUQ2Q2Q2aWl=2!2!2>[e<c$G[p`dZcHd@\
+          l`czi|c$al@i`b:[!2Un`>8TJTJ&J7[&b&e$o`i~aWQ!c(hd2!2!2>[g@e$k]epi|e0i!bph(d$dbGWhA2!2\
+          U2D2.9(['[,@<2W2k`*J*?*!*!k$o!;[a[T2T2c$c~o@>[c6i$p@Uk>GW}`G[!2!2b$h!al`aWQ!Q!Qp`fVl\
+          Zf@UWb6>eX:GWk<&J&J7[c&&JTJTb$G?o`c~i$m`k@U:EW.O(v`T2Tb$a[Fp`M+eZ,M=UWCO-u`Q:RWGO.A(\
+          M$U!Ck@a[]!G8.M(U$[!Ca[i:78&J&Jc$%[g*7?e<g0w$cD#iVAg*$[g~dB]NaaPGft~!f!7[.W(O";
+
+    impl Font {
+        /// Decompresses a bitmap font data.
+        /// `Font::create_zoomed_font` is required for the actual use.
+        pub fn new() -> Font {
+            /// Decompresses a font data from `dwords` and `indices`. (C: `fontdecompress`)
+            fn decompress(dwords: &[u16], indices: &[u8]) -> Vec<u16> {
+                let mut words = vec!(0);
+                for &delta in dwords.iter() {
+                    let last = *words.last().unwrap();
+                    words.push(last + delta);
+                }
+
+                let nindices = indices.len();
+                let mut i = 0;
+                let mut glyphs = Vec::new();
+                while i < nindices {
+                    let code = indices[i] as uint;
+                    i += 1;
+                    match code {
+                        33...97 => { glyphs.push(words[code - 33]); }
+                        98...126 => {
+                            let length = code - 95; // code=98 -> length=3
+                            let distance = indices[i] as uint - 32;
+                            i += 1;
+                            let start = glyphs.len() - distance;
+                            for i in range(start, start + length) {
+                                let v = glyphs[i];
+                                glyphs.push(v);
+                            }
+                        }
+                        _ => panic!("unexpected codeword")
+                    }
+                }
+                glyphs
+            }
+
+            let glyphs = decompress(FONT_DWORDS, FONT_INDICES);
+            assert!(glyphs.len() == 3072);
+            Font { glyphs: glyphs, pixels: Vec::new(), ttfpath: find_unicode_font(),
+                   ttffonts: RefCell::new(HashMap::new()), ttfcache: RefCell::new(HashMap::new()) }
+        }
+
+        /// Creates a zoomed font of scale `zoom`. (C: `fontprocess`)
+        pub fn create_zoomed_font(&mut self, zoom: uint) {
+            assert!(zoom > 0);
+            assert!(zoom <= (8 * std::mem::size_of::<ZoomedFontRow>()) / 8);
+            if zoom < self.pixels.len() && !self.pixels[zoom].is_empty() { return; }
+
+            let nrows = 16;
+            let nglyphs = self.glyphs.len() / nrows / 2;
+            let mut pixels = Vec::from_elem(nglyphs, Vec::from_elem(zoom*nrows, 0u32));
+
+            let put_zoomed_pixel = |pixels: &mut Vec<Vec<ZoomedFontRow>>,
+                                    glyph: uint, row: uint, col: uint, v: u32| {
+                let zoomrow = row * zoom;
+                let zoomcol = col * zoom;
+                for r in range(0, zoom) {
+                    for c in range(0, zoom) {
+                        let mut mask = 0;
+                        if r + c >= zoom    { mask |= 1; } // lower right
+                        if r > c            { mask |= 2; } // lower left
+                        if r < c            { mask |= 4; } // upper right
+                        if r + c < zoom - 1 { mask |= 8; } // upper left
+
+                        // if `zoom` is odd, drawing four corner triangles leaves one center pixel
+                        // intact since we don't draw diagonals for aesthetic reason. such case
+                        // must be specially handled.
+                        if (v & mask) != 0 || v == 15 {
+                            pixels[mut][glyph][mut][zoomrow+r] |= 1 << (zoomcol+c);
+                        }
+                    }
+                }
+            };
+
+            let mut i = 0;
+            for glyph in range(0, nglyphs) {
+                for row in range(0, nrows) {
+                    let data = (self.glyphs[i] as u32 << 16) | (self.glyphs[i+1] as u32);
+                    i += 2;
+                    for col in range(0, 8u) {
+                        let v = (data >> (4 * col)) & 15;
+                        put_zoomed_pixel(&mut pixels, glyph, row, col, v);
+                    }
+                }
+            }
+            if self.pixels.len() <= zoom {
+                let ncopies = zoom - self.pixels.len() + 1;
+                self.pixels.grow(ncopies, Vec::new());
+            }
+            self.pixels[mut][zoom] = pixels;
+        }
+
+        /// Prints a glyph with given position and color (possibly gradient or translucent). This
+        /// method is distinct from `print_glyph` since the glyph #95 is used for the tick marker
+        /// (character code -1 in C). (C: `printchar`)
+        pub fn print_glyph<ColorT:Blend>(&self, pixels: &mut SurfacePixels, x: uint, y: uint,
+                                         zoom: uint, glyph: uint, color: ColorT) {
+            assert!(!self.pixels[zoom].is_empty());
+            for iy in range(0, 16 * zoom) {
+                let row = self.pixels[zoom][glyph][iy];
+                let rowcolor = color.blend(iy as int, 16 * zoom as int);
+                for ix in range(0, 8 * zoom) {
+                    if ((row >> ix) & 1) != 0 {
+                        pixels.put_blended_pixel(x + ix, y + iy, rowcolor);
+                    }
+                }
+            }
+        }
+
+        /// Prints a character with given position and color.
+        pub fn print_char<ColorT:Blend>(&self, pixels: &mut SurfacePixels, x: uint, y: uint,
+                                        zoom: uint, c: char, color: ColorT) {
+            if !c.is_whitespace() {
+                let c = c as uint;
+                let glyph = if 32 <= c && c < 126 {c-32} else {0};
+                self.print_glyph(pixels, x, y, zoom, glyph, color);
+            }
+        }
+
+        /// Renders a string with the TrueType font at given zoom factor, or returns `None` if
+        /// the font is unavailable. The alpha mask is cached for later uses.
+        fn render_ttf_string(&self, s: &str, zoom: uint) -> Option<()> {
+            let key = (s.to_string(), zoom);
+            if self.ttfcache.borrow().contains_key(&key) { return Some(()); }
+
+            let path = match self.ttfpath { Some(ref path) => path, None => { return None; } };
+            let mut fonts = self.ttffonts.borrow_mut();
+            if !fonts.contains_key(&zoom) {
+                // the point size is chosen so that most glyphs fit in 16 pixels per zoom
+                let font = match ttf::Font::open(path, 13 * zoom) {
+                    Ok(font) => Some(font),
+                    Err(err) => {
+                        warn!("failed to open the font {}: {}", path.display(), err);
+                        None
+                    }
+                };
+                fonts.insert(zoom, font);
+            }
+            let mask = match fonts.find(&zoom).unwrap().as_ref().and_then(|font| font.render(s)) {
+                Some(mask) => mask,
+                None => { return None; }
+            };
+            self.ttfcache.borrow_mut().insert(key, mask);
+            Some(())
+        }
+
+        /// Prints a string with the TrueType font, vertically centered to the line of
+        /// the bitmap font. Returns false if the font is unavailable.
+        fn print_ttf_string<ColorT:Blend>(&self, pixels: &mut SurfacePixels, x: uint, y: uint,
+                                          zoom: uint, align: Alignment, s: &str,
+                                          color: ColorT) -> bool {
+            if self.render_ttf_string(s, zoom).is_none() { return false; }
+            let cache = self.ttfcache.borrow();
+            let &(w, h, ref mask) = cache.find(&(s.to_string(), zoom)).unwrap();
+
+            let x = match align {
+                LeftAligned  => x,
+                Centered     => if x > w / 2 {x - w / 2} else {0},
+                RightAligned => if x > w {x - w} else {0},
+            };
+            let y = if h < 16 * zoom {y + (16 * zoom - h) / 2} else {y};
+            for iy in range(0, cmp::min(h, pixels.height - cmp::min(y, pixels.height))) {
+                // `put_blended_pixel` keeps the original pixel as the alpha approaches 255
+                let (r, g, b, opacity) = match color.blend(iy as int, h as int) {
+                    RGB(r, g, b) => (r, g, b, 255),
+                    RGBA(r, g, b, a) => (r, g, b, 255 - a as uint),
+                };
+                for ix in range(0, cmp::min(w, pixels.width - cmp::min(x, pixels.width))) {
+                    let coverage = (mask[ix + iy * w] as uint * opacity / 255) as u8;
+                    if coverage > 0 {
+                        pixels.put_blended_pixel(x + ix, y + iy, RGBA(r, g, b, 255 - coverage));
+                    }
+                }
+            }
+            true
+        }
+
+        /// Prints a string with given position, alignment and color. Non-ASCII strings are
+        /// printed with the TrueType font if available. (C: `printstr`)
+        pub fn print_string<ColorT:Blend>(&self, pixels: &mut SurfacePixels, x: uint, y: uint,
+                                          zoom: uint, align: Alignment, s: &str, color: ColorT) {
+            if s.chars().any(|c| c as uint >= 0x80) &&
+               self.print_ttf_string(pixels, x, y, zoom, align, s, color.clone()) {
+                return;
+            }
+
+            let mut x = match align {
+                LeftAligned  => x,
+                Centered     => x - s.char_len() * (8 * zoom) / 2,
+                RightAligned => x - s.char_len() * (8 * zoom),
+            };
+            for c in s.chars() {
+                let nextx = x + 8 * zoom;
+                if nextx >= pixels.width { break; }
+                self.print_char(pixels, x, y, zoom, c, color.clone());
+                x = nextx;
+            }
+        }
+    }
+
+    //----------------------------------------------------------------------------------------------
+
+}
+
+//==================================================================================================
+// animated images
+
+/**
+ * Decoders for animated GIF and APNG images, which SDL_image can only read the first frame of.
+ * Every frame is composed to the full canvas in advance, so the caller only has to show frames
+ * in turn.
+ *
+ * Only the 8-bit APNG images without the interlacing are decoded; others are left to SDL_image
+ * as static images.
+ */
+pub mod anim {
+    use std::cmp;
+    use std::iter::range_step;
+    use std::io::BufReader;
+
+    /// A frame of the animated image.
+    pub struct Frame {
+        /// The full canvas in the `0xAARRGGBB` format, row by row.
+        pub pixels: Vec<u32>,
+        /// The duration of the frame in milliseconds.
+        pub delay: uint,
+    }
+
+    /// An animated image.
+    pub struct Animation {
+        /// The width of the canvas.
+        pub width: uint,
+        /// The height of the canvas.
+        pub height: uint,
+        /// The list of frames. There is at least one frame.
+        pub frames: Vec<Frame>,
+    }
+
+    impl Animation {
+        /// Returns true if any frame has non-opaque pixels.
+        pub fn has_alpha(&self) -> bool {
+            self.frames.iter().any(|frame| frame.pixels.iter().any(|&c| c >> 24 != 0xff))
+        }
+    }
+
+    /// The minimal delay of frames in milliseconds. Many images with a zero delay are meant to be
+    /// shown with a default delay, as browsers do.
+    const MIN_DELAY: uint = 20;
+    /// The delay used in place of too short delays.
+    const DEFAULT_DELAY: uint = 100;
+
+    /// Returns the delay in milliseconds, replacing too short delays.
+    fn normalize_delay(delay: uint) -> uint {
+        if delay < MIN_DELAY {DEFAULT_DELAY} else {delay}
+    }
+
+    /// Fills the rectangular area of the canvas with given color.
+    fn fill_rect(canvas: &mut [u32], width: uint, (x, y, w, h): (uint, uint, uint, uint),
+                 color: u32) {
+        for row in range(y, y + h) {
+            for col in range(x, x + w) { canvas[row * width + col] = color; }
+        }
+    }
+
+    /// Composes the source pixel over the destination pixel, both in the `0xAARRGGBB` format.
+    fn blend_over(dst: u32, src: u32) -> u32 {
+        let sa = src >> 24;
+        if sa == 0xff { return src; }
+        if sa == 0 { return dst; }
+        let da = dst >> 24;
+        let oa = sa + da * (255 - sa) / 255;
+        if oa == 0 { return 0; }
+        let mix = |shift: uint| {
+            let s = (src >> shift) & 0xff;
+            let d = (dst >> shift) & 0xff;
+            ((s * sa + d * da * (255 - sa) / 255) / oa) << shift
+        };
+        (oa << 24) | mix(16) | mix(8) | mix(0)
+    }
+
+    //----------------------------------------------------------------------------------------------
+    // GIF
+
+    /// Decodes the LZW-compressed GIF image data into color indices.
+    fn decode_lzw(data: &[u8], mincodesize: uint, npixels: uint) -> Result<Vec<u8>,String> {
+        if mincodesize < 2 || mincodesize > 8 { return Err(format!("invalid LZW code size")); }
+        let clear = 1u << mincodesize;
+        let end = clear + 1;
+
+        let mut prefix = Vec::from_elem(4096, 0u16);
+        let mut suffix = Vec::from_elem(4096, 0u8);
+        let mut length = Vec::from_elem(4096, 0u16);
+        for i in range(0, clear) {
+            suffix[mut][i] = i as u8;
+            length[mut][i] = 1;
+        }
+
+        // writes the string for `code` to the end of `out`
+        fn emit(out: &mut Vec<u8>, prefix: &[u16], suffix: &[u8], length: &[u16], code: uint) {
+            let len = length[code] as uint;
+            let start = out.len();
+            out.grow(len, 0);
+            let mut code = code;
+            for i in range(0, len).rev() {
+                out[mut][start + i] = suffix[code];
+                code = prefix[code] as uint;
+            }
+        }
+
+        let mut out = Vec::with_capacity(npixels);
+        let mut codesize = mincodesize + 1;
+        let mut next = end + 1;
+        let mut prev: Option<uint> = None;
+        let mut acc = 0u32;
+        let mut nbits = 0u;
+        for &b in data.iter() {
+            acc |= (b as u32) << nbits;
+            nbits += 8;
+            while nbits >= codesize {
+                let code = (acc & ((1 << codesize) - 1)) as uint;
+                acc >>= codesize;
+                nbits -= codesize;
+
+                if code == clear {
+                    codesize = mincodesize + 1;
+                    next = end + 1;
+                    prev = None;
+                    continue;
+                }
+                if code == end { return Ok(out); }
+
+                match prev {
+                    None => {
+                        if code >= clear { return Err(format!("invalid LZW code")); }
+                        emit(&mut out, prefix[], suffix[], length[], code);
+                    }
+                    Some(prev) => {
+                        if code > next { return Err(format!("invalid LZW code")); }
+                        let start = out.len();
+                        if code < next {
+                            emit(&mut out, prefix[], suffix[], length[], code);
+                        } else {
+                            // the code being defined: the previous string and its first byte
+                            emit(&mut out, prefix[], suffix[], length[], prev);
+                            let first = out[start];
+                            out.push(first);
+                        }
+                        if next < 4096 {
+                            prefix[mut][next] = prev as u16;
+                            suffix[mut][next] = out[start];
+                            length[mut][next] = length[prev] + 1;
+                            next += 1;
+                            if next == 1 << codesize && codesize < 12 { codesize += 1; }
+                        }
+                    }
+                }
+                prev = Some(code);
+                if out.len() >= npixels { return Ok(out); }
+            }
+        }
+        Ok(out)
+    }
+
+    /// Reads data sub-blocks up to the block terminator, concatenated.
+    fn read_sub_blocks(r: &mut BufReader) -> Result<Vec<u8>,String> {
+        let mut data = Vec::new();
+        loop {
+            let size = try!(r.read_u8().map_err(|err| err.to_string())) as uint;
+            if size == 0 { return Ok(data); }
+            data.push_all(try!(r.read_exact(size).map_err(|err| err.to_string()))[]);
+        }
+    }
+
+    /// Reads the color table with given number of entries.
+    fn read_color_table(r: &mut BufReader, ncolors: uint) -> Result<Vec<u32>,String> {
+        let table = try!(r.read_exact(ncolors * 3).map_err(|err| err.to_string()));
+        Ok(Vec::from_fn(ncolors, |i| {
+            0xff000000 | (table[i*3] as u32 << 16) | (table[i*3+1] as u32 << 8) |
+                table[i*3+2] as u32
+        }))
+    }
+
+    /// Decodes the GIF image. Returns `None` if the image has only one frame.
+    pub fn decode_gif(data: &[u8]) -> Result<Option<Animation>,String> {
+        macro_rules! read(
+            ($e:expr) => (try!($e.map_err(|err| err.to_string())))
+        )
+
+        if data.len() < 13 || (data[..6] != b"GIF87a" && data[..6] != b"GIF89a") {
+            return Err(format!("not a GIF image"));
+        }
+        let mut r = BufReader::new(data[6..]);
+        let width = read!(r.read_le_u16()) as uint;
+        let height = read!(r.read_le_u16()) as uint;
+        let flags = read!(r.read_u8());
+        let _bgindex = read!(r.read_u8());
+        let _aspect = read!(r.read_u8());
+        let global = if flags & 0x80 != 0 {
+            Some(try!(read_color_table(&mut r, 2 << (flags & 7) as uint)))
+        } else {
+            None
+        };
+
+        let mut canvas = Vec::from_elem(width * height, 0u32);
+        let mut frames = Vec::new();
+        let mut delay = 0u;
+        let mut disposal = 0u8;
+        let mut transparent = None;
+        loop {
+            match read!(r.read_u8()) {
+                0x21 => { // extension
+                    let label = read!(r.read_u8());
+                    let block = try!(read_sub_blocks(&mut r));
+                    if label == 0xf9 && block.len() >= 4 { // graphic control extension
+                        disposal = (block[0] >> 2) & 7;
+                        delay = (block[1] as uint | (block[2] as uint << 8)) * 10;
+                        transparent = if block[0] & 1 != 0 {Some(block[3])} else {None};
+                    }
+                }
+                0x2c => { // image descriptor
+                    let x = read!(r.read_le_u16()) as uint;
+                    let y = read!(r.read_le_u16()) as uint;
+                    let w = read!(r.read_le_u16()) as uint;
+                    let h = read!(r.read_le_u16()) as uint;
+                    let flags = read!(r.read_u8());
+                    let local = if flags & 0x80 != 0 {
+                        Some(try!(read_color_table(&mut r, 2 << (flags & 7) as uint)))
+                    } else {
+                        None
+                    };
+                    let interlaced = flags & 0x40 != 0;
+                    let mincodesize = read!(r.read_u8()) as uint;
+                    let lzw = try!(read_sub_blocks(&mut r));
+                    let indices = try!(decode_lzw(lzw[], mincodesize, w * h));
+
+                    let table = match (local.as_ref(), global.as_ref()) {
+                        (Some(table), _) | (None, Some(table)) => table,
+                        (None, None) => { return Err(format!("missing color table")); }
+                    };
+                    let saved = if disposal == 3 {Some(canvas.clone())} else {None};
+
+                    // interlaced rows are stored in four passes
+                    let rows: Vec<uint> = if interlaced {
+                        range_step(0, h, 8).chain(range_step(4, h, 8))
+                                           .chain(range_step(2, h, 4))
+                                           .chain(range_step(1, h, 2)).collect()
+                    } else {
+                        range(0, h).collect()
+                    };
+                    for (i, &row) in rows.iter().enumerate() {
+                        if y + row >= height { continue; }
+                        for col in range(0, w) {
+                            if x + col >= width { continue; }
+                            let index = match indices[].get(i * w + col) {
+                                Some(&index) => index,
+                                None => { continue; }
+                            };
+                            if transparent == Some(index) { continue; }
+                            canvas[mut][(y + row) * width + x + col] =
+                                table[].get(index as uint).map_or(0, |&c| c);
+                        }
+                    }
+                    frames.push(Frame { pixels: canvas.clone(), delay: normalize_delay(delay) });
+
+                    // prepares the canvas for the next frame
+                    match (disposal, saved) {
+                        (2, _) => {
+                            let rect = (cmp::min(x, width), cmp::min(y, height),
+                                        cmp::min(w, width - cmp::min(x, width)),
+                                        cmp::min(h, height - cmp::min(y, height)));
+                            fill_rect(canvas[mut], width, rect, 0);
+                        }
+                        (3, Some(saved)) => { canvas = saved; }
+                        (_, _) => {}
+                    }
+                    delay = 0;
+                    disposal = 0;
+                    transparent = None;
+                }
+                0x3b => { break; } // trailer
+                _ => { return Err(format!("corrupted GIF image")); }
+            }
+        }
+
+        if frames.len() < 2 { return Ok(None); }
+        Ok(Some(Animation { width: width, height: height, frames: frames }))
+    }
+
+    //----------------------------------------------------------------------------------------------
+    // APNG
+
+    /// A frame control chunk (`fcTL`) of APNG.
+    struct FrameControl {
+        width: uint, height: uint, x: uint, y: uint, delay: uint, dispose: u8, blend: u8,
+    }
+
+    /// Reverses the PNG filter of each row in place. `bpp` is the number of bytes per pixel.
+    fn unfilter(data: &mut [u8], width: uint, height: uint, bpp: uint) -> Result<(),String> {
+        let stride = width * bpp + 1;
+        if data.len() < stride * height { return Err(format!("truncated image data")); }
+        for row in range(0, height) {
+            let start = row * stride;
+            let kind = data[start];
+            for i in range(1, stride) {
+                let a = if i > bpp {data[start + i - bpp] as int} else {0};
+                let b = if row > 0 {data[start - stride + i] as int} else {0};
+                let c = if row > 0 && i > bpp {data[start - stride + i - bpp] as int} else {0};
+                let pred = match kind {
+                    0 => 0,
+                    1 => a,
+                    2 => b,
+                    3 => (a + b) / 2,
+                    4 => {
+                        let p = a + b - c;
+                        let (pa, pb, pc) = ((p - a).abs(), (p - b).abs(), (p - c).abs());
+                        if pa <= pb && pa <= pc {a} else if pb <= pc {b} else {c}
+                    }
+                    _ => { return Err(format!("invalid filter type")); }
+                };
+                data[start + i] = (data[start + i] as int + pred) as u8;
+            }
+        }
+        Ok(())
+    }
+
+    /// Decodes the APNG image. Returns `None` if the image is not animated or not supported, in
+    /// which case it should be read as a static image.
+    pub fn decode_apng(data: &[u8]) -> Result<Option<Animation>,String> {
+        macro_rules! read(
+            ($e:expr) => (try!($e.map_err(|err| err.to_string())))
+        )
+
+        if data.len() < 8 || data[..8] != b"\x89PNG\r\n\x1a\n" {
+            return Err(format!("not a PNG image"));
+        }
+
+        // collects chunks, grouping image data by frames
+        let mut r = BufReader::new(data[8..]);
+        let mut header = None;
+        let mut palette = Vec::new();
+        let mut trns = Vec::new();
+        let mut animated = false;
+        let mut controls: Vec<FrameControl> = Vec::new();
+        let mut framedata: Vec<Vec<u8>> = Vec::new();
+        while !r.eof() {
+            let len = read!(r.read_be_u32()) as uint;
+            let kind = read!(r.read_exact(4));
+            let chunk = read!(r.read_exact(len));
+            let _crc = read!(r.read_be_u32());
+            let mut c = BufReader::new(chunk[]);
+            match kind[] {
+                b"IHDR" => {
+                    let width = read!(c.read_be_u32()) as uint;
+                    let height = read!(c.read_be_u32()) as uint;
+                    let depth = read!(c.read_u8());
+                    let colortype = read!(c.read_u8());
+                    let _compression = read!(c.read_u8());
+                    let _filter = read!(c.read_u8());
+                    let interlace = read!(c.read_u8());
+                    if depth != 8 || interlace != 0 { return Ok(None); }
+                    header = Some((width, height, colortype));
+                }
+                b"PLTE" => { palette = chunk.clone(); }
+                b"tRNS" => { trns = chunk.clone(); }
+                b"acTL" => { animated = true; }
+                b"fcTL" => {
+                    let _seq = read!(c.read_be_u32());
+                    let width = read!(c.read_be_u32()) as uint;
+                    let height = read!(c.read_be_u32()) as uint;
+                    let x = read!(c.read_be_u32()) as uint;
+                    let y = read!(c.read_be_u32()) as uint;
+                    let num = read!(c.read_be_u16()) as uint;
+                    let den = read!(c.read_be_u16()) as uint;
+                    let dispose = read!(c.read_u8());
+                    let blend = read!(c.read_u8());
+                    let den = if den == 0 {100} else {den};
+                    controls.push(FrameControl { width: width, height: height, x: x, y: y,
+                                                 delay: num * 1000 / den, dispose: dispose,
+                                                 blend: blend });
+                    framedata.push(Vec::new());
+                }
+                b"IDAT" => {
+                    // the default image is the first frame only if `fcTL` precedes it
+                    if !controls.is_empty() && controls.len() == framedata.len() {
+                        framedata[mut][controls.len() - 1].push_all(chunk[]);
+                    }
+                }
+                b"fdAT" => {
+                    if framedata.is_empty() { return Err(format!("fdAT without fcTL")); }
+                    let last = framedata.len() - 1;
+                    framedata[mut][last].push_all(chunk[4..]);
+                }
+                b"IEND" => { break; }
+                _ => {}
+            }
+        }
+
+        let (width, height, colortype) = match header {
+            Some(header) => header,
+            None => { return Err(format!("missing IHDR chunk")); }
+        };
+        if !animated || controls.len() < 2 { return Ok(None); }
+        let bpp = match colortype {
+            0 => 1, // grayscale
+            2 => 3, // RGB
+            3 => 1, // indexed
+            4 => 2, // grayscale with alpha
+            6 => 4, // RGBA
+            _ => { return Ok(None); }
+        };
+
+        // converts the unfiltered pixel at given offset to the `0xAARRGGBB` format
+        let to_argb = |p: &[u8]| -> u32 {
+            match colortype {
+                0 => {
+                    let alpha = if trns.len() >= 2 && trns[1] == p[0] {0} else {0xff};
+                    (alpha << 24) | (p[0] as u32 * 0x10101)
+                }
+                2 => {
+                    let opaque = trns.len() < 6 ||
+                                 (trns[1], trns[3], trns[5]) != (p[0], p[1], p[2]);
+                    (if opaque {0xff000000} else {0}) |
+                        (p[0] as u32 << 16) | (p[1] as u32 << 8) | p[2] as u32
+                }
+                3 => {
+                    let i = p[0] as uint;
+                    if i * 3 + 2 >= palette.len() { return 0; }
+                    let alpha = if i < trns.len() {trns[i] as u32} else {0xff};
+                    (alpha << 24) | (palette[i*3] as u32 << 16) |
+                        (palette[i*3+1] as u32 << 8) | palette[i*3+2] as u32
+                }
+                4 => (p[1] as u32 << 24) | (p[0] as u32 * 0x10101),
+                _ => (p[3] as u32 << 24) | (p[0] as u32 << 16) | (p[1] as u32 << 8) | p[2] as u32,
+            }
+        };
+
+        let mut canvas = Vec::from_elem(width * height, 0u32);
+        let mut frames = Vec::new();
+        for (control, data) in controls.iter().zip(framedata.iter()) {
+            if control.x + control.width > width || control.y + control.height > height {
+                return Err(format!("frame out of bounds"));
+            }
+            let mut raw = match ::flate::inflate_bytes_zlib(data[]) {
+                Some(raw) => raw.as_slice().to_vec(),
+                None => { return Err(format!("corrupted image data")); }
+            };
+            try!(unfilter(raw[mut], control.width, control.height, bpp));
+
+            let saved = if control.dispose == 2 {Some(canvas.clone())} else {None};
+            let stride = control.width * bpp + 1;
+            for row in range(0, control.height) {
+                for col in range(0, control.width) {
+                    let offset = row * stride + 1 + col * bpp;
+                    let src = to_argb(raw[offset..offset + bpp]);
+                    let idx = (control.y + row) * width + control.x + col;
+                    canvas[mut][idx] = if control.blend == 1 {blend_over(canvas[idx], src)}
+                                       else {src};
+                }
+            }
+            frames.push(Frame { pixels: canvas.clone(), delay: normalize_delay(control.delay) });
+
+            // prepares the canvas for the next frame
+            match (control.dispose, saved) {
+                (1, _) => {
+                    let rect = (control.x, control.y, control.width, control.height);
+                    fill_rect(canvas[mut], width, rect, 0);
+                }
+                (2, Some(saved)) => { canvas = saved; }
+                (_, _) => {}
+            }
+        }
+
+        Ok(Some(Animation { width: width, height: height, frames: frames }))
+    }
+}
+
+//==================================================================================================
+// software mixer
+
+/**
+ * The software mixer for the game play. SDL_mixer is still used for opening the audio device and
+ * decoding sounds, but its channels have to be allocated in advance and start only when the
+ * sound is requested, so the mixer takes over SDL_mixer's music hook and mixes sounds itself.
+ *
+ * Each voice has a start position in the mixer clock, which counts sample frames mixed so far.
+ * `clock_at` converts the timestamp to the mixer clock, so that the sound can be started at
+ * the exact sample regardless of when it is requested during the frame.
+ */
+pub mod mixer {
+    use libc::{c_int, c_void};
+    use std::{cmp, mem, ptr, slice, u64};
+    use sdl;
+    use sdl_mixer::Chunk;
+
+    /// The group for key sounds.
+    pub const KEY_GROUP: uint = 0;
+    /// The group for BGMs.
+    pub const BGM_GROUP: uint = 1;
+    /// The group for beeps, assist claps and metronome clicks.
+    pub const SYSTEM_GROUP: uint = 2;
+
+    /// The identifier of beep voices. Identifiers below this are free to use.
+    pub const BEEP_ID: uint = -1 as uint;
+    /// The identifier of assist clap and metronome click voices.
+    pub const CLAP_ID: uint = -2 as uint;
+
+    /// The default maximum number of voices played at once, excluding `SYSTEM_GROUP`.
+    pub const DEFAULT_POLYPHONY: uint = 64;
+
+    /// Specifies which voice is stopped when a new voice would exceed the polyphony limit.
+    #[deriving(PartialEq,Eq,Clone)]
+    pub enum StealPolicy {
+        /// The voice started earliest is stopped.
+        StealOldest,
+        /// The voice which will be heard most quietly is stopped, as measured by the volume times
+        /// the peak amplitude of its next samples (see `ENVELOPE_FRAMES`).
+        StealQuietest
+    }
+
+    impl StealPolicy {
+        /// Returns the voice stealing policy with given name (case-insensitive) if any.
+        pub fn from_name(name: &str) -> Option<StealPolicy> {
+            use std::ascii::AsciiExt;
+            match name.to_ascii_lower()[] {
+                "oldest" => Some(StealOldest),
+                "quietest" => Some(StealQuietest),
+                _ => None
+            }
+        }
+    }
+
+    mod ll {
+        use libc::{c_int, c_void};
+        #[link(name = "SDL")]
+        extern {
+            pub fn SDL_LockAudio();
+            pub fn SDL_UnlockAudio();
+        }
+        #[link(name = "SDL_mixer")]
+        extern {
+            pub fn Mix_HookMusic(mix_func: Option<extern "C" fn(*mut c_void, *mut u8, c_int)>,
+                                 arg: *mut c_void);
+        }
+    }
+
+    /// The number of sample frames examined for the peak amplitude by `StealQuietest`.
+    const ENVELOPE_FRAMES: uint = 1024;
+
+    /// A sound being played or scheduled.
+    struct Voice {
+        /// Samples of the chunk, 16-bit stereo interleaved. The chunk should outlive the voice.
+        samples: *const i16,
+        /// The number of sample frames in `samples`.
+        nframes: uint,
+        /// The next sample frame to be mixed.
+        pos: uint,
+        /// The mixer clock at which the voice starts.
+        start: u64,
+        /// The mixer clock at which the voice is cut off, set when other voice with the same
+        /// identifier is scheduled to start.
+        end: u64,
+        /// The volume out of 128.
+        volume: i32,
+        /// The group of the voice.
+        group: uint,
+        /// The identifier if any. Starting a voice stops other voices with the same identifier.
+        id: Option<uint>,
+    }
+
+    /// A function filling given buffer with 16-bit stereo interleaved samples, with the same
+    /// signature as SDL audio callbacks.
+    pub type StreamFn = unsafe extern "C" fn(*mut c_void, *mut u8, c_int) -> c_int;
+
+    /// An external source of samples mixed along with voices, e.g. the audio track of a movie.
+    struct Stream {
+        /// The function pulling samples from the source.
+        fill: StreamFn,
+        /// The first argument to `fill`, which also identifies the stream.
+        udata: *mut c_void,
+        /// The volume out of 128.
+        volume: i32,
+    }
+
+    /// The mixer state shared with the audio callback.
+    struct State {
+        /// The sampling rate.
+        samplerate: uint,
+        /// The size of the audio buffer in sample frames.
+        bufsize: uint,
+        /// Voices being played or scheduled.
+        voices: Vec<Voice>,
+        /// Streams being played.
+        streams: Vec<Stream>,
+        /// A buffer for samples pulled from streams.
+        streambuf: Vec<i16>,
+        /// The number of sample frames mixed so far.
+        clock: u64,
+        /// The timestamp at the last callback, and the mixer clock at that time.
+        lastcall: (uint, u64),
+        /// A buffer for accumulating samples without clipping.
+        acc: Vec<i32>,
+        /// The maximum number of voices outside `SYSTEM_GROUP`.
+        polyphony: uint,
+        /// The policy for stopping a voice beyond `polyphony`.
+        steal: StealPolicy,
+    }
+
+    impl Voice {
+        /// Returns true if the voice counts toward the polyphony limit when a new voice starts
+        /// at the mixer clock `at`. Voices in `SYSTEM_GROUP` and voices cut off by then (e.g. by
+        /// the voice with the same identifier) are not counted.
+        fn is_counted(&self, at: u64) -> bool {
+            self.group != SYSTEM_GROUP && self.end > at
+        }
+
+        /// Returns the loudness of the voice from now on, that is, the volume times the peak
+        /// amplitude of the next `ENVELOPE_FRAMES` sample frames.
+        fn loudness(&self) -> i64 {
+            let end = cmp::min(self.pos + ENVELOPE_FRAMES, self.nframes);
+            let mut peak = 0;
+            for i in range(self.pos * 2, end * 2) {
+                let sample = unsafe { *self.samples.offset(i as int) } as i64;
+                peak = cmp::max(peak, if sample < 0 {-sample} else {sample});
+            }
+            peak * self.volume as i64
+        }
+    }
+
+    impl State {
+        /// Stops one voice counted toward the polyphony limit at the mixer clock `at` (see
+        /// `Voice::is_counted`) according to the stealing policy.
+        fn steal_voice(&mut self, at: u64) {
+            let mut victim: Option<(uint, i64)> = None;
+            for (i, voice) in self.voices.iter().enumerate() {
+                if !voice.is_counted(at) { continue; }
+                // lower is stolen first
+                let rank = match self.steal {
+                    StealOldest => voice.start as i64,
+                    StealQuietest => voice.loudness(),
+                };
+                if victim.map_or(true, |(_, best)| rank < best) { victim = Some((i, rank)); }
+            }
+            for &(i, _) in victim.iter() { self.voices.remove(i); }
+        }
+    }
+
+    /// The mixer state, allocated by `init`. Only accessed from the audio callback or while
+    /// the audio is locked.
+    static mut STATE: *mut State = 0 as *mut State;
+
+    /// The audio callback hooked into SDL_mixer, which fills `stream` with mixed voices.
+    extern "C" fn callback(_udata: *mut c_void, stream: *mut u8, len: c_int) {
+        let state = unsafe { &mut *STATE };
+        let nframes = len as uint / 4;
+        state.lastcall = (sdl::get_ticks(), state.clock);
+
+        let acc = &mut state.acc;
+        acc.truncate(0);
+        acc.grow(nframes * 2, 0);
+        let clockend = state.clock + nframes as u64;
+        for voice in state.voices.iter_mut() {
+            let from = cmp::max(voice.start, state.clock);
+            let to = cmp::min(voice.end, clockend);
+            if from >= to { continue; }
+            let begin = (from - state.clock) as uint;
+            let count = cmp::min((to - from) as uint, voice.nframes - voice.pos);
+            for i in range(0, count) {
+                let (src, dst) = ((voice.pos + i) * 2, (begin + i) * 2);
+                let (left, right) = unsafe {
+                    (*voice.samples.offset(src as int), *voice.samples.offset(src as int + 1))
+                };
+                acc[mut][dst] += left as i32 * voice.volume;
+                acc[mut][dst + 1] += right as i32 * voice.volume;
+            }
+            voice.pos += count;
+        }
+        state.voices.retain(|voice| voice.pos < voice.nframes && voice.end > clockend);
+        state.clock = clockend;
+
+        // streams are filled with silence first, since they may mix into the buffer
+        let buf = &mut state.streambuf;
+        for stream in state.streams.iter() {
+            buf.truncate(0);
+            buf.grow(nframes * 2, 0);
+            unsafe { (stream.fill)(stream.udata, buf.as_mut_ptr() as *mut u8, len); }
+            for (acc, &sample) in acc.iter_mut().zip(buf.iter()) {
+                *acc += sample as i32 * stream.volume;
+            }
+        }
+
+        unsafe {
+            slice::raw::mut_buf_as_slice(stream as *mut i16, nframes * 2, |out| {
+                for (out, &sample) in out.iter_mut().zip(acc.iter()) {
+                    let sample = sample >> 7;
+                    *out = if sample > 32767 {32767}
+                           else if sample < -32768 {-32768}
+                           else {sample as i16};
+                }
+            });
+        }
+    }
+
+    /// Runs given function while the audio callback is blocked.
+    fn with_state<R>(f: |&mut State| -> R) -> R {
+        assert!(unsafe { !STATE.is_null() });
+        unsafe { ll::SDL_LockAudio(); }
+        let ret = f(unsafe { &mut *STATE });
+        unsafe { ll::SDL_UnlockAudio(); }
+        ret
+    }
+
+    /// Same as `with_state` but returns `None` without calling `f` if the mixer has not been
+    /// started, e.g. in the benchmark without any audio.
+    fn with_state_if_started<R>(f: |&mut State| -> R) -> Option<R> {
+        if unsafe { STATE.is_null() } { return None; }
+        Some(with_state(f))
+    }
+
+    /// Starts the mixer for the audio device opened by SDL_mixer with given sampling rate and
+    /// buffer size in sample frames. The audio format should be 16-bit stereo.
+    pub fn init(samplerate: uint, bufsize: uint) {
+        let state = box State { samplerate: samplerate, bufsize: bufsize, voices: Vec::new(),
+                                streams: Vec::new(), streambuf: Vec::with_capacity(bufsize * 2),
+                                clock: 0, lastcall: (sdl::get_ticks(), 0),
+                                acc: Vec::with_capacity(bufsize * 2),
+                                polyphony: DEFAULT_POLYPHONY, steal: StealOldest };
+        unsafe {
+            ll::SDL_LockAudio();
+            if !STATE.is_null() {
+                let _: Box<State> = mem::transmute(STATE);
+            }
+            STATE = mem::transmute(state);
+            ll::SDL_UnlockAudio();
+            ll::Mix_HookMusic(Some(callback), ptr::null_mut());
+        }
+    }
+
+    /// Returns the mixer clock corresponding to the timestamp `ticks`, as returned by
+    /// `sdl::get_ticks`. The result is never earlier than the next sample frame to be mixed.
+    pub fn clock_at(ticks: uint) -> u64 {
+        with_state_if_started(|state| {
+            let (lastticks, lastclock) = state.lastcall;
+            let elapsed = if ticks > lastticks {(ticks - lastticks) as u64} else {0};
+            let clock = lastclock + state.bufsize as u64 +
+                        elapsed * state.samplerate as u64 / 1000;
+            if clock > state.clock {clock} else {state.clock}
+        }).unwrap_or(0)
+    }
+
+    /**
+     * Plays the chunk from the sample frame `offset` at the mixer clock `start`, with given
+     * volume out of 128. If `id` is given, other voices with the same identifier are cut off at
+     * `start`, and those scheduled to start later are removed.
+     *
+     * The chunk should be in the mixer format and outlive the voice; call `stop_all` or check
+     * `is_playing` before deallocating the chunk. Does nothing if the mixer has not been started.
+     */
+    pub fn play(chunk: &Chunk, id: Option<uint>, group: uint, volume: uint, offset: uint,
+                start: u64) {
+        let raw = chunk.to_ll_chunk();
+        let (samples, nframes) = unsafe { ((*raw).abuf as *const i16, (*raw).alen as uint / 4) };
+        if offset >= nframes { return; }
+        with_state_if_started(|state| {
+            if id.is_some() {
+                state.voices.retain(|voice| voice.id != id || voice.start < start);
+                for voice in state.voices.iter_mut() {
+                    if voice.id == id { voice.end = cmp::min(voice.end, start); }
+                }
+            }
+            if group != SYSTEM_GROUP {
+                loop {
+                    let nvoices = state.voices.iter().filter(|v| v.is_counted(start)).count();
+                    if nvoices < state.polyphony { break; }
+                    state.steal_voice(start);
+                }
+            }
+            state.voices.push(Voice { samples: samples, nframes: nframes, pos: offset,
+                                      start: start, end: u64::MAX, volume: volume as i32,
+                                      group: group, id: id });
+        });
+    }
+
+    /// Sets the maximum number of voices played at once, excluding `SYSTEM_GROUP`, and
+    /// the policy for choosing a voice to stop when the limit is reached.
+    pub fn set_polyphony(polyphony: uint, steal: StealPolicy) {
+        assert!(polyphony > 0);
+        with_state_if_started(|state| {
+            state.polyphony = polyphony;
+            state.steal = steal;
+        });
+    }
+
+    /**
+     * Mixes samples pulled from `fill` with given volume out of 128, until `remove_stream` is
+     * called with the same `udata`. Adding a stream with the same `udata` again only updates
+     * its volume.
+     *
+     * `fill` is called from the audio callback and `udata` should be valid until the stream is
+     * removed, which is unsafe.
+     */
+    pub unsafe fn add_stream(fill: StreamFn, udata: *mut c_void, volume: uint) {
+        with_state(|state| {
+            state.streams.retain(|stream| stream.udata != udata);
+            state.streams.push(Stream { fill: fill, udata: udata, volume: volume as i32 });
+        });
+    }
+
+    /// Stops mixing the stream added with given `udata` if any.
+    pub fn remove_stream(udata: *mut c_void) {
+        if unsafe { STATE.is_null() } { return; }
+        with_state(|state| state.streams.retain(|stream| stream.udata != udata));
+    }
+
+    /// Stops mixing all streams.
+    pub fn remove_all_streams() {
+        if unsafe { STATE.is_null() } { return; }
+        with_state(|state| state.streams.clear());
+    }
+
+    /// Stops all voices.
+    pub fn stop_all() {
+        if unsafe { STATE.is_null() } { return; }
+        with_state(|state| state.voices.clear());
+    }
+
+    /// Returns true if a voice with given identifier is being played or scheduled.
+    pub fn is_playing(id: uint) -> bool {
+        with_state_if_started(|state| {
+            state.voices.iter().any(|voice| voice.id == Some(id))
+        }).unwrap_or(false)
+    }
+
+    /// Returns the number of voices in given group being played or scheduled.
+    pub fn num_playing(group: uint) -> uint {
+        with_state_if_started(|state| {
+            state.voices.iter().filter(|voice| voice.group == group).count()
+        }).unwrap_or(0)
+    }
+}
+
+//==================================================================================================
+// game play
+
+/**
+ * Game play logics. This module contains whooping 2000+ lines of code, reflecting the fact that
+ * Angolmois is not well refactored. (In fact, the game logic is usually hard to refactor, right?)
+ */
+pub mod player {
+    use {std, libc};
+    use std::{io, slice, cmp, num, iter, hash};
+    use std::rc::Rc;
+    use std::sync::{Arc, Mutex};
+    use std::cell::{Cell, RefCell};
+    use std::rand::Rng;
+    use std::collections::{HashMap, RingBuf};
+
+    use {sdl, sdl_image, sdl_mixer};
+    use sdl::{audio, video, event, joy};
+    use sdl::video::{RGB, RGBA, Surface, Color};
+    use sdl::event::{NoEvent, KeyEvent, JoyButtonEvent, JoyAxisEvent, QuitEvent};
+    use sdl_mixer::Chunk;
+    use util::smpeg;
+    use util::smpeg::MPEG;
+    use mixer;
+
+    use {parser, gfx};
+    use parser::{Key, Lane, NLANES, KeyKind, BPM, Damage, GaugeDamage, InstantDeath};
+    use parser::{BGALayer, NLAYERS, Layer1, Layer2, Layer3, PoorBGA};
+    use parser::{Obj, ObjData, ObjQueryOps, ImageRef, SoundRef, BGM, SetBGA, SetBPM, Stop,
+                 Visible, LNStart, LNDone, Bomb};
+    use parser::{Bms, BmsInfo, Timeline, KeySpec, BlitCmd};
+    use parser::{BeatDivision, NBEATDIVISIONS};
+    use gfx::{Gradient, Blend, Font, LeftAligned, Centered, RightAligned};
+    use gfx::{SurfaceAreaUtil, SurfacePixelsUtil, Canvas, SoftwareCanvas, GLCanvas};
+    use replay::{Replay, ReplayEvent, ChartHash};
+    use {AngolmoisResult, IoFailure, SdlFailure, InvalidKeySpec, InvalidKeyMap, Interrupted};
+
+    /// The width of screen, unless the exclusive mode.
+    pub const SCREENW: uint = 800;
+    /// The height of screen, unless the exclusive mode.
+    pub const SCREENH: uint = 600;
+    /// The width of BGA, or the width of screen for the exclusive mode.
+    pub const BGAW: uint = 256;
+    /// The height of BGA, or the height of screen for the exclusive mode.
+    pub const BGAH: uint = 256;
+
+    //----------------------------------------------------------------------------------------------
+    // options
+
+    /// Game play modes. (C: `enum mode`)
+    #[deriving(PartialEq,Eq,Clone)]
+    pub enum Mode {
+        /// Normal game play. The graphical display and input is enabled. (C: `PLAY_MODE`)
+        PlayMode,
+        /// Automatic game play. The graphical display is enabled but the input is mostly ignored
+        /// except for the play speed change. (C: `AUTOPLAY_MODE`)
+        AutoPlayMode,
+        /// Exclusive (headless) mode. The graphical display is reduced to the BGA or absent at all
+        /// (when `NoBga` is also set). (C: `EXCLUSIVE_MODE`)
+        ExclusiveMode
+    }
+
+    /// Modifiers that affect the game data. (C: `enum modf`)
+    #[deriving(PartialEq,Eq,Clone)]
+    pub enum Modf {
+        /// Swaps all "key" (i.e. `KeyKind::counts_as_key` returns true) lanes in the reverse order.
+        /// See `player::apply_mirror_modf` for the detailed algorithm. (C: `MIRROR_MODF`)
+        MirrorModf,
+        /// Swaps all "key" lanes in the random order. See `player::apply_shuffle_modf` for
+        /// the detailed algorithm. (C: `SHUFFLE_MODF`)
+        ShuffleModf,
+        /// Swaps all lanes in the random order. (C: `SHUFFLEEX_MODF`)
+        ShuffleExModf,
+        /// Swaps all "key" lanes in the random order, where the order is determined per object.
+        /// See `player::apply_random_modf` for the detailed algorithm. (C: `RANDOM_MODF`)
+        RandomModf,
+        /// Swaps all lanes in the random order, where the order is determined per object.
+        /// (C: `RANDOMEX_MODF`)
+        RandomExModf,
+        /// Moves each object in "key" lanes to a random lane independently. See
+        /// `parser::apply_srandom_modf` for the detailed algorithm.
+        SRandomModf,
+        /// Same as `SRandomModf` but avoids consecutive notes in the same lane. See
+        /// `parser::apply_hrandom_modf` for the detailed algorithm.
+        HRandomModf
+    }
+
+    /// Specifies how the BGA is displayed. (C: `enum bga`)
+    #[deriving(PartialEq,Eq,Clone)]
+    pub enum Bga {
+        /// Both the BGA image and movie is displayed. (C: `BGA_AND_MOVIE`)
+        BgaAndMovie,
+        /// The BGA is displayed but the movie is not loaded. (C: `BGA_BUT_NO_MOVIE`)
+        BgaButNoMovie,
+        /// The BGA is not displayed. When used with `ExclusiveMode` it also disables the graphical
+        /// display entirely. (C: `NO_BGA`)
+        NoBga
+    }
+
+    /// Specifies how the BGA is scaled beyond its original 256x256 size.
+    #[deriving(PartialEq,Eq,Clone)]
+    pub enum BgaStretch {
+        /// The BGA is scaled to fill the area beside the lanes, keeping its aspect ratio.
+        StretchBeside,
+        /// The BGA is scaled to the whole backdrop at the reduced opacity.
+        StretchBackdrop
+    }
+
+    impl BgaStretch {
+        /// Returns the BGA stretching mode with given name (case-insensitive) if any.
+        pub fn from_name(name: &str) -> Option<BgaStretch> {
+            use std::ascii::AsciiExt;
+            match name.to_ascii_lower()[] {
+                "beside" => Some(StretchBeside),
+                "backdrop" => Some(StretchBackdrop),
+                _ => None
+            }
+        }
+    }
+
+    /// The default duration of the POOR BGA after the MISS grade in milliseconds.
+    pub const DEFAULT_POOR_DURATION: uint = 600;
+
+    /// Specifies how the POOR BGA is displayed after the MISS grade.
+    #[deriving(PartialEq,Eq,Clone)]
+    pub enum PoorBga {
+        /// The POOR BGA replaces other layers.
+        PoorReplaces,
+        /// The POOR BGA is drawn over other layers.
+        PoorOverlays,
+        /// The POOR BGA is not displayed.
+        NoPoorBga
+    }
+
+    impl PoorBga {
+        /// Returns the POOR BGA mode with given name (case-insensitive) if any.
+        pub fn from_name(name: &str) -> Option<PoorBga> {
+            use std::ascii::AsciiExt;
+            match name.to_ascii_lower()[] {
+                "replace" => Some(PoorReplaces),
+                "overlay" => Some(PoorOverlays),
+                "off" => Some(NoPoorBga),
+                _ => None
+            }
+        }
+    }
+
+    /// Specifies when the assist clap is played, which helps players to practice timing.
+    #[deriving(PartialEq,Eq,Clone)]
+    pub enum AssistClap {
+        /// The clap is played at the exact time of each note, in sync with BGMs.
+        ClapOnNote,
+        /// The clap is played whenever the input is graded against a note.
+        ClapOnHit
+    }
+
+    impl AssistClap {
+        /// Returns the assist clap mode with given name (case-insensitive) if any.
+        pub fn from_name(name: &str) -> Option<AssistClap> {
+            use std::ascii::AsciiExt;
+            match name.to_ascii_lower()[] {
+                "note" => Some(ClapOnNote),
+                "hit" => Some(ClapOnHit),
+                _ => None
+            }
+        }
+    }
+
+    /// The minimum width of lanes that can be customized.
+    pub const MIN_LANE_WIDTH: uint = 10;
+    /// The maximum width of lanes that can be customized. Every key kind has its own area in
+    /// the sprite, so this should be small enough to fit all of them.
+    pub const MAX_LANE_WIDTH: uint = 60;
+
+    /// A customized appearance of lanes for given key kind, which overrides the default one in
+    /// `LaneStyle::from_kind`.
+    #[deriving(Clone)]
+    pub struct LaneCustomStyle {
+        /// The key kind customized.
+        pub kind: KeyKind,
+        /// The base color of lanes and objects in RGB, if overridden.
+        pub color: Option<(u8,u8,u8)>,
+        /// The width of lanes, if overridden.
+        pub width: Option<uint>,
+    }
+
+    impl LaneCustomStyle {
+        /**
+         * Parses a list of customizations separated by whitespaces or semicolons. Each
+         * customization consists of a mnemonic character for the key kind (as in the key
+         * specification), `=` and either the color like `#4080ff`, the width in pixels or both
+         * separated by `,`. For example, `a=#ffffff b=#4080ff,30` makes white keys white, and black
+         * keys blue and wider.
+         */
+        pub fn parse_list(s: &str) -> Option<Vec<LaneCustomStyle>> {
+            let mut styles = Vec::new();
+            for spec in s.split(|c: char| c.is_whitespace() || c == ';') {
+                if spec.is_empty() { continue; }
+                let (kind, rest) = match spec.find('=') {
+                    Some(idx) => (spec[..idx], spec[idx+1..]),
+                    None => { return None; }
+                };
+                let kind = match kind.char_len() {
+                    1 => match KeyKind::from_char(kind.char_at(0)) {
+                        Some(kind) => kind,
+                        None => { return None; }
+                    },
+                    _ => { return None; }
+                };
+
+                let mut style = LaneCustomStyle { kind: kind, color: None, width: None };
+                for part in rest.split(',') {
+                    if part.starts_with("#") && part.len() == 7 {
+                        match num::from_str_radix::<u32>(part[1..], 16) {
+                            Some(c) => {
+                                style.color = Some(((c >> 16) as u8, (c >> 8) as u8, c as u8));
+                            }
+                            None => { return None; }
+                        }
+                    } else {
+                        match from_str::<uint>(part) {
+                            Some(w) if MIN_LANE_WIDTH <= w && w <= MAX_LANE_WIDTH => {
+                                style.width = Some(w);
+                            }
+                            _ => { return None; }
+                        }
+                    }
+                }
+                styles.push(style);
+            }
+            Some(styles)
+        }
+    }
+
+    /// Specifies what the pacemaker compares the current EX score against.
+    #[deriving(PartialEq,Clone)]
+    pub enum Pacemaker {
+        /// Given ratio (from 0 to 1) of the maximum EX score.
+        PaceTarget(f64),
+        /// The best EX score previously recorded for the chart.
+        PaceBest,
+        /// The EX score of the ghost replay (`Options::ghost`).
+        PaceGhost
+    }
+
+    impl Pacemaker {
+        /// Parses the pacemaker specification: `best`, `ghost` or a percentage like `80%` (the
+        /// percent sign is optional).
+        pub fn from_spec(spec: &str) -> Option<Pacemaker> {
+            use std::ascii::AsciiExt;
+            match spec.to_ascii_lower()[] {
+                "best" => Some(PaceBest),
+                "ghost" => Some(PaceGhost),
+                spec => {
+                    let spec = if spec.ends_with("%") {spec[..spec.len()-1]} else {spec};
+                    match from_str::<f64>(spec) {
+                        Some(pct) if 0.0 <= pct && pct <= 100.0 => Some(PaceTarget(pct / 100.0)),
+                        _ => None
+                    }
+                }
+            }
+        }
+    }
+
+    /// Global options set from the command line and environment variables.
+    #[deriving(Clone)]
+    pub struct Options {
+        /// A path to the BMS file. Used for finding the resource when `BMS::basepath` is not set.
+        /// (C: `bmspath`)
+        pub bmspath: String,
+        /// Game play mode. (C: `opt_mode`)
+        pub mode: Mode,
+        /// Modifiers that affect the game data. (C: `opt_modf`)
+        pub modf: Option<Modf>,
+        /// Specifies how the BGA is displayed. (C: `opt_bga`)
+        pub bga: Bga,
+        /// Specifies how the BGA is scaled if any. The BGA is displayed in the original size
+        /// otherwise.
+        pub bgastretch: Option<BgaStretch>,
+        /// Specifies how the POOR BGA is displayed after the MISS grade. Usually `PoorReplaces`.
+        pub poorbga: PoorBga,
+        /// The duration of the POOR BGA in milliseconds. Usually `DEFAULT_POOR_DURATION`.
+        pub poorduration: uint,
+        /// True if the metadata (either overlaid in the loading screen or printed separately
+        /// in the console) is displayed. (C: `opt_showinfo`)
+        pub showinfo: bool,
+        /// True if lanes and notes are drawn to the terminal in the exclusive mode without BGA,
+        /// instead of the status line only.
+        pub terminal: bool,
+        /// True if the full screen is enabled. (C: `opt_fullscreen`)
+        pub fullscreen: bool,
+        /// Indices to the joystick devices to be opened. Inputs from each device are
+        /// distinguished, so that two players can use their own controllers. (C: `opt_joystick`)
+        pub joystick: Vec<uint>,
+        /// The deadzone of joystick axes without the calibration. Usually `DEFAULT_DEADZONE`.
+        pub deadzone: uint,
+        /// The calibration of joystick axes, read from the file written by `calibrate_axes` and
+        /// then overridden by the command line.
+        pub axes: Vec<AxisCalibration>,
+        /// A key specification preset name if any. (C: `preset`)
+        pub preset: Option<String>,
+        /// A left-hand-side key specification if any. (C: `leftkeys`)
+        pub leftkeys: Option<String>,
+        /// A right-hand-side key specification if any. Can be an empty string. (C: `rightkeys`)
+        pub rightkeys: Option<String>,
+        /// An initial play speed. (C: `playspeed`)
+        pub playspeed: f64,
+        /// True if `playspeed` is replaced by the one saved for the chart if any, i.e. it is not
+        /// given explicitly. See `read_chart_settings`.
+        pub restorespeed: bool,
+        /// Same as `restorespeed` but for `cover`.
+        pub restorecover: bool,
+        /// The height of the lane cover over the top of the note area in pixels.
+        pub cover: uint,
+        /// The height of the lift under the bottom of the note area in pixels, which raises
+        /// the judgement line.
+        pub lift: uint,
+        /// The fraction of the visible note area from the top where objects fade out, if any.
+        /// Also known as "sudden".
+        pub sudden: Option<f64>,
+        /// The fraction of the visible note area from the judgement line where objects fade out,
+        /// if any. Also known as "hidden".
+        pub hidden: Option<f64>,
+        /// Set to true if objects should scroll at a constant rate derived from the main BPM,
+        /// ignoring BPM changes and scroll stoppers. Sounds and grading are not affected.
+        pub constant: bool,
+        /// Set to true if the left and right sides of the double play chart should be swapped.
+        pub flip: bool,
+        /// Set to true if the single play chart should be duplicated to both sides for two
+        /// players, with separate scores and gauges as in the Couple Play.
+        pub battle: bool,
+        /// When the assist clap is played, if any.
+        pub assistclap: Option<AssistClap>,
+        /// Set to true if a click should be played at each beat, louder at the start of measures.
+        pub metronome: bool,
+        /// Set to true if measure bars should flash at the start of measures.
+        pub metronomeflash: bool,
+        /// A path to the CSV file where samples of the score and gauge are written, if any.
+        pub historycsv: Option<String>,
+        /// A path to the JSON file where the result of the game play is written, if any.
+        pub resultjson: Option<String>,
+        /// The pacemaker compared against the current EX score, if any.
+        pub pacemaker: Option<Pacemaker>,
+        /// The URL of the internet ranking endpoint where the result is submitted, if any.
+        pub ir: Option<String>,
+        /// True if the screen is presented through OpenGL for the vertical synchronization.
+        /// The game play also draws lanes, objects and BGAs with the GPU then.
+        pub opengl: bool,
+        /// The maximum number of frames per second if any. Otherwise `DEFAULT_FPS` is used.
+        pub fps: Option<uint>,
+        /// True if the frame rate and the frame time are shown on the screen.
+        pub showfps: bool,
+        /// The internal sampling rate for SDL_mixer. Usually `DEFAULT_SAMPLERATE`.
+        pub samplerate: uint,
+        /// The size of the audio buffer in samples. Usually `DEFAULT_AUDIOBUFFER`.
+        pub audiobuffer: uint,
+        /// The playback rate, between 0.5 and 2.0. The chart is played `rate` times as fast, and
+        /// sounds are time-stretched without changing the pitch. Usually 1.0.
+        pub rate: f64,
+        /// The volume of key sounds out of 128. Usually 128.
+        pub keyvolume: uint,
+        /// The volume of BGMs out of 128. Usually 96.
+        pub bgmvolume: uint,
+        /// The volume of audio tracks in BGA movies out of 128, or `None` if they are not played.
+        pub movievolume: Option<uint>,
+        /// The maximum number of BGMs and key sounds played at once.
+        /// Usually `mixer::DEFAULT_POLYPHONY`.
+        pub polyphony: uint,
+        /// The policy for stopping a sound when `polyphony` is reached.
+        pub voicesteal: mixer::StealPolicy,
+        /// Customized appearances of lanes, later ones overriding earlier ones.
+        pub lanestyles: Vec<LaneCustomStyle>,
+        /// True if objects are colored by their beat divisions instead of lanes.
+        pub beatcolor: bool,
+        /// A path to the directory where rendered frames and the audio are exported, if any.
+        /// Only meaningful in the AUTO PLAY mode.
+        pub exportvideo: Option<String>,
+        /// The time in milliseconds an object stays on the screen, if the play speed should be
+        /// derived from the current BPM instead of `playspeed`. Also known as "green number".
+        pub greennumber: Option<f64>,
+        /// The time in milliseconds an object should stay on the screen with the main BPM of
+        /// the chart, if the play speed achieving it should be suggested in the loading screen.
+        /// See `suggested_playspeed`.
+        pub suggestgreen: Option<f64>,
+        /// True if only the preview is played. The preview is either the audio clip specified by
+        /// BMS #PREVIEW command, or the first `PREVIEW_MEASURES` measures of the chart.
+        pub preview: bool,
+        /// A path to the bmson file if the chart should be exported instead of played.
+        pub exportbmson: Option<String>,
+        /// True if the chart is sanitized before being exported. Always true when played.
+        pub sanitize: bool,
+        /// Determines how the BMS parser treats the divergences between BMS players.
+        pub compat: parser::CompatProfile,
+        /// Determines how conflicting objects are treated while sanitizing the chart.
+        /// `ReportOnly` only applies to the export, as an unsanitized chart cannot be played.
+        pub sanitizepolicy: parser::SanitizePolicy,
+        /// True if sound resources are loaded on demand during the game play instead of before
+        /// the game play. See `LazySounds` for the details.
+        pub lazysounds: bool,
+        /// If not `None`, the game play starts as soon as resources used in the first given
+        /// seconds are loaded, and remaining sound resources are loaded during the game play.
+        /// See `BackgroundSounds` for the details.
+        pub earlystart: Option<f64>,
+        /// Additional directories searched for resources after the resource directory of
+        /// the chart, in order. See `resolve_relative_path`.
+        pub searchdirs: Vec<String>,
+        /// If not `None`, sound resources which failed to load are replaced with a tick of given
+        /// length in milliseconds, so that key sounds are still audible in broken packages.
+        pub placeholdersound: Option<uint>,
+        /// A path to the replay file written at the end of play if any.
+        pub recordreplay: Option<String>,
+        /// A path to the replay file played back instead of the actual input if any.
+        pub replay: Option<String>,
+        /// A path to the replay file displayed as a ghost if any.
+        pub ghost: Option<String>,
+        /// A random seed for #RANDOM and modifiers if any. A fresh seed is used otherwise.
+        pub seed: Option<u64>,
+        /// The measure where the game play starts if any. Used for practicing the section.
+        pub startmeasure: Option<uint>,
+        /// The measure where the game play ends if any.
+        pub endmeasure: Option<uint>,
+        /// True if the game play rewinds to `startmeasure` at `endmeasure` instead of ending.
+        pub loopsection: bool,
+        /// Charts played after `bmspath` in the course mode, which carries over the gauge, combo
+        /// and score between charts. The course mode is disabled if empty.
+        pub course: Vec<String>,
+        /// The gauge type.
+        pub gauge: GaugeKind,
+        /// The input offset in milliseconds, which is subtracted from the input time when grading
+        /// and added to the time of BGMs. Positive if the player tends to press late, e.g. due to
+        /// the display latency. The per-chart offset, if any, is added to this before playing.
+        pub offset: f64,
+        /// The per-chart offset in milliseconds to be saved and used for this chart if any.
+        pub chartoffset: Option<f64>,
+        /// The maximum distance in milliseconds from the closest soundable object to the input
+        /// time for its key sound to be played when the key is pressed. Every pressed key plays
+        /// the closest key sound however far it is if `None`, which is the default.
+        pub keysoundwindow: Option<f64>,
+        /// The maximum distance in milliseconds from the end of LN to the release time for LN to
+        /// be completed. Usually `DEFAULT_LN_RELEASE_WINDOW`.
+        pub lnreleasewindow: f64,
+        /// True if LN held for `LENIENT_LN_RATIO` of its duration is completed however early
+        /// it is released, and LN held past its end is completed as well.
+        pub lenientln: bool,
+    }
+
+    /// The default value of `Options::lnreleasewindow`, same as the BAD grade.
+    pub const DEFAULT_LN_RELEASE_WINDOW: f64 = BAD_CUTOFF;
+
+    /// The fraction of LN duration to be held for completing LN with `Options::lenientln`.
+    pub const LENIENT_LN_RATIO: f64 = 0.8;
+
+    /// The number of measures played as a preview when BMS #PREVIEW command is absent.
+    pub const PREVIEW_MEASURES: uint = 8;
+
+    impl Options {
+        /// Returns true if the exclusive mode is enabled. This enables a text-based interface.
+        /// (C: `opt_mode >= EXCLUSIVE_MODE`)
+        pub fn is_exclusive(&self) -> bool { self.mode == ExclusiveMode }
+
+        /// Returns true if the input is ignored. Escape key or speed-changing keys are still
+        /// available as long as the graphical screen is enabled. (C: `!!opt_mode`)
+        pub fn is_autoplay(&self) -> bool { self.mode != PlayMode }
+
+        /// Returns true if the BGA is displayed. (C: `opt_bga < NO_BGA`)
+        pub fn has_bga(&self) -> bool { self.bga != NoBga }
+
+        /// Returns true if the BGA movie is enabled. (C: `opt_bga < BGA_BUT_NO_MOVIE`)
+        pub fn has_movie(&self) -> bool { self.bga == BgaAndMovie }
+
+        /// Returns true if the graphical screen is enabled.
+        /// (C: `opt_mode < EXCLUSIVE_MODE || opt_bga < NO_BGA`)
+        pub fn has_screen(&self) -> bool { !self.is_exclusive() || self.has_bga() }
+    }
+
+    //----------------------------------------------------------------------------------------------
+    // bms utilities
+
+    /// Parses a key specification from the options.
+    pub fn key_spec(bms: &Bms, opts: &Options) -> AngolmoisResult<KeySpec> {
+        use std::ascii::AsciiExt;
+        use util::option::StrOption;
+
+        let (leftkeys, rightkeys) =
+            if opts.leftkeys.is_none() && opts.rightkeys.is_none() {
+                let bmspath = opts.bmspath[].to_ascii_lower();
+                let preset =
+                    if opts.preset.is_none() && bmspath[].ends_with(".pms") {
+                        Some("pms".to_string())
+                    } else if opts.preset.is_none() && bmspath[].ends_with(".ojn") {
+                        Some("o2jam".to_string())
+                    } else {
+                        opts.preset.clone()
+                    };
+                match parser::preset_to_key_spec(bms, preset) {
+                    Some(leftright) => leftright,
+                    None => {
+                        return Err(InvalidKeySpec(format!("Invalid preset name: {}",
+                                                          opts.preset.as_ref_slice_or(""))));
+                    }
+                }
+            } else {
+                (opts.leftkeys.as_ref_slice_or("").to_string(),
+                 opts.rightkeys.as_ref_slice_or("").to_string())
+            };
+
+        let mut keyspec = KeySpec { split: 0, order: Vec::new(),
+                                    kinds: Vec::from_fn(NLANES, |_| None) };
+        let parse_and_add = |keyspec: &mut KeySpec, keys: &str| -> Option<uint> {
+            match parser::parse_key_spec(keys) {
+                None => None,
+                Some(ref left) if left.is_empty() => None,
+                Some(left) => {
+                    let mut err = false;
+                    for &(lane,kind) in left.iter() {
+                        if keyspec.kinds[*lane].is_some() { err = true; break; }
+                        keyspec.order.push(lane);
+                        keyspec.kinds[mut][*lane] = Some(kind);
+                    }
+                    if err {None} else {Some(left.len())}
+                }
+            }
+        };
+
+        if !leftkeys.is_empty() {
+            match parse_and_add(&mut keyspec, leftkeys[]) {
+                None => {
+                    return Err(InvalidKeySpec(format!("Invalid key spec for left hand side: {}",
+                                                      leftkeys)));
+                }
+                Some(nkeys) => { keyspec.split += nkeys; }
+            }
+        } else {
+            return Err(InvalidKeySpec(format!("No key model is specified using -k or -K")));
+        }
+        if !rightkeys.is_empty() {
+            match parse_and_add(&mut keyspec, rightkeys[]) {
+                None => {
+                    return Err(InvalidKeySpec(format!("Invalid key spec for right hand side: {}",
+                                                      rightkeys)));
+                }
+                Some(nkeys) => { // no split panes except for #PLAYER 2
+                    if bms.player != parser::COUPLE_PLAY { keyspec.split += nkeys; }
+                }
+            }
+        }
+        Ok(keyspec)
+    }
+
+    /// Applies given modifier to the game data. The target lanes of the modifier is determined
+    /// from given key specification. This function should be called twice for the Couple Play,
+    /// since 1P and 2P should be treated separately. (C: `shuffle_bms`)
+    pub fn apply_modf<R: Rng>(bms: &mut Bms, modf: Modf, r: &mut R,
+                              keyspec: &KeySpec, begin: uint, end: uint) {
+        let mut lanes = Vec::new();
+        for i in range(begin, end) {
+            let lane = keyspec.order[i];
+            let kind = keyspec.kinds[*lane];
+            if modf == ShuffleExModf || modf == RandomExModf ||
+                    kind.map_or(false, |kind| kind.counts_as_key()) {
+                lanes.push(lane);
+            }
+        }
+
+        match modf {
+            MirrorModf => parser::apply_mirror_modf(bms, lanes[]),
+            ShuffleModf | ShuffleExModf => parser::apply_shuffle_modf(bms, r, lanes[]),
+            RandomModf | RandomExModf => parser::apply_random_modf(bms, r, lanes[]),
+            SRandomModf => parser::apply_srandom_modf(bms, r, lanes[]),
+            HRandomModf => parser::apply_hrandom_modf(bms, r, lanes[])
+        }
+    }
+
+    //----------------------------------------------------------------------------------------------
+    // utilities
+
+    /// Checks if the user pressed the escape key or the quit button, and returns `Interrupted`
+    /// if so. `atexit` is called before returning the error. (C: `check_exit`)
+    pub fn check_exit(atexit: ||) -> AngolmoisResult<()> {
+        check_exit_or_enter(atexit).map(|_| ())
+    }
+
+    /// Same as `check_exit` but also returns true if the user pressed the return key.
+    pub fn check_exit_or_enter(atexit: ||) -> AngolmoisResult<bool> {
+        let mut entered = false;
+        loop {
+            match event::poll_event() {
+                KeyEvent(event::EscapeKey,_,_,_) | QuitEvent => {
+                    atexit();
+                    return Err(Interrupted);
+                },
+                KeyEvent(event::ReturnKey,true,_,_) => { entered = true; },
+                NoEvent => { break; },
+                _ => {}
+            }
+        }
+        Ok(entered)
+    }
+
+    /// Writes a line to the console without advancing to the next line. `s` should be short enough
+    /// to be replaced (currently up to 72 bytes).
+    pub fn update_line(s: &str) {
+        let _ = write!(&mut std::io::stderr(), "\r{:72}\r{}", "", s);
+    }
+
+    /// A periodic timer for thresholding the rate of information display.
+    pub struct Ticker {
+        /// Minimal required milliseconds after the last display.
+        pub interval: uint,
+        /// The timestamp at the last display. It is a return value from `sdl::get_ticks` and
+        /// measured in milliseconds. May be a `None` if the ticker is at the initial state or
+        /// has been reset by `reset` method. (C: `lastinfo`)
+        pub lastinfo: Option<uint>
+    }
+
+    impl Ticker {
+        /// Returns a new ticker with a default display interval.
+        pub fn new() -> Ticker {
+            /// A reasonable interval for the console and graphic display.
+            /// Currently set to about 21fps. (C: `INFO_INTERVAL`)
+            const INFO_INTERVAL: uint = 47;
+            Ticker { interval: INFO_INTERVAL, lastinfo: None }
+        }
+
+        /// Calls `f` only when required milliseconds have passed after the last display.
+        /// `now` should be a return value from `sdl::get_ticks`.
+        pub fn on_tick(&mut self, now: uint, f: ||) {
+            if self.lastinfo.map_or(true, |t| now - t >= self.interval) {
+                self.lastinfo = Some(now);
+                f();
+            }
+        }
+
+        /// Lets the next call to `on_tick` always call the callback.
+        pub fn reset(&mut self) {
+            self.lastinfo = None;
+        }
+    }
+
+    //----------------------------------------------------------------------------------------------
+    // initialization
+
+    /// The default internal sampling rate for SDL_mixer.
+    pub const DEFAULT_SAMPLERATE: uint = 44100;
+
+    /// The default size of the audio buffer in samples. Smaller buffers reduce the latency but
+    /// can cause underruns on slow systems.
+    pub const DEFAULT_AUDIOBUFFER: uint = 2048;
+
+    /// An internal sampling rate for SDL_mixer, as actually opened by `init_audio` (which can
+    /// differ from the requested rate). Every chunk loaded is first converted to this sampling
+    /// rate for the purpose of mixing.
+    static mut SAMPLERATE: i32 = DEFAULT_SAMPLERATE as i32;
+
+    /// Returns the internal sampling rate.
+    fn sample_rate() -> i32 { unsafe { SAMPLERATE } }
+
+    /// Returns the number of bytes in the chunk converted to an internal sampling rate.
+    fn bytes_per_sec() -> i32 { sample_rate() * 2 * 2 } // stereo, 16 bits/sample
+
+    /// Creates a small screen for BGAs (`BGAW` by `BGAH` pixels) if `exclusive` is set,
+    /// or a full-sized screen (`SCREENW` by `SCREENH` pixels) otherwise. `fullscreen` is ignored
+    /// when `exclusive` is set. The window is kept if the video mode is already set with
+    /// the same size and flags, e.g. between charts in the course mode.
+    ///
+    /// If `opengl` is set, the returned screen is an off-screen surface and the presenter is also
+    /// returned, which should be given to `present` and kept until the screen is no longer used.
+    /// (C: `init_ui` and `init_video`)
+    pub fn init_video(exclusive: bool, fullscreen: bool, opengl: bool)
+                    -> AngolmoisResult<(Surface, Option<::util::gl::Presenter>)> {
+        if !sdl::init([sdl::InitVideo]) {
+            return Err(SdlFailure(format!("SDL Initialization Failure: {}", sdl::get_error())));
+        }
+        sdl_image::init([sdl_image::InitJPG, sdl_image::InitPNG]);
+
+        let (width, height) = if exclusive {(BGAW, BGAH)} else {(SCREENW, SCREENH)};
+        let current = video::get_video_surface().ok().and_then(|screen| {
+            let (w, h, flags) = unsafe { ((*screen.raw).w, (*screen.raw).h, (*screen.raw).flags) };
+            let isgl = (flags & video::OpenGL as u32) != 0;
+            let isfull = (flags & video::Fullscreen as u32) != 0;
+            if w as uint == width && h as uint == height && isgl == opengl &&
+                    isfull == (fullscreen && !exclusive) {
+                Some(screen)
+            } else {
+                None
+            }
+        });
+        let result =
+            if current.is_some() {
+                // the OpenGL mode renders to the off-screen surface, which is created anew
+                if opengl {gfx::new_surface(width, height)} else {Ok(current.unwrap())}
+            } else if opengl {
+                ::util::gl::set_attributes();
+                let flags = if fullscreen && !exclusive {vec!(video::OpenGL, video::Fullscreen)}
+                            else {vec!(video::OpenGL)};
+                video::set_video_mode(width as int, height as int, 32, [], flags[])
+                    .and_then(|_| gfx::new_surface(width, height))
+            } else if exclusive {
+                video::set_video_mode(BGAW as int, BGAH as int, 32,
+                                      [video::SWSurface], [video::DoubleBuf])
+            } else if !fullscreen {
+                video::set_video_mode(SCREENW as int, SCREENH as int, 32,
+                                      [video::SWSurface], [video::DoubleBuf])
+            } else {
+                video::set_video_mode(SCREENW as int, SCREENH as int, 32, [], [video::Fullscreen])
+            };
+        let screen =
+            match result {
+                Ok(screen) => screen,
+                Err(err) => {
+                    return Err(SdlFailure(format!("SDL Video Initialization Failure: {}", err)));
+                }
+            };
+        let presenter = if opengl {
+            match ::util::gl::Presenter::new(width, height) {
+                Ok(presenter) => Some(presenter),
+                Err(err) => {
+                    return Err(SdlFailure(format!("OpenGL Initialization Failure: {}", err)));
+                }
+            }
+        } else {
+            None
+        };
+        if !exclusive {
+            sdl::mouse::set_cursor_visible(false);
+        }
+        sdl::wm::set_caption(::version()[], "");
+        ::util::enable_drop_files();
+        Ok((screen, presenter))
+    }
+
+    /// Shows the rendered screen, either by flipping the screen or by the OpenGL presenter
+    /// returned by `init_video`.
+    pub fn present(screen: &Surface, presenter: Option<&::util::gl::Presenter>) {
+        match presenter {
+            Some(presenter) => presenter.present(screen),
+            None => { screen.flip(); }
+        }
+    }
+
+    // SDL_mixer 1.2 decoder flags for `Mix_Init`.
+    const MIX_INIT_FLAC: libc::c_int = 1;
+    const MIX_INIT_MP3: libc::c_int = 4;
+    const MIX_INIT_OGG: libc::c_int = 8;
+
+    /// Initializes SDL_mixer with given sampling rate and buffer size in samples. The output
+    /// device, if any, is chosen by SDL environment variables such as `AUDIODEV`. The device may
+    /// open with a different sampling rate, which is then used instead. (C: `init_ui`)
+    pub fn init_audio(samplerate: uint, buffer: uint) -> AngolmoisResult<()> {
+        if !sdl::init([sdl::InitAudio]) {
+            return Err(SdlFailure(format!("SDL Initialization Failure: {}", sdl::get_error())));
+        }
+        // SDL_mixer loads decoders other than WAV on demand, but FLAC should be initialized
+        // explicitly. failing decoders are reported when the resource fails to load.
+        unsafe { rwops::Mix_Init(MIX_INIT_FLAC | MIX_INIT_MP3 | MIX_INIT_OGG); }
+        if sdl_mixer::open(samplerate as i32, audio::S16_AUDIO_FORMAT, audio::Stereo,
+                           buffer as int).is_err() {
+            return Err(SdlFailure(format!("SDL Mixer Initialization Failure")));
+        }
+        let mut frequency = 0;
+        let mut format = 0;
+        let mut channels = 0;
+        if unsafe { rwops::Mix_QuerySpec(&mut frequency, &mut format, &mut channels) } == 0 {
+            return Err(SdlFailure(format!("SDL Mixer Initialization Failure")));
+        }
+        let samplerate = frequency as i32;
+        unsafe { SAMPLERATE = samplerate; }
+        ::mixer::init(samplerate as uint, buffer);
+        Ok(())
+    }
+
+    /// Initializes a joystick with given index.
+    pub fn init_joystick(joyidx: uint) -> AngolmoisResult<joy::Joystick> {
+        if !sdl::init([sdl::InitJoystick]) {
+            return Err(SdlFailure(format!("SDL Initialization Failure: {}", sdl::get_error())));
+        }
+        unsafe {
+            joy::ll::SDL_JoystickEventState(1); // TODO rust-sdl patch
+        }
+        joy::Joystick::open(joyidx as int).map_err(|err| {
+            SdlFailure(format!("SDL Joystick Initialization Failure: {}", err))
+        })
+    }
+
+    //----------------------------------------------------------------------------------------------
+    // virtual input
+
+    /// Actual input. Mapped to zero or more virtual inputs by input mapping.
+    #[deriving(PartialEq,Eq)]
+    enum Input {
+        /// Keyboard input.
+        KeyInput(event::Key),
+        /// Joystick axis input, with the device index (`None` for any opened device in
+        /// the mapping) and the axis index.
+        JoyAxisInput(Option<uint>, uint),
+        /// Joystick button input, with the device index (`None` for any opened device in
+        /// the mapping) and the button index.
+        JoyButtonInput(Option<uint>, uint),
+        /// Joystick axis input from the analog turntable, with the device index (`None` for any
+        /// opened device in the mapping) and the axis index. The motion of the axis is tracked by
+        /// `TurntableState` instead of its position.
+        JoyTurntableInput(Option<uint>, uint)
+    }
+
+    impl Input {
+        /// Returns the same input from any opened device, i.e. without the device index.
+        fn from_any_device(&self) -> Input {
+            match *self {
+                KeyInput(key) => KeyInput(key),
+                JoyAxisInput(_, axis) => JoyAxisInput(None, axis),
+                JoyButtonInput(_, button) => JoyButtonInput(None, button),
+                JoyTurntableInput(_, axis) => JoyTurntableInput(None, axis),
+            }
+        }
+    }
+
+    impl hash::Hash for Input {
+        fn hash(&self, state: &mut hash::sip::SipState) {
+            match *self {
+                KeyInput(key) => { 0u8.hash(state); (key as uint).hash(state); }
+                JoyAxisInput(dev, axis) => { 1u8.hash(state); dev.hash(state); axis.hash(state); }
+                JoyButtonInput(dev, button) => {
+                    2u8.hash(state); dev.hash(state); button.hash(state);
+                }
+                JoyTurntableInput(dev, axis) => {
+                    3u8.hash(state); dev.hash(state); axis.hash(state);
+                }
+            }
+        }
+    }
+
+    /// Virtual input.
+    #[deriving(PartialEq,Eq)]
+    enum VirtualInput {
+        /// Virtual input mapped to the lane.
+        LaneInput(Lane),
+        /// Speed down input (normally F3).
+        SpeedDownInput,
+        /// Speed up input (normally F4).
+        SpeedUpInput,
+        /// Input setting the start of the loop, or the A point (normally F5).
+        LoopStartInput,
+        /// Input setting or clearing the end of the loop, or the B point (normally F6).
+        LoopEndInput,
+        /// Input restarting the game play (normally F7).
+        RetryInput,
+        /// Input raising the bottom of the lane cover (normally F8).
+        CoverUpInput,
+        /// Input lowering the bottom of the lane cover (normally F9).
+        CoverDownInput,
+        /// Input raising the judgement line (normally F10).
+        LiftUpInput,
+        /// Input lowering the judgement line (normally F11).
+        LiftDownInput,
+        /// Input saving the screenshot (normally F12).
+        ScreenshotInput
+    }
+
+    /**
+     * State of virtual input elements. There are three states: neutral, and positive or negative.
+     * There is no difference between positive and negative states (the naming is arbitrary)
+     * except for that they are distinct.
+     *
+     * The states should really be one of pressed (non-neutral) or unpressed (neutral) states,
+     * but we need two non-neutral states since the actual input device with continuous values
+     * (e.g. joystick axes) can trigger the state transition *twice* without hitting the neutral
+     * state. We solve this problem by making the transition from negative to positive (and vice
+     * versa) temporarily hit the neutral state.
+     */
+    #[deriving(PartialEq,Eq,Clone)]
+    pub enum InputState {
+        /// Positive input state. Occurs when the button is pressed or the joystick axis is moved
+        /// in the positive direction.
+        Positive = 1,
+        /// Neutral input state. Occurs when the button is not pressed or the joystick axis is moved
+        /// back to the origin.
+        Neutral = 0,
+        /// Negative input state. Occurs when the joystick axis is moved in the negative direction.
+        Negative = -1
+    }
+
+    impl VirtualInput {
+        /// Returns true if the virtual input has a specified key kind in the key specification.
+        pub fn active_in_key_spec(&self, kind: KeyKind, keyspec: &KeySpec) -> bool {
+            match *self {
+                LaneInput(Lane(lane)) => keyspec.kinds[lane] == Some(kind),
+                SpeedDownInput | SpeedUpInput | LoopStartInput | LoopEndInput | RetryInput |
+                CoverUpInput | CoverDownInput | LiftUpInput | LiftDownInput |
+                ScreenshotInput => true
+            }
+        }
+    }
+
+    /// The default deadzone of joystick axes in the axis units. An axis within this distance
+    /// from the center is in the neutral state.
+    pub const DEFAULT_DEADZONE: uint = 3200;
+
+    /// The calibration of a joystick axis, either measured by `calibrate_axes` or given in
+    /// the command line.
+    #[deriving(PartialEq,Eq,Clone)]
+    pub struct AxisCalibration {
+        /// The index to the joystick device.
+        pub device: uint,
+        /// The index to the axis in the device.
+        pub axis: uint,
+        /// The axis position at rest.
+        pub center: int,
+        /// The distance from `center` within which the axis is in the neutral state.
+        pub deadzone: uint,
+    }
+
+    impl AxisCalibration {
+        /// Parses the deadzone of the axis centered at zero, e.g. `"axis 2=8000"` or
+        /// `"joy 1 axis 2=8000"`.
+        pub fn from_spec(s: &str) -> Option<AxisCalibration> {
+            let mut calib = AxisCalibration { device: 0, axis: 0, center: 0, deadzone: 0 };
+            let mut rest = "";
+            let s = s.trim();
+            let prefixed = lex!(s; lit "joy", ws, uint -> calib.device, ws, str -> rest);
+            let s = if prefixed {rest} else {s};
+            if lex!(s; lit "axis", ws, uint -> calib.axis, ws*, lit '=', ws*,
+                       uint -> calib.deadzone, ws*, !) {
+                Some(calib)
+            } else {
+                None
+            }
+        }
+
+        /// Returns the input state of the axis at given position.
+        pub fn state(&self, pos: i16) -> InputState {
+            let delta = pos as int - self.center;
+            if delta > self.deadzone as int {
+                Positive
+            } else if delta < -(self.deadzone as int) {
+                Negative
+            } else {
+                Neutral
+            }
+        }
+    }
+
+    /// Returns the calibration of given axis from `axes` (the last one wins), or the default
+    /// calibration with `deadzone`.
+    pub fn find_axis_calibration(axes: &[AxisCalibration], deadzone: uint,
+                                 device: uint, axis: uint) -> AxisCalibration {
+        for calib in axes.iter().rev() {
+            if calib.device == device && calib.axis == axis { return calib.clone(); }
+        }
+        AxisCalibration { device: device, axis: axis, center: 0, deadzone: deadzone }
+    }
+
+    /// The time in milliseconds since the last motion of the analog turntable, after which
+    /// the turntable is considered stopped.
+    const TURNTABLE_TIMEOUT: uint = 80;
+    /// The motion of the analog turntable in the axis units which registers the spin. Smaller
+    /// motions in the same direction are accumulated, so that slow scratches are registered too.
+    const TURNTABLE_THRESHOLD: int = 256;
+
+    /**
+     * The state of the analog turntable mapped by `JoyTurntableInput`. Turntable controllers
+     * report the rotation as the axis position, which wraps around, so the direction of motion
+     * is used as the input state instead: the spin in either direction presses the lane, the
+     * reversed spin (e.g. back-spin) presses it again, and the lane is released when the turntable
+     * stops for `TURNTABLE_TIMEOUT`.
+     */
+    pub struct TurntableState {
+        /// The last axis position if any.
+        last: Option<i16>,
+        /// The motion accumulated since the last registered motion, in the axis units.
+        acc: int,
+        /// The direction of the ongoing spin, or `Neutral` if stopped.
+        pub dir: InputState,
+        /// The timestamp of the last registered motion.
+        lastmove: uint,
+    }
+
+    impl TurntableState {
+        /// Returns a state for the turntable at rest.
+        pub fn new() -> TurntableState {
+            TurntableState { last: None, acc: 0, dir: Neutral, lastmove: 0 }
+        }
+
+        /// Updates the state with the new axis position at the timestamp `now`. Returns the new
+        /// direction if it has changed.
+        pub fn update(&mut self, pos: i16, now: uint) -> Option<InputState> {
+            let last = match self.last {
+                Some(last) => last,
+                None => { self.last = Some(pos); return None; }
+            };
+            self.last = Some(pos);
+
+            // the position wraps around, so the shorter way is assumed
+            let mut delta = pos as int - last as int;
+            if delta > 32768 { delta -= 65536; } else if delta < -32768 { delta += 65536; }
+            if delta == 0 { return None; }
+            if (delta > 0) != (self.acc > 0) { self.acc = 0; }
+            self.acc += delta;
+            if num::abs(self.acc) < TURNTABLE_THRESHOLD { return None; }
+
+            let dir = if self.acc > 0 {Positive} else {Negative};
+            self.acc = 0;
+            self.lastmove = now;
+            if dir == self.dir { return None; }
+            self.dir = dir;
+            Some(dir)
+        }
+
+        /// Returns `Some(Neutral)` if the spinning turntable has stopped at the timestamp `now`.
+        pub fn check_stopped(&mut self, now: uint) -> Option<InputState> {
+            if self.dir == Neutral || now < self.lastmove + TURNTABLE_TIMEOUT { return None; }
+            self.dir = Neutral;
+            self.acc = 0;
+            Some(Neutral)
+        }
+    }
+
+    /// An information about an environment variable for multiple keys.
+    struct KeySet {
+        envvar: &'static str,
+        default: &'static str,
+        mapping: &'static [(Option<KeyKind>, &'static [VirtualInput])],
+    }
+
+    /// A list of environment variables that set the mapping for multiple keys, and corresponding
+    /// default values and the order of keys. (C: `envvars`)
+    static KEYSETS: &'static [KeySet] = &[
+        KeySet { envvar: "ANGOLMOIS_1P_KEYS",
+                 default: "left shift%axis 3|z%button 3|s%button 6|x%button 2|d%button 7|\
+                            c%button 1|f%button 4|v%axis 2|left alt",
+                 mapping: &[(Some(parser::Scratch),   &[LaneInput(Lane(6))]),
+                            (Some(parser::WhiteKey),  &[LaneInput(Lane(1))]),
+                            (Some(parser::BlackKey),  &[LaneInput(Lane(2))]),
+                            (Some(parser::WhiteKey),  &[LaneInput(Lane(3))]),
+                            (Some(parser::BlackKey),  &[LaneInput(Lane(4))]),
+                            (Some(parser::WhiteKey),  &[LaneInput(Lane(5))]),
+                            (Some(parser::BlackKey),  &[LaneInput(Lane(8))]),
+                            (Some(parser::WhiteKey),  &[LaneInput(Lane(9))]),
+                            (Some(parser::FootPedal), &[LaneInput(Lane(7))])] },
+        KeySet { envvar: "ANGOLMOIS_2P_KEYS",
+                 default: "right alt|m|k|,|l|.|;|/|right shift",
+                 mapping: &[(Some(parser::FootPedal), &[LaneInput(Lane(36+7))]),
+                            (Some(parser::WhiteKey),  &[LaneInput(Lane(36+1))]),
+                            (Some(parser::BlackKey),  &[LaneInput(Lane(36+2))]),
+                            (Some(parser::WhiteKey),  &[LaneInput(Lane(36+3))]),
+                            (Some(parser::BlackKey),  &[LaneInput(Lane(36+4))]),
+                            (Some(parser::WhiteKey),  &[LaneInput(Lane(36+5))]),
+                            (Some(parser::BlackKey),  &[LaneInput(Lane(36+8))]),
+                            (Some(parser::WhiteKey),  &[LaneInput(Lane(36+9))]),
+                            (Some(parser::Scratch),   &[LaneInput(Lane(36+6))])] },
+        KeySet { envvar: "ANGOLMOIS_PMS_KEYS",
+                 default: "z|s|x|d|c|f|v|g|b",
+                 mapping: &[(Some(parser::Button1), &[LaneInput(Lane(1))]),
+                            (Some(parser::Button2), &[LaneInput(Lane(2))]),
+                            (Some(parser::Button3), &[LaneInput(Lane(3))]),
+                            (Some(parser::Button4), &[LaneInput(Lane(4))]),
+                            (Some(parser::Button5), &[LaneInput(Lane(5))]),
+                            (Some(parser::Button4), &[LaneInput(Lane(8)), LaneInput(Lane(36+2))]),
+                            (Some(parser::Button3), &[LaneInput(Lane(9)), LaneInput(Lane(36+3))]),
+                            (Some(parser::Button2), &[LaneInput(Lane(6)), LaneInput(Lane(36+4))]),
+                            (Some(parser::Button1), &[LaneInput(Lane(7)), LaneInput(Lane(36+5))])]
+               },
+        KeySet { envvar: "ANGOLMOIS_O2JAM_KEYS",
+                 default: "s|d|f|space|j|k|l",
+                 mapping: &[(Some(parser::WhiteKey),    &[LaneInput(Lane(1))]),
+                            (Some(parser::BlackKey),    &[LaneInput(Lane(2))]),
+                            (Some(parser::WhiteKey),    &[LaneInput(Lane(3))]),
+                            (Some(parser::WhiteKeyAlt), &[LaneInput(Lane(4))]),
+                            (Some(parser::WhiteKey),    &[LaneInput(Lane(5))]),
+                            (Some(parser::BlackKey),    &[LaneInput(Lane(8))]),
+                            (Some(parser::WhiteKey),    &[LaneInput(Lane(9))])] },
+        KeySet { envvar: "ANGOLMOIS_SPEED_KEYS",
+                 default: "f3|f4",
+                 mapping: &[(None, &[SpeedDownInput]),
+                            (None, &[SpeedUpInput])] },
+        KeySet { envvar: "ANGOLMOIS_LOOP_KEYS",
+                 default: "f5|f6",
+                 mapping: &[(None, &[LoopStartInput]),
+                            (None, &[LoopEndInput])] },
+        KeySet { envvar: "ANGOLMOIS_RETRY_KEY",
+                 default: "f7",
+                 mapping: &[(None, &[RetryInput])] },
+        KeySet { envvar: "ANGOLMOIS_COVER_KEYS",
+                 default: "f8|f9|f10|f11",
+                 mapping: &[(None, &[CoverUpInput]),
+                            (None, &[CoverDownInput]),
+                            (None, &[LiftUpInput]),
+                            (None, &[LiftDownInput])] },
+        KeySet { envvar: "ANGOLMOIS_SCREENSHOT_KEY",
+                 default: "f12",
+                 mapping: &[(None, &[ScreenshotInput])] },
+    ];
+
+    /// An input mapping, i.e. a mapping from the actual input to the virtual input.
+    pub type KeyMap = HashMap<Input,VirtualInput>;
+
+    /// Finds the virtual input mapped to the actual input from given device. The mapping for
+    /// the device takes precedence over the mapping for any device.
+    fn find_mapping<'r>(keymap: &'r KeyMap, input: &Input) -> Option<&'r VirtualInput> {
+        keymap.find(input).or_else(|| keymap.find(&input.from_any_device()))
+    }
+
+    /// Reads an input mapping from the environment variables. (C: `read_keymap`)
+    pub fn read_keymap(keyspec: &KeySpec,
+                       getenv: |&str| -> Option<String>) -> AngolmoisResult<KeyMap> {
+        use std::ascii::{AsciiExt, OwnedAsciiExt};
+
+        /// Finds an SDL virtual key with the given name. Matching is done case-insensitively.
+        fn sdl_key_from_name(name: &str) -> Option<event::Key> {
+            let name = name.to_ascii_lower();
+            unsafe {
+                let firstkey = 0u16;
+                let lastkey = std::mem::transmute(event::LastKey);
+                for keyidx in range(firstkey, lastkey) {
+                    let key = std::mem::transmute(keyidx);
+                    let keyname = event::get_key_name(key).into_ascii_lower();
+                    if keyname == name { return Some(key); }
+                }
+            }
+            None
+        }
+
+        /// Parses an `Input` value from the string. E.g. `"backspace"`, `"button 2"`, `"axis 0"` or
+        /// `"turntable 0"` (for the analog turntable). Joystick inputs are from any opened device
+        /// unless prefixed like `"joy 1 button 2"`.
+        fn parse_input(s: &str) -> Option<Input> {
+            let mut idx = 0;
+            let mut devidx = 0;
+            let mut rest = "";
+            let mut s = s.trim();
+            let prefixed = lex!(s; lit "joy", ws, uint -> devidx, ws, str -> rest);
+            if prefixed { s = rest; }
+            let dev = if prefixed {Some(devidx)} else {None};
+            if lex!(s; lit "button", ws, uint -> idx) {
+                Some(JoyButtonInput(dev, idx))
+            } else if lex!(s; lit "axis", ws, uint -> idx) {
+                Some(JoyAxisInput(dev, idx))
+            } else if lex!(s; lit "turntable", ws, uint -> idx) {
+                Some(JoyTurntableInput(dev, idx))
+            } else if prefixed {
+                None
+            } else {
+                sdl_key_from_name(s).map(|key| KeyInput(key))
+            }
+        }
+
+        let mut map = HashMap::new();
+        let add_mapping = |map: &mut KeyMap, kind: Option<KeyKind>,
+                           input: Input, vinput: VirtualInput| {
+            if kind.map_or(true, |kind| vinput.active_in_key_spec(kind, keyspec)) {
+                map.insert(input, vinput);
+            }
+        };
+
+        for &keyset in KEYSETS.iter() {
+            let spec = getenv(keyset.envvar);
+            let spec = spec.unwrap_or(keyset.default.to_string());
+
+            let mut i = 0;
+            for part in spec[].split('|') {
+                let (kind, vinputs) = keyset.mapping[i];
+                for s in part.split('%') {
+                    match parse_input(s) {
+                        Some(input) => {
+                            for &vinput in vinputs.iter() {
+                                add_mapping(&mut map, kind, input, vinput);
+                            }
+                        }
+                        None => {
+                            return Err(InvalidKeyMap(format!("Unknown key name in the environment \
+                                                              variable {}: {}", keyset.envvar, s)));
+                        }
+                    }
+                }
+
+                i += 1;
+                if i >= keyset.mapping.len() { break; }
+            }
+        }
+
+        for &lane in keyspec.order.iter() {
+            let key = Key(36 + *lane as int);
+            let kind = keyspec.kinds[*lane].unwrap();
+            let envvar = format!("ANGOLMOIS_{}{}_KEY", key, kind.to_char());
+            for s in getenv(envvar[]).iter() {
+                match parse_input(s[]) {
+                    Some(input) => { add_mapping(&mut map, Some(kind), input, LaneInput(lane)); }
+                    None => {
+                        return Err(InvalidKeyMap(format!("Unknown key name in the environment \
+                                                          variable {}: {}", envvar, *s)));
+                    }
+                }
+            }
+        }
+
+        Ok(map)
+    }
+
+    //----------------------------------------------------------------------------------------------
+    // resource management
+
+    /// Alternative file extensions for sound resources. (C: `SOUND_EXTS`)
+    pub static SOUND_EXTS: &'static [&'static str] =
+        &[".WAV", ".OGG", ".MP3", ".FLAC", ".OPUS"];
+    /// Alternative file extensions for image resources. (C: `IMAGE_EXTS`)
+    pub static IMAGE_EXTS: &'static [&'static str] = &[".BMP", ".PNG", ".JPG", ".JPEG", ".GIF"];
+
+    /// Returns a specified or implied resource directory from the BMS file.
+    pub fn get_basedir(bms: &Bms, opts: &Options) -> Path {
+        // TODO this logic assumes that #PATH_WAV is always interpreted as a native path, which
+        // the C version doesn't assume. this difference barely makes the practical issue though.
+        match bms.basepath {
+            Some(ref basepath) => { Path::new(basepath[]) }
+            None => {
+                // Rust: it turns out that `Path("")` is always invalid. huh?
+                let path = Path::new(opts.bmspath[]).dir_path();
+                if path.components().count() == 0 {Path::new(".")} else {path}
+            }
+        }
+    }
+
+    /// Returns the list of directories searched for resources, which is the resource directory
+    /// from `get_basedir` followed by `Options::searchdirs`.
+    pub fn get_basedirs(bms: &Bms, opts: &Options) -> Vec<Path> {
+        let mut basedirs = vec!(get_basedir(bms, opts));
+        basedirs.extend(opts.searchdirs.iter().map(|dir| Path::new(dir[])));
+        basedirs
+    }
+
+    /**
+     * Resolves the specified resource path to the actual path if possible. May fail, but its
+     * success doesn't guarantee that the resource should be read without a failure either.
+     * (C: `resolve_relative_path`)
+     *
+     * The actual resolution is complicated by the fact that many BMSes assume the case-insensitive
+     * matching on file names and the coexistence between WAV resources and MP3 resources while
+     * keeping the same BMS file. Therefore Angolmois adopted the following resolution rules:
+     *
+     * 1. Both `/` and `\` are accepted as a directory separator.
+     * 2. Path components including file names are matched case-insensitively. If there are multiple
+     *    matches then any one can be used, even when a better match exists.
+     * 3. If the initial match on the file name fails, and the file name does contain an extension,
+     *    then a list of alternative extensions is applied with the same matching procedure.
+     * 4. `..` and `.` components refer to the parent and current directory respectively, except
+     *    inside the archive.
+     * 5. Directories in `basedirs` are tried in order, and the first match is used.
+     */
+    pub fn resolve_relative_path(basedirs: &[Path], path: &str, exts: &[&str]) -> Option<Path> {
+        basedirs.iter().filter_map(|basedir| resolve_path_in(basedir, path, exts)).next()
+    }
+
+    /**
+     * A case-insensitive index of the entries in a single directory, used by
+     * `resolve_relative_path`.
+     *
+     * `std::io::fs::readdir` is different from C's `dirent.h`, as it always reads the whole list
+     * of entries (and `std::io::fs::Directories` is no different). This causes a serious slowdown
+     * compared to the C version of Angolmois especially on network drives, so each directory is
+     * read and case-folded only when a path first goes through it, and the index is shared by
+     * every lookup afterwards via `dir_index`.
+     *
+     * Paths in the chart are often in Shift_JIS while file names on the disk are in UTF-8 (or
+     * vice versa). The parser decodes Shift_JIS lines, and file names not in UTF-8 are decoded
+     * in the same way (see `::util::encoding::decode_text`), so that both are compared as
+     * Unicode. The actual path keeps the original bytes of the file name.
+     */
+    pub struct DirIndex {
+        /// Maps uppercased file names to actual paths.
+        paths: HashMap<String,Path>,
+        /// Same as `paths` but without the last extension, for trying alternative extensions.
+        /// Entries without an extension are not included.
+        stems: HashMap<String,Path>,
+    }
+
+    impl DirIndex {
+        /// Reads the directory at `dir`. An unreadable directory is indexed as empty.
+        pub fn new(dir: &Path) -> DirIndex {
+            use std::io;
+            use std::ascii::AsciiExt;
+
+            let mut index = DirIndex { paths: HashMap::new(), stems: HashMap::new() };
+            let entries = io::fs::readdir(dir).ok().unwrap_or(Vec::new());
+            for entry in entries.into_iter() {
+                let name = match entry.filename() {
+                    Some(name) => ::util::encoding::decode_text(name).as_slice().to_ascii_upper(),
+                    None => { continue; }
+                };
+
+                // if there are multiple matches any one can be used, so the first one is kept
+                for &idx in name[].rfind('.').iter() {
+                    let stem = name[..idx].to_string();
+                    if !index.stems.contains_key(&stem) { index.stems.insert(stem, entry.clone()); }
+                }
+                if !index.paths.contains_key(&name) { index.paths.insert(name, entry); }
+            }
+            index
+        }
+
+        /// Finds the entry with given uppercased file name, trying alternative extensions in
+        /// `exts` if the name itself is missing.
+        pub fn find(&self, name: &str, exts: &[&str]) -> Option<Path> {
+            match self.paths.find(&name.to_string()) {
+                Some(path) => { return Some(path.clone()); }
+                None => {}
+            }
+            for ext in exts.iter() {
+                if !name.ends_with(*ext) { continue; }
+                let stem = name[..name.len() - ext.len()].to_string();
+                match self.stems.find(&stem) {
+                    Some(path) => { return Some(path.clone()); }
+                    None => {}
+                }
+            }
+            None
+        }
+    }
+
+    /// The cache of directory indices keyed by the directory path, shared by every task.
+    pub type DirCache = Arc<Mutex<HashMap<Path,Arc<DirIndex>>>>;
+
+    /// Returns the process-wide `DirCache`, so that the linter, the loading screen, the resource
+    /// loading and `LazySounds` never read the same directory twice.
+    pub fn dir_cache() -> DirCache {
+        use std::mem;
+        use std::sync::{Once, ONCE_INIT};
+
+        static mut CACHE: *const DirCache = 0 as *const DirCache;
+        static INIT: Once = ONCE_INIT;
+        unsafe {
+            INIT.doit(|| {
+                let cache: Box<DirCache> = box Arc::new(Mutex::new(HashMap::new()));
+                CACHE = mem::transmute(cache);
+            });
+            (*CACHE).clone()
+        }
+    }
+
+    /// Returns the index of the directory at `dir` from given cache, reading it if not cached.
+    pub fn dir_index(cache: &DirCache, dir: &Path) -> Arc<DirIndex> {
+        match cache.lock().find(dir) {
+            Some(index) => { return index.clone(); }
+            None => {}
+        }
+        // the lock is not held while reading, so that other tasks are not blocked by slow drives.
+        // a directory read concurrently by two tasks is simply indexed twice.
+        let index = Arc::new(DirIndex::new(dir));
+        cache.lock().insert(dir.clone(), index.clone());
+        index
+    }
+
+    /// Resolves the specified resource path in a single directory. See `resolve_relative_path`.
+    fn resolve_path_in(basedir: &Path, path: &str, exts: &[&str]) -> Option<Path> {
+        use std::ascii::AsciiExt;
+
+        let mut parts = Vec::new();
+        for part in path.split(|c: char| c == '/' || c == '\\') {
+            if part.is_empty() { continue; }
+            parts.push(part);
+        }
+        if parts.is_empty() { return None; }
+
+        // the resource inside the archive is resolved to the path continuing from the archive
+        // path, so that `load_chunk` and `load_surface` can read it back.
+        match ::zip::split_archive_path(basedir.as_str().unwrap_or("")) {
+            Some((archive, inner)) => {
+                let name = if inner.is_empty() {parts.connect("/")}
+                           else {inner + "/" + parts.connect("/")[]};
+                let ret = ::zip::with_archive(&archive, |zip| {
+                    zip.find(name[], exts).map(|entry| archive.join(entry.name[]))
+                });
+                return ret.ok().and_then(|ret| ret);
+            }
+            None => {}
+        }
+
+        // `..` components leaving the directory move the indexed directory instead
+        let mut base = basedir.clone();
+        let mut relparts = Vec::new();
+        for &part in parts.iter() {
+            match part {
+                "." => {}
+                ".." => { if relparts.pop().is_none() { base = base.join(".."); } }
+                _ => { relparts.push(part.to_ascii_upper()); }
+            }
+        }
+        if relparts.is_empty() { return None; }
+
+        // every directory in the path is indexed only when the path goes through it
+        let cache = dir_cache();
+        for dir in relparts.init().iter() {
+            base = match dir_index(&cache, &base).find(dir[], []) {
+                Some(path) => path,
+                None => { return None; }
+            };
+        }
+        dir_index(&cache, &base).find(relparts.last().unwrap()[], exts)
+    }
+
+    /// Minimal bindings for loading resources from the memory.
+    mod rwops {
+        use libc::{c_void, c_int};
+        use sdl::video::ll::{SDL_RWops, SDL_Surface};
+        use sdl_mixer::ll::Mix_Chunk;
+
+        #[link(name = "SDL")]
+        extern {
+            pub fn SDL_RWFromConstMem(mem: *const c_void, size: c_int) -> *mut SDL_RWops;
+        }
+        #[link(name = "SDL_mixer")]
+        extern {
+            pub fn Mix_Init(flags: c_int) -> c_int;
+            pub fn Mix_QuerySpec(frequency: *mut c_int, format: *mut u16,
+                                 channels: *mut c_int) -> c_int;
+            pub fn Mix_LoadWAV_RW(src: *mut SDL_RWops, freesrc: c_int) -> *mut Mix_Chunk;
+            pub fn Mix_FreeChunk(chunk: *mut Mix_Chunk);
+        }
+        #[link(name = "SDL_image")]
+        extern {
+            pub fn IMG_Load_RW(src: *mut SDL_RWops, freesrc: c_int) -> *mut SDL_Surface;
+        }
+    }
+
+    /// Returns true if the sound resource at given path is an Opus file, which is decoded by
+    /// libopusfile instead of SDL_mixer.
+    fn is_opus_path(path: &str) -> bool {
+        use std::ascii::AsciiExt;
+        path.to_ascii_lower()[].ends_with(".opus")
+    }
+
+    /// Decodes the contents of an Opus file into samples in the mixer format. Samples are
+    /// linearly resampled to the mixer rate.
+    fn decode_opus(data: &[u8]) -> Result<Vec<u8>,String> {
+        use util::opus;
+
+        let samples = try!(opus::decode(data));
+        let nsrc = samples.len() / 2;
+        if nsrc == 0 { return Ok(Vec::new()); }
+        let (srcrate, dstrate) = (opus::OPUS_RATE as u64, sample_rate() as u64);
+        let ndst = (nsrc as u64 * dstrate / srcrate) as uint;
+        let mut buf = Vec::with_capacity(ndst * 4);
+        for i in range(0, ndst) {
+            let pos = i as u64 * srcrate;
+            let j = (pos / dstrate) as uint;
+            let frac = (pos % dstrate) as i64;
+            for ch in range(0u, 2) {
+                let a = samples[j*2+ch] as i64;
+                let b = if j + 1 < nsrc {samples[(j+1)*2+ch] as i64} else {a};
+                let sample = (a + (b - a) * frac / dstrate as i64) as i16;
+                // the mixer format is in the native byte order
+                let bytes: [u8, ..2] = unsafe { std::mem::transmute(sample) };
+                buf.push_all(bytes[]);
+            }
+        }
+        Ok(buf)
+    }
+
+    /// Decodes a sound file from the resolved path, which may be inside the archive, into samples
+    /// in the mixer format. This does not create any SDL object owned by the task, so it can be
+    /// called from worker tasks.
+    fn decode_chunk(pathstr: &str) -> Result<Vec<u8>,String> {
+        let data = try!(::zip::read_file(pathstr).map_err(|err| err.to_string()));
+        if is_opus_path(pathstr) { return decode_opus(data[]); }
+        let raw = unsafe {
+            let src = rwops::SDL_RWFromConstMem(data.as_ptr() as *const libc::c_void,
+                                                data.len() as libc::c_int);
+            rwops::Mix_LoadWAV_RW(src, 1)
+        };
+        if raw.is_null() { return Err(sdl::get_error()); }
+
+        // Rust: `Chunk` cannot take the ownership of the raw chunk, so the samples are copied.
+        let buf = unsafe {
+            let buf = slice::raw::buf_as_slice((*raw).abuf as *const u8, (*raw).alen as uint,
+                                               |buf| buf.to_vec());
+            rwops::Mix_FreeChunk(raw);
+            buf
+        };
+        Ok(buf)
+    }
+
+    /**
+     * Time-stretches samples in the mixer format so that they are played `rate` times as fast,
+     * without changing the pitch. This uses the waveform similarity overlap-add (WSOLA) method:
+     * windowed segments are taken at the scaled positions, shifted within a small range so that
+     * they best continue the previous segment, and added with the fixed overlap.
+     *
+     * XXX the similarity search is coarse for the speed, so tonal sounds may slightly warble.
+     */
+    fn stretch_samples(data: Vec<u8>, rate: f64) -> Vec<u8> {
+        if rate == 1.0 { return data; }
+        let nframes = data.len() / 4;
+        if nframes == 0 { return data; }
+        let input: Vec<i16> = unsafe {
+            slice::raw::buf_as_slice(data.as_ptr() as *const i16, nframes * 2, |buf| buf.to_vec())
+        };
+        let at = |frame: int, ch: uint| -> f32 {
+            if frame < 0 || frame as uint >= nframes {0.0}
+            else {input[frame as uint * 2 + ch] as f32}
+        };
+
+        // 40 ms windows with the half overlap, and the search range of 10 ms
+        let window = cmp::max(sample_rate() as uint / 25, 16) & !1;
+        let synhop = window / 2;
+        let tolerance = window as int / 4;
+        let weights = Vec::from_fn(window, |i| {
+            0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / window as f32).cos()
+        });
+
+        let nout = (nframes as f64 / rate) as uint;
+        let mut acc = Vec::from_elem((nout + window) * 2, 0.0f32);
+        let mut norm = Vec::from_elem(nout + window, 0.0f32);
+        let mut prev = 0i; // the start of the previous segment in the input
+        let mut k = 0u;
+        while k * synhop < nout {
+            let nominal = (k as f64 * synhop as f64 * rate) as int;
+            let mut start = nominal;
+            if k > 0 {
+                // find the segment most similar to the natural continuation of the previous one
+                let natural = prev + synhop as int;
+                let mut best = std::f32::NEG_INFINITY;
+                let mut delta = -tolerance;
+                while delta <= tolerance {
+                    let mut corr = 0.0f32;
+                    let mut i = 0;
+                    while i < synhop as int {
+                        let a = at(natural + i, 0) + at(natural + i, 1);
+                        let b = at(nominal + delta + i, 0) + at(nominal + delta + i, 1);
+                        corr += a * b;
+                        i += 4;
+                    }
+                    if corr > best { best = corr; start = nominal + delta; }
+                    delta += 8;
+                }
+            }
+            let base = k * synhop;
+            for i in range(0, window) {
+                let w = weights[i];
+                acc[mut][(base + i) * 2] += at(start + i as int, 0) * w;
+                acc[mut][(base + i) * 2 + 1] += at(start + i as int, 1) * w;
+                norm[mut][base + i] += w;
+            }
+            prev = start;
+            k += 1;
+        }
+
+        let mut buf = Vec::with_capacity(nout * 4);
+        for i in range(0, nout * 2) {
+            let w = norm[i / 2];
+            let sample = if w > 0.01 {acc[i] / w} else {0.0};
+            let sample = if sample > 32767.0 {32767} else if sample < -32768.0 {-32768}
+                         else {sample as i16};
+            // the mixer format is in the native byte order
+            let bytes: [u8, ..2] = unsafe { std::mem::transmute(sample) };
+            buf.push_all(bytes[]);
+        }
+        buf
+    }
+
+    /// Loads a sound chunk from the resolved path, which may be inside the archive.
+    fn load_chunk(path: &Path) -> Result<Chunk,String> {
+        let pathstr = path.as_str().unwrap_or("");
+        if ::zip::split_archive_path(pathstr).is_none() && !is_opus_path(pathstr) {
+            return Chunk::from_wav(path);
+        }
+        decode_chunk(pathstr).map(|buf| Chunk::new(buf, 128))
+    }
+
+    /// Loads an image from the resolved path, which may be inside the archive.
+    fn load_surface(path: &Path) -> Result<Surface,String> {
+        let pathstr = path.as_str().unwrap_or("");
+        if ::zip::split_archive_path(pathstr).is_none() { return sdl_image::load(path); }
+
+        let data = try!(::zip::read_file(pathstr).map_err(|err| err.to_string()));
+        surface_from_data(data[])
+    }
+
+    /// Loads an image from the contents of the image file.
+    fn surface_from_data(data: &[u8]) -> Result<Surface,String> {
+        let raw = unsafe {
+            let src = rwops::SDL_RWFromConstMem(data.as_ptr() as *const libc::c_void,
+                                                data.len() as libc::c_int);
+            rwops::IMG_Load_RW(src, 1)
+        };
+        if raw.is_null() { return Err(sdl::get_error()); }
+        Ok(Surface { raw: raw, owned: true })
+    }
+
+    /// Loads an animated GIF or APNG image from the contents `data` of the image file at `path`,
+    /// as a list of surfaces and frame durations. Returns `None` if the image is not animated, so
+    /// that it can be loaded with `surface_from_data` instead. The surfaces have the alpha channel
+    /// only when some pixel is not opaque.
+    fn load_animation(path: &Path, data: &[u8]) -> Result<Option<Vec<(Surface, uint)>>,String> {
+        use std::ascii::AsciiExt;
+
+        let lower = path.as_str().unwrap_or("").to_ascii_lower();
+        let anim = if lower[].ends_with(".gif") {
+            try!(::anim::decode_gif(data))
+        } else if lower[].ends_with(".png") {
+            try!(::anim::decode_apng(data))
+        } else {
+            None
+        };
+        let anim = match anim {
+            Some(anim) => anim,
+            None => { return Ok(None); }
+        };
+
+        let amask = if anim.has_alpha() {0xff000000} else {0};
+        let mut frames = Vec::new();
+        for frame in anim.frames.iter() {
+            let surface = try!(Surface::new([video::SWSurface], anim.width as int,
+                                            anim.height as int, 32,
+                                            0xff0000, 0xff00, 0xff, amask));
+            surface.with_pixels(|pixels| {
+                for y in range(0, anim.height) {
+                    for x in range(0, anim.width) {
+                        let c = frame.pixels[y * anim.width + x];
+                        pixels.put_pixel(x, y, RGBA((c >> 16) as u8, (c >> 8) as u8, c as u8,
+                                                    (c >> 24) as u8));
+                    }
+                }
+            });
+            frames.push((surface, frame.delay));
+        }
+        Ok(Some(frames))
+    }
+
+    /// Sound resource associated to `SoundRef`. It contains the actual SDL_mixer chunk that can be
+    /// readily played. (C: the type of `sndres`)
+    pub enum SoundResource {
+        /// No sound resource is associated, or error occurred while loading.
+        NoSound,
+        /// Sound resource is associated.
+        Sound(Chunk)
+    }
+
+    impl SoundResource {
+        /// Creates a placeholder tick of given length in milliseconds, used in place of missing
+        /// sound resources. See `Options::placeholdersound`.
+        pub fn placeholder(msec: uint) -> SoundResource {
+            // stereo, 16 bits/sample
+            let nframes = cmp::max(msec * (bytes_per_sec() as uint / 4) / 1000, 1);
+            let samples: Vec<i16> = Vec::from_fn(nframes * 2,
+                // square wave at 1225 Hz (for 44.1 kHz), linear decay
+                |i| {
+                    let t = i / 2;
+                    let amp = (if t % 36 < 18 {16} else {-16}) * (nframes - t) as int;
+                    (amp * 1200 / nframes as int) as i16
+                });
+            let chunk = unsafe {
+                slice::raw::buf_as_slice(samples.as_ptr() as *const u8, samples.len() * 2,
+                                         |samples| sdl_mixer::Chunk::new(samples.to_vec(), 128))
+            };
+            Sound(chunk)
+        }
+
+        /// Returns the associated chunk if any.
+        pub fn chunk<'r>(&'r self) -> Option<&'r Chunk> {
+            match *self {
+                NoSound => None,
+                Sound(ref chunk) => Some(chunk)
+            }
+        }
+
+        /// Returns the associated chunk if any.
+        pub fn mut_chunk<'r>(&'r mut self) -> Option<&'r mut Chunk> {
+            match *self {
+                NoSound => None,
+                Sound(ref mut chunk) => Some(chunk)
+            }
+        }
+
+        /// Returns the length of associated sound chunk in seconds. This is used for determining
+        /// the actual duration of the song in presence of key and background sounds, so it may
+        /// return 0.0 if no sound is present.
+        pub fn duration(&self) -> f64 {
+            match *self {
+                NoSound => 0.0,
+                Sound(ref chunk) => {
+                    let chunk = chunk.to_ll_chunk();
+                    (unsafe {(*chunk).alen} as f64) / (bytes_per_sec() as f64)
+                }
+            }
+        }
+    }
+
+    /// Image resource associated to `ImageRef`. It can be either a static image, an animated image
+    /// or a movie, and all of them contain an SDL surface that can be blitted to the screen.
+    /// (C: the type of `imgres`)
+    pub enum ImageResource {
+        /// No image resource is associated, or error occurred while loading.
+        NoImage,
+        /// A static image is associated. The surface may have a transparency which is already
+        /// handled by `load_image`.
+        Image(Surface),
+        /// A movie is associated. A playback starts when `start_movie` method is called, and stops
+        /// when `stop_movie` is called. An associated surface is updated from the separate thread
+        /// during the playback. The audio track is mixed with given volume out of 128 if any.
+        Movie(Surface, MPEG, Option<uint>),
+        /// An animated GIF or APNG image is associated. Each frame comes with its duration in
+        /// milliseconds, and the animation loops from the time given by `start_movie` method.
+        Animation(Vec<(Surface, uint)>, Cell<uint>),
+    }
+
+    impl ImageResource {
+        /// Returns an associated surface if any.
+        pub fn surface<'r>(&'r self) -> Option<&'r Surface> {
+            match *self {
+                NoImage => None,
+                Image(ref surface) | Movie(ref surface,_,_) => Some(surface),
+                Animation(ref frames,_) => { let (ref surface, _) = frames[0]; Some(surface) }
+            }
+        }
+
+        /// Returns an associated surface to be displayed at given time (in milliseconds, as
+        /// returned by `sdl::get_ticks`) if any. Only differs from `surface` for animated images.
+        pub fn surface_at<'r>(&'r self, now: uint) -> Option<&'r Surface> {
+            match *self {
+                Animation(ref frames, ref start) => {
+                    let total = frames.iter().fold(0, |sum, &(_, delay)| sum + delay);
+                    let mut offset = (now - cmp::min(now, start.get())) % total;
+                    for &(ref surface, delay) in frames.iter() {
+                        if offset < delay { return Some(surface); }
+                        offset -= delay;
+                    }
+                    self.surface()
+                }
+                _ => self.surface()
+            }
+        }
+
+        /// Stops the movie playback if possible.
+        pub fn stop_movie(&self) {
+            match *self {
+                NoImage | Image(_) | Animation(..) => {}
+                Movie(_,ref mpeg,volume) => {
+                    if volume.is_some() { mixer::remove_stream(mpeg.raw as *mut libc::c_void); }
+                    mpeg.stop();
+                }
+            }
+        }
+
+        /// Starts (or restarts, if the movie was already being played) the movie playback
+        /// if possible.
+        pub fn start_movie(&self) {
+            match *self {
+                NoImage | Image(_) => {}
+                Movie(_,ref mpeg,volume) => {
+                    mpeg.rewind();
+                    mpeg.play();
+                    // the stream is removed by `stop_movie` or the destructor, before MPEG is freed
+                    for &volume in volume.iter() {
+                        unsafe {
+                            mixer::add_stream(fill_movie_audio, mpeg.raw as *mut libc::c_void,
+                                              volume);
+                        }
+                    }
+                }
+                Animation(_,ref start) => { start.set(sdl::get_ticks()); }
+            }
+        }
+    }
+
+    impl Drop for ImageResource {
+        /// Stops mixing the audio track of the movie, which would otherwise be pulled from
+        /// the freed MPEG by the audio callback.
+        fn drop(&mut self) {
+            match *self {
+                Movie(_,ref mpeg,Some(_)) => {
+                    mixer::remove_stream(mpeg.raw as *mut libc::c_void);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Pulls the samples of the movie audio track for `mixer::add_stream`.
+    unsafe extern "C" fn fill_movie_audio(mpeg: *mut libc::c_void, stream: *mut u8,
+                                          len: libc::c_int) -> libc::c_int {
+        smpeg::ll::SMPEG_playAudio(mpeg as *mut smpeg::ll::SMPEG, stream, len)
+    }
+
+    /// Converts a surface to the native display format, while preserving a transparency or
+    /// setting a color key if required.
+    fn to_display_format(surface: Surface) -> Result<Surface,String> {
+        if unsafe {(*(*surface.raw).format).Amask} != 0 {
+            let res = surface.display_format_alpha();
+            match res {
+                Ok(ref surface) => {
+                    surface.set_alpha([video::SrcAlpha, video::RLEAccel], 255);
+                }
+                _ => {}
+            }
+            res
+        } else {
+            let res = surface.display_format();
+            match res {
+                Ok(ref surface) => {
+                    surface.set_color_key([video::SrcColorKey, video::RLEAccel], RGB(0,0,0));
+                }
+                _ => {}
+            }
+            res
+        }
+    }
+
+    /// Decodes an image resource other than movies from the contents `data` of the image file at
+    /// the resolved path.
+    fn decode_image(path: &Path, data: &[u8]) -> Result<ImageResource,String> {
+        match try!(load_animation(path, data)) {
+            Some(frames) => {
+                let frames = frames.into_iter().map(|(surface, delay)| {
+                    to_display_format(surface).map(|surface| (surface, delay))
+                }).collect::<Result<Vec<(Surface,uint)>,String>>();
+                frames.map(|frames| Animation(frames, Cell::new(0)))
+            }
+            None => surface_from_data(data).and_then(|surface| {
+                to_display_format(surface).and_then(|surface| Ok(Image(surface)))
+            })
+        }
+    }
+
+    /// Returns true if the image resource at given path should be loaded as a movie.
+    fn is_movie_path(path: &str) -> bool {
+        use std::ascii::AsciiExt;
+        path.to_ascii_lower()[].ends_with(".mpg")
+    }
+
+    /// Loads an image resource.
+    fn load_image(key: Key, path: &str, opts: &Options, basedirs: &[Path]) -> ImageResource {
+        if is_movie_path(path) {
+            if opts.has_movie() {
+                let res = match resolve_relative_path(basedirs, path, []) {
+                    Some(fullpath) => MPEG::from_path(&fullpath),
+                    None => Err(format!("not found"))
+                };
+                let res = res.and_then(|movie| {
+                    gfx::new_surface(BGAW, BGAH).map(|surface| (surface, movie))
+                });
+                match res {
+                    Ok((surface, movie)) => {
+                        movie.enable_video(true);
+                        movie.set_loop(true);
+                        movie.set_display(&surface);
+                        let samplerate = sample_rate() as uint;
+                        let volume = opts.movievolume.and_then(|volume| {
+                            if movie.set_audio_rate(samplerate) {Some(volume)} else {None}
+                        });
+                        movie.enable_audio(volume.is_some());
+                        return Movie(surface, movie, volume);
+                    }
+                    Err(_) => { warn!("failed to load image \\#BMP{} ({})", key, path); }
+                }
+            }
+        } else if opts.has_bga() {
+            let res = match resolve_relative_path(basedirs, path, IMAGE_EXTS) {
+                Some(fullpath) => {
+                    let data = ::zip::read_file(fullpath.as_str().unwrap_or(""));
+                    let data = data.map_err(|err| err.to_string());
+                    data.and_then(|data| decode_image(&fullpath, data[]))
+                }
+                None => Err(format!("not found"))
+            };
+            match res {
+                Ok(res) => { return res; },
+                Err(_) => { warn!("failed to load image \\#BMP{} ({})", key, path); }
+            }
+        }
+        NoImage
+    }
+
+    /// Applies the blit command to given list of image resources. (C: a part of `load_resource`)
+    fn apply_blitcmd(imgres: &mut [ImageResource], bc: &BlitCmd) {
+        use std::mem;
+
+        let src = **bc.src as uint;
+        let dst = **bc.dst as uint;
+        if src == dst { return; }
+
+        match imgres[src] {
+            Image(..) => {}
+            _ => { return; }
+        }
+        match imgres[dst] {
+            Image(..) => {}
+            NoImage => {
+                let surface = match gfx::new_surface(BGAW, BGAH) {
+                    Ok(surface) => surface,
+                    Err(_) => { warn!("failed to create image \#BMP{}", *bc.dst); return; }
+                };
+                surface.fill(RGB(0, 0, 0));
+                surface.set_color_key([video::SrcColorKey, video::RLEAccel], RGB(0, 0, 0));
+                imgres[dst] = Image(surface);
+            }
+            _ => { return; }
+        }
+
+        // temporarily swap imgres[src], otherwise it will cause an error
+        let savedorigin = mem::replace(&mut imgres[src], NoImage);
+        {
+            let origin = savedorigin.surface().unwrap();
+            let target = imgres[dst].surface().unwrap();
+
+            let x1 = cmp::max(bc.x1, 0);
+            let y1 = cmp::max(bc.y1, 0);
+            let x2 = cmp::min(bc.x2, bc.x1 + BGAW as int);
+            let y2 = cmp::min(bc.y2, bc.y1 + BGAH as int);
+            target.blit_area(origin, (x1,y1), (bc.dx,bc.dy), (x2-x1,y2-y1));
+        }
+        imgres[src] = savedorigin;
+    }
+
+    /// A list of image references displayed in BGA layers (henceforth the BGA state). Not all image
+    /// referenced here is directly rendered, but the references themselves are kept.
+    pub type BGAState = [Option<ImageRef>, ..NLAYERS];
+
+    /// Returns the initial BGA state. Note that merely setting a particular layer doesn't start
+    /// the movie playback; `poorbgafix` in `parser::parse` function handles it.
+    pub fn initial_bga_state() -> BGAState {
+        [None, None, None, Some(ImageRef(Key(0)))]
+    }
+
+    /// A trait for BGA state.
+    trait BGAStateOps {
+        /// Updates the BGA state. This method prepares given image resources for the next
+        /// rendering, notably by starting and stopping the movie playback.
+        fn update(&mut self, current: &BGAState, imgres: &[ImageResource]);
+        /// Renders the image resources for the specified layers to the specified region of
+        /// `canvas`. Animated images show the frame for given time `now`.
+        fn render(&self, canvas: &Canvas, layers: &[BGALayer], imgres: &[ImageResource],
+                  x: uint, y: uint, now: uint);
+    }
+
+    impl BGAStateOps for BGAState {
+        fn update(&mut self, current: &BGAState, imgres: &[ImageResource]) {
+            for layer in range(0, NLAYERS) {
+                // TODO this design can't handle the case that a BGA layer is updated to the same
+                // image reference, which should rewind the movie playback. the original Angolmois
+                // does handle it.
+                if self[layer] != current[layer] {
+                    for &iref in self[layer].iter() {
+                        imgres[**iref as uint].stop_movie();
+                    }
+                    for &iref in current[layer].iter() {
+                        imgres[**iref as uint].start_movie();
+                    }
+                }
+            }
+            *self = *current;
+        }
+
+        fn render(&self, canvas: &Canvas, layers: &[BGALayer], imgres: &[ImageResource],
+                  x: uint, y: uint, now: uint) {
+            canvas.fill_area((x,y), (256u,256u), RGB(0,0,0));
+            for &layer in layers.iter() {
+                for &iref in self[layer as uint].iter() {
+                    let res = &imgres[**iref as uint];
+                    for &surface in res.surface_at(now).iter() {
+                        // movies are updated in the background
+                        match *res { Movie(..) => canvas.refresh(surface), _ => {} }
+                        canvas.blit_area(surface, (0u,0u), (x,y), (256u,256u));
+                    }
+                }
+            }
+        }
+    }
+
+    //----------------------------------------------------------------------------------------------
+    // song selection
+
+    /// File extensions recognized as charts in the song selection screen and ZIP archives.
+    pub static CHART_EXTS: &'static [&'static str] =
+        &[".BMS", ".BME", ".BML", ".PMS", ".OSU", ".OJN"];
+
+    /// An entry in the song selection screen.
+    struct SelectEntry {
+        /// A path to the directory or chart file.
+        path: Path,
+        /// True if the entry is a directory.
+        isdir: bool,
+        /// The displayed name, i.e. the directory name or the chart title.
+        label: String,
+    }
+
+    /// Lists subdirectories and charts in the directory. Charts are listed after subdirectories
+    /// with their titles from `titles` (keyed by the path), or their file names if not found.
+    /// No chart is parsed. The parent directory is always listed first.
+    fn list_select_entries(dir: &Path, titles: &HashMap<String, String>) -> Vec<SelectEntry> {
+        use std::{io, str};
+        use std::ascii::AsciiExt;
+        use std::io::fs::PathExtensions;
+
+        let mut dirs = Vec::new();
+        let mut charts = Vec::new();
+        let mut paths = io::fs::readdir(dir).ok().unwrap_or(Vec::new());
+        paths.sort();
+        for path in paths.into_iter() {
+            let name = match path.filename().and_then(str::from_utf8) {
+                Some(name) => name.to_string(),
+                None => { continue; }
+            };
+            if name[].starts_with(".") { continue; }
+            if path.is_dir() {
+                dirs.push(SelectEntry { path: path, isdir: true, label: name + "/" });
+            } else {
+                let uppername = name[].to_ascii_upper();
+                if !CHART_EXTS.iter().any(|ext| uppername[].ends_with(*ext)) { continue; }
+                let title = path.as_str().and_then(|s| titles.find_equiv(&s));
+                let label = match title {
+                    Some(title) => format!("{} ({})", title, name),
+                    None => name,
+                };
+                charts.push(SelectEntry { path: path, isdir: false, label: label });
+            }
+        }
+
+        let mut entries = vec!(SelectEntry { path: dir.dir_path(), isdir: true,
+                                             label: "../".to_string() });
+        entries.extend(dirs.into_iter());
+        entries.extend(charts.into_iter());
+        entries
+    }
+
+    /// The number of entries displayed at once in the song selection screen.
+    const SELECT_ROWS: uint = (SCREENH - 60) / 16;
+
+    /**
+     * Shows the song selection screen starting from the directory `dir`, and returns a path to
+     * the selected chart. Returns `None` if the user quits the screen. The screen should be
+     * initialized with `init_video`.
+     *
+     * Chart titles are read from the song library rooted at `dir` (see `library::scan_library`),
+     * which only reads the headers of new or modified charts. Charts are parsed only after
+     * being selected.
+     *
+     * Up and down keys move the cursor, page up and down keys move by a page, and the return key
+     * enters the directory or selects the chart. The backspace key moves to the parent directory.
+     */
+    pub fn select_chart(screen: &Surface, font: &Font, dir: Path) -> Option<String> {
+        use std::os;
+        use sdl::event::{UpKey, DownKey, PageUpKey, PageDownKey, ReturnKey, BackspaceKey};
+
+        let mut dir = os::make_absolute(&dir);
+        let mut titles = HashMap::new();
+        match ::library::scan_library(&dir) {
+            Ok(library) => {
+                for entry in library.into_iter() {
+                    match entry.title {
+                        Some(title) => { titles.insert(entry.path, title); }
+                        None => {}
+                    }
+                }
+            }
+            Err(err) => { warn!("{}", err); }
+        }
+        let mut entries = list_select_entries(&dir, &titles);
+        let mut cursor = 0u;
+        loop {
+            // keeps the cursor in the middle of the page if possible
+            let top = if cursor < SELECT_ROWS / 2 {0} else {cursor - SELECT_ROWS / 2};
+            let top = cmp::max(0, cmp::min(top as int,
+                                           entries.len() as int - SELECT_ROWS as int)) as uint;
+
+            screen.fill(RGB(0,0,0));
+            screen.fill_area((0u, 40 + (cursor - top) * 16), (SCREENW, 16u), RGB(0x30,0x30,0x60));
+            screen.with_pixels(|pixels| {
+                let white = Gradient::new(RGB(0xff,0xff,0xff), RGB(0x80,0x80,0x80));
+                let gray = Gradient::new(RGB(0xc0,0xc0,0xc0), RGB(0x60,0x60,0x60));
+                let dirname = dir.display().to_string();
+                font.print_string(pixels, 8, 8, 1, LeftAligned, dirname[], white);
+                font.print_string(pixels, 8, SCREENH-20, 1, LeftAligned,
+                                  "RETURN: select   BACKSPACE: parent   ESC: quit", gray);
+                for (i, entry) in entries.iter().enumerate().skip(top).take(SELECT_ROWS) {
+                    let label: String = entry.label[].chars().take(SCREENW / 8 - 2).collect();
+                    let color = if entry.isdir {gray} else {white};
+                    font.print_string(pixels, 8, 40 + (i - top) * 16, 1, LeftAligned,
+                                      label[], color);
+                }
+            });
+            present(screen, None);
+
+            let event = event::wait_event();
+
+            // a chart dropped onto the window is played immediately
+            match ::util::poll_dropped_file() {
+                Some(path) => { return Some(path); }
+                None => {}
+            }
+
+            match event {
+                QuitEvent | KeyEvent(event::EscapeKey,true,_,_) => { return None; }
+                KeyEvent(UpKey,true,_,_) => {
+                    if cursor > 0 { cursor -= 1; }
+                }
+                KeyEvent(DownKey,true,_,_) => {
+                    if cursor + 1 < entries.len() { cursor += 1; }
+                }
+                KeyEvent(PageUpKey,true,_,_) => {
+                    cursor = if cursor > SELECT_ROWS {cursor - SELECT_ROWS} else {0};
+                }
+                KeyEvent(PageDownKey,true,_,_) => {
+                    cursor = cmp::min(cursor + SELECT_ROWS, entries.len() - 1);
+                }
+                KeyEvent(BackspaceKey,true,_,_) => {
+                    dir = dir.dir_path();
+                    entries = list_select_entries(&dir, &titles);
+                    cursor = 0;
+                }
+                KeyEvent(ReturnKey,true,_,_) => {
+                    if entries[cursor].isdir {
+                        dir = entries[cursor].path.clone();
+                        entries = list_select_entries(&dir, &titles);
+                        cursor = 0;
+                    } else {
+                        return entries[cursor].path.as_str().map(|s| s.to_string());
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    //----------------------------------------------------------------------------------------------
+    // calibration
+
+    /// Returns a directory for the user configuration, i.e. `~/.config/angolmois`.
+    pub fn config_dir() -> Option<Path> {
+        std::os::homedir().map(|home| home.join(".config").join("angolmois"))
+    }
+
+    /// Reads the global input offset in milliseconds saved by the calibration if any.
+    pub fn read_global_offset() -> Option<f64> {
+        use std::io;
+        config_dir().and_then(|dir| {
+            let ret = io::File::open(&dir.join("offset")).and_then(|mut f| f.read_to_string());
+            ret.ok().and_then(|s| from_str::<f64>(s[].trim()))
+        })
+    }
+
+    /// Reads the calibration of joystick axes saved by `calibrate_axes` if any. Each line of
+    /// the file contains the device index, the axis index, the center and the deadzone.
+    pub fn read_axis_calibration() -> Vec<AxisCalibration> {
+        use std::io;
+        let contents = config_dir().and_then(|dir| {
+            io::File::open(&dir.join("axes")).and_then(|mut f| f.read_to_string()).ok()
+        });
+        let mut axes = Vec::new();
+        for contents in contents.iter() {
+            for line in contents[].lines() {
+                let mut calib = AxisCalibration { device: 0, axis: 0, center: 0, deadzone: 0 };
+                if lex!(line; ws*, uint -> calib.device, ws, uint -> calib.axis, ws,
+                              int -> calib.center, ws, uint -> calib.deadzone, ws*, !) {
+                    axes.push(calib);
+                }
+            }
+        }
+        axes
+    }
+
+    /// Writes the calibration of joystick axes. Returns a path to the written file.
+    pub fn write_axis_calibration(axes: &[AxisCalibration]) -> AngolmoisResult<Path> {
+        use std::io;
+        let dir = match config_dir() {
+            Some(dir) => dir,
+            None => { return Err(IoFailure("~".to_string(),
+                                           io::standard_error(io::PathDoesntExist))); }
+        };
+        let path = dir.join("axes");
+        let ret = io::fs::mkdir_recursive(&dir, io::USER_RWX).and_then(|_| {
+            io::File::create(&path).and_then(|mut f| {
+                for calib in axes.iter() {
+                    try!(writeln!(&mut f, "{} {} {} {}",
+                                  calib.device, calib.axis, calib.center, calib.deadzone));
+                }
+                Ok(())
+            })
+        });
+        match ret {
+            Ok(()) => Ok(path),
+            Err(err) => Err(IoFailure(path.display().to_string(), err)),
+        }
+    }
+
+    /// The duration of sampling axes at rest in `calibrate_axes`, in milliseconds.
+    pub const AXIS_CALIBRATION_TIME: uint = 3000;
+    /// The minimum deadzone measured by `calibrate_axes`.
+    const MIN_CALIBRATED_DEADZONE: uint = 1000;
+    /// The maximum deadzone measured by `calibrate_axes`.
+    const MAX_CALIBRATED_DEADZONE: uint = 16000;
+
+    /**
+     * Measures the center and the noise of every axis in given joysticks, which should be left at
+     * rest for `AXIS_CALIBRATION_TIME`. The deadzone is set to twice the noise within
+     * `MIN_CALIBRATED_DEADZONE` and `MAX_CALIBRATED_DEADZONE`, so that a jittery axis does not
+     * trigger inputs by itself. `atexit` is called when the user quits.
+     */
+    pub fn calibrate_axes(joysticks: &[(uint, joy::Joystick)],
+                          atexit: ||) -> AngolmoisResult<Vec<AxisCalibration>> {
+        let mut ranges = Vec::new();
+        for &(device, ref joystick) in joysticks.iter() {
+            for axis in range(0, joystick.get_num_axes() as uint) {
+                ranges.push((device, axis, std::i16::MAX, std::i16::MIN));
+            }
+        }
+
+        let start = sdl::get_ticks();
+        while sdl::get_ticks() < start + AXIS_CALIBRATION_TIME {
+            try!(check_exit(|| atexit()));
+            unsafe { joy::ll::SDL_JoystickUpdate(); }
+            for range in ranges.iter_mut() {
+                let (device, axis, min, max) = *range;
+                for &(_, ref joystick) in joysticks.iter().filter(|&&(d, _)| d == device) {
+                    let pos = joystick.get_axis(axis as int);
+                    *range = (device, axis, cmp::min(min, pos), cmp::max(max, pos));
+                }
+            }
+            ::util::delay(10);
+        }
+
+        Ok(ranges.into_iter().map(|(device, axis, min, max)| {
+            let (min, max) = (min as int, max as int);
+            let deadzone = (max - min) as uint; // twice the noise around the center
+            AxisCalibration {
+                device: device, axis: axis, center: (min + max) / 2,
+                deadzone: cmp::min(cmp::max(deadzone, MIN_CALIBRATED_DEADZONE),
+                                   MAX_CALIBRATED_DEADZONE),
+            }
+        }).collect())
+    }
+
+    /// Writes the global input offset in milliseconds. Returns a path to the written file.
+    pub fn write_global_offset(offset: f64) -> AngolmoisResult<Path> {
+        use std::io;
+        let dir = match config_dir() {
+            Some(dir) => dir,
+            None => { return Err(IoFailure("~".to_string(),
+                                           io::standard_error(io::PathDoesntExist))); }
+        };
+        let path = dir.join("offset");
+        let ret = io::fs::mkdir_recursive(&dir, io::USER_RWX).and_then(|_| {
+            io::File::create(&path).and_then(|mut f| writeln!(&mut f, "{}", offset))
+        });
+        match ret {
+            Ok(()) => Ok(path),
+            Err(err) => Err(IoFailure(path.display().to_string(), err)),
+        }
+    }
+
+    /// Returns the prefixes of lines for the per-chart record of the chart with given hash and
+    /// the compatibility profile, keyed by the MD5 digest and the legacy hash respectively.
+    /// Other profiles than the canonical one append its name to the digest, and have no records
+    /// keyed by the legacy hash which predate the profiles.
+    fn chart_record_prefixes(hash: &ChartHash,
+                             compat: parser::CompatProfile) -> (String, Option<String>) {
+        if compat == parser::Canonical {
+            (format!("{} ", hash.md5), Some(format!("{} ", hash.legacy)))
+        } else {
+            (format!("{}:{} ", hash.md5, compat.name()), None)
+        }
+    }
+
+    /// Reads the per-chart record for the chart with given hash and the compatibility profile
+    /// from the file `name` in the configuration directory, if any. See `write_chart_record` for
+    /// the format. The record keyed by the legacy hash is read if there is no record keyed by
+    /// the MD5 digest.
+    fn read_chart_record(name: &str, hash: &ChartHash,
+                         compat: parser::CompatProfile) -> Option<String> {
+        use std::io;
+        let path = match config_dir() {
+            Some(dir) => dir.join(name),
+            None => { return None; }
+        };
+        let ret = io::File::open(&path).and_then(|mut f| f.read_to_string());
+        let s = match ret {
+            Ok(s) => s,
+            Err(_) => { return None; }
+        };
+        let mut legacy = None;
+        let (prefix, legacyprefix) = chart_record_prefixes(hash, compat);
+        for line in s[].lines() {
+            if line.starts_with(prefix[]) {
+                return Some(line[prefix.len()..].trim().to_string());
+            }
+            for legacyprefix in legacyprefix.iter() {
+                if line.starts_with(legacyprefix[]) {
+                    legacy = Some(line[legacyprefix.len()..].trim().to_string());
+                }
+            }
+        }
+        legacy
+    }
+
+    /// Writes the per-chart record for the chart with given hash and the compatibility profile to
+    /// the file `name` in the configuration directory, replacing the existing record if any.
+    /// The records are kept in a file with one chart per line, consisting of the MD5 digest of
+    /// the chart (followed by a colon and the profile name unless canonical) and the record.
+    /// The record keyed by the legacy hash, if any, is replaced as well.
+    fn write_chart_record(name: &str, hash: &ChartHash, compat: parser::CompatProfile,
+                          record: &str) -> AngolmoisResult<()> {
+        use std::io;
+        let dir = match config_dir() {
+            Some(dir) => dir,
+            None => { return Err(IoFailure("~".to_string(),
+                                           io::standard_error(io::PathDoesntExist))); }
+        };
+        let path = dir.join(name);
+        let old = io::File::open(&path).and_then(|mut f| f.read_to_string());
+        let old = old.unwrap_or(String::new());
+        let (prefix, legacyprefix) = chart_record_prefixes(hash, compat);
+        let ret = io::fs::mkdir_recursive(&dir, io::USER_RWX).and_then(|_| {
+            let mut f = try!(io::File::create(&path));
+            for line in old[].lines() {
+                if line.is_empty() || line.starts_with(prefix[]) ||
+                   legacyprefix.as_ref().map_or(false, |p| line.starts_with(p[])) { continue; }
+                try!(writeln!(&mut f, "{}", line));
+            }
+            writeln!(&mut f, "{}{}", prefix, record)
+        });
+        ret.map_err(|err| IoFailure(path.display().to_string(), err))
+    }
+
+    /// Reads the per-chart input offset in milliseconds for the chart with given hash if any.
+    /// The offset is shared by every compatibility profile.
+    pub fn read_chart_offset(hash: &ChartHash) -> Option<f64> {
+        read_chart_record("offsets", hash, parser::Canonical).and_then(|s| from_str::<f64>(s[]))
+    }
+
+    /// Writes the per-chart input offset in milliseconds for the chart with given hash,
+    /// replacing the existing offset if any.
+    pub fn write_chart_offset(hash: &ChartHash, offset: f64) -> AngolmoisResult<()> {
+        write_chart_record("offsets", hash, parser::Canonical, offset.to_string()[])
+    }
+
+    /// Reads the play speed and the height of the lane cover last used for the chart with given
+    /// hash and the compatibility profile if any.
+    pub fn read_chart_settings(hash: &ChartHash,
+                               compat: parser::CompatProfile) -> Option<(f64, uint)> {
+        read_chart_record("settings", hash, compat).and_then(|s| {
+            let mut playspeed = 0.0;
+            let mut cover = 0;
+            if lex!(s[]; f64 -> playspeed, ws, uint -> cover, ws*, !) && playspeed > 0.0 {
+                Some((playspeed, cover))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Writes the play speed and the height of the lane cover used for the chart with given hash
+    /// and the compatibility profile, replacing the existing settings if any.
+    pub fn write_chart_settings(hash: &ChartHash, compat: parser::CompatProfile, playspeed: f64,
+                                cover: uint) -> AngolmoisResult<()> {
+        write_chart_record("settings", hash, compat, format!("{} {}", playspeed, cover)[])
+    }
+
+    /// The interval between beats of the calibration metronome in milliseconds (120 BPM).
+    const CALIBRATION_INTERVAL: uint = 500;
+    /// The number of beats in the calibration.
+    const CALIBRATION_BEATS: uint = 36;
+    /// The number of initial beats where key presses are ignored.
+    const CALIBRATION_LEADIN: uint = 4;
+    /// The minimum number of key presses required for the calibration.
+    const CALIBRATION_MINPRESSES: uint = 8;
+
+    /**
+     * Plays a metronome with flashing beats on the screen, and measures the average difference
+     * between key presses and beats. Returns the input offset in milliseconds as used by
+     * `Options::offset`, or `None` if there were too few key presses.
+     *
+     * The key press is matched to the nearest beat, so the measured offset is at most a half of
+     * `CALIBRATION_INTERVAL` in magnitude. The result includes both the audio and video latency.
+     */
+    pub fn calibrate(screen: &Surface, font: &Font) -> AngolmoisResult<Option<f64>> {
+        let beep = create_beep();
+        let start = sdl::get_ticks() + 1000;
+        let end = start + CALIBRATION_INTERVAL * CALIBRATION_BEATS;
+        let mut nextbeat = 0u;
+        let mut diffs = Vec::new();
+        loop {
+            let now = sdl::get_ticks();
+            if now >= end { break; }
+
+            if nextbeat < CALIBRATION_BEATS && now >= start + nextbeat * CALIBRATION_INTERVAL {
+                beep.play(None, 0);
+                nextbeat += 1;
+            }
+
+            loop {
+                match event::poll_event() {
+                    KeyEvent(event::EscapeKey,_,_,_) | QuitEvent => { return Err(Interrupted); }
+                    KeyEvent(_,true,_,_) if now >= start => {
+                        let elapsed = (now - start) as int;
+                        let interval = CALIBRATION_INTERVAL as int;
+                        let beat = (elapsed + interval / 2) / interval;
+                        if beat >= CALIBRATION_LEADIN as int && beat < CALIBRATION_BEATS as int {
+                            diffs.push((elapsed - beat * interval) as f64);
+                        }
+                    }
+                    NoEvent => { break; }
+                    _ => {}
+                }
+            }
+
+            let average = if diffs.is_empty() {None} else {
+                Some(diffs.iter().fold(0.0, |sum, &diff| sum + diff) / diffs.len() as f64)
+            };
+
+            screen.fill(RGB(0,0,0));
+            if now >= start && (now - start) % CALIBRATION_INTERVAL < 100 {
+                screen.fill_area((SCREENW/2 - 50, SCREENH/2 - 50), (100u, 100u),
+                                 RGB(0xff,0xff,0xff));
+            }
+            screen.with_pixels(|pixels| {
+                let white = Gradient::new(RGB(0xff,0xff,0xff), RGB(0x80,0x80,0x80));
+                let gray = Gradient::new(RGB(0xc0,0xc0,0xc0), RGB(0x60,0x60,0x60));
+                font.print_string(pixels, SCREENW/2, 40, 1, Centered,
+                                  "Press any key on the beat", white);
+                font.print_string(pixels, SCREENW/2, SCREENH/2 + 80, 1, Centered,
+                                  format!("BEAT {} / {}", nextbeat, CALIBRATION_BEATS)[], gray);
+                for &average in average.iter() {
+                    font.print_string(pixels, SCREENW/2, SCREENH/2 + 100, 1, Centered,
+                                      format!("OFFSET {:+.1} ms ({} presses)",
+                                              average, diffs.len())[], white);
+                }
+                font.print_string(pixels, SCREENW/2, SCREENH-20, 1, Centered, "ESC: quit", gray);
+            });
+            present(screen, None);
+        }
+
+        if diffs.len() < CALIBRATION_MINPRESSES { return Ok(None); }
+        Ok(Some(diffs.iter().fold(0.0, |sum, &diff| sum + diff) / diffs.len() as f64))
+    }
+
+    //----------------------------------------------------------------------------------------------
+    // score records
+
+    /// Clear lamps as returned by `Player::clear_lamp`, in the increasing order of the rank.
+    pub static CLEAR_LAMPS: &'static [&'static str] = &[
+        "FAILED", "EASY CLEAR", "CLEAR", "HARD CLEAR", "EX-HARD CLEAR", "HAZARD CLEAR",
+        "FULL COMBO", "PERFECT",
+    ];
+
+    /// The best record for a chart, kept in the local score database. Each field is the best
+    /// one so far, which may come from different game plays.
+    #[deriving(Clone)]
+    pub struct ScoreRecord {
+        /// The best EX score.
+        pub exscore: uint,
+        /// The best score.
+        pub score: uint,
+        /// The best combo number.
+        pub maxcombo: uint,
+        /// The best clear lamp, one of `CLEAR_LAMPS`.
+        pub lamp: String,
+    }
+
+    impl ScoreRecord {
+        /// Merges the result of the finished game play into the record.
+        pub fn merge(&self, player: &Player) -> ScoreRecord {
+            let rank = |lamp: &str| CLEAR_LAMPS.iter().position(|&l| l == lamp).unwrap_or(0);
+            let lamp = player.clear_lamp();
+            ScoreRecord {
+                exscore: cmp::max(self.exscore, player.exscore),
+                score: cmp::max(self.score, player.score),
+                maxcombo: cmp::max(self.maxcombo, player.bestcombo),
+                lamp: if rank(lamp) > rank(self.lamp[]) {lamp.to_string()}
+                      else {self.lamp.clone()},
+            }
+        }
+    }
+
+    /// Reads the best record for the chart with given hash and the compatibility profile from
+    /// the local score database if any.
+    pub fn read_score_record(hash: &ChartHash,
+                             compat: parser::CompatProfile) -> Option<ScoreRecord> {
+        read_chart_record("scores", hash, compat).and_then(|s| {
+            let fields: Vec<&str> = s[].words().collect();
+            if fields.len() < 4 { return None; }
+            let lamp = fields[3..].connect(" ");
+            if !CLEAR_LAMPS.iter().any(|&l| l == lamp[]) { return None; }
+            match (from_str::<uint>(fields[0]), from_str::<uint>(fields[1]),
+                   from_str::<uint>(fields[2])) {
+                (Some(exscore), Some(score), Some(maxcombo)) =>
+                    Some(ScoreRecord { exscore: exscore, score: score, maxcombo: maxcombo,
+                                       lamp: lamp }),
+                (_, _, _) => None
+            }
+        })
+    }
+
+    /// Updates the local score database with the result of the finished game play. The database
+    /// is a file with one chart per line, consisting of the hash, the best EX score, score,
+    /// combo number and clear lamp. The record is kept for the compatibility profile used.
+    pub fn update_score_record(hash: &ChartHash, player: &Player) -> AngolmoisResult<()> {
+        let compat = player.opts.compat;
+        let empty = ScoreRecord { exscore: 0, score: 0, maxcombo: 0, lamp: "FAILED".to_string() };
+        let record = read_score_record(hash, compat).unwrap_or(empty).merge(player);
+        let line = format!("{} {} {} {}", record.exscore, record.score, record.maxcombo,
+                           record.lamp);
+        write_chart_record("scores", hash, compat, line[])
+    }
+
+    //----------------------------------------------------------------------------------------------
+    // loading
+
+    /// Returns the interface string common to the graphical and textual loading screen. The best
+    /// record for the chart, if given, is appended to the metadata.
+    fn displayed_info(bms: &Bms, infos: &BmsInfo, keyspec: &KeySpec,
+                      record: Option<&ScoreRecord>) -> (String, String, String, String) {
+        use util::option::StrOption;
+
+        let bpmrange = if infos.minbpm < infos.maxbpm {
+            format!(" ({:.2}-{:.2})", infos.minbpm, infos.maxbpm)
+        } else if infos.hasbpmchange {
+            "?".to_string()
+        } else {
+            String::new()
+        };
+        let duration = infos.duration as uint;
+        let stoptime = if infos.stoptime > 0.0 {format!(" (STOP {:.1}s)", infos.stoptime)}
+                       else {String::new()};
+        let mut meta = format!("Level {level} | BPM {bpm:.2}{bpmrange} | \
+                                {min}:{sec:02}{stoptime} | \
+                                {nnotes} {nnotes_text} [{nkeys}KEY{haslongnote}]",
+                               level = bms.playlevel, bpm = *bms.initbpm, bpmrange = bpmrange,
+                               min = duration / 60, sec = duration % 60, stoptime = stoptime,
+                               nnotes = infos.nnotes as uint,
+                               nnotes_text = if infos.nnotes == 1 {"note"} else {"notes"},
+                               nkeys = keyspec.nkeys(),
+                               haslongnote = if infos.haslongnote {"-LN"} else {""});
+        for record in record.iter() {
+            meta.push_str(format!(" | BEST EX {} / {} COMBO / {}",
+                                  record.exscore, record.maxcombo, record.lamp)[]);
+        }
+        let title = bms.title.as_ref_slice_or("").to_string();
+        let genre = bms.genre.as_ref_slice_or("").to_string();
+        let artist = bms.artist.as_ref_slice_or("").to_string();
+        (meta, title, genre, artist)
+    }
+
+    /// The height of the note density graph in the loading screen.
+    const DENSITY_GRAPH_HEIGHT: uint = 32;
+
+    /// Renders the note density graph from `BmsInfo::density` to the area at `(x,y)` with
+    /// the size `(w,h)`. Each column shows the densest measure in it. Columns before `played`
+    /// are drawn with `playedcolor` and remaining columns are drawn with `color`.
+    pub fn render_density_graph(screen: &Surface, density: &[uint], x: uint, y: uint,
+                                w: uint, h: uint, played: uint,
+                                playedcolor: Color, color: Color) {
+        let nmeasures = density.len();
+        let maxdensity = density.iter().map(|&n| n).max().unwrap_or(0);
+        if nmeasures == 0 || maxdensity == 0 || w == 0 { return; }
+
+        for i in range(0, w) {
+            let start = i * nmeasures / w;
+            let end = cmp::max((i + 1) * nmeasures / w, start + 1);
+            let n = density[start..end].iter().map(|&n| n).max().unwrap_or(0);
+            if n == 0 { continue; }
+            let height = cmp::max(n * h / maxdensity, 1);
+            let color = if i < played {playedcolor} else {color};
+            screen.fill_area((x + i, y + h - height), (1u, height), color);
+        }
+    }
+
+    /// Loads an image used only in the loading screen (#STAGEFILE, #BANNER and #BACKBMP). Unlike
+    /// `load_image` the image is never a movie and the color key is not set.
+    fn load_plain_image(basedirs: &[Path], path: &str) -> Option<Surface> {
+        resolve_relative_path(basedirs, path, IMAGE_EXTS).and_then(|path| {
+            load_surface(&path).and_then(|s| s.display_format()).ok()
+        })
+    }
+
+    /// Renders the graphical loading screen by blitting BMS #STAGEFILE, #BACKBMP and #BANNER
+    /// images (if any) and showing the metadata.
+    /// (C: `play_show_stagefile` when `opt_mode < EXCLUSIVE_MODE`)
+    pub fn show_stagefile_screen(bms: &Bms, infos: &BmsInfo, keyspec: &KeySpec, opts: &Options,
+                                 record: Option<&ScoreRecord>, suggested: Option<f64>,
+                                 screen: &Surface, presenter: Option<&::util::gl::Presenter>,
+                                 font: &Font) {
+        let (meta, title, genre, artist) = displayed_info(bms, infos, keyspec, record);
+
+        screen.with_pixels(|pixels| {
+            font.print_string(pixels, SCREENW/2, SCREENH/2-16, 2, Centered, "loading bms file...",
+                              Gradient::new(RGB(0x80,0x80,0x80), RGB(0x20,0x20,0x20)));
+        });
+        present(screen, presenter);
+
+        // the #BACKBMP image is drawn first, so it is only visible when #STAGEFILE is absent or
+        // failed to load.
+        let basedirs = get_basedirs(bms, opts);
+        let backbmp = bms.backbmp.as_ref().and_then(|path| load_plain_image(basedirs[], path[]));
+        let stagefile = bms.stagefile.as_ref()
+                           .and_then(|path| load_plain_image(basedirs[], path[]));
+        let banner = bms.banner.as_ref().and_then(|path| load_plain_image(basedirs[], path[]));
+
+        screen.with_pixels(|pixels| {
+            for surface in backbmp.iter().chain(stagefile.iter()) {
+                surface.with_pixels(|srcpixels| {
+                    gfx::bicubic_interpolation(srcpixels, pixels);
+                });
+            }
+        });
+
+        // the banner is not scaled, and placed at the bottom center just above the metadata
+        // and the density graph.
+        for surface in banner.iter() {
+            let (w, h) = surface.get_size();
+            let w = cmp::min(w as uint, SCREENW);
+            let h = cmp::min(h as uint, SCREENH - 62 - DENSITY_GRAPH_HEIGHT);
+            screen.blit_area(surface, (0u,0u),
+                             ((SCREENW - w) / 2, SCREENH - 24 - DENSITY_GRAPH_HEIGHT - h), (w, h));
+        }
+
+        screen.with_pixels(|pixels| {
+            if opts.showinfo {
+                let bg = RGBA(0x10,0x10,0x10,0x40);
+                let fg = Gradient::new(RGB(0xff,0xff,0xff), RGB(0x80,0x80,0x80));
+                for i in range(0, SCREENW) {
+                    for j in range(0, 42u) {
+                        pixels.put_blended_pixel(i, j, bg);
+                    }
+                    for j in range(SCREENH-20, SCREENH) {
+                        pixels.put_blended_pixel(i, j, bg);
+                    }
+                }
+                font.print_string(pixels, 6, 4, 2, LeftAligned, title[], fg);
+                font.print_string(pixels, SCREENW-8, 4, 1, RightAligned, genre[], fg);
+                font.print_string(pixels, SCREENW-8, 20, 1, RightAligned, artist[], fg);
+                font.print_string(pixels, 3, SCREENH-18, 1, LeftAligned, meta[], fg);
+            }
+            for &speed in suggested.iter() {
+                let msg = format!("Press Enter to play at the suggested speed {:.1}x \
+                                   (currently {:.1}x)", speed, opts.playspeed);
+                for i in range(0, SCREENW) {
+                    for j in range(42u, 62) {
+                        pixels.put_blended_pixel(i, j, RGBA(0x10,0x10,0x10,0x40));
+                    }
+                }
+                font.print_string(pixels, 6, 44, 1, LeftAligned, msg[],
+                                  Gradient::new(RGB(0xff,0xff,0xc0), RGB(0xc0,0xc0,0x40)));
+            }
+        });
+        if opts.showinfo {
+            render_density_graph(screen, infos.density[], 6, SCREENH - 22 - DENSITY_GRAPH_HEIGHT,
+                                 SCREENW - 12, DENSITY_GRAPH_HEIGHT, 0,
+                                 RGB(0xc0,0xc0,0xc0), RGB(0xc0,0xc0,0xc0));
+        }
+
+        present(screen, presenter);
+    }
+
+    /// Renders the textual loading screen by printing the metadata.
+    /// (C: `play_show_stagefile` when `opt_mode >= EXCLUSIVE_MODE`)
+    pub fn show_stagefile_noscreen(bms: &Bms, infos: &BmsInfo, keyspec: &KeySpec, opts: &Options,
+                                   record: Option<&ScoreRecord>) {
+        if opts.showinfo {
+            let (meta, title, genre, artist) = displayed_info(bms, infos, keyspec, record);
+            let _ = writeln!(&mut std::io::stderr(), "\
+----------------------------------------------------------------------------------------------
+Title:    {title}
+Genre:    {genre}
+Artist:   {artist}
+MD5:      {md5}
+{meta}
+----------------------------------------------------------------------------------------------",
+                title = title, genre = genre, artist = artist,
+                md5 = infos.md5.as_ref().map_or("(unknown)", |md5| md5[]), meta = meta);
+        }
+    }
+
+    /// The number of worker tasks reading and decoding resource files in `load_resource`.
+    pub const LOADING_WORKERS: uint = 4;
+    /// The interval in milliseconds between checks for inputs while `load_resource` is waiting.
+    pub const LOADING_POLL_INTERVAL: uint = 10;
+
+    /// A resource file to be read by the worker task.
+    struct FetchJob {
+        /// True if the file is a sound resource, false if it is an image resource.
+        sound: bool,
+        /// An index to `Bms::sndpath` or `Bms::imgpath`.
+        index: uint,
+        /// An index to `Bms::objs` for the first object using the resource, or the number of
+        /// objects if the resource is not used at all.
+        firstuse: uint,
+        /// The path as written in the chart.
+        path: String,
+        /// The resolved path, which may be inside the archive.
+        fullpath: Path,
+    }
+
+    impl FetchJob {
+        /// Returns a human-readable description of the resource, e.g. `#WAV01 (foo.wav)`.
+        fn label(&self) -> String {
+            let cmd = if self.sound {"WAV"} else {"BMP"};
+            format!("\\#{}{} ({})", cmd, Key(self.index as int), self.path)
+        }
+
+        /// Warns that the resource has failed to load.
+        fn warn(&self) {
+            let kind = if self.sound {"sound"} else {"image"};
+            warn!("failed to load {} {}", kind, self.label());
+        }
+    }
+
+    /// Warns the summary of resources failed to load after loading, as individual warnings can be
+    /// easily missed in the middle of loading.
+    fn warn_missing_resources(missing: &[String]) {
+        if missing.is_empty() { return; }
+        warn!("{} resource(s) failed to load: {}", missing.len(), missing.connect(", "));
+    }
+
+    /**
+     * Returns the list of resource files to be read, sorted by the time of their first use so that
+     * resources needed early are ready early. Sound and image resources are included only when
+     * `sounds` and `images` are true respectively. Unresolved paths are warned and returned
+     * separately; movies are skipped as they are streamed from the file.
+     */
+    fn fetch_jobs(bms: &Bms, basedirs: &[Path], sounds: bool,
+                  images: bool) -> (Vec<FetchJob>, Vec<FetchJob>) {
+        let mut sndorder = Vec::from_elem(bms.sndpath.len(), bms.objs.len());
+        let mut imgorder = Vec::from_elem(bms.imgpath.len(), bms.objs.len());
+        for (i, obj) in bms.objs.iter().enumerate() {
+            for &sref in obj.sounds().iter() {
+                let order = &mut sndorder[mut][**sref as uint];
+                *order = cmp::min(*order, i);
+            }
+            for &iref in obj.images().iter() {
+                let order = &mut imgorder[mut][**iref as uint];
+                *order = cmp::min(*order, i);
+            }
+        }
+
+        let mut jobs = Vec::new();
+        let mut unresolved = Vec::new();
+        let kinds = [(true, if sounds {bms.sndpath[]} else {[][]}, sndorder[], SOUND_EXTS),
+                     (false, if images {bms.imgpath[]} else {[][]}, imgorder[], IMAGE_EXTS)];
+        for &(sound, paths, order, exts) in kinds.iter() {
+            for (i, path) in paths.iter().enumerate() {
+                let path = match *path {
+                    Some(ref path) if sound || !is_movie_path(path[]) => path,
+                    _ => { continue; }
+                };
+                let mut job = FetchJob { sound: sound, index: i, firstuse: order[i],
+                                         path: path.clone(), fullpath: Path::new(".") };
+                match resolve_relative_path(basedirs, path[], exts) {
+                    Some(fullpath) => { job.fullpath = fullpath; jobs.push((order[i], job)); }
+                    None => { job.warn(); unresolved.push(job); }
+                }
+            }
+        }
+        jobs.sort_by(|&(a, _), &(b, _)| a.cmp(&b));
+        (jobs.into_iter().map(|(_, job)| job).collect(), unresolved)
+    }
+
+    /// Spawns up to `LOADING_WORKERS` worker tasks reading given resource files, and returns
+    /// a channel for receiving the job and its result whenever a file has been read. See
+    /// `fetch_resources` for the details.
+    fn spawn_fetch_workers(jobs: Vec<FetchJob>,
+                           rate: f64) -> Receiver<(FetchJob, Result<Vec<u8>,String>)> {
+        // workers pop jobs from the end of the queue
+        let nworkers = cmp::min(LOADING_WORKERS, jobs.len());
+        let mut jobs = jobs;
+        jobs.reverse();
+        let queue = Arc::new(Mutex::new(jobs));
+        let (tx, rx) = channel();
+        for _ in range(0, nworkers) {
+            let queue = queue.clone();
+            let tx = tx.clone();
+            spawn(proc() {
+                loop {
+                    let job = match queue.lock().pop() {
+                        Some(job) => job,
+                        None => { break; }
+                    };
+                    let pathstr = job.fullpath.as_str().unwrap_or("");
+                    let res = if job.sound {
+                        decode_chunk(pathstr).map(|buf| stretch_samples(buf, rate))
+                    } else {
+                        ::zip::read_file(pathstr).map_err(|err| err.to_string())
+                    };
+                    if tx.send_opt((job, res)).is_err() { break; }
+                }
+            });
+        }
+        rx
+    }
+
+    /**
+     * Reads given resource files with `LOADING_WORKERS` worker tasks, and calls a callback with
+     * the job, its result and the progress (the number of finished jobs and the total number of
+     * jobs) whenever a file has been read. Sound files are decoded into samples and stretched by
+     * `rate` (see `Options::rate`) by the worker, and image files are returned as is.
+     * The callback is also called with `None` every `LOADING_POLL_INTERVAL` milliseconds while
+     * waiting for workers, so that the caller can respond to inputs even when a large file is
+     * being read.
+     *
+     * Loading stops as soon as the callback returns an error; remaining workers stop when they
+     * notice that the results are no longer received.
+     *
+     * XXX images are decoded in the main task, as SDL surfaces cannot be sent across tasks.
+     */
+    fn fetch_resources(jobs: Vec<FetchJob>, rate: f64,
+                       callback: |Option<(FetchJob, Result<Vec<u8>,String>)>, (uint, uint)|
+                                 -> AngolmoisResult<()>) -> AngolmoisResult<()> {
+        let total = jobs.len();
+        if total == 0 { return Ok(()); }
+
+        let rx = spawn_fetch_workers(jobs, rate);
+        let mut done = 0;
+        while done < total {
+            match rx.try_recv() {
+                Ok((job, res)) => {
+                    done += 1;
+                    try!(callback(Some((job, res)), (done, total)));
+                }
+                Err(_) => {
+                    try!(callback(None, (done, total)));
+                    ::util::delay(LOADING_POLL_INTERVAL);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Loads the image and sound resources and calls a callback with the path and the progress
+     * (the number of loaded resources and the total number of resources) whenever a new resource
+     * has been loaded. The callback is also periodically called with the last path while waiting
+     * for resources. Loading stops as soon as the callback returns an error, even in the middle
+     * of reading a file. Sound resources are left empty if `Options::lazysounds` is set, as
+     * `LazySounds` loads them later. If `Options::earlystart` is set, sound resources used after
+     * the given seconds are left empty and loaded by returned `BackgroundSounds` instead.
+     *
+     * Sound resources failed to load are replaced with placeholders if
+     * `Options::placeholdersound` is set. Resources failed to load are summarized after loading,
+     * and returned as a list of their descriptions. (C: `load_resource`)
+     */
+    pub fn load_resource(bms: &Bms, opts: &Options,
+                         callback: |Option<String>, (uint, uint)| -> AngolmoisResult<()>)
+                         -> AngolmoisResult<(Vec<SoundResource>, Vec<ImageResource>,
+                                             Option<BackgroundSounds>, Vec<String>)> {
+        let basedirs = get_basedirs(bms, opts);
+
+        // movies are streamed from the file and loaded in place
+        let mut sndres = Vec::from_fn(bms.sndpath.len(), |_| NoSound);
+        let mut imgres = Vec::from_fn(bms.imgpath.len(), |i| {
+            match bms.imgpath[i] {
+                Some(ref path) if is_movie_path(path[]) => {
+                    load_image(Key(i as int), path[], opts, basedirs[])
+                }
+                _ => NoImage
+            }
+        });
+
+        let (jobs, unresolved) = fetch_jobs(bms, basedirs[], !opts.lazysounds, opts.has_bga());
+        let (jobs, background) = match opts.earlystart {
+            Some(secs) => {
+                // sounds first used after the cutoff are deferred. the cutoff is measured from
+                // the start measure, as the game play starts there.
+                let timeline = Timeline::new(bms, 0.0);
+                let start = opts.startmeasure.map_or(0.0, |measure| measure as f64);
+                let cutoff = timeline.time_to_msec(bms, start) + secs * 1000.0;
+                let (later, now) = jobs.partition(|job| {
+                    job.sound && job.firstuse < bms.objs.len() &&
+                        timeline.time_to_msec(bms, bms.objs[job.firstuse].time) >= cutoff
+                });
+                (now, if later.is_empty() {None} else {Some(BackgroundSounds::new(later, opts))})
+            }
+            None => (jobs, None)
+        };
+
+        let mut failed = unresolved;
+        let mut lastpath = None;
+        try!(fetch_resources(jobs, opts.rate, |fetched, progress| {
+            let (job, res) = match fetched {
+                Some(fetched) => fetched,
+                None => { return callback(lastpath.clone(), progress); }
+            };
+            let res = res.and_then(|data| {
+                if job.sound {
+                    sndres[mut][job.index] = Sound(Chunk::new(data, 128));
+                } else {
+                    imgres[mut][job.index] = try!(decode_image(&job.fullpath, data[]));
+                }
+                Ok(())
+            });
+            lastpath = Some(job.path.clone());
+            if res.is_err() {
+                job.warn();
+                failed.push(job);
+            }
+            callback(lastpath.clone(), progress)
+        }));
+
+        for bc in bms.blitcmd.iter() {
+            apply_blitcmd(imgres[mut], bc);
+        }
+        for &msec in opts.placeholdersound.iter() {
+            for job in failed.iter().filter(|job| job.sound) {
+                sndres[mut][job.index] = SoundResource::placeholder(msec);
+            }
+        }
+        let missing: Vec<String> = failed.iter().map(|job| job.label()).collect();
+        warn_missing_resources(missing[]);
+        Ok((sndres, imgres, background, missing))
+    }
+
+    /// Same as `load_resource` but loads the sound resources only. This does not require
+    /// the screen.
+    pub fn load_sound_resource(bms: &Bms, opts: &Options,
+                               callback: |Option<String>, (uint, uint)| -> AngolmoisResult<()>)
+                               -> AngolmoisResult<Vec<SoundResource>> {
+        let basedirs = get_basedirs(bms, opts);
+
+        let mut sndres = Vec::from_fn(bms.sndpath.len(), |_| NoSound);
+        let (jobs, unresolved) = fetch_jobs(bms, basedirs[], true, false);
+        let mut failed = unresolved;
+        let mut lastpath = None;
+        try!(fetch_resources(jobs, opts.rate, |fetched, progress| {
+            let (job, res) = match fetched {
+                Some(fetched) => fetched,
+                None => { return callback(lastpath.clone(), progress); }
+            };
+            lastpath = Some(job.path.clone());
+            match res {
+                Ok(data) => { sndres[mut][job.index] = Sound(Chunk::new(data, 128)); }
+                Err(_) => { job.warn(); failed.push(job); }
+            }
+            callback(lastpath.clone(), progress)
+        }));
+
+        for &msec in opts.placeholdersound.iter() {
+            for job in failed.iter() { sndres[mut][job.index] = SoundResource::placeholder(msec); }
+        }
+        let missing: Vec<String> = failed.iter().map(|job| job.label()).collect();
+        warn_missing_resources(missing[]);
+        Ok(sndres)
+    }
+
+    /**
+     * Sound resources loaded in the background during the game play when `Options::earlystart`
+     * is set. Unlike `LazySounds` every sound is kept once loaded, and sounds are loaded in
+     * the order of their first use so that they are likely to be ready in time.
+     *
+     * A sound which has not been loaded in time is silently skipped, and the song duration
+     * (`bms_duration`) does not account for the lengths of such sounds.
+     */
+    pub struct BackgroundSounds {
+        /// A channel for receiving decoded samples from worker tasks.
+        rx: Receiver<(FetchJob, Result<Vec<u8>,String>)>,
+        /// The number of sound resources yet to be received.
+        pub remaining: uint,
+        /// The length of placeholders for sounds failed to load. See `Options::placeholdersound`.
+        placeholder: Option<uint>,
+    }
+
+    impl BackgroundSounds {
+        /// Starts worker tasks for given jobs.
+        fn new(jobs: Vec<FetchJob>, opts: &Options) -> BackgroundSounds {
+            let remaining = jobs.len();
+            BackgroundSounds { rx: spawn_fetch_workers(jobs, opts.rate), remaining: remaining,
+                               placeholder: opts.placeholdersound }
+        }
+
+        /// Moves decoded sounds to `sndres`. Returns false if every sound has been received.
+        pub fn update(&mut self, sndres: &mut [SoundResource]) -> bool {
+            while self.remaining > 0 {
+                match self.rx.try_recv() {
+                    Ok((job, Ok(data))) => {
+                        sndres[job.index] = Sound(Chunk::new(data, 128));
+                        self.remaining -= 1;
+                    }
+                    Ok((job, Err(_))) => {
+                        job.warn();
+                        for &msec in self.placeholder.iter() {
+                            sndres[job.index] = SoundResource::placeholder(msec);
+                        }
+                        self.remaining -= 1;
+                    }
+                    Err(_) => { break; }
+                }
+            }
+            self.remaining > 0
+        }
+    }
+
+    /// The amount of the chart in measures, after the grading line, whose sounds are requested to
+    /// `LazySounds` in advance.
+    pub const LAZY_LOOKAHEAD: f64 = 4.0;
+    /// The amount of the chart in measures, before the grading line, whose sounds are kept by
+    /// `LazySounds` after the last use.
+    pub const LAZY_KEEPBEHIND: f64 = 4.0;
+
+    /**
+     * Sound resources loaded on demand when `Options::lazysounds` is set. Sounds used shortly
+     * after the grading line are decoded by a background task just before they are played, and
+     * released shortly after their last use, so that huge charts can be played with a bounded
+     * memory and without waiting for every sound to load.
+     *
+     * A sound which has not been decoded in time is silently skipped, and the song duration
+     * (`bms_duration`) does not account for the sound lengths.
+     */
+    pub struct LazySounds {
+        /// Resolved paths to sound resources, or `None` if the path cannot be resolved.
+        paths: Vec<Option<Path>>,
+        /// The virtual time of the last use of each sound resource.
+        lastuse: Vec<f64>,
+        /// True if the sound resource has been requested and not yet released.
+        requested: Vec<bool>,
+        /// A channel for sending requests to the background task.
+        tx: Sender<(uint, Path)>,
+        /// A channel for receiving decoded samples from the background task.
+        rx: Receiver<(uint, Result<Vec<u8>,String>)>,
+    }
+
+    impl LazySounds {
+        /// Resolves paths to sound resources and starts the background task.
+        pub fn new(bms: &Bms, opts: &Options) -> LazySounds {
+            let basedirs = get_basedirs(bms, opts);
+            let paths = bms.sndpath.iter().enumerate().map(|(i, path)| {
+                path.as_ref().and_then(|path| {
+                    let fullpath = resolve_relative_path(basedirs[], path[], SOUND_EXTS);
+                    if fullpath.is_none() {
+                        warn!("failed to load sound \\#WAV{} ({})", Key(i as int), path);
+                    }
+                    fullpath
+                })
+            }).collect();
+            let mut lastuse = Vec::from_elem(bms.sndpath.len(), std::f64::NEG_INFINITY);
+            for obj in bms.objs.iter() {
+                for &sref in obj.sounds().iter() { lastuse[mut][**sref as uint] = obj.time; }
+            }
+
+            let (tx, reqrx) = channel::<(uint, Path)>();
+            let (restx, rx) = channel();
+            let rate = opts.rate;
+            spawn(proc() {
+                for (index, path) in reqrx.iter() {
+                    let res = decode_chunk(path.as_str().unwrap_or(""))
+                                  .map(|buf| stretch_samples(buf, rate));
+                    if restx.send_opt((index, res)).is_err() { break; }
+                }
+            });
+
+            let nsounds = bms.sndpath.len();
+            LazySounds { paths: paths, lastuse: lastuse, requested: Vec::from_elem(nsounds, false),
+                         tx: tx, rx: rx }
+        }
+
+        /**
+         * Updates sound resources for the grading line at `line`. Sounds used before
+         * `LAZY_LOOKAHEAD` measures after the line, starting from `pos` in `bms.objs`, are
+         * requested; decoded sounds are moved to `sndres`; and sounds not used after
+         * `LAZY_KEEPBEHIND` measures before the line are released unless being played.
+         */
+        pub fn update(&mut self, bms: &Bms, pos: uint, line: f64, sndres: &mut [SoundResource]) {
+            for obj in bms.objs[pos..].iter() {
+                if obj.time > line + LAZY_LOOKAHEAD { break; }
+                for &sref in obj.sounds().iter() {
+                    let index = **sref as uint;
+                    if self.requested[index] { continue; }
+                    for path in self.paths[index].iter() {
+                        self.requested[mut][index] = true;
+                        let _ = self.tx.send_opt((index, path.clone()));
+                    }
+                }
+            }
+
+            loop {
+                match self.rx.try_recv() {
+                    Ok((index, res)) => {
+                        // the sound may have been released while being decoded
+                        if !self.requested[index] { continue; }
+                        match res {
+                            Ok(data) => { sndres[index] = Sound(Chunk::new(data, 128)); }
+                            Err(_) => {
+                                warn!("failed to load sound \\#WAV{} ({})", Key(index as int),
+                                      self.paths[index].as_ref().unwrap().display());
+                            }
+                        }
+                    }
+                    Err(_) => { break; }
+                }
+            }
+
+            for index in range(0, sndres.len()) {
+                if !self.requested[index] || self.lastuse[index] >= line - LAZY_KEEPBEHIND {
+                    continue;
+                }
+                if mixer::is_playing(index) { continue; }
+                self.requested[mut][index] = false;
+                sndres[index] = NoSound;
+            }
+        }
+    }
+
+    /// Saves a portion of the screen for the use in `graphic_update_status`.
+    pub fn save_screen_for_loading(screen: &Surface) -> AngolmoisResult<Surface> {
+        let saved_screen = try!(gfx::new_surface(SCREENW, 20).map_err(SdlFailure));
+        saved_screen.blit_area(screen, (0u,SCREENH-20), (0u,0u), (SCREENW,20u));
+        Ok(saved_screen)
+    }
+
+    /// A callback template for `load_resource` with the graphical loading screen. The percentage
+    /// bar is drawn at the bottom of the screen from `progress`, or filled if it is `None`.
+    /// Returns true if the user pressed the return key. (C: `resource_loaded`)
+    pub fn graphic_update_status(path: Option<String>, progress: Option<(uint, uint)>,
+                                 screen: &Surface, presenter: Option<&::util::gl::Presenter>,
+                                 saved_screen: &Surface, font: &Font, ticker: &mut Ticker,
+                                 atexit: ||) -> AngolmoisResult<bool> {
+        use std::mem;
+
+        let mut path = path;
+        ticker.on_tick(sdl::get_ticks(), || {
+            let path = mem::replace(&mut path, None);
+            let (done, total) = progress.unwrap_or((1, 1));
+            let percent = if total > 0 {done * 100 / total} else {100};
+            let msg = format!("{:3}% {}", percent, path.unwrap_or("loading...".to_string()));
+            screen.blit_at(saved_screen, 0, (SCREENH-20) as i16);
+            screen.with_pixels(|pixels| {
+                font.print_string(pixels, SCREENW-3, SCREENH-18, 1, RightAligned, msg[],
+                                  Gradient::new(RGB(0xc0,0xc0,0xc0), RGB(0x80,0x80,0x80)));
+            });
+            screen.fill_area((0u, SCREENH-2), (SCREENW * percent / 100, 2u),
+                             RGB(0x80,0xc0,0xff));
+            present(screen, presenter);
+        });
+        check_exit_or_enter(atexit)
+    }
+
+    /// A callback template for `load_resource` with the textual loading screen.
+    /// (C: `resource_loaded`)
+    pub fn text_update_status(path: Option<String>, ticker: &mut Ticker,
+                              atexit: ||) -> AngolmoisResult<()> {
+        use std::mem;
+
+        let mut path = path;
+        ticker.on_tick(sdl::get_ticks(), || {
+            match mem::replace(&mut path, None) {
+                Some(path) => {
+                    use util::str::StrUtil;
+                    let path = if path.len() < 63 {path[]} else {path[].slice_upto(0, 63)};
+                    update_line(format!("Loading: {}", path)[]);
+                }
+                None => { update_line("Loading done."); }
+            }
+        });
+        check_exit(atexit)
+    }
+
+    /// Plays the audio clip specified by BMS #PREVIEW command until it ends. Returns false if
+    /// the command is absent or the clip cannot be loaded, so that the caller can fall back to
+    /// playing the first `PREVIEW_MEASURES` measures. SDL_mixer should have been initialized.
+    pub fn play_preview_clip(bms: &Bms, opts: &Options, atexit: ||) -> AngolmoisResult<bool> {
+        let path = match bms.preview {
+            Some(ref path) => path,
+            None => { return Ok(false); }
+        };
+        let basedirs = get_basedirs(bms, opts);
+        let res = match resolve_relative_path(basedirs[], path[], SOUND_EXTS) {
+            Some(fullpath) => load_chunk(&fullpath),
+            None => Err(format!("not found"))
+        };
+        let mut chunk = match res {
+            Ok(chunk) => chunk,
+            Err(_) => {
+                warn!("failed to load the preview clip ({})", path);
+                return Ok(false);
+            }
+        };
+
+        if chunk.play(None, 0) < 0 { return Ok(false); }
+        while sdl_mixer::num_playing(None) > 0 {
+            try!(check_exit(|| atexit()));
+            ::util::delay(10);
+        }
+        Ok(true)
+    }
+
+    //----------------------------------------------------------------------------------------------
+    // pointers
+
+    /// A pointer to the object. A pointer is used to implement common operations, e.g. iterating
+    /// until given position, or finding the closest object with given condition. A pointer can also
+    /// be used like an object when it points to the valid object.
+    pub struct Pointer {
+        /// A BMS data holding objects.
+        pub bms: Rc<Bms>,
+        /// The current position. Can be the past-the-end value.
+        pub pos: uint,
+        /// The next position used by `next_*` methods, which are required to delay advancing `pos`
+        /// by one step (so that the first iteration sees the current pointer yet to be updated).
+        /// Therefore `next` is initially set to `None`, then each `next_*` call sets `next` to
+        /// what `pos` needs to be after the next invocation.
+        pub next: Option<uint>,
+    }
+
+    /// Returns whichever pointer is closer to the virtual time `base`, preferring `next` on ties.
+    fn closer_pointer(base: f64, previous: Option<Pointer>,
+                      next: Option<Pointer>) -> Option<Pointer> {
+        match (previous, next) {
+            (None, None) => None,
+            (None, Some(next)) => Some(next),
+            (Some(previous), None) => Some(previous),
+            (Some(previous), Some(next)) =>
+                if num::abs(previous.time() - base) <
+                   num::abs(next.time() - base) { Some(previous) }
+                else { Some(next) }
+        }
+    }
+
+    /// Returns true if two pointers share the common BMS data.
+    fn has_same_bms(lhs: &Pointer, rhs: &Pointer) -> bool {
+        lhs.bms.deref() as *const Bms == rhs.bms.deref() as *const Bms
+    }
+
+    impl PartialEq for Pointer {
+        fn eq(&self, other: &Pointer) -> bool {
+            has_same_bms(self, other) && self.pos == other.pos
+        }
+        fn ne(&self, other: &Pointer) -> bool {
+            !has_same_bms(self, other) || self.pos != other.pos
+        }
+    }
+
+    impl PartialOrd for Pointer {
+        fn partial_cmp(&self, other: &Pointer) -> Option<Ordering> {
+            assert!(has_same_bms(self, other));
+            self.pos.partial_cmp(&other.pos)
+        }
+    }
+
+    impl Clone for Pointer {
+        fn clone(&self) -> Pointer {
+            Pointer { bms: self.bms.clone(), pos: self.pos, next: None }
+        }
+    }
+
+    impl ObjQueryOps for Pointer {
+        fn is_visible(&self) -> bool { self.objs()[self.pos].is_visible() }
+        fn is_invisible(&self) -> bool { self.objs()[self.pos].is_invisible() }
+        fn is_lnstart(&self) -> bool { self.objs()[self.pos].is_lnstart() }
+        fn is_lndone(&self) -> bool { self.objs()[self.pos].is_lndone() }
+        fn is_ln(&self) -> bool { self.objs()[self.pos].is_ln() }
+        fn is_bomb(&self) -> bool { self.objs()[self.pos].is_bomb() }
+        fn is_soundable(&self) -> bool { self.objs()[self.pos].is_soundable() }
+        fn is_gradable(&self) -> bool { self.objs()[self.pos].is_gradable() }
+        fn is_renderable(&self) -> bool { self.objs()[self.pos].is_renderable() }
+        fn is_object(&self) -> bool { self.objs()[self.pos].is_object() }
+        fn is_bgm(&self) -> bool { self.objs()[self.pos].is_bgm() }
+        fn is_setbga(&self) -> bool { self.objs()[self.pos].is_setbga() }
+        fn is_setbpm(&self) -> bool { self.objs()[self.pos].is_setbpm() }
+        fn is_stop(&self) -> bool { self.objs()[self.pos].is_stop() }
+
+        fn object_lane(&self) -> Option<Lane> { self.objs()[self.pos].object_lane() }
+        fn sounds(&self) -> Vec<SoundRef> { self.objs()[self.pos].sounds() }
+        fn keydown_sound(&self) -> Option<SoundRef> { self.objs()[self.pos].keydown_sound() }
+        fn keyup_sound(&self) -> Option<SoundRef> { self.objs()[self.pos].keyup_sound() }
+        fn through_sound(&self) -> Option<SoundRef> { self.objs()[self.pos].through_sound() }
+        fn images(&self) -> Vec<ImageRef> { self.objs()[self.pos].images() }
+        fn through_damage(&self) -> Option<Damage> { self.objs()[self.pos].through_damage() }
+    }
+
+    impl Pointer {
+        /// Returns a pointer pointing the first object in `bms`.
+        pub fn new(bms: Rc<Bms>) -> Pointer {
+            Pointer { bms: bms, pos: 0, next: None }
+        }
+
+        /// Returns a pointer pointing given object in `bms`.
+        pub fn new_with_pos(bms: Rc<Bms>, pos: uint) -> Pointer {
+            Pointer { bms: bms, pos: pos, next: None }
+        }
+
+        /// Returns a reference to the list of underlying objects.
+        fn objs<'r>(&'r self) -> &'r [Obj] { self.bms.objs[] }
+
+        /// Returns the time of pointed object.
+        pub fn time(&self) -> f64 { self.objs()[self.pos].time }
+
+        /// Returns the number of a measure containing the pointed object.
+        pub fn measure(&self) -> int { self.objs()[self.pos].measure() }
+
+        /// Returns the associated game data of pointed object.
+        pub fn data(&self) -> ObjData { self.objs()[self.pos].data }
+
+        /// Resets the internal iteration state.
+        pub fn reset(&mut self) {
+            self.next = None;
+        }
+
+        /// Seeks to the first object which time is past the limit, if any.
+        pub fn seek_until(&mut self, limit: f64) {
+            let objs = self.bms.objs[];
+            let nobjs = objs.len();
+            while self.pos < nobjs {
+                if objs[self.pos].time >= limit { break; }
+                self.pos += 1;
+            }
+            self.next = None;
+        }
+
+        /// Tries to advance to the next object which time is within the limit.
+        /// Returns false if it's impossible.
+        pub fn next_until(&mut self, limit: f64) -> bool {
+            let objs = self.bms.objs[];
+            match self.next {
+                Some(next) => { self.pos = next; }
+                None => {}
+            }
+            if self.pos < objs.len() && objs[self.pos].time < limit {
+                self.next = Some(self.pos + 1);
+                true
+            } else {
+                self.next = None;
+                false
+            }
+        }
+
+        /// Seeks to the object pointed by the other pointer.
+        pub fn seek_to(&mut self, limit: &Pointer) {
+            assert!(has_same_bms(self, limit));
+            assert!(limit.pos <= self.bms.objs.len());
+            self.pos = limit.pos;
+            self.next = None;
+        }
+
+        /// Tries to advance to the next object which precedes the other pointer.
+        /// Returns false if it's impossible.
+        pub fn next_to(&mut self, limit: &Pointer) -> bool {
+            assert!(has_same_bms(self, limit));
+            match self.next {
+                Some(next) => { self.pos = next; }
+                None => {}
+            }
+            if self.pos >= limit.pos { return false; }
+            self.next = Some(self.pos + 1);
+            true
+        }
+
+        /// Seeks to the end of objects.
+        pub fn seek_to_end(&mut self) {
+            self.pos = self.bms.objs.len();
+            self.next = None;
+        }
+
+        /// Tries to advance to the next object. Returns false if it's the end of objects.
+        pub fn next_to_end(&mut self) -> bool {
+            let objs = self.bms.objs[];
+            match self.next {
+                Some(next) => { self.pos = next; }
+                None => {}
+            }
+            if self.pos >= objs.len() { return false; }
+            self.next = Some(self.pos + 1);
+            true
+        }
+
+        /// Finds the next object that satisfies given condition if any, without updating itself.
+        pub fn find_next_of_type(&self, cond: |&Obj| -> bool) -> Option<Pointer> {
+            let objs = self.bms.objs[];
+            let nobjs = objs.len();
+            let mut i = self.pos;
+            while i < nobjs {
+                if cond(&objs[i]) {
+                    return Some(Pointer { bms: self.bms.clone(), pos: i, next: None });
+                }
+                i += 1;
+            }
+            None
+        }
+
+        /// Finds the previous object that satisfies given condition if any, without updating
+        /// itself.
+        pub fn find_previous_of_type(&self, cond: |&Obj| -> bool) -> Option<Pointer> {
+            let objs = self.bms.objs[];
+            let mut i = self.pos;
+            while i > 0 {
+                i -= 1;
+                if cond(&objs[i]) {
+                    return Some(Pointer { bms: self.bms.clone(), pos: i, next: None });
+                }
+            }
+            None
+        }
+
+        /// Finds the closest object from the virtual time `base` that satisfies given condition
+        /// if any. `base` should lie between the pointed object and the previous object.
+        /// The proximity is measured in terms of virtual time, which can differ from actual time.
+        pub fn find_closest_of_type(&self, base: f64, cond: |&Obj| -> bool) -> Option<Pointer> {
+            let previous = self.find_previous_of_type(|obj| cond(obj));
+            let next = self.find_next_of_type(|obj| cond(obj));
+            closer_pointer(base, previous, next)
+        }
+
+        /// Same as `find_next_of_type` but only looks at objects in the sorted list of object
+        /// indices `index`, typically built by `parser::lane_objects`.
+        pub fn find_next_in(&self, index: &[uint], cond: |&Obj| -> bool) -> Option<Pointer> {
+            let objs = self.bms.objs[];
+            for &i in index[parser::lower_bound(index, self.pos)..].iter() {
+                if cond(&objs[i]) {
+                    return Some(Pointer { bms: self.bms.clone(), pos: i, next: None });
+                }
+            }
+            None
+        }
+
+        /// Same as `find_previous_of_type` but only looks at objects in the sorted list of object
+        /// indices `index`, typically built by `parser::lane_objects`.
+        pub fn find_previous_in(&self, index: &[uint], cond: |&Obj| -> bool) -> Option<Pointer> {
+            let objs = self.bms.objs[];
+            for &i in index[..parser::lower_bound(index, self.pos)].iter().rev() {
+                if cond(&objs[i]) {
+                    return Some(Pointer { bms: self.bms.clone(), pos: i, next: None });
+                }
+            }
+            None
+        }
+
+        /// Same as `find_closest_of_type` but only looks at objects in the sorted list of object
+        /// indices `index`, typically built by `parser::lane_objects`.
+        pub fn find_closest_in(&self, base: f64, index: &[uint],
+                               cond: |&Obj| -> bool) -> Option<Pointer> {
+            let previous = self.find_previous_in(index, |obj| cond(obj));
+            let next = self.find_next_in(index, |obj| cond(obj));
+            closer_pointer(base, previous, next)
+        }
+    }
+
+    //----------------------------------------------------------------------------------------------
+    // game play logics
+
+    /// Grades. Angolmois performs the time-based grading as long as possible (it can go wrong when
+    /// the object is near the discontinuity due to the current implementation strategy).
+    #[deriving(PartialEq,Eq,Clone)]
+    pub enum Grade {
+        /**
+         * Issued when the player did not input the object at all, the player was pressing the key
+         * while a bomb passes through the corresponding lane, or failed to unpress the key within
+         * the grading area for the end of LN. Resets the combo number, decreases the gauge
+         * by severe amount (`MISS_DAMAGE` unless specified by the bomb) and displays the POOR BGA
+         * for moments.
+         *
+         * Several games also use separate grading areas for empty lanes next to the object,
+         * in order to avoid continuing the consecutive run ("combo") of acceptable grades by
+         * just pressing every keys in the correct timing instead of pressing only lanes containing
+         * objects. While this system is not a bad thing (and there are several BMS implementations
+         * that use it), it is tricky to implement for the all situations. Angolmois currently
+         * does not use this system due to the complexity.
+         */
+        MISS = 0,
+        /// Issued when the player inputed the object and the normalized time difference (that is,
+        /// the time difference multiplied by `Player::gradefactor`) between the input point and
+        /// the object is between `GOOD_CUTOFF` and `BAD_CUTOFF` milliseconds. Resets the combo
+        /// number, decreases the gauge by moderate amount (`BAD_DAMAGE`) and displays the POOR BGA
+        /// for moments.
+        BAD  = 1,
+        /// Issued when the player inputed the object and the normalized time difference is between
+        /// `GREAT_CUTOFF` and `GOOD_CUTOFF` milliseconds. Both the combo number and gauge is
+        /// left unchanged.
+        GOOD = 2,
+        /// Issued when the player inputed the object and the normalized time difference is between
+        /// `COOL_CUTOFF` and `GREAT_CUTOFF` milliseconds. The combo number is increased by one and
+        /// the gauge is replenished by small amount.
+        GREAT = 3,
+        /// Issued when the player inputed the object and the normalized time difference is between
+        /// `PGREAT_CUTOFF` and `COOL_CUTOFF` milliseconds. The combo number is increased by one and
+        /// the gauge is replenished by large amount.
+        COOL = 4,
+        /// Issued when the player inputed the object and the normalized time difference is less
+        /// than `PGREAT_CUTOFF` milliseconds, i.e. the inner half of the COOL window. Same as COOL
+        /// except for the EX score. Corresponds to PGREAT in other implementations, where COOL
+        /// and GREAT both correspond to GREAT.
+        PGREAT = 5,
+    }
+
+    impl Grade {
+        /// Returns the EX score for this grade: 2 points for PGREAT, 1 point for COOL and GREAT
+        /// and none otherwise, as in other implementations.
+        pub fn exscore(&self) -> uint {
+            match *self {
+                PGREAT => 2,
+                COOL | GREAT => 1,
+                GOOD | BAD | MISS => 0,
+            }
+        }
+    }
+
+    /// Required time difference in milliseconds to get PGREAT grade.
+    const PGREAT_CUTOFF: f64 = 7.2;
+    /// Required time difference in milliseconds to get at least COOL grade.
+    const COOL_CUTOFF: f64 = 14.4;
+    /// Required time difference in milliseconds to get at least GREAT grade.
+    const GREAT_CUTOFF: f64 = 48.0;
+    /// Required time difference in milliseconds to get at least GOOD grade.
+    const GOOD_CUTOFF: f64 = 84.0;
+    /// Required time difference in milliseconds to get at least BAD grade.
+    const BAD_CUTOFF: f64 = 144.0;
+
+    /// The number of available grades.
+    pub const NGRADES: uint = 6;
+
+    /// The maximum (internal) value for the gauge.
+    const MAXGAUGE: int = 512;
+    /// The initial value for the gauge.
+    const INITIAL_GAUGE: int = MAXGAUGE * 500 / 1000;
+    /// A base score per exact input. Actual score can increase by the combo (up to 2x) or decrease
+    /// by the larger time difference.
+    const SCOREPERNOTE: f64 = 300.0;
+
+    /// A damage due to the MISS grading. Only applied when the grading is not due to the bomb.
+    const MISS_DAMAGE: Damage = GaugeDamage(0.059);
+    /// A damage due to the BAD grading.
+    const BAD_DAMAGE: Damage = GaugeDamage(0.030);
+
+    /// Gauge types, which determine how the gauge changes and when the player fails.
+    #[deriving(PartialEq,Eq,Clone)]
+    pub enum GaugeKind {
+        /// The gauge starts at the half, takes 80% of the normal damage and requires 20% of
+        /// the full gauge to survive.
+        EasyGauge,
+        /// The gauge starts at the half and requires 29.3% of the full gauge to survive.
+        /// This is the default.
+        NormalGauge,
+        /// The gauge starts at the full, recovers slowly and takes 150% of the normal damage.
+        /// The player fails instantly when the gauge is depleted, but the damage is halved when
+        /// the gauge is below 30%.
+        HardGauge,
+        /// Same as `HardGauge` but takes twice the damage without any relief.
+        ExHardGauge,
+        /// The gauge starts at the full, and the player fails instantly at any BAD or MISS.
+        HazardGauge,
+    }
+
+    impl GaugeKind {
+        /// Returns the gauge kind with given name (case-insensitive) if any.
+        pub fn from_name(name: &str) -> Option<GaugeKind> {
+            use std::ascii::AsciiExt;
+            match name.to_ascii_lower()[] {
+                "easy" => Some(EasyGauge),
+                "normal" => Some(NormalGauge),
+                "hard" => Some(HardGauge),
+                "exhard" | "ex-hard" => Some(ExHardGauge),
+                "hazard" => Some(HazardGauge),
+                _ => None
+            }
+        }
+
+        /// Returns true if the player fails as soon as the gauge is depleted.
+        pub fn is_instant_fail(&self) -> bool {
+            match *self {
+                EasyGauge | NormalGauge => false,
+                HardGauge | ExHardGauge | HazardGauge => true,
+            }
+        }
+
+        /// Returns the initial value for the gauge.
+        pub fn initial_gauge(&self) -> int {
+            if self.is_instant_fail() {MAXGAUGE} else {INITIAL_GAUGE}
+        }
+
+        /// Returns the health gauge required to survive at the end of the song. The instant-fail
+        /// gauges only require the gauge not to be depleted.
+        pub fn survival(&self) -> int {
+            match *self {
+                EasyGauge => MAXGAUGE * 200 / 1000,
+                NormalGauge => MAXGAUGE * 293 / 1000,
+                HardGauge | ExHardGauge | HazardGauge => 1,
+            }
+        }
+
+        /// Returns the gauge recovery for GREAT, COOL or PGREAT grade with given combo number.
+        pub fn recovery(&self, grade: Grade, combo: uint) -> int {
+            match *self {
+                EasyGauge | NormalGauge => {
+                    // at most 5/512(1%) recover when the combo is topped
+                    let weight = if grade == GREAT {2} else {3};
+                    weight + cmp::min(combo as int, 100) / 50
+                }
+                HardGauge | ExHardGauge | HazardGauge => 1,
+            }
+        }
+
+        /// Returns the multiplier to the gauge damage with given current gauge.
+        pub fn damage_factor(&self, gauge: int) -> f64 {
+            match *self {
+                EasyGauge => 0.8,
+                NormalGauge | HazardGauge => 1.0,
+                HardGauge => if gauge < MAXGAUGE * 300 / 1000 {0.75} else {1.5},
+                ExHardGauge => 3.0,
+            }
+        }
+
+        /// Returns the color of the gauge bar.
+        pub fn color(&self) -> Color {
+            match *self {
+                EasyGauge => RGB(0x40,0xc0,0x40),
+                NormalGauge => RGB(0xc0,0,0),
+                HardGauge => RGB(0xc0,0xc0,0xc0),
+                ExHardGauge => RGB(0xc0,0xc0,0),
+                HazardGauge => RGB(0xa0,0x40,0xc0),
+            }
+        }
+
+        /// Applies the optional damage to the current gauge. Returns the updated gauge and false
+        /// if the damage resulted in the instant death.
+        pub fn apply_damage(&self, gauge: int, damage: Option<Damage>) -> (int, bool) {
+            match damage {
+                Some(GaugeDamage(_)) if *self == HazardGauge => (cmp::min(gauge, 0), false),
+                Some(GaugeDamage(ratio)) => {
+                    let damage = MAXGAUGE as f64 * ratio * self.damage_factor(gauge);
+                    let gauge = gauge - damage as int;
+                    if self.is_instant_fail() && gauge <= 0 {(0, false)} else {(gauge, true)}
+                }
+                Some(InstantDeath) => (cmp::min(gauge, 0), false),
+                None => (gauge, true)
+            }
+        }
+    }
+
+    /// Scoring states of one side in the Couple Play, where players on the left and right sides
+    /// have their own scores and gauges. The combined states are kept in `Player` as usual.
+    pub struct SideState {
+        /// Lanes belonging to this side.
+        pub lanes: Vec<Lane>,
+        /// The number of visible objects in this side. See also `BmsInfo::nnotes`.
+        pub nnotes: uint,
+        /// The maximum possible score of this side. See also `BmsInfo::maxscore`.
+        pub maxscore: uint,
+        /// The numbers of each grades in this side.
+        pub gradecounts: [uint, ..NGRADES],
+        /// The last combo number in this side.
+        pub lastcombo: uint,
+        /// The best combo number in this side so far.
+        pub bestcombo: uint,
+        /// The current score of this side.
+        pub score: uint,
+        /// The current EX score of this side.
+        pub exscore: uint,
+        /// The current health gauge of this side.
+        pub gauge: int,
+        /// True if the gauge of this side has been depleted by the instant death or
+        /// the instant-fail gauge. The gauge no longer recovers once set.
+        pub failed: bool,
+    }
+
+    impl SideState {
+        /// Creates a new side state for given lanes.
+        pub fn new(bms: &Bms, lanes: &[Lane], initgauge: int) -> SideState {
+            let mut nnotes = 0;
+            for obj in bms.objs.iter() {
+                if !(obj.is_lnstart() || obj.is_visible()) { continue; }
+                if obj.object_lane().map_or(false, |lane| lanes.contains(&lane)) { nnotes += 1; }
+            }
+            let mut maxscore = 0;
+            for i in range(0, nnotes) {
+                let ratio = (i as f64) / (nnotes as f64);
+                maxscore += (300.0 * (1.0 + ratio)) as uint;
+            }
+            SideState { lanes: lanes.to_vec(), nnotes: nnotes, maxscore: maxscore,
+                        gradecounts: [0, ..NGRADES], lastcombo: 0, bestcombo: 0, score: 0,
+                        exscore: 0, gauge: initgauge, failed: false }
+        }
+
+        /// Resets the scoring states for restarting the game play.
+        pub fn reset(&mut self, initgauge: int) {
+            self.gradecounts = [0, ..NGRADES];
+            self.lastcombo = 0;
+            self.bestcombo = 0;
+            self.score = 0;
+            self.exscore = 0;
+            self.gauge = initgauge;
+            self.failed = false;
+        }
+
+        /// Updates the scoring states of this side. Analogous to `Player::update_grade`, but
+        /// the instant death only empties the gauge of this side, which stays empty for the rest
+        /// of the game play. Returns false if this side has failed.
+        pub fn update_grade(&mut self, kind: &GaugeKind, grade: Grade, scoredelta: f64,
+                            damage: Option<Damage>) -> bool {
+            self.gradecounts[grade as uint] += 1;
+            self.score += (scoredelta * SCOREPERNOTE *
+                           (1.0 + (self.lastcombo as f64) / (self.nnotes as f64))) as uint;
+            match grade {
+                MISS | BAD => { self.lastcombo = 0; }
+                GOOD => {}
+                GREAT | COOL | PGREAT => {
+                    self.exscore += grade.exscore();
+                    let recovery = kind.recovery(grade, self.lastcombo);
+                    self.lastcombo += 1;
+                    if !self.failed { self.gauge = cmp::min(self.gauge + recovery, MAXGAUGE); }
+                }
+            }
+            self.bestcombo = cmp::max(self.bestcombo, self.lastcombo);
+            if !self.failed {
+                let (gauge, keepgoing) = kind.apply_damage(self.gauge, damage);
+                self.gauge = gauge;
+                self.failed = !keepgoing;
+            }
+            !self.failed
+        }
+    }
+
+    /// A sample of the score and gauge, recorded whenever an object is graded.
+    pub struct ScoreSample {
+        /// The time of the sample in milliseconds, relative to `Player::origintime`.
+        pub time: uint,
+        /// The score at that time.
+        pub score: uint,
+        /// The EX score at that time.
+        pub exscore: uint,
+        /// The health gauge at that time.
+        pub gauge: int,
+    }
+
+    /// A source of timestamps for the game play, which determines the virtual time of the chart.
+    pub trait Clock {
+        /// Returns the current timestamp in milliseconds. Should never decrease.
+        fn ticks(&self) -> uint;
+    }
+
+    /// The real clock, same as `sdl::get_ticks`.
+    pub struct SdlClock;
+
+    impl Clock for SdlClock {
+        fn ticks(&self) -> uint { sdl::get_ticks() }
+    }
+
+    /// The simulated clock advanced by the caller, for driving the game play deterministically.
+    /// Clones share the same time, so the caller can keep one while the player owns another.
+    #[deriving(Clone)]
+    pub struct ManualClock {
+        /// The current timestamp in milliseconds.
+        now: Rc<Cell<uint>>,
+    }
+
+    impl ManualClock {
+        /// Creates a new simulated clock starting at given timestamp.
+        pub fn new(now: uint) -> ManualClock {
+            ManualClock { now: Rc::new(Cell::new(now)) }
+        }
+
+        /// Advances the clock by given milliseconds.
+        pub fn advance(&self, msec: uint) {
+            self.now.set(self.now.get() + msec);
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn ticks(&self) -> uint { self.now.get() }
+    }
+
+    /// A source of input events and sound states for the game play, so that the game play can be
+    /// driven without SDL along with `ManualClock`.
+    pub trait EventSource {
+        /// Returns the next pending event, or `NoEvent` if there is none.
+        fn poll_event(&mut self) -> event::Event;
+        /// Returns the number of voices in given mixer group being played or scheduled, which
+        /// delays the end of the game play.
+        fn num_playing(&self, group: uint) -> uint;
+    }
+
+    /// The real events from SDL and the states of the actual mixer.
+    pub struct SdlEventSource;
+
+    impl EventSource for SdlEventSource {
+        fn poll_event(&mut self) -> event::Event { event::poll_event() }
+        fn num_playing(&self, group: uint) -> uint { mixer::num_playing(group) }
+    }
+
+    /// The queue of simulated events fed by the caller, where no sounds are ever played.
+    /// Clones share the same queue, so the caller can keep one while the player owns another.
+    #[deriving(Clone)]
+    pub struct EventQueue {
+        /// Pending events in the order of arrival.
+        events: Rc<RefCell<RingBuf<event::Event>>>,
+    }
+
+    impl EventQueue {
+        /// Creates a new empty queue.
+        pub fn new() -> EventQueue {
+            EventQueue { events: Rc::new(RefCell::new(RingBuf::new())) }
+        }
+
+        /// Adds an event to be returned by the next updates.
+        pub fn push(&self, ev: event::Event) {
+            self.events.borrow_mut().push_back(ev);
+        }
+    }
+
+    impl EventSource for EventQueue {
+        fn poll_event(&mut self) -> event::Event {
+            self.events.borrow_mut().pop_front().unwrap_or(NoEvent)
+        }
+        fn num_playing(&self, _group: uint) -> uint { 0 }
+    }
+
+    /// Game play states independent to the display.
+    pub struct Player {
+        /// The game play options.
+        pub opts: Options,
+        /// The current BMS data.
+        pub bms: Rc<Bms>,
+        /// The derived BMS information.
+        pub infos: BmsInfo,
+        /// The mapping between the virtual time and actual time. Also used to scroll objects at
+        /// a constant rate as requested by `Options::constant`.
+        pub timeline: Timeline,
+        /// The length of BMS file in seconds as calculated by `bms_duration`. (C: `duration`)
+        pub duration: f64,
+        /// The key specification.
+        pub keyspec: KeySpec,
+        /// The input mapping.
+        pub keymap: KeyMap,
+
+        /// Indices to objects in `bms.objs` for each lane, as built by `parser::lane_objects`.
+        pub laneobjs: Vec<Vec<uint>>,
+
+        /// Set to true if the corresponding object in `bms.objs` had graded and should not be
+        /// graded twice. Its length equals to that of `bms.objs`. (C: `nograding` field in
+        /// `struct obj`)
+        pub nograding: Vec<bool>,
+        /// Sound resources. (C: `res` field in `sndres`)
+        pub sndres: Vec<SoundResource>,
+        /// The on-demand loader for `sndres` if `Options::lazysounds` is set.
+        pub lazysounds: Option<LazySounds>,
+        /// The background loader for `sndres` if `Options::earlystart` is set and some sounds are
+        /// yet to be loaded.
+        pub background: Option<BackgroundSounds>,
+        /// The number of resources failed to load, shown during the game play.
+        pub nmissing: uint,
+        /// A sound chunk used for beeps. It always plays on the channel #0. (C: `beep`)
+        pub beep: Chunk,
+        /// A sound chunk used for the assist clap. It always plays on the channel #1.
+        pub clap: Chunk,
+        /// Currently active BGA layers. (C: `bga`)
+        pub bga: BGAState,
+
+        /// The chart expansion rate, or "play speed". One measure has the length of 400 pixels
+        /// times the play speed, so higher play speed means that objects will fall much more
+        /// quickly (hence the name). (C: `playspeed`)
+        pub playspeed: f64,
+        /// The play speed targeted for speed change if any. It is also the value displayed while
+        /// the play speed is changing. (C: `targetspeed`)
+        pub targetspeed: Option<f64>,
+        /// The time in milliseconds an object stays on the screen if the play speed is derived
+        /// from the current BPM. Speed change inputs adjust this instead of the play speed.
+        pub greennumber: Option<f64>,
+        /// The current height of the lane cover in pixels. Initially set to `Options::cover`.
+        pub cover: uint,
+        /// The current height of the lift in pixels. Initially set to `Options::lift`.
+        pub lift: uint,
+        /// Set to true if the screenshot is requested since the last frame. The display saves
+        /// the screenshot after rendering, and the caller resets this after the frame.
+        pub screenshot: bool,
+        /// The clock driving the game play. Usually `SdlClock`.
+        pub clock: Box<Clock+'static>,
+        /// The source of input events and sound states. Usually `SdlEventSource`.
+        pub events: Box<EventSource+'static>,
+        /// The timestamp at the last update. It is a return value from `Clock::ticks` and measured
+        /// in milliseconds. (C: `now`)
+        pub now: uint,
+        /// The timestamp at the first tick. (C: `origintime`)
+        pub origintime: uint,
+        /**
+         * The timestamp at the last discontinuity that breaks a linear relationship between
+         * the virtual time and actual time. (C: `starttime`) Currently the following are
+         * considered a discontinuity:
+         *
+         * - `origintime`
+         * - A change in BPM
+         * - A change in scaling factor of measure
+         * - A scroll stopper (in this case, `stoptime` is first updated and `starttime` is updated
+         *   at the end of stop)
+         */
+        pub starttime: uint,
+        /// The timestamp at the end of ongoing scroll stopper, if any. (C: `stoptime`)
+        pub stoptime: Option<uint>,
+        /// The virtual time at the last discontinuity. (C: `startoffset`)
+        pub startoffset: f64,
+        /// The current scaling factor of measure. (C: `startshorten`)
+        pub startshorten: f64,
+
+        /// The virtual time at the bottom of the visible chart. (C: `bottom`)
+        pub bottom: f64,
+        /// The virtual time at the grading line. Currently same as `bottom`. (C: `line`)
+        pub line: f64,
+        /// The virtual time at the top of the visible chart. (C: `top`)
+        pub top: f64,
+        /// A pointer to the first `Obj` after `bottom`. (C: `pfront`)
+        pub pfront: Pointer,
+        /// A pointer to the first `Obj` after `line`. (C: `pcur`)
+        pub pcur: Pointer,
+        /// A pointer to the first `Obj` that haven't escaped the grading area. It is possible that
+        /// this `Obj` haven't reached the grading area either. (C: `pcheck`)
+        pub pcheck: Pointer,
+        /// A pointer to the next BGM `Obj` to be queued to the mixer. BGMs are delayed by
+        /// `Options::offset` so that they are in sync with the grading, and queued up to
+        /// `SOUND_LOOKAHEAD` ahead, therefore it can differ from `pcur`. Key sounds are queued
+        /// with this pointer as well in the automatic play.
+        pub paudio: Pointer,
+        /// The virtual time of the next metronome click. Delayed by `Options::offset` as BGMs.
+        pub nextclick: f64,
+        /// The timestamp of the last metronome click at the start of measure, if any.
+        pub lastdownbeat: Option<uint>,
+        /// Pointers to `Obj`s for the start of LN which grading is in progress. (C: `pthru`)
+        //
+        // Rust: this is intended to be `[Option<Pointer>, ..NLANES]` but a fixed-size vector cannot
+        //       be cloned.
+        pub pthru: Vec<Option<Pointer>>,
+
+        /// The scale factor for grading area. The factor less than 1 causes the grading area
+        /// shrink. (C: `gradefactor`)
+        pub gradefactor: f64,
+        /// (C: `grademode` and `gradetime`)
+        pub lastgrade: Option<(Grade,uint)>,
+        /// The last grade and its time per lane, used for the per-lane effects.
+        pub lasthits: Vec<Option<(Grade,uint)>>,
+        /// True if the last grade was early (FAST), false if late (SLOW). Only set for the grades
+        /// other than COOL and PGREAT that were graded by the input timing.
+        pub lastfast: Option<bool>,
+        /// The number of early (FAST) grades other than COOL and PGREAT.
+        pub fastcount: uint,
+        /// The number of late (SLOW) grades other than COOL and PGREAT.
+        pub slowcount: uint,
+        /// The numbers of each grades. (C: `scocnt`)
+        pub gradecounts: [uint, ..NGRADES],
+        /// The last combo number, i.e. the number of objects graded at least GREAT. GOOD doesn't
+        /// cause the combo number reset; BAD and MISS do. (C: `scombo`)
+        pub lastcombo: uint,
+        /// The best combo number so far. If the player manages to get no BADs and MISSes, then
+        /// the combo number should end up with the number of note and LN objects
+        /// (`BMSInfo::nnotes`). (C: `smaxcombo`)
+        pub bestcombo: uint,
+        /// The current score. (C: `score`)
+        pub score: uint,
+        /// The current EX score, which counts 2 points per PGREAT and 1 point per COOL or GREAT
+        /// (see `Grade::exscore`), so this is comparable to EX scores in other implementations.
+        /// The maximum is twice the number of notes.
+        pub exscore: uint,
+        /// The current health gauge. Should be no larger than `MAXGAUGE`. This can go negative
+        /// (not displayed directly), which will require players much more efforts to survive.
+        /// (C: `gauge`)
+        pub gauge: int,
+        /// The health gauge required to survive at the end of the song. Note that the gaugex
+        /// less than this value (or even zero) doesn't cause the instant game over;
+        /// only `InstantDeath` value from `Damage` does. (C: `survival`)
+        pub survival: int,
+        /// Scoring states of the left and right sides for the Couple Play. Empty otherwise.
+        pub sides: Vec<SideState>,
+        /// Samples of the score and gauge so far, used for the graph in the result screen.
+        pub history: Vec<ScoreSample>,
+
+        /// The number of keyboard or joystick keys, mapped to each lane and and currently pressed.
+        /// (C: `keypressed[0]`)
+        pub keymultiplicity: [uint, ..NLANES],
+        /// The state of joystick axes. (C: `keypressed[1]`)
+        pub joystate: [InputState, ..NLANES],
+        /// The state of analog turntables, keyed by the device index and the axis index.
+        pub turntables: HashMap<(uint, uint), TurntableState>,
+
+        /// Inputs to the lanes recorded so far, used for writing the replay.
+        pub replay: Vec<ReplayEvent>,
+        /// The replay played back instead of the actual input if any.
+        pub playback: Option<Replay>,
+        /// An index to the next event to be played back in `playback`.
+        pub playbackpos: uint,
+        /// The replay compared against the current game play if any.
+        pub ghost: Option<Replay>,
+        /// The number of events in `ghost` that have been passed.
+        pub ghostpos: uint,
+        /// The best record for the chart before this game play if any. Used for the pacemaker.
+        pub record: Option<ScoreRecord>,
+        /// The start of the A-B loop (or the A point) and the gauge at that point, if set.
+        pub loopstart: Option<(f64, int)>,
+        /// The end of the A-B loop (or the B point) if set. The game play rewinds to `loopstart`
+        /// and restores the gauge whenever it passes this point.
+        pub loopend: Option<f64>,
+    }
+
+    /// A list of play speed marks. `SpeedUpInput` and `SpeedDownInput` changes the play speed to
+    /// the next/previous nearest mark. (C: `speeds`)
+    static SPEED_MARKS: &'static [f64] = &[0.1, 0.2, 0.4, 0.6, 0.8, 1.0, 1.2, 1.5, 2.0, 2.5, 3.0,
+        3.5, 4.0, 4.5, 5.0, 5.5, 6.0, 7.0, 8.0, 10.0, 15.0, 25.0, 40.0, 60.0, 99.0];
+
+    /// The change of the green number by `SpeedUpInput` and `SpeedDownInput` in milliseconds.
+    const GREEN_NUMBER_STEP: f64 = 50.0;
+    /// The minimum green number in milliseconds.
+    const MIN_GREEN_NUMBER: f64 = 100.0;
+    /// The maximum green number in milliseconds.
+    const MAX_GREEN_NUMBER: f64 = 10000.0;
+
+    /// The height of the note area between the top panel and the judgement line in pixels.
+    const NOTE_AREA_HEIGHT: uint = SCREENH - 100;
+    /// The minimum height of the note area not covered by the lane cover or the lift.
+    const MIN_VISIBLE_HEIGHT: uint = 50;
+    /// The change of the lane cover or the lift by the corresponding inputs in pixels.
+    const COVER_STEP: uint = 10;
+    /// The height of the band where objects gradually fade out under the sudden and hidden
+    /// modifiers, in pixels.
+    const FADE_HEIGHT: uint = 40;
+    /// How far ahead BGMs and automatically played key sounds are queued to the mixer, in
+    /// milliseconds. Should be longer than a typical frame, or sounds may start late.
+    const SOUND_LOOKAHEAD: f64 = 100.0;
+    /// The interval between `Player::update` calls in milliseconds, regardless of the frame rate.
+    /// Bounds the delay of the input processing.
+    pub const UPDATE_INTERVAL: uint = 1;
+    /// The maximum number of frames per second when `Options::fps` is not given. The vertical
+    /// synchronization may further limit the frame rate.
+    pub const DEFAULT_FPS: uint = 120;
+
+    /// Clamps the heights of the lane cover and the lift so that the note area is not entirely
+    /// covered. The lift takes precedence.
+    fn clamp_cover_and_lift(cover: uint, lift: uint) -> (uint, uint) {
+        let lift = cmp::min(lift, NOTE_AREA_HEIGHT - MIN_VISIBLE_HEIGHT);
+        let cover = cmp::min(cover, NOTE_AREA_HEIGHT - MIN_VISIBLE_HEIGHT - lift);
+        (cover, lift)
+    }
+
+    /// Returns the play speed where an object stays on the visible part of the note area, which
+    /// is `height` pixels tall, for given milliseconds with given BPM. The result is clamped to
+    /// the valid range of play speeds.
+    fn playspeed_from_green_number(green: f64, bpm: BPM, height: uint) -> f64 {
+        // one measure has the length of 400 pixels times the play speed
+        let measures = bpm.msec_to_measure(green);
+        let speed = height as f64 / (400.0 * measures);
+        speed.max(0.1).min(99.0)
+    }
+
+    /// Returns the play speed where an object stays on the screen for `Options::suggestgreen`
+    /// milliseconds with the main BPM of the chart, taking the lane cover and the lift into
+    /// account. Returns `None` if no suggestion is requested, the main BPM is not positive or
+    /// the play speed is derived from `Options::greennumber` anyway.
+    pub fn suggested_playspeed(bms: &Bms, infos: &BmsInfo, opts: &Options) -> Option<f64> {
+        if opts.greennumber.is_some() { return None; }
+        opts.suggestgreen.and_then(|green| {
+            let mainbpm = Timeline::new(bms, infos.originoffset).mainbpm;
+            if *mainbpm <= 0.0 { return None; }
+            let (cover, lift) = clamp_cover_and_lift(opts.cover, opts.lift);
+            Some(playspeed_from_green_number(green, mainbpm, NOTE_AREA_HEIGHT - cover - lift))
+        })
+    }
+
+    /// Finds the next nearest play speed mark if any.
+    fn next_speed_mark(current: f64) -> Option<f64> {
+        let mut prev = None;
+        for &speed in SPEED_MARKS.iter() {
+            if speed < current - 0.001 {
+                prev = Some(speed);
+            } else {
+                return prev;
+            }
+        }
+        None
+    }
+
+    /// Finds the previous nearest play speed mark if any.
+    fn previous_speed_mark(current: f64) -> Option<f64> {
+        let mut next = None;
+        for &speed in SPEED_MARKS.iter().rev() {
+            if speed > current + 0.001 {
+                next = Some(speed);
+            } else {
+                return next;
+            }
+        }
+        None
+    }
+
+    /// Creates a beep sound played on the play speed change. (C: `create_beep`)
+    fn create_beep() -> Chunk {
+        let samples: Vec<i32> = Vec::from_fn(12000, // approx. 0.14 seconds
+            // sawtooth wave at 3150 Hz, quadratic decay after 0.02 seconds.
+            |i| { let i = i as i32; (i%28-14) * cmp::min(2000, (12000-i)*(12000-i)/50000) });
+        unsafe {
+            slice::raw::buf_as_slice(samples.as_ptr() as *const u8, samples.len() * 4, |samples| {
+                sdl_mixer::Chunk::new(samples.to_vec(), 128)
+            })
+        }
+    }
+
+    /// Creates a short tick sound played by the assist clap.
+    fn create_clap() -> Chunk {
+        let samples: Vec<i16> = Vec::from_fn(2400, // 1200 stereo frames, approx. 0.03 seconds
+            // square wave at 1837.5 Hz, linear decay
+            |i| { let t = (i / 2) as i16; (if t % 24 < 12 {20} else {-20}) * (1200 - t) });
+        unsafe {
+            slice::raw::buf_as_slice(samples.as_ptr() as *const u8, samples.len() * 2, |samples| {
+                sdl_mixer::Chunk::new(samples.to_vec(), 128)
+            })
+        }
+    }
+
+    impl Player {
+        /// Creates a new player object. The player object owns other related structures, including
+        /// the options, BMS file, key specification, input mapping, sound resources, clock and
+        /// event source.
+        pub fn new(opts: Options, bms: Bms, infos: BmsInfo, duration: f64, keyspec: KeySpec,
+                   keymap: KeyMap, sndres: Vec<SoundResource>, clock: Box<Clock+'static>,
+                   events: Box<EventSource+'static>) -> Player {
+            let now = clock.ticks();
+            let initplayspeed = opts.playspeed;
+            let greennumber = opts.greennumber;
+            let (cover, lift) = clamp_cover_and_lift(opts.cover, opts.lift);
+            let originoffset = infos.originoffset;
+            let startshorten = bms.shorten(originoffset as int);
+            let gradefactor = 1.5 - cmp::min(bms.rank, 5) as f64 * 0.25;
+            let survival = opts.gauge.survival();
+            let initgauge = opts.gauge.initial_gauge();
+            let nobjs = bms.objs.len();
+            let laneobjs = parser::lane_objects(&bms);
+            let timeline = Timeline::new(&bms, originoffset);
+            let lazysounds = if opts.lazysounds {Some(LazySounds::new(&bms, &opts))} else {None};
+            let nextclick = bms.beat_at_or_after(originoffset);
+            let sides = if bms.player == parser::COUPLE_PLAY && !keyspec.right_lanes().is_empty() {
+                vec!(SideState::new(&bms, keyspec.left_lanes(), initgauge),
+                     SideState::new(&bms, keyspec.right_lanes(), initgauge))
+            } else {
+                Vec::new()
+            };
+
+            let bms = Rc::new(bms);
+            let pfront = Pointer::new(bms.clone());
+            let pcur = Pointer::new(bms.clone());
+            let pcheck = Pointer::new(bms.clone());
+            let paudio = Pointer::new(bms.clone());
+            let mut player = Player {
+                opts: opts, bms: bms, infos: infos, timeline: timeline, duration: duration,
+                keyspec: keyspec, keymap: keymap, laneobjs: laneobjs,
+
+                nograding: Vec::from_elem(nobjs, false), sndres: sndres, lazysounds: lazysounds,
+                background: None, nmissing: 0,
+                beep: create_beep(), clap: create_clap(),
+                bga: initial_bga_state(),
+
+                playspeed: initplayspeed, targetspeed: None, greennumber: greennumber,
+                cover: cover, lift: lift, screenshot: false,
+                clock: clock, events: events, now: now,
+                origintime: now, starttime: now, stoptime: None, startoffset: originoffset,
+                startshorten: startshorten,
+
+                bottom: originoffset, line: originoffset, top: originoffset,
+                pfront: pfront, pcur: pcur, pcheck: pcheck, paudio: paudio,
+                nextclick: nextclick, lastdownbeat: None,
+                pthru: Vec::from_fn(NLANES, |_| None),
+
+                gradefactor: gradefactor, lastgrade: None, lasthits: Vec::from_elem(NLANES, None),
+                lastfast: None, fastcount: 0,
+                slowcount: 0, gradecounts: [0, ..NGRADES],
+                lastcombo: 0, bestcombo: 0, score: 0, exscore: 0,
+                gauge: initgauge, survival: survival, sides: sides, history: Vec::new(),
+
+                keymultiplicity: [0, ..NLANES], joystate: [Neutral, ..NLANES],
+                turntables: HashMap::new(),
+                replay: Vec::new(), playback: None, playbackpos: 0, ghost: None, ghostpos: 0,
+                record: None,
+                loopstart: None, loopend: None,
+            };
+
+            let startmeasure = player.opts.startmeasure;
+            for &measure in startmeasure.iter() { player.seek(measure as f64); }
+            player
+        }
+
+        /// Returns the distance of the virtual time `time` from the bottom of the visible chart,
+        /// in measures as rendered on the screen. One measure has the length of 400 pixels times
+        /// the play speed.
+        pub fn object_position(&self, time: f64) -> f64 {
+            let timeline = &self.timeline;
+            if self.opts.constant {
+                let msec = timeline.time_to_msec(&*self.bms, time) -
+                           timeline.time_to_msec(&*self.bms, self.bottom);
+                timeline.mainbpm.msec_to_measure(msec)
+            } else {
+                timeline.adjust_object_position(&*self.bms, self.bottom, time)
+            }
+        }
+
+        /// Returns true if the specified lane is being pressed, either by keyboard, joystick
+        /// buttons or axes.
+        pub fn key_pressed(&self, lane: Lane) -> bool {
+            self.keymultiplicity[*lane] > 0 || self.joystate[*lane] != Neutral
+        }
+
+        /// Returns the play speed displayed. Can differ from the actual play speed
+        /// (`self.playspeed`) when the play speed is changing.
+        pub fn nominal_playspeed(&self) -> f64 {
+            self.targetspeed.unwrap_or(self.playspeed)
+        }
+
+        /// Updates the score and associated statistics according to grading. `lane` is the lane
+        /// of the graded object, `scoredelta` is an weight normalized to [0,1] that is calculated
+        /// from the distance between the object and the input time, and `damage` is
+        /// an optionally associated `Damage` value for bombs. Returns false when the damage
+        /// resulted in the instant death, or the instant-fail gauge has been depleted. In
+        /// the Couple Play it returns false only when every side has failed. (C: `update_grade`)
+        pub fn update_grade(&mut self, lane: Lane, grade: Grade, scoredelta: f64,
+                            damage: Option<Damage>) -> bool {
+            self.gradecounts[grade as uint] += 1;
+            self.lastgrade = Some((grade, self.now));
+            self.lasthits[*lane] = Some((grade, self.now));
+            self.lastfast = None;
+            self.score += (scoredelta * SCOREPERNOTE *
+                           (1.0 + (self.lastcombo as f64) /
+                                  (self.infos.nnotes as f64))) as uint;
+
+            match grade {
+                MISS | BAD => { self.lastcombo = 0; }
+                GOOD => {}
+                GREAT | COOL | PGREAT => {
+                    self.exscore += grade.exscore();
+                    let recovery = self.opts.gauge.recovery(grade, self.lastcombo);
+                    self.lastcombo += 1;
+                    self.gauge = cmp::min(self.gauge + recovery, MAXGAUGE);
+                }
+            }
+            self.bestcombo = cmp::max(self.bestcombo, self.lastcombo);
+
+            for side in self.sides.iter_mut().filter(|side| side.lanes.contains(&lane)) {
+                side.update_grade(&self.opts.gauge, grade, scoredelta, damage);
+            }
+
+            let (gauge, keepgoing) = self.opts.gauge.apply_damage(self.gauge, damage);
+            self.gauge = gauge;
+            self.history.push(ScoreSample { time: self.now - self.origintime, score: self.score,
+                                            exscore: self.exscore, gauge: self.gauge });
+            if self.sides.is_empty() {
+                keepgoing
+            } else {
+                self.sides.iter().any(|side| !side.failed)
+            }
+        }
+
+        /// Same as `update_grade`, but the grade is calculated from the normalized difference
+        /// between the object and input time in milliseconds. The normalized distance equals to
+        /// the actual time difference when `gradefactor` is 1.0. (C: `update_grade(grade,
+        /// scoredelta, 0)` where `grade` and `scoredelta` are pre-calculated from `dist`)
+        ///
+        /// The positive distance means that the input was earlier than the object. Returns false
+        /// when the grade resulted in the death as in `update_grade`.
+        pub fn update_grade_from_distance(&mut self, lane: Lane, dist: f64) -> bool {
+            let fast = dist > 0.0;
+            let dist = num::abs(dist);
+            let (grade, damage) = if      dist < PGREAT_CUTOFF {(PGREAT,None)}
+                                  else if dist <  COOL_CUTOFF {(COOL,None)}
+                                  else if dist < GREAT_CUTOFF {(GREAT,None)}
+                                  else if dist <  GOOD_CUTOFF {(GOOD,None)}
+                                  else if dist <   BAD_CUTOFF {(BAD,Some(BAD_DAMAGE))}
+                                  else                        {(MISS,Some(MISS_DAMAGE))};
+            let scoredelta = 1.0 - dist / BAD_CUTOFF;
+            let scoredelta = if scoredelta < 0.0 {0.0} else {scoredelta};
+            let keepgoing = self.update_grade(lane, grade, scoredelta, damage);
+            if self.opts.assistclap == Some(ClapOnHit) { self.play_clap(128); }
+
+            if grade != COOL && grade != PGREAT {
+                self.lastfast = Some(fast);
+                if fast { self.fastcount += 1; } else { self.slowcount += 1; }
+            }
+            keepgoing
+        }
+
+        /// Same as `update_grade`, but with the predetermined damage value. Always results in MISS
+        /// grade. Returns false when the damage resulted in the death.
+        /// (C: `update_grade(0, 0, damage)`)
+        pub fn update_grade_from_damage(&mut self, lane: Lane, damage: Damage) -> bool {
+            self.update_grade(lane, MISS, 0.0, Some(damage))
+        }
+
+        /// Same as `update_grade`, but always results in MISS grade with the standard damage value.
+        /// Returns false when the damage resulted in the death, which is possible with
+        /// the instant-fail gauges. (C: `update_grade(0, 0, 0)`)
+        pub fn update_grade_to_miss(&mut self, lane: Lane) -> bool {
+            self.update_grade(lane, MISS, 0.0, Some(MISS_DAMAGE))
+        }
+
+        /// Ends the game play by the death. The chart is skipped to the end, so that the death
+        /// counts as finished. Always returns false, to be returned from `update`.
+        fn die(&mut self) -> bool {
+            self.pcur.seek_to_end();
+            false
+        }
+
+        /// Returns the mixer clock `delay` milliseconds after the current tick.
+        fn mixer_clock_after(&self, delay: f64) -> u64 {
+            let delay = if delay > 0.0 {delay} else {0.0};
+            mixer::clock_at(self.now) + (delay * sample_rate() as f64 / 1000.0) as u64
+        }
+
+        /// Plays a given sound referenced by `sref`. `bgm` indicates that the sound is a BGM and
+        /// should be played with `Options::bgmvolume` instead of `Options::keyvolume` and should
+        /// in the different group from key sounds. The sound stops if it was already playing.
+        /// The sound starts at the mixer clock corresponding to the current tick, so it is not
+        /// affected by the processing time.
+        /// (C: `play_sound`)
+        pub fn play_sound(&mut self, sref: SoundRef, bgm: bool) {
+            self.queue_sound(sref, bgm, 0.0);
+        }
+
+        /// Same as `play_sound`, but the sound starts `delay` milliseconds after the current tick.
+        /// The sound already playing is cut off at that time.
+        pub fn queue_sound(&mut self, sref: SoundRef, bgm: bool, delay: f64) {
+            let start = self.mixer_clock_after(delay);
+            let sref = **sref as uint;
+            let chunk = match self.sndres[sref].chunk() {
+                Some(chunk) => chunk,
+                None => { return; }
+            };
+            let (group, volume) = if bgm {(mixer::BGM_GROUP, self.opts.bgmvolume)}
+                                  else {(mixer::KEY_GROUP, self.opts.keyvolume)};
+            mixer::play(chunk, Some(sref), group, volume, 0, start);
+        }
+
+        /// Queues a given sound if `sref` is not zero. This reflects the fact that an alphanumeric
+        /// key `00` is normally a placeholder.
+        pub fn queue_sound_if_nonzero(&mut self, sref: SoundRef, bgm: bool, delay: f64) {
+            if **sref > 0 { self.queue_sound(sref, bgm, delay); }
+        }
+
+        /// Plays a beep. The beep is in its own group, which is excluded from the uniform key
+        /// sound and BGM management. (C: `Mix_PlayChannel(0, beep, 0)`)
+        pub fn play_beep(&mut self) {
+            let start = mixer::clock_at(self.now);
+            mixer::play(&self.beep, Some(mixer::BEEP_ID), mixer::SYSTEM_GROUP, 128, 0, start);
+        }
+
+        /// Plays an assist clap or metronome click with given volume (up to 128). The clap is
+        /// in the same group as the beep, which is excluded from the uniform key sound and BGM
+        /// management as well.
+        pub fn play_clap(&mut self, volume: libc::c_int) {
+            self.queue_clap(volume, 0.0);
+        }
+
+        /// Same as `play_clap`, but the clap starts `delay` milliseconds after the current tick.
+        pub fn queue_clap(&mut self, volume: libc::c_int, delay: f64) {
+            let start = self.mixer_clock_after(delay);
+            mixer::play(&self.clap, Some(mixer::CLAP_ID), mixer::SYSTEM_GROUP, volume as uint, 0,
+                        start);
+        }
+
+        /// Returns the current BPM, which is in effect since the last discontinuity. Can be
+        /// negative, in that case the chart will scroll backwards. (C: `bpm`)
+        pub fn bpm(&self) -> BPM {
+            self.timeline.bpm_at(self.startoffset)
+        }
+
+        /// Returns the actual time of the grading line in milliseconds, as in `Timeline`.
+        /// The time keeps advancing during the scroll stopper while the line stays.
+        fn line_msec(&self) -> f64 {
+            let msec = self.timeline.time_to_msec_after_stop(&*self.bms, self.line);
+            match self.stoptime {
+                Some(t) if t > self.now => msec - (t - self.now) as f64,
+                _ => msec
+            }
+        }
+
+        /// Returns the distance in milliseconds from the grading line to an object at the virtual
+        /// time `time`, which is positive if the object is yet to reach the line. The distance is
+        /// adjusted by the input offset and the grading factor.
+        fn grading_distance(&self, time: f64) -> f64 {
+            let msec = self.timeline.time_to_msec(&*self.bms, time) - self.line_msec();
+            (msec + self.opts.offset) * self.gradefactor
+        }
+
+        /// Returns the delay in milliseconds from the current tick until an object at the virtual
+        /// time `time` reaches the grading line, or `None` if it is never reached.
+        fn delay_until(&self, time: f64) -> Option<f64> {
+            if *self.bpm() <= 0.0 { return None; }
+            let delay = self.timeline.time_to_msec(&*self.bms, time) - self.line_msec();
+            if delay.is_finite() {Some(delay)} else {None}
+        }
+
+        /// Restarts the game play from the beginning (or `Options::startmeasure`) without
+        /// reloading resources. Scores, the gauge, recorded inputs and the A-B loop are reset.
+        pub fn restart(&mut self) {
+            self.gradecounts = [0, ..NGRADES];
+            self.lastgrade = None;
+            self.lasthits = Vec::from_elem(NLANES, None);
+            self.lastfast = None;
+            self.fastcount = 0;
+            self.slowcount = 0;
+            self.lastcombo = 0;
+            self.bestcombo = 0;
+            self.score = 0;
+            self.exscore = 0;
+            self.gauge = self.opts.gauge.initial_gauge();
+            let initgauge = self.gauge;
+            for side in self.sides.iter_mut() { side.reset(initgauge); }
+            self.history.clear();
+            self.replay.clear();
+            self.playbackpos = 0;
+            self.ghostpos = 0;
+            self.loopstart = None;
+            self.loopend = None;
+
+            let start = self.opts.startmeasure.map_or(self.infos.originoffset,
+                                                      |measure| measure as f64);
+            self.seek(start);
+            self.origintime = self.now;
+        }
+
+        /// Stops all sounds including the beep.
+        pub fn stop_all_sounds(&mut self) {
+            mixer::stop_all();
+        }
+
+        /// Plays a given BGM from `offset` seconds after its beginning.
+        fn resume_bgm(&mut self, sref: SoundRef, offset: f64) {
+            let sref = **sref as uint;
+            let chunk = match self.sndres[sref].chunk() {
+                Some(chunk) => chunk,
+                None => { return; }
+            };
+            let offset = (offset * sample_rate() as f64) as uint;
+            mixer::play(chunk, Some(sref), mixer::BGM_GROUP, self.opts.bgmvolume, offset,
+                        mixer::clock_at(self.now));
+        }
+
+        /**
+         * Moves the game play to given virtual time, as if the chart has been played until then
+         * without any input. The BPM, BGA layers and BGMs still playing at that time are restored,
+         * and every object after that time becomes gradable again. Scores and gauges are kept.
+         *
+         * Scroll stoppers overlapping that time are ignored.
+         */
+        pub fn seek(&mut self, to: f64) {
+            self.stop_all_sounds();
+
+            // replays the object-like effects before `to`. the BPM follows from the timeline.
+            let bms = self.bms.clone();
+            let mut bga = initial_bga_state();
+            let mut bgms = Vec::new();
+            let mut first = bms.objs.len();
+            for (i, obj) in bms.objs.iter().enumerate() {
+                if obj.time >= to { first = i; break; }
+                match obj.data {
+                    BGM(sref) => { bgms.push((obj.time, sref)); }
+                    SetBGA(layer, iref) => { bga[layer as uint] = iref; }
+                    _ => {}
+                }
+            }
+
+            let msec = self.timeline.time_to_msec(&*bms, to);
+            for &(time, sref) in bgms.iter() {
+                let offset = (msec - self.timeline.time_to_msec(&*bms, time)) / 1000.0;
+                if **sref > 0 && offset < self.sndres[**sref as uint].duration() {
+                    self.resume_bgm(sref, offset);
+                }
+            }
+
+            self.bga = bga;
+            self.now = self.clock.ticks();
+            self.starttime = self.now;
+            self.stoptime = None;
+            self.startoffset = to;
+            self.startshorten = bms.shorten(to.floor() as int);
+            self.bottom = to;
+            self.line = to;
+            self.top = to;
+            self.pfront = Pointer::new_with_pos(bms.clone(), first);
+            self.pcur = Pointer::new_with_pos(bms.clone(), first);
+            self.pcheck = Pointer::new_with_pos(bms.clone(), first);
+            self.paudio = Pointer::new_with_pos(bms.clone(), first);
+            self.nextclick = bms.beat_at_or_after(to);
+            for i in range(0, self.nograding.len()) {
+                self.nograding[mut][i] = i < first;
+            }
+            for thru in self.pthru.iter_mut() { *thru = None; }
+        }
+
+        /// Breaks a continuity at given virtual time.
+        fn break_continuity(&mut self, at: f64) {
+            assert!(at >= self.startoffset);
+            self.starttime += (self.bpm().measure_to_msec(at - self.startoffset) *
+                               self.startshorten) as uint;
+            self.startoffset = at;
+        }
+
+        /// Processes an input to the lane, either from the actual input or from the replay, and
+        /// records it to `replay`. Returns false when the resulting grade caused the death.
+        fn process_lane_input(&mut self, lane: Lane, continuous: bool,
+                              state: InputState) -> bool {
+            // Returns true if the given lane is previously pressed and now unpressed.
+            // When the virtual input is mapped to multiple actual inputs it can update
+            // the internal state but still return false.
+            let is_unpressed = |player: &mut Player, lane: Lane,
+                                continuous: bool, state: InputState| {
+                if state == Neutral || (continuous &&
+                                        player.joystate[*lane] != state) {
+                    if continuous {
+                        player.joystate[*lane] = state; true
+                    } else {
+                        if player.keymultiplicity[*lane] > 0 {
+                            player.keymultiplicity[*lane] -= 1;
+                        }
+                        (player.keymultiplicity[*lane] == 0)
+                    }
+                } else {
+                    false
+                }
+            };
+
+            // Returns true if the given lane is previously unpressed and now pressed.
+            // When the virtual input is mapped to multiple actual inputs it can update
+            // the internal state but still return false.
+            let is_pressed = |player: &mut Player, lane: Lane,
+                              continuous: bool, state: InputState| {
+                if state != Neutral {
+                    if continuous {
+                        player.joystate[*lane] = state; true
+                    } else {
+                        player.keymultiplicity[*lane] += 1;
+                        (player.keymultiplicity[*lane] == 1)
+                    }
+                } else {
+                    false
+                }
+            };
+
+            let process_unpress = |player: &mut Player, lane: Lane| {
+                // if LN grading is in progress and it is not within the threshold then
+                // MISS grade is issued, unless it has been held long enough with lenient LN
+                let lnstart = player.pthru[*lane].as_ref().map(|thru| thru.time());
+                let nextlndone =
+                    player.pthru[*lane].as_ref().and_then(|thru| {
+                        thru.find_next_in(player.laneobjs[*lane][], |obj| obj.is_lndone())
+                    });
+                let mut keepgoing = true;
+                for p in nextlndone.iter() {
+                    let delta = player.grading_distance(p.time());
+                    let mostlyheld = player.opts.lenientln && lnstart.map_or(false, |start| {
+                        let start = player.timeline.time_to_msec(&*player.bms, start);
+                        let end = player.timeline.time_to_msec(&*player.bms, p.time());
+                        player.line_msec() - start >= (end - start) * LENIENT_LN_RATIO
+                    });
+                    if num::abs(delta) < player.opts.lnreleasewindow || mostlyheld {
+                        player.nograding[mut][p.pos] = true;
+                    } else {
+                        keepgoing = player.update_grade_to_miss(lane);
+                    }
+                }
+                player.pthru[mut][*lane] = None;
+                keepgoing
+            };
+
+            let process_press = |player: &mut Player, lane: Lane| {
+                // plays the closest key sound, unless it is too far to be related to the press
+                let soundable = player.pcur.find_closest_in(player.line, player.laneobjs[*lane][],
+                                                            |obj| obj.is_soundable());
+                for p in soundable.iter() {
+                    let dist = player.grading_distance(p.time());
+                    match player.opts.keysoundwindow {
+                        Some(window) if num::abs(dist) > window => continue,
+                        _ => {}
+                    }
+                    for &sref in p.sounds().iter() {
+                        player.play_sound(sref, false);
+                    }
+                }
+
+                // tries to grade the closest gradable object in
+                // the grading area
+                let mut keepgoing = true;
+                let gradable = player.pcur.find_closest_in(player.line, player.laneobjs[*lane][],
+                                                           |obj| obj.is_gradable());
+                for p in gradable.iter() {
+                    if p.pos >= player.pcheck.pos && !player.nograding[p.pos] &&
+                                                     !p.is_lndone() {
+                        let dist = player.grading_distance(p.time());
+                        if num::abs(dist) < BAD_CUTOFF {
+                            if p.is_lnstart() {
+                                player.pthru[mut][*lane] =
+                                    Some(Pointer::new_with_pos(player.bms.clone(), p.pos));
+                            }
+                            player.nograding[mut][p.pos] = true;
+                            keepgoing = player.update_grade_from_distance(lane, dist);
+                        }
+                    }
+                }
+                keepgoing
+            };
+
+            let ngraded = self.gradecounts.iter().fold(0, |a, &b| a + b);
+            let mut keepgoing = true;
+            if is_unpressed(self, lane, continuous, state) {
+                keepgoing = process_unpress(self, lane);
+            }
+            if is_pressed(self, lane, continuous, state) {
+                keepgoing = process_press(self, lane) && keepgoing;
+            }
+
+            // records the input and the grade it caused if any
+            let graded = self.gradecounts.iter().fold(0, |a, &b| a + b) > ngraded;
+            let grade = if graded {self.lastgrade.map(|(g,_)| g)} else {None};
+            self.replay.push(ReplayEvent { time: self.now - self.origintime, lane: lane,
+                                           state: state, continuous: continuous, grade: grade,
+                                           score: self.score, combo: self.lastcombo });
+            keepgoing
+        }
+
+        /// Returns the score and combo number of the ghost at the current time, if any.
+        pub fn ghost_status(&self) -> Option<(uint, uint)> {
+            self.ghost.as_ref().map(|ghost| {
+                if self.ghostpos == 0 {
+                    (0, 0)
+                } else {
+                    let ev = &ghost.events[self.ghostpos - 1];
+                    (ev.score, ev.combo)
+                }
+            })
+        }
+
+        /// Returns the EX score of the pacemaker at the current time, if any. Targets other than
+        /// the ghost are prorated by the number of graded objects so far.
+        pub fn pacemaker_exscore(&self) -> Option<uint> {
+            let ngraded = self.gradecounts.iter().fold(0, |a, &b| a + b);
+            let nnotes = cmp::max(self.infos.nnotes as uint, 1);
+            match self.opts.pacemaker {
+                Some(PaceTarget(ratio)) => Some((ratio * 2.0 * ngraded as f64).round() as uint),
+                Some(PaceBest) => self.record.as_ref().map(|r| r.exscore * ngraded / nnotes),
+                Some(PaceGhost) => self.ghost.as_ref().map(|ghost| {
+                    ghost.events[..self.ghostpos].iter().fold(0, |ex, ev| {
+                        ex + ev.grade.map_or(0, |grade| grade.exscore())
+                    })
+                }),
+                None => None
+            }
+        }
+
+        /// Updates the states only relevant to the display, i.e. the smooth play speed change and
+        /// the top of the note area. Should be called once before each frame is rendered, so that
+        /// these states change at the same pace regardless of how often `update` is called.
+        pub fn prepare_frame(&mut self) {
+            // smoothly change the play speed
+            if self.targetspeed.is_some() {
+                let target = self.targetspeed.unwrap();
+                let delta = target - self.playspeed;
+                if num::abs(delta) < 0.001 {
+                    self.playspeed = target;
+                    self.targetspeed = None;
+                } else {
+                    self.playspeed += delta * 0.1;
+                }
+            }
+
+            // derive the play speed from the current BPM if requested. objects scroll with
+            // the main BPM instead in the constant scroll mode.
+            for &green in self.greennumber.iter() {
+                let bpm = if self.opts.constant {self.timeline.mainbpm} else {self.bpm()};
+                if *bpm > 0.0 {
+                    let height = NOTE_AREA_HEIGHT - self.cover - self.lift;
+                    self.playspeed = playspeed_from_green_number(green, bpm, height);
+                    self.targetspeed = None;
+                }
+            }
+
+            let visible = (NOTE_AREA_HEIGHT - self.lift) as f64 / 400.0;
+            self.top = if self.opts.constant {
+                let msec = self.timeline.time_to_msec(&*self.bms, self.bottom) +
+                           self.timeline.mainbpm.measure_to_msec(visible / self.playspeed);
+                self.timeline.msec_to_time(&*self.bms, msec)
+            } else {
+                self.timeline.adjust_object_time(&*self.bms, self.bottom, visible / self.playspeed)
+            };
+            self.pfront.seek_until(self.bottom);
+        }
+
+        /// Updates the game play states, including the current time, the inputs and the grading.
+        /// Should be called at a high fixed rate (see `UPDATE_INTERVAL`) independently from
+        /// the rendering, since the inputs are graded against the time they are processed.
+        /// Returns false when the game play should stop. (C: `play_process`)
+        pub fn update(&mut self) -> bool {
+            // process the ongoing scroll stopper if any
+            self.now = self.clock.ticks();
+            self.bottom = match self.stoptime {
+                Some(t) => {
+                    if self.now >= t {
+                        self.starttime = t;
+                        self.stoptime = None;
+                    }
+                    self.startoffset
+                }
+                None => {
+                    let msecdiff = (self.now - self.starttime) as f64;
+                    let measurediff = self.bpm().msec_to_measure(msecdiff);
+                    self.startoffset + measurediff / self.startshorten
+                }
+            };
+
+            // ends or rewinds the practice section if requested
+            let endmeasure = self.opts.endmeasure;
+            for &measure in endmeasure.iter() {
+                if self.bottom >= measure as f64 {
+                    if !self.opts.loopsection { return false; }
+                    let start = self.opts.startmeasure.map_or(self.infos.originoffset,
+                                                              |measure| measure as f64);
+                    self.seek(start);
+                }
+            }
+
+            // rewinds to the A point when the B point is passed
+            match (self.loopstart, self.loopend) {
+                (Some((start, gauge)), Some(end)) if self.bottom >= end => {
+                    self.seek(start);
+                    self.gauge = gauge;
+                }
+                (_, _) => {}
+            }
+
+            // process the measure scale factor change
+            let bottommeasure = self.bottom.floor();
+            let curshorten = self.bms.shorten(bottommeasure as int);
+            if bottommeasure >= -1.0 && self.startshorten != curshorten {
+                self.break_continuity(bottommeasure);
+                self.startshorten = curshorten;
+            }
+
+            //self.line = self.bms.adjust_object_time(self.bottom, 0.03 / self.playspeed);
+            self.line = self.bottom;
+
+            // prepare sounds around the grading line if they are loaded on demand
+            let lazypos = cmp::min(self.pcheck.pos, self.paudio.pos);
+            for lazy in self.lazysounds.iter_mut() {
+                lazy.update(&*self.bms, lazypos, self.line, self.sndres[mut]);
+            }
+            let loading = match self.background {
+                Some(ref mut background) => background.update(self.sndres[mut]),
+                None => false
+            };
+            if !loading { self.background = None; }
+
+            // apply object-like effects while advancing to new `pcur`
+            let mut prevpcur = Pointer::new_with_pos(self.bms.clone(), self.pcur.pos);
+            self.pcur.reset();
+            while self.pcur.next_until(self.line) {
+                let time = self.pcur.time();
+                match self.pcur.data() {
+                    SetBGA(layer, iref) => {
+                        self.bga[layer as uint] = iref;
+                    }
+                    SetBPM(..) => {
+                        // the new BPM is in effect from the new discontinuity
+                        self.break_continuity(time);
+                    }
+                    Stop(duration) => {
+                        let msecs = duration.to_msec(self.bpm());
+                        let newstoptime = msecs as uint + self.now;
+                        self.stoptime =
+                            Some(self.stoptime.map_or(newstoptime,
+                                                      |t| cmp::max(t, newstoptime)));
+                        self.startoffset = time;
+                    }
+                    Visible(lane,_) | LNStart(lane,_) => {
+                        // key sounds are queued along with BGMs
+                        if self.opts.is_autoplay() && !self.update_grade_from_distance(lane, 0.0) {
+                            return self.die();
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            // queue BGMs to the mixer ahead of time, delayed by the input offset. the actual time
+            // of objects is known from the timeline, even past BPM changes and scroll stoppers.
+            let offset = self.opts.offset;
+            let linemsec = self.line_msec();
+            let audioline = self.timeline.msec_to_time(&*self.bms, linemsec - offset);
+            let lookahead = SOUND_LOOKAHEAD - if offset < 0.0 {offset} else {0.0};
+            let queueline = self.timeline.msec_to_time(&*self.bms, linemsec + lookahead)
+                                         .max(audioline);
+            self.paudio.reset();
+            while self.paudio.next_until(queueline) {
+                let time = self.paudio.time();
+                let delay = match self.delay_until(time) {
+                    Some(delay) => delay,
+                    None if time < audioline => -offset, // played right away
+                    None => { break; }
+                };
+                match self.paudio.data() {
+                    BGM(sref) => { self.queue_sound_if_nonzero(sref, true, delay + offset); }
+                    Visible(_,sref) | LNStart(_,sref) => {
+                        if self.opts.is_autoplay() {
+                            for &sref in sref.iter() {
+                                self.queue_sound_if_nonzero(sref, false, delay);
+                            }
+                        }
+                        if self.opts.assistclap == Some(ClapOnNote) {
+                            self.queue_clap(128, delay + offset);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            // play metronome clicks, also delayed by the input offset
+            if self.opts.metronome {
+                while self.nextclick <= audioline {
+                    let downbeat = self.nextclick == self.nextclick.floor();
+                    self.play_clap(if downbeat {128} else {48});
+                    if downbeat { self.lastdownbeat = Some(self.now); }
+                    self.nextclick = self.bms.beat_at_or_after(self.nextclick + 1e-6);
+                }
+            }
+
+            // grade objects that have escaped the grading area. LN ends have a separate release
+            // window, so an object still within its own window is skipped and revisited later,
+            // while later objects which have escaped are graded and marked as such.
+            if !self.opts.is_autoplay() {
+                let horizon = BAD_CUTOFF.min(self.opts.lnreleasewindow);
+                let mut pending = None;
+                self.pcheck.reset();
+                while self.pcheck.next_to(&self.pcur) {
+                    let dist = -self.grading_distance(self.pcheck.time());
+                    if dist < horizon { break; }
+                    let cutoff = match self.pcheck.data() {
+                        LNDone(..) => self.opts.lnreleasewindow,
+                        _ => BAD_CUTOFF,
+                    };
+                    if dist < cutoff {
+                        if pending.is_none() { pending = Some(self.pcheck.pos); }
+                        continue;
+                    }
+
+                    if !self.nograding[self.pcheck.pos] {
+                        for &Lane(lane) in self.pcheck.object_lane().iter() {
+                            // LN still being held is completed instead with lenient LN
+                            let (missable, completed) =
+                                match self.pcheck.data() {
+                                    Visible(..) | LNStart(..) => (true, false),
+                                    LNDone(..) => {
+                                        let held = self.pthru[lane].is_some();
+                                        (held && !self.opts.lenientln,
+                                         held && self.opts.lenientln)
+                                    }
+                                    _ => (false, false),
+                                };
+                            if missable && !self.update_grade_to_miss(Lane(lane)) {
+                                return self.die();
+                            }
+                            if missable || completed {
+                                self.pthru[mut][lane] = None;
+                            }
+                        }
+                        if pending.is_some() { self.nograding[mut][self.pcheck.pos] = true; }
+                    }
+                }
+                for &pos in pending.iter() {
+                    self.pcheck.pos = pos;
+                    self.pcheck.reset();
+                }
+            }
+
+            // process inputs
+            loop {
+                // map to the virtual input. results in `vkey` (virtual key), `state` (input state)
+                // and `continuous` (true if the input is not discrete and `Negative` input state
+                // matters).
+                let (key, state) = match self.events.poll_event() {
+                    NoEvent => { break; }
+                    QuitEvent | KeyEvent(event::EscapeKey,_,_,_) => { return false; }
+                    KeyEvent(key,true,_,_) => (KeyInput(key), Positive),
+                    KeyEvent(key,false,_,_) => (KeyInput(key), Neutral),
+                    JoyButtonEvent(which,button,true) =>
+                        (JoyButtonInput(Some(which as uint), button as uint), Positive),
+                    JoyButtonEvent(which,button,false) =>
+                        (JoyButtonInput(Some(which as uint), button as uint), Neutral),
+                    JoyAxisEvent(which,axis,pos)
+                            if find_mapping(&self.keymap,
+                                            &JoyTurntableInput(Some(which as uint),
+                                                               axis as uint)).is_some() => {
+                        let (dev, axis) = (which as uint, axis as uint);
+                        if !self.turntables.contains_key(&(dev, axis)) {
+                            self.turntables.insert((dev, axis), TurntableState::new());
+                        }
+                        let now = self.now;
+                        match self.turntables.find_mut(&(dev, axis)).unwrap().update(pos, now) {
+                            Some(state) => (JoyTurntableInput(Some(dev), axis), state),
+                            None => { continue; }
+                        }
+                    }
+                    JoyAxisEvent(which,axis,pos) => {
+                        let (dev, axis) = (which as uint, axis as uint);
+                        let calib = find_axis_calibration(self.opts.axes[], self.opts.deadzone,
+                                                          dev, axis);
+                        (JoyAxisInput(Some(dev), axis), calib.state(pos))
+                    }
+                    _ => { continue; }
+                };
+                let vkey = match find_mapping(&self.keymap, &key) {
+                    Some(&vkey) => vkey,
+                    None => { continue; }
+                };
+                let continuous = match key {
+                    KeyInput(..) | JoyButtonInput(..) => false,
+                    JoyAxisInput(..) | JoyTurntableInput(..) => true
+                };
+
+                if self.opts.is_exclusive() { continue; }
+
+                match (vkey, state) {
+                    (SpeedDownInput, Positive) | (SpeedDownInput, Negative) => {
+                        match self.greennumber {
+                            Some(green) => {
+                                let green = (green + GREEN_NUMBER_STEP).min(MAX_GREEN_NUMBER);
+                                self.greennumber = Some(green);
+                                self.play_beep();
+                            }
+                            None => {
+                                let current = self.targetspeed.unwrap_or(self.playspeed);
+                                for &newspeed in next_speed_mark(current).iter() {
+                                    self.targetspeed = Some(newspeed);
+                                    self.play_beep();
+                                }
+                            }
+                        }
+                    }
+                    (SpeedUpInput, Positive) | (SpeedUpInput, Negative) => {
+                        match self.greennumber {
+                            Some(green) => {
+                                let green = (green - GREEN_NUMBER_STEP).max(MIN_GREEN_NUMBER);
+                                self.greennumber = Some(green);
+                                self.play_beep();
+                            }
+                            None => {
+                                let current = self.targetspeed.unwrap_or(self.playspeed);
+                                for &newspeed in previous_speed_mark(current).iter() {
+                                    self.targetspeed = Some(newspeed);
+                                    self.play_beep();
+                                }
+                            }
+                        }
+                    }
+                    (RetryInput, Positive) | (RetryInput, Negative) => {
+                        self.restart();
+                    }
+                    (CoverUpInput, Positive) | (CoverUpInput, Negative) => {
+                        self.cover = if self.cover > COVER_STEP {self.cover - COVER_STEP} else {0};
+                    }
+                    (CoverDownInput, Positive) | (CoverDownInput, Negative) => {
+                        let (cover, _) = clamp_cover_and_lift(self.cover + COVER_STEP, self.lift);
+                        self.cover = cover;
+                    }
+                    (LiftUpInput, Positive) | (LiftUpInput, Negative) => {
+                        let (cover, lift) = clamp_cover_and_lift(self.cover,
+                                                                 self.lift + COVER_STEP);
+                        self.cover = cover;
+                        self.lift = lift;
+                    }
+                    (LiftDownInput, Positive) | (LiftDownInput, Negative) => {
+                        self.lift = if self.lift > COVER_STEP {self.lift - COVER_STEP} else {0};
+                    }
+                    (ScreenshotInput, Positive) | (ScreenshotInput, Negative) => {
+                        self.screenshot = true;
+                    }
+                    (LoopStartInput, Positive) | (LoopStartInput, Negative) => {
+                        // setting the A point again clears the B point
+                        self.loopstart = Some((self.line, self.gauge));
+                        self.loopend = None;
+                        self.play_beep();
+                    }
+                    (LoopEndInput, Positive) | (LoopEndInput, Negative) => {
+                        // the B point clears the loop if already set
+                        if self.loopend.is_some() {
+                            self.loopstart = None;
+                            self.loopend = None;
+                            self.play_beep();
+                        } else {
+                            match self.loopstart {
+                                Some((start, _)) if self.line > start => {
+                                    self.loopend = Some(self.line);
+                                    self.play_beep();
+                                }
+                                _ => {}
+                            }
+                        }
+                    }
+                    (LaneInput(lane), state) => {
+                        // the replay being played back replaces the actual lane inputs
+                        if !self.opts.is_autoplay() && self.playback.is_none() &&
+                                !self.process_lane_input(lane, continuous, state) {
+                            return self.die();
+                        }
+                    }
+                    (_, _) => {}
+                }
+
+            }
+
+            // release the lanes of analog turntables which have stopped
+            let mut stopped = Vec::new();
+            for (&(dev, axis), turntable) in self.turntables.iter_mut() {
+                if turntable.check_stopped(self.now).is_some() {
+                    stopped.push(JoyTurntableInput(Some(dev), axis));
+                }
+            }
+            for input in stopped.iter() {
+                match find_mapping(&self.keymap, input) {
+                    Some(&LaneInput(lane)) if !self.opts.is_exclusive() &&
+                                              !self.opts.is_autoplay() &&
+                                              self.playback.is_none() => {
+                        if !self.process_lane_input(lane, true, Neutral) {
+                            return self.die();
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            // feed the recorded inputs if the replay is being played back
+            let elapsed = self.now - self.origintime;
+            loop {
+                let ev = match self.playback {
+                    Some(ref replay) if self.playbackpos < replay.events.len() => {
+                        replay.events[self.playbackpos].clone()
+                    }
+                    _ => { break; }
+                };
+                if ev.time > elapsed { break; }
+                self.playbackpos += 1;
+                if !self.process_lane_input(ev.lane, ev.continuous, ev.state) {
+                    return self.die();
+                }
+            }
+
+            // advance the ghost to the current time
+            for ghost in self.ghost.iter() {
+                while self.ghostpos < ghost.events.len() &&
+                      ghost.events[self.ghostpos].time <= elapsed {
+                    self.ghostpos += 1;
+                }
+            }
+
+            // process bombs
+            if !self.opts.is_autoplay() {
+                prevpcur.reset();
+                while prevpcur.next_to(&self.pcur) {
+                    match prevpcur.data() {
+                        Bomb(lane,sref,damage) if self.key_pressed(lane) => {
+                            // ongoing long note is not graded twice
+                            self.pthru[mut][*lane] = None;
+                            for &sref in sref.iter() {
+                                self.play_sound(sref, false);
+                            }
+                            if !self.update_grade_from_damage(lane, damage) {
+                                return self.die(); // instant death
+                            }
+                        },
+                        _ => {}
+                    }
+                }
+            }
+
+            // determines if we should keep playing
+            if self.bottom > (self.bms.nmeasures + 1) as f64 {
+                if self.opts.is_autoplay() {
+                    self.events.num_playing(mixer::KEY_GROUP) +
+                        self.events.num_playing(mixer::BGM_GROUP) > 0
+                } else {
+                    self.events.num_playing(mixer::BGM_GROUP) > 0
+                }
+            } else if self.bottom < self.infos.originoffset {
+                false // special casing the negative BPM
+            } else {
+                true
+            }
+        }
+
+        /// Returns the clear lamp of the finished game play: `FAILED`, `PERFECT` (every note was
+        /// graded COOL or PGREAT), `FULL COMBO` (no BADs and MISSes) or the clear type of
+        /// the gauge.
+        pub fn clear_lamp(&self) -> &'static str {
+            if self.gauge < self.survival { return "FAILED"; }
+            let nnotes = self.infos.nnotes as uint;
+            let ncools = self.gradecounts[COOL as uint] + self.gradecounts[PGREAT as uint];
+            if ncools == nnotes { return "PERFECT"; }
+            if self.gradecounts[BAD as uint] + self.gradecounts[MISS as uint] == 0 {
+                return "FULL COMBO";
+            }
+            match self.opts.gauge {
+                EasyGauge => "EASY CLEAR",
+                NormalGauge => "CLEAR",
+                HardGauge => "HARD CLEAR",
+                ExHardGauge => "EX-HARD CLEAR",
+                HazardGauge => "HAZARD CLEAR",
+            }
+        }
+
+        /// Writes samples of the score and gauge to the CSV file. The gauge is in percents.
+        pub fn write_history_csv(&self, path: &str) -> AngolmoisResult<()> {
+            use std::io;
+            let ret = io::File::create(&Path::new(path)).and_then(|mut f| {
+                try!(writeln!(&mut f, "time,score,exscore,gauge"));
+                for sample in self.history.iter() {
+                    try!(writeln!(&mut f, "{},{},{},{:.1}", sample.time, sample.score,
+                                  sample.exscore, sample.gauge as f64 * 100.0 / MAXGAUGE as f64));
+                }
+                Ok(())
+            });
+            ret.map_err(|err| IoFailure(path.to_string(), err))
+        }
+
+        /// Returns true if the game play reached the last gradable object. Otherwise the game play
+        /// was terminated by the user or the instant death.
+        pub fn is_finished(&self) -> bool {
+            self.pcur.find_next_of_type(|obj| obj.is_gradable()).is_none()
+        }
+
+        /// Returns the outcome of the game play. The unfinished game play counts as failed
+        /// only if the instant-fail gauge has been depleted.
+        pub fn outcome(&self) -> PlayOutcome {
+            let survived = self.gauge >= self.survival;
+            if self.is_finished() {
+                if survived {Cleared} else {Failed}
+            } else if self.opts.gauge.is_instant_fail() && !survived {
+                Failed
+            } else {
+                Aborted
+            }
+        }
+
+        /// Writes the outcome, grades, score and samples of the gauge to the JSON file.
+        /// The gauge is in percents.
+        pub fn write_result_json(&self, path: &str) -> AngolmoisResult<()> {
+            use std::io;
+            use serialize::json;
+
+            #[deriving(Encodable)]
+            struct GaugeSample { time: uint, gauge: f64 }
+
+            #[deriving(Encodable)]
+            struct PlayResult {
+                path: String, md5: Option<String>, outcome: String, lamp: String,
+                pgreat: uint, cool: uint, great: uint, good: uint, bad: uint, miss: uint,
+                fast: uint, slow: uint, score: uint, exscore: uint, maxcombo: uint,
+                gauge: f64, history: Vec<GaugeSample>,
+            }
+
+            let percent = |gauge: int| gauge as f64 * 100.0 / MAXGAUGE as f64;
+            let result = PlayResult {
+                path: self.opts.bmspath.clone(), md5: self.infos.md5.clone(),
+                outcome: self.outcome().name().to_string(),
+                lamp: self.clear_lamp().to_string(), pgreat: self.gradecounts[PGREAT as uint],
+                cool: self.gradecounts[COOL as uint], great: self.gradecounts[GREAT as uint],
+                good: self.gradecounts[GOOD as uint], bad: self.gradecounts[BAD as uint],
+                miss: self.gradecounts[MISS as uint],
+                fast: self.fastcount, slow: self.slowcount,
+                score: self.score, exscore: self.exscore, maxcombo: self.bestcombo,
+                gauge: percent(self.gauge),
+                history: self.history.iter().map(|sample| {
+                    GaugeSample { time: sample.time, gauge: percent(sample.gauge) }
+                }).collect(),
+            };
+            let ret = io::File::create(&Path::new(path)).and_then(|mut f| {
+                f.write_str(json::encode(&result)[])
+            });
+            ret.map_err(|err| IoFailure(path.to_string(), err))
+        }
+    }
+
+    impl Drop for Player {
+        /// Stops every voice and stream in the mixer, which refer to sound and image resources
+        /// owned by the player.
+        fn drop(&mut self) {
+            mixer::stop_all();
+            mixer::remove_all_streams();
+        }
+    }
+
+    //----------------------------------------------------------------------------------------------
+    // course mode
+
+    /// The outcome of the game play, which also determines the exit code.
+    #[deriving(PartialEq,Eq,Clone,Show)]
+    pub enum PlayOutcome {
+        /// The chart was finished with the gauge above the survival threshold.
+        Cleared,
+        /// The chart was finished with the gauge below the survival threshold, or the player
+        /// died instantly.
+        Failed,
+        /// The game play was terminated by the user.
+        Aborted,
+    }
+
+    impl PlayOutcome {
+        /// Returns the name of the outcome used in the result JSON.
+        pub fn name(&self) -> &'static str {
+            match *self {
+                Cleared => "cleared",
+                Failed => "failed",
+                Aborted => "aborted",
+            }
+        }
+    }
+
+    /// The state carried over between charts in the course mode.
+    pub struct CourseState {
+        /// The number of charts in the course.
+        pub nstages: uint,
+        /// The number of charts finished so far.
+        pub stage: uint,
+        /// The health gauge at the end of the last chart, if any.
+        pub gauge: Option<int>,
+        /// The health gauge required to survive at the end of the course.
+        pub survival: int,
+        /// The combo number at the end of the last chart.
+        pub lastcombo: uint,
+        /// The best combo number so far.
+        pub bestcombo: uint,
+        /// The cumulative numbers of each grades.
+        pub gradecounts: [uint, ..NGRADES],
+        /// The cumulative score.
+        pub score: uint,
+        /// The cumulative maximum score.
+        pub maxscore: uint,
+        /// The cumulative number of early (FAST) grades.
+        pub fastcount: uint,
+        /// The cumulative number of late (SLOW) grades.
+        pub slowcount: uint,
+        /// The cumulative EX score.
+        pub exscore: uint,
+        /// The cumulative maximum EX score.
+        pub maxexscore: uint,
+    }
+
+    impl CourseState {
+        /// Creates a new course state for given number of charts.
+        pub fn new(nstages: uint) -> CourseState {
+            CourseState { nstages: nstages, stage: 0, gauge: None, survival: 0, lastcombo: 0,
+                          bestcombo: 0, gradecounts: [0, ..NGRADES], score: 0, maxscore: 0,
+                          fastcount: 0, slowcount: 0, exscore: 0, maxexscore: 0 }
+        }
+
+        /// Returns true if every chart in the course has been finished.
+        pub fn is_finished(&self) -> bool {
+            self.stage >= self.nstages
+        }
+
+        /// Carries over the gauge and combo to the player for the next chart.
+        pub fn start(&self, player: &mut Player) {
+            for &gauge in self.gauge.iter() { player.gauge = gauge; }
+            player.lastcombo = self.lastcombo;
+            player.bestcombo = self.bestcombo;
+        }
+
+        /// Accumulates the result of the player. Returns false if the chart was not finished, in
+        /// which case the course ends.
+        pub fn finish(&mut self, player: &Player) -> bool {
+            self.gauge = Some(player.gauge);
+            self.survival = player.survival;
+            self.lastcombo = player.lastcombo;
+            self.bestcombo = player.bestcombo;
+            for (total, &count) in self.gradecounts.iter_mut().zip(player.gradecounts.iter()) {
+                *total += count;
+            }
+            self.score += player.score;
+            self.maxscore += player.infos.maxscore;
+            self.fastcount += player.fastcount;
+            self.slowcount += player.slowcount;
+            self.exscore += player.exscore;
+            self.maxexscore += player.infos.nnotes as uint * 2;
+            if !player.is_finished() { return false; }
+            self.stage += 1;
+            true
+        }
+
+        /// Returns true if every chart in the course has been finished without failing.
+        pub fn is_cleared(&self) -> bool {
+            self.is_finished() && self.gauge.map_or(false, |gauge| gauge >= self.survival)
+        }
+
+        /// Prints the combined result of the course to the console.
+        pub fn show_result(&self) {
+            if self.is_cleared() {
+                println!("*** COURSE CLEARED! ***\n\
+                          PGREAT {:4}   COOL  {:4}    GREAT {:4}    GOOD  {:4}\n\
+                          BAD   {:4}    MISS  {:4}    MAX COMBO {}\n\
+                          FAST  {:4}    SLOW  {:4}\n\
+                          SCORE {:07} (max {:07})    EX SCORE {} (max {})",
+                         self.gradecounts[5], self.gradecounts[4], self.gradecounts[3],
+                         self.gradecounts[2], self.gradecounts[1], self.gradecounts[0],
+                         self.bestcombo,
+                         self.fastcount, self.slowcount,
+                         self.score, self.maxscore, self.exscore, self.maxexscore);
+            } else {
+                println!("COURSE FAILED! (stage {} of {})",
+                         cmp::min(self.stage + 1, self.nstages), self.nstages);
+            }
+        }
+    }
+
+    //----------------------------------------------------------------------------------------------
+    // offline rendering
+
+    /**
+     * Mixes every BGM and key sound (as played in AUTO PLAY mode) at their scheduled time, and
+     * writes the result to given writer as a 16-bit stereo WAV file. `sndres` should be loaded
+     * after `init_audio` so that every chunk is in the internal sampling rate.
+     *
+     * As in the game play, a sound stops when the same sound is played again, and BGMs are
+     * played with the lower volume. The input offset is not applied. Sounds scheduled before
+     * the origin (e.g. with the negative `#OFFSET`) are cut at the beginning of the file.
+     *
+     * XXX the rendering stops at the first negative BPM, as in `bms_duration`.
+     */
+    pub fn render_wav(bms: &Bms, infos: &BmsInfo, sndres: &[SoundResource],
+                      f: &mut Writer) -> io::IoResult<()> {
+        let timeline = Timeline::new(bms, infos.originoffset);
+
+        // sounds to be played as (the starting frame, the sound index, the volume up to 128).
+        // the starting frame can be negative, so it should not be converted to `uint` directly.
+        let mut events = Vec::new();
+        for &obj in bms.objs.iter() {
+            let (sref, volume) = match obj.data {
+                BGM(sref) => (sref, 96),
+                Visible(_, Some(sref)) | LNStart(_, Some(sref)) => (sref, 128),
+                SetBPM(bpm) if *bpm <= 0.0 => { break; }
+                _ => { continue; }
+            };
+            if **sref == 0 { continue; }
+            let msec = timeline.time_to_msec(bms, obj.time);
+            let frame = (msec * sample_rate() as f64 / 1000.0).floor() as int;
+            events.push((frame, **sref as uint, volume));
+        }
+
+        // each sound is cut at the next play of the same sound
+        let mut ends = Vec::from_elem(events.len(), None);
+        let mut lastevent = Vec::from_elem(sndres.len(), None);
+        for (i, &(frame, sref, _)) in events.iter().enumerate() {
+            for &j in lastevent[sref].iter() { ends[mut][j] = Some(frame); }
+            lastevent[mut][sref] = Some(i);
+        }
+
+        // mixes samples in the wider type and clips them later
+        let mut mixed: Vec<i32> = Vec::new();
+        for (&(start, sref, volume), end) in events.iter().zip(ends.iter()) {
+            let chunk = match sndres[sref].chunk() {
+                Some(chunk) => chunk.to_ll_chunk(),
+                None => { continue; }
+            };
+            let (abuf, alen) = unsafe { ((*chunk).abuf as *const i16, (*chunk).alen as uint) };
+            unsafe {
+                slice::raw::buf_as_slice(abuf, alen / 2, |samples| {
+                    let nframes = end.map_or(samples.len() as int / 2,
+                                             |end| cmp::min(samples.len() as int / 2, end - start));
+                    let skipped = cmp::max(-start, 0);
+                    if nframes <= skipped { return; }
+                    let first = (start + skipped) as uint;
+                    let (skipped, nframes) = (skipped as uint, nframes as uint);
+                    let needed = (first + nframes - skipped) * 2;
+                    if mixed.len() < needed {
+                        let len = mixed.len();
+                        mixed.grow(needed - len, 0);
+                    }
+                    for i in range(skipped * 2, nframes * 2) {
+                        mixed[mut][first * 2 + i - skipped * 2] +=
+                            samples[i] as i32 * volume / 128;
+                    }
+                });
+            }
+        }
+
+        let datalen = (mixed.len() * 2) as u32;
+        try!(f.write(b"RIFF"));
+        try!(f.write_le_u32(36 + datalen));
+        try!(f.write(b"WAVEfmt "));
+        try!(f.write_le_u32(16));
+        try!(f.write_le_u16(1)); // PCM
+        try!(f.write_le_u16(2)); // stereo
+        try!(f.write_le_u32(sample_rate() as u32));
+        try!(f.write_le_u32(bytes_per_sec() as u32));
+        try!(f.write_le_u16(4)); // bytes per frame
+        try!(f.write_le_u16(16)); // bits per sample
+        try!(f.write(b"data"));
+        try!(f.write_le_u32(datalen));
+        for &sample in mixed.iter() {
+            try!(f.write_le_i16(cmp::max(cmp::min(sample, 32767), -32768) as i16));
+        }
+        Ok(())
+    }
+
+    //----------------------------------------------------------------------------------------------
+    // display
+
+    /// Display interface.
+    pub trait Display {
+        /// Renders the current information from `player` to the screen or console. Called after
+        /// each call to `Player::tick`.
+        fn render(&mut self, player: &Player);
+        /// Shows the game play result from `player` to the screen or console. Called only once.
+        fn show_result(&self, player: &Player);
+        /// Shows the combined result of the course after its last chart (or the failed chart)
+        /// was played by `player`. Called instead of `show_result`. Prints to the console by
+        /// default.
+        fn show_course_result(&self, _player: &Player, course: &CourseState) {
+            course.show_result();
+        }
+    }
+
+    /// Saves the screen to a PNG file in the current directory, named after the current time.
+    /// Returns the path to the saved file.
+    pub fn save_screenshot(screen: &Surface) -> AngolmoisResult<String> {
+        use std::io;
+        use std::io::fs::PathExtensions;
+
+        let time = ::util::unix_time();
+        let mut path = Path::new(format!("angolmois-{}.png", time));
+        let mut suffix = 2u;
+        while path.exists() {
+            path = Path::new(format!("angolmois-{}-{}.png", time, suffix));
+            suffix += 1;
+        }
+
+        let pathstr = path.display().to_string();
+        let ret = io::File::create(&path).and_then(|mut f| {
+            screen.with_pixels(|pixels| gfx::write_png(&mut f, pixels))
+        });
+        match ret {
+            Ok(()) => Ok(pathstr),
+            Err(err) => Err(IoFailure(pathstr, err)),
+        }
+    }
+
+    /// The frame rate of the exported video.
+    pub const VIDEO_FPS: uint = 30;
+
+    /**
+     * Saves rendered frames to the directory as a PNG sequence (`frame00000.png` and so on) with
+     * a fixed frame rate of `VIDEO_FPS`. The audio is separately rendered by `render_wav`, so
+     * the sequence and the audio can be combined by external tools.
+     *
+     * XXX the game play runs in the real time, so frames are duplicated when the rendering
+     * (including the PNG encoding) is slower than the frame rate.
+     */
+    pub struct FrameRecorder {
+        /// The directory where frames are saved.
+        pub dir: Path,
+        /// The number of frames saved so far.
+        pub nframes: uint,
+    }
+
+    impl FrameRecorder {
+        /// Creates a new recorder, creating the directory as needed.
+        pub fn new(dir: &str) -> AngolmoisResult<FrameRecorder> {
+            use std::io;
+            let path = Path::new(dir);
+            let ret = io::fs::mkdir_recursive(&path, io::USER_RWX);
+            match ret {
+                Ok(()) => Ok(FrameRecorder { dir: path, nframes: 0 }),
+                Err(err) => Err(IoFailure(dir.to_string(), err)),
+            }
+        }
+
+        /// Returns the path to the frame with given index.
+        fn frame_path(&self, index: uint) -> Path {
+            self.dir.join(format!("frame{:05}.png", index))
+        }
+
+        /// Saves the screen as frames up to the time `elapsed` in milliseconds since the start of
+        /// the game play. Frames already saved are not touched.
+        pub fn record(&mut self, screen: &Surface, elapsed: uint) -> AngolmoisResult<()> {
+            use std::io;
+
+            let target = elapsed * VIDEO_FPS / 1000 + 1;
+            if self.nframes >= target { return Ok(()); }
+
+            let first = self.frame_path(self.nframes);
+            let mut ret = io::File::create(&first).and_then(|mut f| {
+                screen.with_pixels(|pixels| gfx::write_png(&mut f, pixels))
+            });
+            for index in range(self.nframes + 1, target) {
+                if ret.is_err() { break; }
+                ret = io::fs::copy(&first, &self.frame_path(index));
+            }
+            self.nframes = target;
+            ret.map_err(|err| IoFailure(self.dir.display().to_string(), err))
+        }
+    }
+
+    /// Frame rate statistics shown when `Options::showfps` is set.
+    pub struct FrameStats {
+        /// The number of frames per second, averaged over the last second.
+        pub fps: f64,
+        /// The time between the last two frames in milliseconds.
+        pub frametime: uint,
+        /// The number of frames since `since`.
+        nframes: uint,
+        /// The timestamp when `fps` has been last updated.
+        since: uint,
+        /// The timestamp at the last frame.
+        last: uint,
+    }
+
+    impl FrameStats {
+        /// Creates new statistics starting at the timestamp `now`.
+        pub fn new(now: uint) -> FrameStats {
+            FrameStats { fps: 0.0, frametime: 0, nframes: 0, since: now, last: now }
+        }
+
+        /// Records a new frame at the timestamp `now`.
+        pub fn update(&mut self, now: uint) {
+            self.nframes += 1;
+            if now - self.since >= 1000 {
+                self.fps = self.nframes as f64 * 1000.0 / (now - self.since) as f64;
+                self.nframes = 0;
+                self.since = now;
+            }
+            self.frametime = now - self.last;
+            self.last = now;
+        }
+    }
+
+    /// The opacity of the BGA scaled to the whole backdrop, out of 255.
+    const BACKDROP_ALPHA: u8 = 96;
+
+    /**
+     * The BGA scaled to a larger area by `Options::bgastretch`. BGA layers are composed to
+     * a separate buffer in the original size, which is scaled only when it has been changed since
+     * the last frame.
+     *
+     * XXX the scaling is done by `gfx::bicubic_interpolation` even with the OpenGL backend, so
+     * movies (which change every frame) can noticeably slow down the game play. The OpenGL backend
+     * only uploads the scaled BGA when it has been changed.
+     */
+    pub struct StretchedBga {
+        /// The buffer where BGA layers are composed in the original size.
+        buf: Surface,
+        /// The scaled BGA.
+        scaled: Surface,
+        /// The position of the scaled BGA in the screen.
+        x: uint,
+        /// The position of the scaled BGA in the screen.
+        y: uint,
+        /// The area cleared before the scaled BGA is drawn, as the position and size.
+        area: ((uint, uint), (uint, uint)),
+        /// Pixels of `buf` at the time `scaled` was last updated.
+        last: Vec<Color>,
+    }
+
+    impl StretchedBga {
+        /// Creates a scaled BGA for given stretching mode. `leftmost` and `rightmost` are same as
+        /// `GraphicDisplay` fields.
+        pub fn new(stretch: BgaStretch, leftmost: uint,
+                   rightmost: Option<uint>) -> Result<StretchedBga,String> {
+            let height = SCREENH - 110;
+            let (x, y, w, h, area) = match stretch {
+                StretchBeside => {
+                    let centerwidth = rightmost.unwrap_or(SCREENW) - leftmost;
+                    let size = cmp::min(centerwidth, height);
+                    (leftmost + (centerwidth - size) / 2, 30 + (height - size) / 2, size, size,
+                     ((leftmost, 30), (centerwidth, height)))
+                }
+                StretchBackdrop => (0, 30, SCREENW, height, ((0, 30), (SCREENW, height))),
+            };
+            let buf = try!(gfx::new_surface(BGAW, BGAH));
+            let scaled = try!(gfx::new_surface(w, h));
+            if stretch == StretchBackdrop {
+                scaled.set_alpha([video::SrcAlpha, video::RLEAccel], BACKDROP_ALPHA);
+            }
+            Ok(StretchedBga { buf: buf, scaled: scaled, x: x, y: y, area: area, last: Vec::new() })
+        }
+
+        /// Renders the image resources for the specified layers, scaled to the screen.
+        fn render(&mut self, canvas: &Canvas, bga: &BGAState, layers: &[BGALayer],
+                  imgres: &[ImageResource], now: uint) {
+            bga.render(&SoftwareCanvas(&self.buf), layers, imgres, 0, 0, now);
+
+            let last = &mut self.last;
+            let changed = self.buf.with_pixels(|pixels| {
+                let mut changed = last.is_empty();
+                if changed { last.grow(BGAW * BGAH, RGB(0,0,0)); }
+                for y in range(0, BGAH) {
+                    for x in range(0, BGAW) {
+                        let c = pixels.get_pixel(x, y);
+                        if last[y * BGAW + x] != c {
+                            last[mut][y * BGAW + x] = c;
+                            changed = true;
+                        }
+                    }
+                }
+                changed
+            });
+            if changed {
+                let buf = &self.buf;
+                self.scaled.with_pixels(|pixels| {
+                    buf.with_pixels(|srcpixels| gfx::bicubic_interpolation(srcpixels, pixels));
+                });
+                canvas.refresh(&self.scaled);
+            }
+
+            let ((ax, ay), (aw, ah)) = self.area;
+            let (w, h) = self.scaled.get_size();
+            canvas.fill_area((ax, ay), (aw, ah), RGB(0,0,0));
+            canvas.blit_area(&self.scaled, (0u, 0u), (self.x, self.y), (w as uint, h as uint));
+        }
+    }
+
+    //----------------------------------------------------------------------------------------------
+    // graphic display
+
+    /// Base colors of objects per beat division when `Options::beatcolor` is set: red for beats,
+    /// blue for eighth notes, yellow for sixteenth notes and gray for others.
+    static BEAT_COLORS: [Color, ..NBEATDIVISIONS] = [
+        RGB(0xff,0x40,0x40), RGB(0x40,0x80,0xff), RGB(0xff,0xe0,0x40), RGB(0xa0,0xa0,0xa0),
+    ];
+
+    /// An appearance for each lane. (C: `struct tkeykind` and `tkeyleft`)
+    pub struct LaneStyle {
+        /// The left position of the lane in the final screen. (C: `tkeyleft`)
+        pub left: uint,
+        /// The left position of the lane in the object sprite. (C: `spriteleft` field)
+        pub spriteleft: uint,
+        /// The left position of the lane in the bomb sprite. (C: `spritebombleft` field)
+        pub spritebombleft: uint,
+        /// The left position of the lane in the first beat-colored object sprite, which is only
+        /// rendered when `Options::beatcolor` is set. Other object sprites and then beat-colored
+        /// bomb sprites follow at the interval of `spritestride` in the order of
+        /// `BeatDivision::index`.
+        pub spritebeatleft: uint,
+        /// The distance between beat-colored object sprites.
+        pub spritestride: uint,
+        /// The width of lane. (C: `width` field)
+        pub width: uint,
+        /// The base color of object. The actual `Gradient` for drawing is derived from this color.
+        /// (C: `basecolor` field)
+        pub basecolor: Color
+    }
+
+    impl LaneStyle {
+        /// Returns the width and base color of lanes for given key kind, possibly customized.
+        fn kind_appearance(kind: KeyKind, customs: &[LaneCustomStyle]) -> (uint, Color) {
+            let (mut width, mut color) = match kind {
+                parser::WhiteKey    => (25, RGB(0x80,0x80,0x80)),
+                parser::WhiteKeyAlt => (25, RGB(0xf0,0xe0,0x80)),
+                parser::BlackKey    => (25, RGB(0x80,0x80,0xff)),
+                parser::Button1     => (30, RGB(0xe0,0xe0,0xe0)),
+                parser::Button2     => (30, RGB(0xff,0xff,0x40)),
+                parser::Button3     => (30, RGB(0x80,0xff,0x80)),
+                parser::Button4     => (30, RGB(0x80,0x80,0xff)),
+                parser::Button5     => (30, RGB(0xff,0x40,0x40)),
+                parser::Scratch     => (40, RGB(0xff,0x80,0x80)),
+                parser::FootPedal   => (40, RGB(0x80,0xff,0x80)),
+            };
+            for custom in customs.iter().filter(|custom| custom.kind == kind) {
+                for &w in custom.width.iter() { width = w; }
+                for &(r, g, b) in custom.color.iter() { color = RGB(r, g, b); }
+            }
+            (width, color)
+        }
+
+        /// Returns the total width of every key kind in the sprite.
+        fn total_sprite_width(customs: &[LaneCustomStyle]) -> uint {
+            let mut total = 0;
+            for &kind in KeyKind::all().iter() {
+                let (width, _) = LaneStyle::kind_appearance(kind, customs);
+                total += width;
+            }
+            total
+        }
+
+        /// Returns the width of the object sprite required for given customizations, and
+        /// beat-colored objects and bombs if `beatcolor` is set.
+        pub fn sprite_width(customs: &[LaneCustomStyle], beatcolor: bool) -> uint {
+            let nsprites = if beatcolor {2 + 2 * NBEATDIVISIONS} else {2};
+            SCREENW + nsprites * LaneStyle::total_sprite_width(customs)
+        }
+
+        /// Constructs a new `LaneStyle` object from given key kind and the left or right position.
+        /// The default width and color can be overridden by `customs`. (C: `tkeykinds`)
+        pub fn from_kind(kind: KeyKind, pos: uint, right: bool,
+                         customs: &[LaneCustomStyle]) -> LaneStyle {
+            let (width, color) = LaneStyle::kind_appearance(kind, customs);
+
+            // every key kind has its own area in the sprite in the order of `KeyKind::all`,
+            // and bombs and beat-colored objects are placed after all objects in turn.
+            let mut spriteleft = 0;
+            for &other in KeyKind::all().iter() {
+                if other == kind { break; }
+                let (width, _) = LaneStyle::kind_appearance(other, customs);
+                spriteleft += width;
+            }
+            let stride = LaneStyle::total_sprite_width(customs);
+
+            let left = if right {pos - width} else {pos};
+            LaneStyle { left: left, spriteleft: spriteleft, spritebombleft: stride + spriteleft,
+                        spritebeatleft: stride * 2 + spriteleft, spritestride: stride,
+                        width: width, basecolor: color }
+        }
+
+        /// Renders required object and bomb images to the sprite. Beat-colored objects and bombs
+        /// are also rendered if `beatcolor` is set.
+        pub fn render_to_sprite(&self, sprite: &Surface, beatcolor: bool) {
+            let left = self.spriteleft;
+            let noteleft = self.spriteleft + SCREENW;
+            let bombleft = self.spritebombleft + SCREENW;
+            assert!(sprite.get_width() as uint >= cmp::max(noteleft, bombleft) + self.width);
+
+            // render a background sprite (0 at top, <1 at bottom)
+            let backcolor = Gradient { zero: RGB(0,0,0), one: self.basecolor };
+            for i in range(140, SCREENH - 80) {
+                sprite.fill_area((left, i), (self.width, 1u),
+                                 backcolor.blend(i as int - 140, 1000));
+            }
+
+            // render note and bomb sprites (1/2 at middle, 1 at border)
+            let denom = self.width as int;
+            let notecolor = Gradient { zero: RGB(0xff,0xff,0xff), one: self.basecolor };
+            let bombcolor = Gradient { zero: RGB(0,0,0),          one: RGB(0xc0,0,0) };
+            for i in range(0, self.width / 2) {
+                let num = (self.width - i) as int;
+                sprite.fill_area((noteleft+i, 0u), (self.width-i*2, SCREENH),
+                                 notecolor.blend(num, denom));
+                sprite.fill_area((bombleft+i, 0u), (self.width-i*2, SCREENH),
+                                 bombcolor.blend(num, denom));
+            }
+
+            if beatcolor {
+                for (k, &color) in BEAT_COLORS.iter().enumerate() {
+                    let beatleft = self.spritebeatleft + k * self.spritestride + SCREENW;
+                    let beatbombleft = beatleft + NBEATDIVISIONS * self.spritestride;
+                    let notecolor = Gradient { zero: RGB(0xff,0xff,0xff), one: color };
+                    let bombcolor = Gradient { zero: RGB(0,0,0),
+                                               one: Gradient { zero: RGB(0,0,0), one: color }
+                                                        .blend(3, 4) };
+                    for i in range(0, self.width / 2) {
+                        let num = (self.width - i) as int;
+                        sprite.fill_area((beatleft+i, 0u), (self.width-i*2, SCREENH),
+                                         notecolor.blend(num, denom));
+                        sprite.fill_area((beatbombleft+i, 0u), (self.width-i*2, SCREENH),
+                                         bombcolor.blend(num, denom));
+                    }
+                }
+            }
+        }
+
+        /// Renders the lane background to the canvas from the sprite.
+        pub fn render_back(&self, canvas: &Canvas, sprite: &Surface, pressed: bool) {
+            canvas.fill_area((self.left, 30u), (self.width, SCREENH-110), RGB(0,0,0));
+            if pressed {
+                canvas.blit_area(sprite, (self.spriteleft, 140u), (self.left, 140u),
+                                 (self.width, SCREENH-220));
+            }
+        }
+
+        /// Renders an object to the canvas from the sprite, colored by given beat division
+        /// if any.
+        pub fn render_note(&self, canvas: &Canvas, sprite: &Surface, div: Option<BeatDivision>,
+                           top: uint, bottom: uint) {
+            let left = match div {
+                Some(div) => self.spritebeatleft + div.index() * self.spritestride,
+                None => self.spriteleft
+            };
+            canvas.blit_area(sprite, (left + SCREENW, 0u),
+                             (self.left, top), (self.width, bottom - top));
+        }
+
+        /// Renders the key beam and the hit flash images to the effect sprite, which is
+        /// laid out same as the object sprite except that there are `BEAM_FRAMES` beams and
+        /// `FLASH_FRAMES` flashes (in rows from 0 to `FLASH_HEIGHT`) instead of objects.
+        /// Black pixels are transparent.
+        pub fn render_to_effect_sprite(&self, effects: &Surface) {
+            let bright = Gradient { zero: self.basecolor, one: RGB(0xff,0xff,0xff) }.blend(1, 2);
+            let glow = Gradient { zero: RGB(0,0,0), one: bright };
+
+            // beams get brighter towards the bottom and fade out in later frames
+            for f in range(0, BEAM_FRAMES) {
+                let left = self.spriteleft + f * self.spritestride;
+                let level = (BEAM_FRAMES - f) as int;
+                for i in range(0, SCREENH) {
+                    let num = i as int * level;
+                    effects.fill_area((left, i), (self.width, 1u),
+                                      glow.blend(num, (SCREENH as int) * (BEAM_FRAMES as int)));
+                }
+            }
+
+            // flashes expand from the center and fade out in later frames
+            let (cx, cy) = (self.width as f64 / 2.0, FLASH_HEIGHT as f64 / 2.0);
+            effects.with_pixels(|pixels| {
+                for f in range(0, FLASH_FRAMES) {
+                    let left = self.spriteleft + (BEAM_FRAMES + f) * self.spritestride;
+                    let radius = 0.5 + 0.5 * f as f64 / FLASH_FRAMES as f64;
+                    let level = 1.0 - f as f64 / FLASH_FRAMES as f64;
+                    for y in range(0, FLASH_HEIGHT) {
+                        for x in range(0, self.width) {
+                            let dx = (x as f64 + 0.5 - cx) / cx;
+                            let dy = (y as f64 + 0.5 - cy) / cy;
+                            let dist = (dx * dx + dy * dy).sqrt() / radius;
+                            if dist >= 1.0 { continue; }
+                            let num = ((1.0 - dist) * level * 1000.0) as int;
+                            pixels.put_pixel(left + x, y, glow.blend(num, 1000));
+                        }
+                    }
+                }
+            });
+        }
+
+        /// Renders the key beam ending at the judgement line `judgey` to the canvas from
+        /// the effect sprite. `frame` is from 0 (brightest) to `BEAM_FRAMES - 1`.
+        pub fn render_beam(&self, canvas: &Canvas, effects: &Surface, frame: uint,
+                           judgey: uint) {
+            let left = self.spriteleft + frame * self.spritestride;
+            let height = judgey - 30;
+            canvas.blit_area(effects, (left, SCREENH - height), (self.left, 30u),
+                             (self.width, height));
+        }
+
+        /// Renders the hit flash centered at the judgement line `judgey` to the canvas from
+        /// the effect sprite. `frame` is from 0 (smallest) to `FLASH_FRAMES - 1`.
+        pub fn render_flash(&self, canvas: &Canvas, effects: &Surface, frame: uint,
+                            judgey: uint) {
+            let left = self.spriteleft + (BEAM_FRAMES + frame) * self.spritestride;
+            canvas.blit_area(effects, (left, 0u), (self.left, judgey - FLASH_HEIGHT / 2),
+                             (self.width, FLASH_HEIGHT));
+        }
+
+        /// Renders a bomb object to the canvas from the sprite.
+        pub fn render_bomb(&self, canvas: &Canvas, sprite: &Surface, div: Option<BeatDivision>,
+                           top: uint, bottom: uint) {
+            let left = match div {
+                Some(div) => self.spritebeatleft +
+                             (NBEATDIVISIONS + div.index()) * self.spritestride,
+                None => self.spritebombleft
+            };
+            canvas.blit_area(sprite, (left + SCREENW, 0u),
+                             (self.left, top), (self.width, bottom - top));
+        }
+    }
+
+    /// Builds a list of `LaneStyle`s from the key specification and customizations.
+    fn build_lane_styles(keyspec: &KeySpec, customs: &[LaneCustomStyle]) ->
+                                    Result<(uint, Option<uint>, Vec<(Lane,LaneStyle)>), String> {
+        let mut leftmost = 0;
+        let mut rightmost = SCREENW;
+        let mut styles = Vec::new();
+        for &lane in keyspec.left_lanes().iter() {
+            let kind = keyspec.kinds[*lane];
+            assert!(kind.is_some());
+            let kind = kind.unwrap();
+            let style = LaneStyle::from_kind(kind, leftmost, false, customs);
+            styles.push((lane, style));
+            leftmost += style.width + 1;
+            if leftmost > SCREENW - 20 {
+                return Err(format!("The screen can't hold that many lanes"));
+            }
+        }
+        for &lane in keyspec.right_lanes().iter() {
+            let kind = keyspec.kinds[*lane];
+            assert!(kind.is_some());
+            let kind = kind.unwrap();
+            let style = LaneStyle::from_kind(kind, rightmost, true, customs);
+            styles.push((lane, style));
+            if rightmost < leftmost + 40 {
+                return Err(format!("The screen can't hold that many lanes"));
+            }
+            rightmost -= style.width + 1;
+        }
+        let mut rightmost = if rightmost == SCREENW {None} else {Some(rightmost)};
+
+        // move lanes to the center if there are too small number of lanes
+        let cutoff = 165;
+        if leftmost < cutoff {
+            for i in range(0, keyspec.split) {
+                let (_lane, ref mut style) = styles[mut][i];
+                style.left += (cutoff - leftmost) / 2;
+            }
+            leftmost = cutoff;
+        }
+        if rightmost.map_or(false, |x| x > SCREENW - cutoff) {
+            for i in range(keyspec.split, styles.len()) {
+                let (_lane, ref mut style) = styles[mut][i];
+                style.left -= (rightmost.unwrap() - (SCREENW - cutoff)) / 2;
+            }
+            rightmost = Some(SCREENW - cutoff);
+        }
+
+        Ok((leftmost, rightmost, styles))
+    }
+
+    /// The height of the progress bar at the top of the screen.
+    const PROGRESS_HEIGHT: uint = 3;
+
+    /// The number of frames in the key beam, which fades out after the key is released.
+    const BEAM_FRAMES: uint = 4;
+    /// The duration of the key beam fading out in milliseconds.
+    const BEAM_DURATION: uint = 160;
+    /// The number of frames in the flash at the judgement line when an object is hit.
+    const FLASH_FRAMES: uint = 4;
+    /// The duration of the hit flash in milliseconds.
+    const FLASH_DURATION: uint = 120;
+    /// The height of the hit flash.
+    const FLASH_HEIGHT: uint = 40;
+    /// The duration of the gauge bar flashing on damage in milliseconds.
+    const GAUGE_FLASH_DURATION: uint = 150;
+
+    /// Creates a translucent sprite for key beams and hit flashes, separate from the object
+    /// sprite since it is blitted with the color key.
+    fn create_effect_sprite(opts: &Options,
+                            styles: &[(Lane,LaneStyle)]) -> Result<Surface,String> {
+        let nblocks = BEAM_FRAMES + FLASH_FRAMES;
+        let width = nblocks * LaneStyle::total_sprite_width(opts.lanestyles[]);
+        let effects = try!(gfx::new_surface(width, SCREENH));
+        effects.fill(RGB(0,0,0));
+        for &(_lane,style) in styles.iter() {
+            style.render_to_effect_sprite(&effects);
+        }
+        effects.set_color_key([video::SrcColorKey, video::RLEAccel], RGB(0,0,0));
+        effects.set_alpha([video::SrcAlpha, video::RLEAccel], 192);
+        Ok(effects)
+    }
+
+    /// Creates a sprite. (C: sprite construction portion of `play_prepare`)
+    fn create_sprite(opts: &Options, leftmost: uint, rightmost: Option<uint>,
+                     styles: &[(Lane,LaneStyle)]) -> Result<Surface,String> {
+        let width = LaneStyle::sprite_width(opts.lanestyles[], opts.beatcolor);
+        let sprite = try!(gfx::new_surface(width, SCREENH));
+        let black = RGB(0,0,0);
+        let gray = RGB(0x40,0x40,0x40); // gray used for separators
+
+        // render notes and lane backgrounds
+        for &(_lane,style) in styles.iter() {
+            style.render_to_sprite(&sprite, opts.beatcolor);
+        }
+
+        // render panels
+        sprite.with_pixels(|pixels| {
+            let topgrad = Gradient { zero: RGB(0x60,0x60,0x60), one: RGB(0xc0,0xc0,0xc0) };
+            let botgrad = Gradient { zero: RGB(0x40,0x40,0x40), one: RGB(0xc0,0xc0,0xc0) };
+            for j in range(-244i, 556) {
+                for i in range(-10i, 20) {
+                    let c = (i*2+j*3+750) % 2000;
+                    pixels.put_pixel((j+244) as uint, (i+10) as uint,
+                                     topgrad.blend(850 - num::abs(c-1000), 700));
+                }
+                for i in range(-20i, 60) {
+                    let c = (i*3+j*2+750) % 2000;
+                    let bottom = (SCREENH - 60) as int;
+                    pixels.put_pixel((j+244) as uint, (i+bottom) as uint,
+                                     botgrad.blend(850 - num::abs(c-1000), 700));
+                }
+            }
+        });
+        sprite.fill_area((10u, SCREENH-36), (leftmost, 1u), gray);
+
+        // erase portions of panels left unused
+        let leftgap = leftmost + 20;
+        let rightgap = rightmost.map_or(SCREENW, |x| x - 20);
+        let gapwidth = rightgap - leftgap;
+        sprite.fill_area((leftgap, 0u), (gapwidth, 30u), black);
+        sprite.fill_area((leftgap, SCREENH-80), (gapwidth, 80u), black);
+        sprite.with_pixels(|pixels| {
+            for i in range(0, 20u) {
+                // Rust: this cannot be `uint` since `-1u` underflows!
+                for j in iter::range_step(20i, 0, -1) {
+                    let j = j as uint;
+                    if i*i + j*j <= 400 { break; } // circled border
+                    pixels.put_pixel(leftmost + j, 10 + i, black);
+                    pixels.put_pixel(leftmost + j, (SCREENH-61) - i, black);
+                    for &right in rightmost.iter() {
+                        pixels.put_pixel((right-j) - 1, 10 + i, black);
+                        pixels.put_pixel((right-j) - 1, (SCREENH-61) - i, black);
+                    }
+                }
+            }
+        });
+
+        // draw the gauge bar if needed
+        if !opts.is_autoplay() {
+            sprite.fill_area((0u, SCREENH-16), (368u, 16u), gray);
+            sprite.fill_area((4u, SCREENH-12), (360u, 8u), black);
+        }
+
+        Ok(sprite)
+    }
+
+    /// Full-featured graphic display. Used for the normal game play and automatic play mode.
+    pub struct GraphicDisplay {
+        /// Sprite surface generated by `create_sprite`. (C: `sprite`)
+        pub sprite: Surface,
+        /// Sprite surface for key beams and hit flashes generated by `create_effect_sprite`.
+        pub effects: Surface,
+        /// Display screen. (C: `screen`)
+        pub screen: Surface,
+        /// The OpenGL presenter for the screen if any. See `init_video`.
+        pub presenter: Option<::util::gl::Presenter>,
+        /// Bitmap font.
+        pub font: Font,
+        /// Image resources. (C: `imgres`)
+        pub imgres: Vec<ImageResource>,
+
+        /// The leftmost X coordinate of the area next to the lanes, that is, the total width of
+        /// left-hand-side lanes. (C: `tpanel1`)
+        pub leftmost: uint,
+        /// The rightmost X coordinate of the area next to the lanes, that is, the screen width
+        /// minus the total width of right-hand-side lanes if any. `None` indicates the absence of
+        /// right-hand-side lanes. (C: `tpanel2`)
+        pub rightmost: Option<uint>,
+        /// The order and appearance of lanes. (C: `tkey` and `tkeyleft`)
+        pub lanestyles: Vec<(Lane,LaneStyle)>,
+        /// The beat division of each object in `Bms::objs`, if objects are colored by beats.
+        pub beatdivs: Option<Vec<BeatDivision>>,
+        /// The left coordinate of the BGA. (C: `tbgax`)
+        pub bgax: uint,
+        /// The top coordinate of the BGA. (C: `tbgay`)
+        pub bgay: uint,
+        /// The scaled BGA if requested. `bgax` and `bgay` are not used in this case.
+        pub stretched: Option<StretchedBga>,
+
+        /// Specifies how the POOR BGA is displayed, copied from `Options::poorbga`.
+        pub poorbga: PoorBga,
+        /// The duration of the POOR BGA in milliseconds, copied from `Options::poorduration`.
+        pub poorduration: uint,
+        /// If not `None`, indicates that the POOR BGA should be displayed until this timestamp.
+        /// (C: `poorlimit`)
+        pub poorlimit: Option<uint>,
+        /// If not `None`, indicates that the grading information should be displayed until
+        /// this timestamp. (C: `gradetime`)
+        pub gradelimit: Option<uint>,
+        /// If not `None`, indicates that the combo counter should bounce until this timestamp.
+        pub combolimit: Option<uint>,
+        /// The combo shown in the last frame, used to detect the increase.
+        pub lastcombo: uint,
+        /// True if the lane was pressed in the last frame, per lane.
+        pub lastpressed: Vec<bool>,
+        /// If not `None`, indicates that the key beam of the lane should be fading out until
+        /// this timestamp, per lane.
+        pub beamlimits: Vec<Option<uint>>,
+        /// If not `None`, indicates that the hit flash of the lane should be displayed until
+        /// this timestamp, per lane.
+        pub flashlimits: Vec<Option<uint>>,
+        /// The gauge shown in the last frame per side, used to detect the damage.
+        pub lastgauges: Vec<int>,
+        /// If not `None`, indicates that the gauge bar should flash until this timestamp,
+        /// per side.
+        pub gaugeflashlimits: Vec<Option<uint>>,
+        /// Currently known state of BGAs.
+        pub lastbga: BGAState,
+        /// The frame recorder for the video export if requested.
+        pub recorder: Option<FrameRecorder>,
+        /// Frame rate statistics.
+        pub stats: FrameStats,
+    }
+
+    /**
+     * Renders the line graph of the gauge (in the gauge color) and the EX score ratio (in white)
+     * over the time, from samples recorded by the player. The area is given as the position
+     * and size of the graph.
+     */
+    fn render_history_graph(screen: &Surface, player: &Player, (x, y): (uint, uint),
+                            (w, h): (uint, uint)) {
+        screen.fill_area((x, y), (w, h), RGB(0x20,0x20,0x20));
+        let survival = h - (player.survival as uint * h / MAXGAUGE as uint);
+        screen.fill_area((x, y + survival), (w, 1u), RGB(0x60,0x60,0x60));
+
+        let endtime = match player.history.last() {
+            Some(sample) if sample.time > 0 => sample.time,
+            _ => { return; }
+        };
+        let maxexscore = cmp::max(player.infos.nnotes as uint * 2, 1);
+        let gaugecolor = player.opts.gauge.color();
+        screen.with_pixels(|pixels| {
+            // plots both lines by taking the last sample before each column, and connects
+            // the previous column with a vertical line
+            let mut i = 0;
+            let mut last = None;
+            for col in range(0, w) {
+                let t = col * endtime / (w - 1);
+                while i + 1 < player.history.len() && player.history[i+1].time <= t { i += 1; }
+                let sample = &player.history[i];
+                let gauge = cmp::max(sample.gauge, 0) as uint;
+                let gaugey = h - 1 - cmp::min(gauge * (h - 1) / MAXGAUGE as uint, h - 1);
+                let exy = h - 1 - cmp::min(sample.exscore * (h - 1) / maxexscore, h - 1);
+                let (lastgaugey, lastexy) = last.unwrap_or((gaugey, exy));
+                for j in range(cmp::min(gaugey, lastgaugey), cmp::max(gaugey, lastgaugey) + 1) {
+                    pixels.put_pixel(x + col, y + j, gaugecolor);
+                }
+                for j in range(cmp::min(exy, lastexy), cmp::max(exy, lastexy) + 1) {
+                    pixels.put_pixel(x + col, y + j, RGB(0xff,0xff,0xff));
+                }
+                last = Some((gaugey, exy));
+            }
+        });
+    }
+
+    /**
+     * The list of grade names, corresponding color scheme and animations.
+     * (C: `tgradestr` and `tgradecolor`)
+     *
+     * The grading text is shown for `GRADE_DURATION` milliseconds. It is enlarged for the first
+     * milliseconds given by the third field ("pop"), and fades out for the last milliseconds
+     * given by the fourth field.
+     */
+    static GRADES: &'static [(&'static str,Gradient,uint,uint)] = &[
+        ("MISS",  Gradient { zero: RGB(0xff,0xc0,0xc0), one: RGB(0xff,0x40,0x40) },   0, 400),
+        ("BAD",   Gradient { zero: RGB(0xff,0xc0,0xff), one: RGB(0xff,0x40,0xff) },   0, 300),
+        ("GOOD",  Gradient { zero: RGB(0xff,0xff,0xc0), one: RGB(0xff,0xff,0x40) },   0, 200),
+        ("GREAT", Gradient { zero: RGB(0xc0,0xff,0xc0), one: RGB(0x40,0xff,0x40) },  60, 200),
+        ("COOL",  Gradient { zero: RGB(0xc0,0xc0,0xff), one: RGB(0x40,0x40,0xff) }, 100, 200),
+        ("PGREAT", Gradient { zero: RGB(0xff,0xff,0xff), one: RGB(0x40,0xc0,0xff) }, 100, 200),
+    ];
+
+    /// The duration of the grading text in milliseconds.
+    const GRADE_DURATION: uint = 700;
+
+    /// The duration in milliseconds of the combo counter bouncing up when the combo increases.
+    const COMBO_POP_DURATION: uint = 120;
+
+    impl GraphicDisplay {
+        /// Creates a new graphic display from the options, chart, key specification,
+        /// pre-allocated (usually by `init_video`) screen and its presenter, pre-created bitmap
+        /// fonts and pre-loaded image resources. The last four are owned by the display, others
+        /// are not (in fact, should be owned by `Player`).
+        pub fn new(opts: &Options, bms: &Bms, keyspec: &KeySpec, screen: Surface,
+                   presenter: Option<::util::gl::Presenter>, mut font: Font,
+                   imgres: Vec<ImageResource>) -> AngolmoisResult<GraphicDisplay> {
+            font.create_zoomed_font(3); // for the enlarged grading text
+            let styles = build_lane_styles(keyspec, opts.lanestyles[]);
+            let (leftmost, rightmost, styles) = match styles {
+                Ok(styles) => styles,
+                Err(err) => { return Err(InvalidKeySpec(err)); }
+            };
+            let centerwidth = rightmost.unwrap_or(SCREENW) - leftmost;
+            let bgax = leftmost + (centerwidth - BGAW) / 2;
+            let bgay = (SCREENH - BGAH) / 2;
+            let sprite = create_sprite(opts, leftmost, rightmost, styles[]);
+            let sprite = try!(sprite.map_err(SdlFailure));
+            let effects = try!(create_effect_sprite(opts, styles[]).map_err(SdlFailure));
+            let stretched = match opts.bgastretch {
+                Some(ref stretch) if opts.has_bga() => {
+                    let stretched = StretchedBga::new(stretch.clone(), leftmost, rightmost);
+                    Some(try!(stretched.map_err(SdlFailure)))
+                }
+                _ => None
+            };
+            let recorder = match opts.exportvideo {
+                Some(ref dir) => Some(try!(FrameRecorder::new(dir[]))),
+                None => None
+            };
+            // LN ends take the division of their starts, so that the whole LN is colored alike
+            let beatdivs = if opts.beatcolor {
+                let mut lnstarts = Vec::from_elem(NLANES, None);
+                Some(bms.objs.iter().map(|obj| {
+                    let div = bms.beat_division(obj.time);
+                    match obj.data {
+                        LNStart(lane,_) => { lnstarts[mut][*lane] = Some(div.clone()); div }
+                        LNDone(lane,_) => lnstarts[*lane].clone().unwrap_or(div),
+                        _ => div
+                    }
+                }).collect())
+            } else {
+                None
+            };
+
+            let display = GraphicDisplay {
+                sprite: sprite, effects: effects, screen: screen, presenter: presenter,
+                font: font, imgres: imgres,
+                leftmost: leftmost, rightmost: rightmost,
+                lanestyles: styles, beatdivs: beatdivs,
+                bgax: bgax, bgay: bgay, stretched: stretched,
+                poorbga: opts.poorbga.clone(), poorduration: opts.poorduration,
+                poorlimit: None, gradelimit: None, combolimit: None, lastcombo: 0,
+                lastpressed: Vec::from_elem(NLANES, false),
+                beamlimits: Vec::from_elem(NLANES, None),
+                flashlimits: Vec::from_elem(NLANES, None),
+                lastgauges: Vec::new(), gaugeflashlimits: Vec::new(),
+                lastbga: initial_bga_state(),
+                recorder: recorder, stats: FrameStats::new(sdl::get_ticks()),
+            };
+
+            display.screen.fill(RGB(0,0,0));
+            display.restore_panel();
+            present(&display.screen, display.presenter.as_ref());
+
+            Ok(display)
+        }
+
+        /// Restores the panels by blitting upper and bottom panels to the screen.
+        fn restore_panel(&self) {
+            let screen = &self.screen;
+            let sprite = &self.sprite;
+            screen.blit_area(sprite, (0u,0u), (0u,0u), (SCREENW,30u));
+            screen.blit_area(sprite, (0u,SCREENH-80), (0u,SCREENH-80), (SCREENW,80u));
+        }
+    }
+
+    impl Display for GraphicDisplay {
+        fn render(&mut self, player: &Player) {
+            let screen = &self.screen;
+            let sprite = &self.sprite;
+            let font = &self.font;
+
+            // update display states
+            for &(grade,when) in player.lastgrade.iter() {
+                if grade == MISS && self.poorbga != NoPoorBga {
+                    // switches to the normal BGA after `poorduration`
+                    let minlimit = when + self.poorduration;
+                    self.poorlimit = Some(self.poorlimit.map_or(minlimit,
+                                                                |t| cmp::max(t, minlimit)));
+                }
+                // grade disappears after 700ms
+                let minlimit = when + GRADE_DURATION;
+                self.gradelimit = Some(self.gradelimit.map_or(minlimit,
+                                                              |t| cmp::max(t, minlimit)));
+            }
+            if player.lastcombo != self.lastcombo {
+                if player.lastcombo > self.lastcombo {
+                    self.combolimit = Some(player.now + COMBO_POP_DURATION);
+                }
+                self.lastcombo = player.lastcombo;
+            }
+            for &(lane,_) in self.lanestyles.iter() {
+                // the key beam fades out after the release, and the hit flash is only shown
+                // for the successful hits
+                let pressed = player.key_pressed(lane);
+                if pressed {
+                    self.beamlimits[mut][*lane] = None;
+                } else if self.lastpressed[*lane] {
+                    self.beamlimits[mut][*lane] = Some(player.now + BEAM_DURATION);
+                }
+                self.lastpressed[mut][*lane] = pressed;
+                match player.lasthits[*lane] {
+                    Some((grade, when)) if grade as uint >= GOOD as uint &&
+                                           when + FLASH_DURATION > player.now => {
+                        self.flashlimits[mut][*lane] = Some(when + FLASH_DURATION);
+                    }
+                    _ => {}
+                }
+                if self.beamlimits[*lane] < Some(player.now) {
+                    self.beamlimits[mut][*lane] = None;
+                }
+                if self.flashlimits[*lane] < Some(player.now) {
+                    self.flashlimits[mut][*lane] = None;
+                }
+            }
+            let gauges: Vec<int> = if player.sides.len() == 2 {
+                player.sides.iter().map(|side| side.gauge).collect()
+            } else {
+                vec![player.gauge]
+            };
+            if self.lastgauges.len() != gauges.len() {
+                self.lastgauges = gauges.clone();
+                self.gaugeflashlimits = Vec::from_elem(gauges.len(), None);
+            }
+            for (i, &gauge) in gauges.iter().enumerate() {
+                // the gauge bar flashes whenever the gauge decreases
+                if gauge < self.lastgauges[i] {
+                    self.gaugeflashlimits[mut][i] = Some(player.now + GAUGE_FLASH_DURATION);
+                }
+                if self.gaugeflashlimits[i] < Some(player.now) {
+                    self.gaugeflashlimits[mut][i] = None;
+                }
+            }
+            self.lastgauges = gauges;
+            if self.poorlimit < Some(player.now) { self.poorlimit = None; }
+            if self.gradelimit < Some(player.now) { self.gradelimit = None; }
+            if self.combolimit < Some(player.now) { self.combolimit = None; }
+            self.lastbga.update(&player.bga, self.imgres[]);
+
+            // lanes, objects and BGAs are drawn with the GPU in the OpenGL backend, and the panels
+            // and texts drawn to the screen afterwards are overlaid to them when presented
+            let canvas = match self.presenter {
+                Some(ref presenter) => {
+                    presenter.begin_frame();
+                    ::util::gl::clear_overlay(screen);
+                    GLCanvas(presenter)
+                }
+                None => SoftwareCanvas(screen)
+            };
+
+            // render BGAs (should render before the lanes since lanes can overlap with BGAs)
+            if player.opts.has_bga() {
+                static POOR_LAYERS: [BGALayer, ..1] = [PoorBGA];
+                static OVERLAID_LAYERS: [BGALayer, ..4] = [Layer1, Layer2, Layer3, PoorBGA];
+                static NORM_LAYERS: [BGALayer, ..3] = [Layer1, Layer2, Layer3];
+                let layers = match (self.poorlimit, &self.poorbga) {
+                    (Some(_), &PoorOverlays) => OVERLAID_LAYERS[],
+                    (Some(_), _) => POOR_LAYERS[],
+                    (None, _) => NORM_LAYERS[],
+                };
+                match self.stretched {
+                    Some(ref mut stretched) => {
+                        stretched.render(&canvas, &self.lastbga, layers, self.imgres[],
+                                         player.now);
+                    }
+                    None => {
+                        self.lastbga.render(&canvas, layers, self.imgres[], self.bgax, self.bgay,
+                                            player.now);
+                    }
+                }
+            }
+
+            // fill the lanes to the border color
+            canvas.fill_area((0u, 30u), (self.leftmost, SCREENH-110), RGB(0x40,0x40,0x40));
+            for &rightmost in self.rightmost.iter() {
+                canvas.fill_area((rightmost, 30u), (SCREENH-rightmost, 490u), RGB(0x40,0x40,0x40));
+            }
+            for &(lane,style) in self.lanestyles.iter() {
+                style.render_back(&canvas, sprite, player.key_pressed(lane));
+            }
+
+            // set the clip area to avoid drawing on the panels
+            canvas.set_clip_area((0u, 30u), (SCREENW, SCREENH-110));
+
+            // render objects. the judgement line is raised by the lift.
+            let judgey = (SCREENH-70) - player.lift;
+            let time_to_y = |time| {
+                let adjusted = player.object_position(time);
+                judgey - (400.0 * player.playspeed * adjusted) as uint
+            };
+
+            // render key beams behind objects
+            for &(lane,style) in self.lanestyles.iter() {
+                if player.key_pressed(lane) {
+                    style.render_beam(&canvas, &self.effects, 0, judgey);
+                } else {
+                    for &limit in self.beamlimits[*lane].iter() {
+                        let elapsed = BEAM_DURATION - (limit - player.now);
+                        let frame = cmp::min(elapsed * BEAM_FRAMES / BEAM_DURATION,
+                                             BEAM_FRAMES - 1);
+                        style.render_beam(&canvas, &self.effects, frame, judgey);
+                    }
+                }
+            }
+            for &(lane,style) in self.lanestyles.iter() {
+                let laneobjs = player.laneobjs[*lane][];
+                let front = player.pfront.find_next_in(laneobjs, |obj| obj.is_renderable());
+                if front.is_none() { continue; }
+                let front = front.unwrap();
+
+                let beatdiv = |i: uint| self.beatdivs.as_ref().map(|divs| divs[i].clone());
+
+                // LN starting before the bottom and ending after the top
+                if front.time() > player.top && front.is_lndone() {
+                    style.render_note(&canvas, sprite, beatdiv(front.pos), 30, judgey - 10);
+                } else {
+                    let mut k = parser::lower_bound(laneobjs, front.pos);
+                    let mut nextbottom = None;
+                    let top = player.top;
+                    while k < laneobjs.len() && player.bms.objs[laneobjs[k]].time <= top {
+                        let i = laneobjs[k];
+                        let y = time_to_y(player.bms.objs[i].time);
+                        match player.bms.objs[i].data {
+                            LNStart(lane0,_) if lane0 == lane => {
+                                assert!(nextbottom.is_none());
+                                nextbottom = Some((i, y));
+                            }
+                            LNDone(lane0,_) if lane0 == lane => {
+                                let bottom = judgey - 10;
+                                style.render_note(&canvas, sprite, beatdiv(i), y,
+                                                  nextbottom.map_or(bottom, |(_, y)| y));
+                                nextbottom = None;
+                            }
+                            Visible(lane0,_) if lane0 == lane => {
+                                assert!(nextbottom.is_none());
+                                style.render_note(&canvas, sprite, beatdiv(i), y-5, y);
+                            }
+                            Bomb(lane0,_,_) if lane0 == lane => {
+                                assert!(nextbottom.is_none());
+                                style.render_bomb(&canvas, sprite, beatdiv(i), y-5, y);
+                            }
+                            _ => {}
+                        }
+                        k += 1;
+                    }
+
+                    for &(i, y) in nextbottom.iter() {
+                        style.render_note(&canvas, sprite, beatdiv(i), 30, y);
+                    }
+                }
+            }
+
+            // render measure bars, which briefly flash at the start of measures if requested
+            let flash = player.opts.metronomeflash &&
+                        player.lastdownbeat.map_or(false, |t| player.now < t + 100);
+            let (barcolor, barheight) = if flash {(RGB(0xff,0xff,0xff), 3u)}
+                                        else {(RGB(0xc0,0xc0,0xc0), 1u)};
+            for i in range(player.bottom.floor() as int, player.top.floor() as int + 1) {
+                let y = time_to_y(i as f64);
+                canvas.fill_area((0u, y), (self.leftmost, barheight), barcolor);
+                for &rightmost in self.rightmost.iter() {
+                    canvas.fill_area((rightmost, y), (800-rightmost, barheight), barcolor);
+                }
+            }
+
+            // render A-B loop markers
+            let loopstart = player.loopstart.map(|(start, _)| start);
+            for &t in loopstart.iter().chain(player.loopend.iter()) {
+                if player.bottom <= t && t <= player.top {
+                    canvas.fill_area((0u, time_to_y(t)), (self.leftmost, 2u), RGB(0xff,0xc0,0x40));
+                }
+            }
+
+            // fade out objects under the sudden and hidden modifiers. this only affects the
+            // rendering, so sounds and grading are not affected at all.
+            let visibleheight = judgey - 30 - player.cover;
+            let fadearea = [
+                player.opts.sudden.map(|frac| {
+                    let height = (visibleheight as f64 * frac) as uint;
+                    (30 + player.cover, height, true)
+                }),
+                player.opts.hidden.map(|frac| {
+                    let height = (visibleheight as f64 * frac) as uint;
+                    (judgey - height, height, false)
+                }),
+            ];
+            for &area in fadearea.iter() {
+                let (y, height, fadedown) = match area { Some(area) => area, None => continue };
+                for j in range(0, height) {
+                    // the distance from the visible edge of the faded area
+                    let dist = if fadedown {height - j} else {j + 1};
+                    let alpha = if dist >= FADE_HEIGHT {0}
+                                else {255 - dist * 255 / FADE_HEIGHT};
+                    let color = RGBA(0, 0, 0, alpha as u8);
+                    canvas.fill_area((0u, y + j), (self.leftmost, 1u), color);
+                    for &rightmost in self.rightmost.iter() {
+                        canvas.fill_area((rightmost, y + j), (SCREENW - rightmost, 1u), color);
+                    }
+                }
+            }
+
+            // render the lane cover and the lift over objects
+            let coverarea = [(30u, player.cover), (judgey, SCREENH - 70 - judgey)];
+            for &(y, height) in coverarea.iter() {
+                if height == 0 { continue; }
+                canvas.fill_area((0u, y), (self.leftmost, height), RGB(0x20,0x20,0x20));
+                for &rightmost in self.rightmost.iter() {
+                    canvas.fill_area((rightmost, y), (SCREENW-rightmost, height),
+                                     RGB(0x20,0x20,0x20));
+                }
+            }
+
+            // render hit flashes over objects and covers
+            for &(lane,style) in self.lanestyles.iter() {
+                for &limit in self.flashlimits[*lane].iter() {
+                    let elapsed = FLASH_DURATION - (limit - player.now);
+                    let frame = cmp::min(elapsed * FLASH_FRAMES / FLASH_DURATION,
+                                         FLASH_FRAMES - 1);
+                    style.render_flash(&canvas, &self.effects, frame, judgey);
+                }
+            }
+
+            // render grading text
+            if self.gradelimit.is_some() && player.lastgrade.is_some() {
+                let gradelimit = self.gradelimit.unwrap();
+                let (lastgrade,_) = player.lastgrade.unwrap();
+                let (gradename,gradecolor,pop,fade) = GRADES[lastgrade as uint];
+                let delta = (cmp::max(gradelimit - player.now, 400) - 400) / 15;
+
+                // the grading text is enlarged at first and fades out at last
+                let age = GRADE_DURATION - (gradelimit - player.now);
+                let remaining = gradelimit - player.now;
+                let alpha = if remaining < fade {(remaining * 255 / fade) as u8} else {255};
+                let gradecolor = gfx::Translucent { color: gradecolor, alpha: alpha };
+                // the combo counter bounces up for a moment when increased
+                let bounce = self.combolimit.map_or(0, |t| (t - player.now) * 6 /
+                                                           COMBO_POP_DURATION);
+
+                screen.with_pixels(|pixels| {
+                    if age < pop {
+                        font.print_string(pixels, self.leftmost/2, SCREENH/2 - 48 - delta, 3,
+                                          Centered, gradename, gradecolor);
+                    } else {
+                        font.print_string(pixels, self.leftmost/2, SCREENH/2 - 40 - delta, 2,
+                                          Centered, gradename, gradecolor);
+                    }
+                    match player.lastfast {
+                        Some(true) => {
+                            font.print_string(pixels, self.leftmost/2, SCREENH/2 - 58 - delta, 1,
+                                              Centered, "FAST",
+                                              Gradient::new(RGB(0xc0,0xff,0xff),
+                                                            RGB(0x40,0xc0,0xff)));
+                        }
+                        Some(false) => {
+                            font.print_string(pixels, self.leftmost/2, SCREENH/2 - 58 - delta, 1,
+                                              Centered, "SLOW",
+                                              Gradient::new(RGB(0xff,0xc0,0xc0),
+                                                            RGB(0xff,0x40,0x40)));
+                        }
+                        None => {}
+                    }
+                    if player.lastcombo > 1 {
+                        font.print_string(pixels, self.leftmost/2,
+                                          SCREENH/2 - 12 - delta - bounce, 1,
+                                          Centered, format!("{} COMBO",
+                                                            player.lastcombo)[],
+                                          Gradient::new(RGB(0xff,0xff,0xff), RGB(0x80,0x80,0x80)));
+                    }
+                    if player.opts.is_autoplay() {
+                        font.print_string(pixels, self.leftmost/2, SCREENH/2 + 2 - delta, 1,
+                                          Centered, "(AUTO)",
+                                          Gradient::new(RGB(0xc0,0xc0,0xc0), RGB(0x40,0x40,0x40)));
+                    }
+                    for &(_, ghostcombo) in player.ghost_status().iter() {
+                        font.print_string(pixels, self.leftmost/2, SCREENH/2 + 18 - delta, 1,
+                                          Centered, format!("GHOST {} COMBO", ghostcombo)[],
+                                          Gradient::new(RGB(0xc0,0xc0,0xff), RGB(0x40,0x40,0x80)));
+                    }
+                });
+            }
+
+            canvas.reset_clip_area();
+
+            self.restore_panel();
+
+            // render panel
+            let elapsed = (player.now - player.origintime) / 1000;
+            let duration = player.duration as uint;
+            let durationmsec = (player.duration * 1000.0) as uint;
+            let remaining = if duration > elapsed {duration - elapsed} else {0};
+            screen.with_pixels(|pixels| {
+                let black = RGB(0,0,0);
+                match self.rightmost {
+                    Some(rightmost) if player.sides.len() == 2 => {
+                        // the Couple Play shows scores of both sides in their own panels
+                        let (left, right) = (&player.sides[0], &player.sides[1]);
+                        font.print_string(pixels, 10, 8, 1, LeftAligned,
+                                          format!("SCORE {:07}", left.score)[], black);
+                        font.print_string(pixels, self.leftmost - 10, 8, 1, RightAligned,
+                                          format!("EX {:05}", left.exscore)[], black);
+                        font.print_string(pixels, rightmost + 10, 8, 1, LeftAligned,
+                                          format!("SCORE {:07}", right.score)[], black);
+                        font.print_string(pixels, SCREENW - 10, 8, 1, RightAligned,
+                                          format!("EX {:05}", right.exscore)[], black);
+                    }
+                    _ => {
+                        font.print_string(pixels, 10, 8, 1, LeftAligned,
+                                          format!("SCORE {:07}", player.score)[], black);
+                        font.print_string(pixels, self.rightmost.unwrap_or(SCREENW) - 10, 8, 1,
+                                          RightAligned, format!("EX {:05}", player.exscore)[],
+                                          black);
+                        for &pace in player.pacemaker_exscore().iter() {
+                            let diff = player.exscore as int - pace as int;
+                            font.print_string(pixels, self.rightmost.unwrap_or(SCREENW) - 82, 8,
+                                              1, RightAligned, format!("{:+05}", diff)[], black);
+                        }
+                    }
+                }
+                for &(ghostscore, _) in player.ghost_status().iter() {
+                    let diff = player.score as int - ghostscore as int;
+                    font.print_string(pixels, self.leftmost-10, 8, 1, RightAligned,
+                                      format!("{:+07}", diff)[], black);
+                }
+                let nominalplayspeed = player.nominal_playspeed();
+                match player.greennumber {
+                    Some(green) => {
+                        font.print_string(pixels, 5, SCREENH-78, 1, LeftAligned,
+                                          format!("{:4.1}x", nominalplayspeed)[], black);
+                        font.print_string(pixels, 5, SCREENH-62, 1, LeftAligned,
+                                          format!("{:4.0}ms", green)[], black);
+                    }
+                    None => {
+                        // the effective scroll speed as if the chart were played at 1.0x
+                        let scroll = num::abs(*player.bpm()) * nominalplayspeed;
+                        font.print_string(pixels, 5, SCREENH-78, 1, LeftAligned,
+                                          format!("{:4.1}x", nominalplayspeed)[], black);
+                        font.print_string(pixels, 5, SCREENH-62, 1, LeftAligned,
+                                          format!("={:4.0}", scroll)[], black);
+                    }
+                }
+                font.print_string(pixels, self.leftmost-94, SCREENH-35, 1, LeftAligned,
+                                  format!("{:02}:{:02} / {:02}:{:02}",
+                                          elapsed/60, elapsed%60,
+                                          duration/60, duration%60)[], black);
+                font.print_string(pixels, 5, SCREENH-35, 1, LeftAligned,
+                                  format!("-{:02}:{:02}", remaining/60, remaining%60)[], black);
+                if player.nmissing > 0 {
+                    font.print_string(pixels, 60, SCREENH-35, 1, LeftAligned,
+                                      format!("{} MISSING", player.nmissing)[], RGB(0xc0,0,0));
+                }
+                font.print_string(pixels, 95, SCREENH-62, 1, LeftAligned,
+                                  format!("@{:9.4}", player.bottom)[], black);
+                font.print_string(pixels, 95, SCREENH-78, 1, LeftAligned,
+                                  format!("BPM {:6.2}", *player.bpm())[], black);
+            });
+
+            // render the note density graph with the tick at the current position
+            let graphwidth = self.leftmost - 20;
+            let nmeasures = player.infos.density.len();
+            let postick = if player.bottom <= 0.0 || nmeasures == 0 {0}
+                          else {cmp::min(graphwidth,
+                                         (player.bottom * graphwidth as f64 /
+                                          nmeasures as f64) as uint)};
+            render_density_graph(screen, player.infos.density[], 10, SCREENH-50, graphwidth, 12,
+                                 postick, RGB(0x60,0x60,0x60), RGB(0x90,0x90,0x90));
+            screen.with_pixels(|pixels| {
+                font.print_glyph(pixels, 6 + postick, SCREENH-52, 1,
+                                 95, RGB(0x40,0x40,0x40)); // glyph #95: tick
+            });
+
+            // render the progress bar along the top edge of the screen
+            let progress = cmp::min(SCREENW, (player.now - player.origintime) * SCREENW /
+                                             cmp::max(durationmsec, 1));
+            screen.fill_area((0u, 0u), (SCREENW, PROGRESS_HEIGHT), RGB(0x20,0x20,0x20));
+            screen.fill_area((0u, 0u), (progress, PROGRESS_HEIGHT), RGB(0x80,0xc0,0xff));
+
+            // render gauge
+            if !player.opts.is_autoplay() {
+                // cycles four times per measure, [0,40)
+                let cycle = (160.0 * player.startshorten * player.bottom).floor() % 40.0;
+                // the part below the survival threshold ends where the bar would end at
+                // the threshold, so it uses the same formula as the bar
+                let survwidth = player.survival * 400 / MAXGAUGE - (cycle as int);
+                let survwidth = cmp::min(cmp::max(survwidth, 0), 360) as uint;
+                // returns the width of the bar, the width of the part below the survival
+                // threshold, the colors of both parts and the percentage of the gauge
+                let gauge_bar = |gauge: int, flashing: bool| {
+                    let width = if gauge < 0 {0} else {gauge * 400 / MAXGAUGE - (cycle as int)};
+                    let width = cmp::min(cmp::max(width, 5), 360) as uint;
+                    let color = match player.opts.gauge.color() {
+                        _ if flashing => RGB(0xff,0xff,0xff),
+                        RGB(r,g,b) if gauge < player.survival => {
+                            let dim = |v: u8| v - (v as f64 * cycle / 48.0) as u8;
+                            RGB(dim(r), dim(g), dim(b))
+                        }
+                        color => color
+                    };
+                    let lowcolor = match color {
+                        RGB(r,g,b) if !flashing => RGB(r/2, g/2, b/2),
+                        color => color
+                    };
+                    let percent = cmp::max(gauge, 0) as f64 * 100.0 / MAXGAUGE as f64;
+                    (width, cmp::min(survwidth, width), lowcolor, color, percent)
+                };
+                let flashing = |side: uint| self.gaugeflashlimits[side].is_some();
+                if player.sides.len() == 2 {
+                    // the gauge of the right side grows from the right edge. percentages are
+                    // rounded to fit in the gap between two gauges
+                    let (width, low, lowcolor, color, percent) =
+                        gauge_bar(player.sides[0].gauge, flashing(0));
+                    screen.fill_area((4u, SCREENH-12), (low, 8u), lowcolor);
+                    screen.fill_area((4 + low, SCREENH-12), (width - low, 8u), color);
+                    screen.with_pixels(|pixels| {
+                        font.print_string(pixels, 368, SCREENH-16, 1, LeftAligned,
+                                          format!("{:.0}%", percent)[], color);
+                    });
+                    screen.fill_area((SCREENW-368, SCREENH-16), (368u, 16u), RGB(0x40,0x40,0x40));
+                    screen.fill_area((SCREENW-364, SCREENH-12), (360u, 8u), RGB(0,0,0));
+                    let (width, low, lowcolor, color, percent) =
+                        gauge_bar(player.sides[1].gauge, flashing(1));
+                    screen.fill_area((SCREENW-4-low, SCREENH-12), (low, 8u), lowcolor);
+                    screen.fill_area((SCREENW-4-width, SCREENH-12), (width - low, 8u), color);
+                    screen.with_pixels(|pixels| {
+                        font.print_string(pixels, SCREENW-368, SCREENH-16, 1, RightAligned,
+                                          format!("{:.0}%", percent)[], color);
+                    });
+                } else {
+                    let (width, low, lowcolor, color, percent) =
+                        gauge_bar(player.gauge, flashing(0));
+                    screen.fill_area((4u, SCREENH-12), (low, 8u), lowcolor);
+                    screen.fill_area((4 + low, SCREENH-12), (width - low, 8u), color);
+                    screen.with_pixels(|pixels| {
+                        font.print_string(pixels, 372, SCREENH-16, 1, LeftAligned,
+                                          format!("{:.1}%", percent)[], color);
+                    });
+                }
+            }
+
+            self.stats.update(sdl::get_ticks());
+            if player.opts.showfps {
+                let stats = format!("{:.1} FPS / {} ms", self.stats.fps, self.stats.frametime);
+                screen.with_pixels(|pixels| {
+                    font.print_string(pixels, SCREENW-3, 33, 1, RightAligned, stats[],
+                                      RGB(0xc0,0xc0,0xc0));
+                });
+            }
+
+            // the OpenGL backend composes the frame only when presenting, so the frame is read
+            // back for the screenshot and the recorder. otherwise the screenshot should be taken
+            // before the flip, which may swap buffers
+            let capture = match self.presenter {
+                Some(ref presenter) => {
+                    let capture = if player.screenshot || self.recorder.is_some() {
+                        gfx::new_surface(SCREENW, SCREENH).ok()
+                    } else {
+                        None
+                    };
+                    presenter.present_overlay(screen, capture.as_ref());
+                    capture
+                }
+                None => None
+            };
+            let frame = capture.as_ref().unwrap_or(screen);
+            if player.screenshot {
+                match save_screenshot(frame) {
+                    Ok(_) => {}
+                    Err(err) => warn!("failed to save the screenshot: {}", err)
+                }
+            }
+            let elapsed = player.now - player.origintime;
+            let mut failed = false;
+            for recorder in self.recorder.iter_mut() {
+                match recorder.record(frame, elapsed) {
+                    Ok(()) => {}
+                    Err(err) => { warn!("failed to save the frame: {}", err); failed = true; }
+                }
+            }
+            if failed { self.recorder = None; }
+
+            if self.presenter.is_none() { present(screen, None); }
+        }
+
+        fn show_result(&self, player: &Player) {
+            if player.opts.is_autoplay() { return; }
+
+            // check if the song reached the last gradable object (otherwise the game play was
+            // terminated by the user)
+            if !player.is_finished() { return; }
+
+            let screen = &self.screen;
+            let font = &self.font;
+            let (meta, title, genre, artist) =
+                displayed_info(&*player.bms, &player.infos, &player.keyspec, None);
+            let lamp = player.clear_lamp();
+            let lampcolor = match lamp {
+                "FAILED" => RGB(0xff,0x40,0x40),
+                "PERFECT" => RGB(0xff,0xe0,0x40),
+                "FULL COMBO" => RGB(0x40,0xe0,0xff),
+                _ => player.opts.gauge.color(),
+            };
+
+            screen.set_clip_rect(&screen.get_rect());
+            screen.fill(RGB(0,0,0));
+            screen.with_pixels(|pixels| {
+                let white = Gradient::new(RGB(0xff,0xff,0xff), RGB(0x80,0x80,0x80));
+                let gray = Gradient::new(RGB(0xc0,0xc0,0xc0), RGB(0x60,0x60,0x60));
+                font.print_string(pixels, SCREENW/2, 30, 2, Centered, title[], white);
+                font.print_string(pixels, SCREENW/2, 70, 1, Centered, genre[], gray);
+                font.print_string(pixels, SCREENW/2, 86, 1, Centered, artist[], gray);
+                font.print_string(pixels, SCREENW/2, 102, 1, Centered, meta[], gray);
+                font.print_string(pixels, SCREENW/2, 140, 2, Centered, lamp, lampcolor);
+
+                // grade breakdown on the left, other statistics on the right
+                for (i, &(name, color, _, _)) in GRADES.iter().rev().enumerate() {
+                    let y = 200 + i * 24;
+                    font.print_string(pixels, SCREENW/2 - 220, y, 1, LeftAligned, name, color);
+                    font.print_string(pixels, SCREENW/2 - 40, y, 1, RightAligned,
+                                      format!("{}", player.gradecounts[NGRADES-1-i])[], white);
+                }
+                let stats = [
+                    ("MAX COMBO", format!("{} / {}", player.bestcombo, player.infos.nnotes)),
+                    ("EX SCORE", format!("{} / {}", player.exscore, player.infos.nnotes * 2)),
+                    ("SCORE", format!("{:07} / {:07}", player.score, player.infos.maxscore)),
+                    ("FAST / SLOW", format!("{} / {}", player.fastcount, player.slowcount)),
+                    ("GAUGE", format!("{}%", cmp::max(player.gauge, 0) * 100 / MAXGAUGE)),
+                ];
+                for (i, &(name, ref value)) in stats.iter().enumerate() {
+                    let y = 200 + i * 24;
+                    font.print_string(pixels, SCREENW/2 + 20, y, 1, LeftAligned, name, gray);
+                    font.print_string(pixels, SCREENW/2 + 220, y, 1, RightAligned,
+                                      value[], white);
+                }
+
+                // per-side results for the Couple Play
+                for (i, side) in player.sides.iter().enumerate() {
+                    let cleared = if side.gauge >= player.survival {"CLEARED"} else {"FAILED"};
+                    font.print_string(pixels, SCREENW/2, 380 + i * 20, 1, Centered,
+                                      format!("{}P {:7}  SCORE {:07}  EX {}  MAX COMBO {}",
+                                              i + 1, cleared, side.score, side.exscore,
+                                              side.bestcombo)[], white);
+                }
+
+                font.print_string(pixels, SCREENW/2, SCREENH-30, 1, Centered,
+                                  "Press any key to continue", gray);
+            });
+
+            // the gauge at the end
+            let width = (cmp::max(player.gauge, 0) * 400 / MAXGAUGE) as uint;
+            screen.fill_area((SCREENW/2 - 204, 340u), (408u, 16u), RGB(0x40,0x40,0x40));
+            screen.fill_area((SCREENW/2 - 200, 344u), (400u, 8u), RGB(0,0,0));
+            screen.fill_area((SCREENW/2 - 200, 344u), (width, 8u), player.opts.gauge.color());
+            let survival = (player.survival * 400 / MAXGAUGE) as uint;
+            screen.fill_area((SCREENW/2 - 200 + survival, 340u), (1u, 16u), RGB(0xff,0xff,0xff));
+            render_history_graph(screen, player, (SCREENW/2 - 300, 430), (600, 120));
+            present(screen, self.presenter.as_ref());
+            wait_for_result_key(screen, player);
+        }
+
+        fn show_course_result(&self, player: &Player, course: &CourseState) {
+            let screen = &self.screen;
+            let font = &self.font;
+            let (title, titlecolor) =
+                if course.is_cleared() {
+                    ("COURSE CLEARED!".to_string(), RGB(0xff,0xe0,0x40))
+                } else {
+                    (format!("COURSE FAILED! (stage {} of {})",
+                             cmp::min(course.stage + 1, course.nstages), course.nstages),
+                     RGB(0xff,0x40,0x40))
+                };
+
+            screen.set_clip_rect(&screen.get_rect());
+            screen.fill(RGB(0,0,0));
+            screen.with_pixels(|pixels| {
+                let white = Gradient::new(RGB(0xff,0xff,0xff), RGB(0x80,0x80,0x80));
+                let gray = Gradient::new(RGB(0xc0,0xc0,0xc0), RGB(0x60,0x60,0x60));
+                font.print_string(pixels, SCREENW/2, 60, 2, Centered, title[], titlecolor);
+
+                // same layout as the result screen of a single chart
+                for (i, &(name, color, _, _)) in GRADES.iter().rev().enumerate() {
+                    let y = 200 + i * 24;
+                    font.print_string(pixels, SCREENW/2 - 220, y, 1, LeftAligned, name, color);
+                    font.print_string(pixels, SCREENW/2 - 40, y, 1, RightAligned,
+                                      format!("{}", course.gradecounts[NGRADES-1-i])[], white);
+                }
+                let stats = [
+                    ("STAGES", format!("{} / {}", course.stage, course.nstages)),
+                    ("MAX COMBO", format!("{}", course.bestcombo)),
+                    ("EX SCORE", format!("{} / {}", course.exscore, course.maxexscore)),
+                    ("SCORE", format!("{:07} / {:07}", course.score, course.maxscore)),
+                    ("FAST / SLOW", format!("{} / {}", course.fastcount, course.slowcount)),
+                ];
+                for (i, &(name, ref value)) in stats.iter().enumerate() {
+                    let y = 200 + i * 24;
+                    font.print_string(pixels, SCREENW/2 + 20, y, 1, LeftAligned, name, gray);
+                    font.print_string(pixels, SCREENW/2 + 220, y, 1, RightAligned,
+                                      value[], white);
+                }
+
+                font.print_string(pixels, SCREENW/2, SCREENH-30, 1, Centered,
+                                  "Press any key to continue", gray);
+            });
+            present(screen, self.presenter.as_ref());
+            wait_for_result_key(screen, player);
+        }
+    }
+
+    /// Waits for a key press on the result screen. The screenshot key saves the result screen
+    /// instead of closing it.
+    fn wait_for_result_key(screen: &Surface, player: &Player) {
+        loop {
+            match event::wait_event() {
+                KeyEvent(key,true,_,_)
+                        if player.keymap.find(&KeyInput(key)) == Some(&ScreenshotInput) => {
+                    match save_screenshot(screen) {
+                        Ok(_) => {}
+                        Err(err) => warn!("failed to save the screenshot: {}", err)
+                    }
+                }
+                KeyEvent(_,true,_,_) | QuitEvent => { break; }
+                // a chart dropped onto the window is played next (see `::play`)
+                _ if ::util::has_dropped_file() => { break; }
+                _ => {}
+            }
+        }
+    }
+
+    //----------------------------------------------------------------------------------------------
+    // text display
+
+    /// Text-only display. Used for the exclusive mode with BGA disabled.
+    pub struct TextDisplay {
+        /// Ticker used for printing to the console.
+        pub ticker: Ticker
+    }
+
+    impl TextDisplay {
+        /// Creates a new text-only display.
+        pub fn new() -> TextDisplay {
+            TextDisplay { ticker: Ticker::new() }
+        }
+    }
+
+    /// Returns the status line for the console, with the elapsed and remaining time, current
+    /// measure, BPM and the score (or the combo if the score is not meaningful). Fits in
+    /// `update_line`.
+    fn status_line(player: &Player) -> String {
+        let elapsed = (player.now - player.origintime) / 1000;
+        let duration = player.duration as uint;
+        let remaining = if duration > elapsed {duration - elapsed} else {0};
+        let measure = cmp::max(player.bottom.floor() as int, 0);
+        // the score in the exclusive mode comes from the automatic play, but still tells
+        // how far the chart went
+        let progress = if player.opts.is_autoplay() {
+            format!("SCORE {:07}", player.score)
+        } else {
+            format!("{} / {} notes", player.lastcombo, player.infos.nnotes)
+        };
+        format!("{:02}:{:02} / {:02}:{:02} (-{:02}:{:02}) | \\#{:03} | BPM {:6.2} | {}",
+                elapsed/60, elapsed%60, duration/60, duration%60, remaining/60, remaining%60,
+                measure, *player.bpm(), progress)
+    }
+
+    impl Display for TextDisplay {
+        fn render(&mut self, player: &Player) {
+            if !player.opts.showinfo { return; }
+
+            self.ticker.on_tick(player.now, || {
+                update_line(status_line(player)[]);
+            });
+        }
+
+        fn show_result(&self, _player: &Player) {
+            update_line("");
+        }
+    }
+
+    //----------------------------------------------------------------------------------------------
+    // terminal display
+
+    /// The number of rows for lanes in the terminal display.
+    const TERMINAL_ROWS: uint = 20;
+
+    /// Terminal display, which draws lanes and falling notes with Unicode block characters to
+    /// the console using ANSI escape sequences. Used for the exclusive mode with BGA disabled
+    /// when `Options::terminal` is set.
+    pub struct TerminalDisplay {
+        /// Ticker used for printing to the console.
+        pub ticker: Ticker,
+        /// True if lanes have been drawn, so that the next frame should overwrite them.
+        pub drawn: bool,
+    }
+
+    impl TerminalDisplay {
+        /// Creates a new terminal display.
+        pub fn new() -> TerminalDisplay {
+            TerminalDisplay { ticker: Ticker::new(), drawn: false }
+        }
+    }
+
+    impl Display for TerminalDisplay {
+        fn render(&mut self, player: &Player) {
+            let mut drawn = self.drawn;
+            self.ticker.on_tick(player.now, || {
+                // the graphic display shows 1.25 units of `Player::object_position` (500 pixels)
+                // above the grading line, and so does this. rows are counted from the bottom.
+                let row_at = |time: f64| -> uint {
+                    let pos = player.playspeed * player.object_position(time) * 0.8 *
+                              TERMINAL_ROWS as f64;
+                    if pos < 0.0 {0} else {cmp::min(pos as uint, TERMINAL_ROWS - 1)}
+                };
+
+                let lanes = player.keyspec.order[];
+                let mut grid = Vec::from_elem(TERMINAL_ROWS, Vec::from_elem(lanes.len(), "  "));
+                for (col, &lane) in lanes.iter().enumerate() {
+                    let laneobjs = player.laneobjs[*lane][];
+                    let front = match player.pfront.find_next_in(laneobjs,
+                                                                 |obj| obj.is_renderable()) {
+                        Some(front) => front,
+                        None => { continue; }
+                    };
+
+                    // LN starting before the bottom fills the lane from the bottom
+                    let mut lnbottom = if front.is_lndone() {Some(0)} else {None};
+                    let mut k = parser::lower_bound(laneobjs, front.pos);
+                    while k < laneobjs.len() && player.bms.objs[laneobjs[k]].time <= player.top {
+                        let obj = &player.bms.objs[laneobjs[k]];
+                        let row = row_at(obj.time);
+                        match obj.data {
+                            LNStart(lane0,_) if lane0 == lane => { lnbottom = Some(row); }
+                            LNDone(lane0,_) if lane0 == lane => {
+                                for i in range(lnbottom.unwrap_or(0), row + 1) {
+                                    grid[mut][i][mut][col] = "\u2592\u2592";
+                                }
+                                lnbottom = None;
+                            }
+                            Visible(lane0,_) if lane0 == lane => {
+                                grid[mut][row][mut][col] = "\u2588\u2588";
+                            }
+                            Bomb(lane0,_,_) if lane0 == lane => {
+                                grid[mut][row][mut][col] = "\u00d7\u00d7";
+                            }
+                            _ => {}
+                        }
+                        k += 1;
+                    }
+                    for &bottom in lnbottom.iter() {
+                        for i in range(bottom, TERMINAL_ROWS) {
+                            grid[mut][i][mut][col] = "\u2592\u2592";
+                        }
+                    }
+                }
+
+                // the left and right sides of the couple play are separated by a gap
+                let split = player.keyspec.split;
+                let mut out = String::new();
+                if drawn { out.push_str(format!("\r\x1b[{}A", TERMINAL_ROWS + 1)[]); }
+                for cells in grid.iter().rev() {
+                    out.push_str("\r\u2502");
+                    for (col, cell) in cells.iter().enumerate() {
+                        if col == split && col > 0 { out.push_str("\u2502 \u2502"); }
+                        out.push_str(*cell);
+                    }
+                    out.push_str("\u2502\n");
+                }
+                out.push_str("\r\u2514");
+                for col in range(0, lanes.len()) {
+                    if col == split && col > 0 { out.push_str("\u2518 \u2514"); }
+                    out.push_str("\u2500\u2500");
+                }
+                out.push_str("\u2518\n\r\x1b[K");
+                if player.opts.showinfo { out.push_str(status_line(player)[]); }
+                let _ = write!(&mut std::io::stderr(), "{}", out);
+                drawn = true;
+            });
+            self.drawn = drawn;
+        }
+
+        fn show_result(&self, _player: &Player) {
+            // erases lanes and the status line
+            if self.drawn {
+                let _ = write!(&mut std::io::stderr(), "\r\x1b[{}A\x1b[J", TERMINAL_ROWS + 1);
+            }
+        }
+    }
+
+    //----------------------------------------------------------------------------------------------
+    // BGA-only display
+
+    /// BGA-only display. Used for the exclusive mode with BGA enabled.
+    pub struct BGAOnlyDisplay {
+        /// The underlying text-only display (as the BGA-only display lacks the on-screen display).
+        pub textdisplay: TextDisplay,
+        /// Display screen. (C: `screen`)
+        pub screen: Surface,
+        /// The OpenGL presenter for the screen if any. See `init_video`.
+        pub presenter: Option<::util::gl::Presenter>,
+        /// Image resources. (C: `imgres`)
+        pub imgres: Vec<ImageResource>,
+        /// Currently known state of BGAs.
+        pub lastbga: BGAState,
+    }
+
+    impl BGAOnlyDisplay {
+        /// Creates a new BGA-only display from the pre-created screen and its presenter (usually
+        /// by `init_video`) and pre-loaded image resources.
+        pub fn new(screen: Surface, presenter: Option<::util::gl::Presenter>,
+                   imgres: Vec<ImageResource>) -> BGAOnlyDisplay {
+            BGAOnlyDisplay { textdisplay: TextDisplay::new(), screen: screen, presenter: presenter,
+                             imgres: imgres, lastbga: initial_bga_state() }
+        }
+    }
+
+    impl Display for BGAOnlyDisplay {
+        fn render(&mut self, player: &Player) {
+            self.lastbga.update(&player.bga, self.imgres[]);
+
+            let layers = &[Layer1, Layer2, Layer3];
+            self.lastbga.render(&SoftwareCanvas(&self.screen), layers, self.imgres[], 0, 0,
+                                player.now);
+            present(&self.screen, self.presenter.as_ref());
+
+            self.textdisplay.render(player);
+        }
+
+        fn show_result(&self, player: &Player) {
+            self.textdisplay.show_result(player);
+        }
+    }
+
+    //----------------------------------------------------------------------------------------------
+
+}
+
+//==================================================================================================
+// entry point
+
+/// Shows the song selection screen starting from given directory, and returns a path to
+/// the selected chart if any.
+pub fn select_chart(fullscreen: bool, dir: Path) -> AngolmoisResult<Option<String>> {
+    let (screen, _) = try!(player::init_video(false, fullscreen, false));
+    let mut font = gfx::Font::new();
+    font.create_zoomed_font(1);
+    Ok(player::select_chart(&screen, &font, dir))
+}
+
+/// Runs the calibration and saves the measured input offset, which is used by later game plays.
+/// `samplerate` and `audiobuffer` are same as `player::Options` fields.
+pub fn calibrate(fullscreen: bool, samplerate: uint, audiobuffer: uint) -> AngolmoisResult<()> {
+    try!(player::init_audio(samplerate, audiobuffer));
+    let (screen, _) = try!(player::init_video(false, fullscreen, false));
+    let mut font = gfx::Font::new();
+    font.create_zoomed_font(1);
+    let ret = player::calibrate(&screen, &font);
+    sdl_mixer::allocate_channels(0);
+
+    match try!(ret) {
+        Some(offset) => {
+            let path = try!(player::write_global_offset(offset));
+            println!("Input offset {:+.1} ms saved to {}", offset, path.display());
+        }
+        None => { println!("Too few key presses for the calibration."); }
+    }
+    Ok(())
+}
+
+/// Runs the calibration of joystick axes in given devices, which should be left at rest, and
+/// saves the measured centers and deadzones, which are used by later game plays.
+pub fn calibrate_axes(devices: &[uint]) -> AngolmoisResult<()> {
+    let mut joysticks = Vec::new();
+    for &device in devices.iter() {
+        joysticks.push((device, try!(player::init_joystick(device))));
+    }
+    if joysticks.is_empty() {
+        joysticks.push((0, try!(player::init_joystick(0))));
+    }
+
+    println!("Leave every axis of the joystick at rest for {} seconds...",
+             player::AXIS_CALIBRATION_TIME / 1000);
+    let axes = try!(player::calibrate_axes(joysticks[], || {}));
+    for calib in axes.iter() {
+        println!("Joystick {} axis {}: center {}, deadzone {}",
+                 calib.device, calib.axis, calib.center, calib.deadzone);
+    }
+    let path = try!(player::write_axis_calibration(axes[]));
+    println!("Axis calibration saved to {}", path.display());
+    Ok(())
+}
+
+/// Returns the random seed given by the options, or a fresh seed if none.
+fn random_seed(opts: &player::Options) -> u64 {
+    use std::rand::Rng;
+    opts.seed.unwrap_or_else(|| std::rand::task_rng().gen())
+}
+
+/// Creates a random number generator for #RANDOM and modifiers. The same seed always results in
+/// the same chart. The generator is fixed to the 32-bit ISAAC seeded with both halves of
+/// the seed, since `StdRng` differs between 32-bit and 64-bit platforms.
+fn seeded_rng(seed: u64) -> std::rand::IsaacRng {
+    std::rand::SeedableRng::from_seed([seed as u32, (seed >> 32) as u32][])
+}
+
+/// Parses the chart file, choosing the parser by the file extension.
+fn parse_chart<R:std::rand::Rng>(path: &str, profile: parser::CompatProfile,
+                                 r: &mut R) -> AngolmoisResult<parser::Bms> {
+    parse_chart_with(path, profile, |max| r.gen_range(1, max + 1))
+}
+
+/// Same as `parse_chart`, but `gen` chooses the value for each #RANDOM command. See
+/// `parser::parse_bms_from_reader_with`. The compatibility profile only applies to BMS files.
+fn parse_chart_with(path: &str, profile: parser::CompatProfile,
+                    gen: |int| -> int) -> AngolmoisResult<parser::Bms> {
+    use std::ascii::AsciiExt;
+    let lowerpath = path.to_ascii_lower();
+    if lowerpath[].ends_with(".osu") {
+        osu::parse_osu(path)
+    } else if lowerpath[].ends_with(".ojn") {
+        o2jam::parse_ojn(path, o2jam::DEFAULT_DIFFICULTY)
+    } else {
+        parser::parse_bms_with(path, profile, gen)
+    }
+}
+
+/// Sanitizes the chart to be played or rendered according to `opts.sanitizepolicy`, which is
+/// treated as `FixConflicts` if `ReportOnly`. Fails if the strict policy changed anything.
+fn sanitize_chart(bms: &mut parser::Bms, opts: &player::Options) -> AngolmoisResult<()> {
+    let policy = match opts.sanitizepolicy {
+        parser::ReportOnly => parser::FixConflicts,
+        policy => policy,
+    };
+    let nchanged = parser::sanitize_bms(bms, policy);
+    if policy == parser::Strict && nchanged > 0 {
+        let first = bms.diagnostics.iter().find(|diag| diag.line == 0).unwrap();
+        return Err(InvalidChart(format!("{}: {} conflicting object(s) found, first at {}",
+                                        opts.bmspath, nchanged, first.message)));
+    }
+    Ok(())
+}
+
+/// Parses the chart file without sanitizing it and prints every problem found to the standard
+/// output. Returns the highest severity of problems if any.
+pub fn lint_chart(opts: &player::Options) -> AngolmoisResult<Option<parser::Severity>> {
+    let mut r = seeded_rng(random_seed(opts));
+    let bms = try!(parse_chart(opts.bmspath[], opts.compat, &mut r));
+
+    let mut worst = None;
+    for diag in bms.diagnostics.iter() {
+        println!("{}: {}", opts.bmspath, diag);
+        if worst.map_or(true, |worst| worst < diag.severity) { worst = Some(diag.severity); }
+    }
+    for issue in lint::lint_bms(&bms, opts).iter() {
+        println!("{}: {}", opts.bmspath, issue);
+        if worst.map_or(true, |worst| worst < issue.severity) { worst = Some(issue.severity); }
+    }
+    Ok(worst)
+}
+
+/// Chart metadata printed by `info_json`.
+#[deriving(Encodable)]
+struct ChartInfo {
+    path: String, title: Option<String>, artist: Option<String>, genre: Option<String>,
+    level: int, initbpm: f64, minbpm: f64, maxbpm: f64, nnotes: int, duration: f64,
+    md5: Option<String>,
+}
+
+/// Parses and analyzes the chart file, and returns its metadata as JSON. The duration does not
+/// account for the length of sounds, as no sound is loaded.
+pub fn info_json(opts: &player::Options) -> AngolmoisResult<String> {
+    use serialize::json;
+
+    let mut r = seeded_rng(random_seed(opts));
+    let mut bms = try!(parse_chart(opts.bmspath[], opts.compat, &mut r));
+    try!(sanitize_chart(&mut bms, opts));
+    let keyspec = try!(player::key_spec(&bms, opts));
+    parser::compact_bms(&mut bms, &keyspec);
+    let infos = parser::analyze_bms(&bms);
+
+    let info = ChartInfo {
+        path: opts.bmspath.clone(), title: bms.title.clone(), artist: bms.artist.clone(),
+        genre: bms.genre.clone(), level: bms.playlevel, initbpm: *bms.initbpm,
+        minbpm: infos.minbpm, maxbpm: infos.maxbpm, nnotes: infos.nnotes,
+        duration: infos.duration,
+        md5: infos.md5.clone(),
+    };
+    Ok(json::encode(&info))
+}
+
+/// The maximum number of #RANDOM branch combinations tried by `analyze_random_branches`.
+const MAX_RANDOM_BRANCHES: uint = 1024;
+
+/// Same as `parse_chart_with`, but `gen` also receives the index of #RANDOM command being
+/// evaluated, and the values chosen are returned along with their maximums.
+fn parse_chart_recording(path: &str, profile: parser::CompatProfile, gen: |uint, int| -> int)
+                        -> AngolmoisResult<(parser::Bms, Vec<(int,int)>)> {
+    let mut chosen = Vec::new();
+    let bms = try!(parse_chart_with(path, profile, |max| {
+        let val = gen(chosen.len(), max);
+        chosen.push((val, max));
+        val
+    }));
+    Ok((bms, chosen))
+}
+
+/// Prints the statistics of the chart resulted from given values chosen for #RANDOM commands.
+/// Returns false if the chart looks broken, i.e. it has no notes or cannot be played at all.
+fn report_random_branch(opts: &player::Options, mut bms: parser::Bms,
+                        chosen: &[(int,int)]) -> bool {
+    let label = if chosen.is_empty() {
+        "no #RANDOM".to_string()
+    } else {
+        let values: Vec<String> = chosen.iter().map(|&(val, max)| format!("{}/{}", val, max))
+                                               .collect();
+        format!("#RANDOM {}", values.connect(" "))
+    };
+
+    parser::sanitize_bms(&mut bms, parser::FixConflicts);
+    let keyspec = match player::key_spec(&bms, opts) {
+        Ok(keyspec) => keyspec,
+        Err(err) => {
+            println!("{}: {}: {}", opts.bmspath, label, err);
+            return false;
+        }
+    };
+    parser::compact_bms(&mut bms, &keyspec);
+    let infos = parser::analyze_bms(&bms);
+
+    let duration = infos.duration as uint;
+    let average = if infos.duration > 0.0 {infos.nnotes as f64 / infos.duration} else {0.0};
+    let peak = infos.density.iter().max().map_or(0, |&n| n);
+    println!("{}: {}: {} notes, {}:{:02}, {:.2} notes/s on average, {} notes at most per measure{}",
+             opts.bmspath, label, infos.nnotes, duration / 60, duration % 60, average, peak,
+             if infos.nnotes == 0 {" (no notes!)"} else {""});
+    infos.nnotes > 0
+}
+
+/**
+ * Parses the chart file for every combination of values chosen for #RANDOM commands, and prints
+ * the number of notes and the note density of each resulting chart. Nested #RANDOM commands are
+ * only enumerated in the branches reaching them. Returns the number of branches which look broken
+ * (see `report_random_branch`).
+ *
+ * If `nsamples` is given, the given number of combinations are randomly chosen instead, and the
+ * same combination is reported only once. The enumeration stops after `MAX_RANDOM_BRANCHES`
+ * combinations.
+ */
+pub fn analyze_random_branches(opts: &player::Options,
+                               nsamples: Option<uint>) -> AngolmoisResult<uint> {
+    use std::rand::Rng;
+    use std::collections::HashSet;
+    use std::cmp::min;
+
+    let mut nbroken = 0;
+    match nsamples {
+        Some(nsamples) => {
+            let mut r = seeded_rng(random_seed(opts));
+            let mut seen = HashSet::new();
+            for _ in range(0, nsamples) {
+                let (bms, chosen) =
+                    try!(parse_chart_recording(opts.bmspath[], opts.compat,
+                                               |_, max| r.gen_range(1, max + 1)));
+                if seen.contains(&chosen) { continue; }
+                if !report_random_branch(opts, bms, chosen[]) { nbroken += 1; }
+                seen.insert(chosen);
+            }
+        }
+
+        None => {
+            // every combination is visited in the lexicographical order. `prefix` is the values
+            // to be chosen first, and 1 is chosen afterwards.
+            let mut prefix: Vec<int> = Vec::new();
+            let mut nbranches = 0u;
+            loop {
+                let (bms, chosen) = try!(parse_chart_recording(
+                    opts.bmspath[], opts.compat,
+                    |i, max| if i < prefix.len() {min(prefix[i], max)} else {1}));
+                if !report_random_branch(opts, bms, chosen[]) { nbroken += 1; }
+                nbranches += 1;
+
+                // advances the last value which has not reached the maximum
+                match chosen.iter().rposition(|&(val, max)| val < max) {
+                    Some(i) => {
+                        prefix = chosen[..i].iter().map(|&(val, _)| val).collect();
+                        let (val, _) = chosen[i];
+                        prefix.push(val + 1);
+                    }
+                    None => { break; }
+                }
+                if nbranches >= MAX_RANDOM_BRANCHES {
+                    println!("{}: stopped after {} combinations, try sampling them instead",
+                             opts.bmspath, nbranches);
+                    break;
+                }
+            }
+        }
+    }
+    Ok(nbroken)
+}
+
+/// Parses the chart file and renders its sounds to the WAV file at `path` without the screen.
+/// See `player::render_wav` for details.
+pub fn render_chart(opts: &player::Options, path: &str) -> AngolmoisResult<()> {
+    use std::io;
+
+    // SDL_mixer is still required for decoding sounds, but nothing has to be heard
+    std::os::setenv("SDL_AUDIODRIVER", "dummy");
+    try!(player::init_audio(opts.samplerate, opts.audiobuffer));
+
+    let mut r = seeded_rng(random_seed(opts));
+    let mut bms = try!(parse_chart(opts.bmspath[], opts.compat, &mut r));
+    try!(sanitize_chart(&mut bms, opts));
+    if opts.rate != 1.0 { parser::apply_rate(&mut bms, opts.rate); }
+    let keyspec = try!(player::key_spec(&bms, opts));
+    parser::compact_bms(&mut bms, &keyspec);
+    let infos = parser::analyze_bms(&bms);
+    let sndres = try!(player::load_sound_resource(&bms, opts, |_, _| Ok(())));
+
+    let ret = io::File::create(&Path::new(path)).and_then(|f| {
+        player::render_wav(&bms, &infos, sndres[], &mut io::BufferedWriter::new(f))
+    });
+    ret.map_err(|err| IoFailure(path.to_string(), err))
+}
+
+/// The duration of each simulated tick in the benchmark, in milliseconds.
+const BENCHMARK_TICK: uint = 1;
+
+/**
+ * Parses the chart and runs the automatic game play with a simulated clock as fast as possible,
+ * without the display, sounds and input, so that performance regressions can be measured.
+ *
+ * The result is printed as a single line of space-separated `key=value` pairs, which can be
+ * compared between runs: the wall time taken by parsing (`parse_ms`) and the game play
+ * (`play_ms`), the number of ticks (`ticks`), the simulated duration (`simulated_ms`),
+ * the number of graded notes (`graded`), the number of bytes allocated at the end (`allocated`),
+ * the number of allocations (`allocs`) and its breakdown by size classes (`allocs_by_size`,
+ * a comma-separated list of `size:count` for non-empty small size classes followed by `large`
+ * and `huge` classes), and the peak resident set size of the process (`peak_rss`, in bytes).
+ * The allocator statistics are for the whole process, and any unavailable value is `unknown`.
+ */
+pub fn benchmark(opts: &player::Options) -> AngolmoisResult<()> {
+    use std::collections::HashMap;
+
+    // only the timer is initialized
+    if !sdl::init([]) {
+        return Err(SdlFailure(format!("SDL Initialization Failure: {}", sdl::get_error())));
+    }
+    let mut opts = opts.clone();
+    opts.mode = player::AutoPlayMode;
+
+    let start = sdl::get_ticks();
+    let mut r = seeded_rng(random_seed(&opts));
+    let mut bms = try!(parse_chart(opts.bmspath[], opts.compat, &mut r));
+    try!(sanitize_chart(&mut bms, &opts));
+    if opts.rate != 1.0 { parser::apply_rate(&mut bms, opts.rate); }
+    let keyspec = try!(player::key_spec(&bms, &opts));
+    parser::compact_bms(&mut bms, &keyspec);
+    let infos = parser::analyze_bms(&bms);
+    let parsed = sdl::get_ticks();
+
+    let duration = parser::bms_duration(&bms, infos.originoffset, |_| 0.0);
+    let sndres = Vec::from_fn(parser::MAXKEY as uint, |_| player::NoSound);
+    let clock = player::ManualClock::new(parsed);
+    let mut player = player::Player::new(opts, bms, infos, duration, keyspec,
+                                         HashMap::new(), sndres, box clock.clone(),
+                                         box player::EventQueue::new());
+    let mut nticks = 0u;
+    while player.update() {
+        clock.advance(BENCHMARK_TICK);
+        nticks += 1;
+    }
+    let played = sdl::get_ticks();
+
+    let (allocated, allocs, allocsbysize) = match util::heap_stats() {
+        Some(stats) => {
+            let mut classes: Vec<String> = stats.small.iter().filter(|&&(_, count)| count > 0)
+                                                .map(|&(size, count)| format!("{}:{}", size, count))
+                                                .collect();
+            classes.push(format!("large:{}", stats.large));
+            classes.push(format!("huge:{}", stats.huge));
+            let allocs = stats.small.iter().fold(stats.large + stats.huge, |a, &(_, b)| a + b);
+            (stats.allocated.to_string(), allocs.to_string(), classes.connect(","))
+        }
+        None => ("unknown".to_string(), "unknown".to_string(), "unknown".to_string())
+    };
+    let peakrss = util::peak_rss().map_or("unknown".to_string(), |rss| rss.to_string());
+    println!("parse_ms={} play_ms={} ticks={} simulated_ms={} graded={} allocated={} allocs={} \
+              allocs_by_size={} peak_rss={}",
+             parsed - start, played - parsed, nticks, player.now - player.origintime,
+             player.gradecounts.iter().fold(0, |a, &b| a + b), allocated, allocs, allocsbysize,
+             peakrss);
+    Ok(())
+}
+
+/// Reads the replay file given by `opts.replay` if any, and checks if it is recorded from
+/// the chart.
+fn read_playback(opts: &player::Options) -> AngolmoisResult<Option<replay::Replay>> {
+    match opts.replay {
+        Some(ref path) => {
+            let replay = try!(replay::read_replay_file(path[]));
+            if !try!(replay::chart_hash(opts.bmspath[])).matches(replay.hash[]) {
+                return Err(InvalidReplay(format!("{} is not recorded from {}",
+                                                 path, opts.bmspath)));
+            }
+            Ok(Some(replay))
+        }
+        None => Ok(None)
+    }
+}
+
+/**
+ * Parses the chart file and writes it to the BMS file at `path` as actually generated, i.e. with
+ * every #RANDOM block resolved and no sanitization. The seed is taken from the replay given by
+ * `opts.replay` if any, so that the chart played in the replay can be inspected, and written
+ * as a comment for reproducing the same chart with `--seed`. See `parser::write_bms` for details.
+ */
+pub fn dump_resolved_chart(opts: &player::Options, path: &str) -> AngolmoisResult<()> {
+    use std::io;
+
+    let (seed, compat) = match try!(read_playback(opts)) {
+        Some(replay) => (replay.seed, replay.compat),
+        None => (random_seed(opts), opts.compat)
+    };
+    let mut r = seeded_rng(seed);
+    let bms = try!(parse_chart(opts.bmspath[], compat, &mut r));
+
+    let ret = io::File::create(&Path::new(path)).and_then(|mut f| {
+        try!(writeln!(&mut f, "; resolved from {} with the seed {}", opts.bmspath, seed));
+        parser::write_bms(&bms, &mut f)
+    });
+    ret.map_err(|err| IoFailure(path.to_string(), err))
+}
+
+/// Reads the course definition file, which lists paths to the charts one per line. Paths are
+/// relative to the course file, and empty lines and lines starting with `#` are ignored.
+pub fn read_course_file(path: &str) -> AngolmoisResult<Vec<String>> {
+    use std::io;
+    let basedir = Path::new(path).dir_path();
+    let ret = io::File::open(&Path::new(path)).and_then(|f| {
+        let mut paths = Vec::new();
+        for line in io::BufferedReader::new(f).lines() {
+            let line = try!(line);
+            let line = line[].trim();
+            if line.is_empty() || line.starts_with("#") { continue; }
+            paths.push(basedir.join(line).display().to_string());
+        }
+        Ok(paths)
+    });
+    ret.map_err(|err| IoFailure(path.to_string(), err))
+}
+
+/// Parses the BMS file, initializes the display, shows the loading screen and runs the game play
+/// loop. Returns the outcome of the last chart played, or `Interrupted` if the user quits before
+/// the game play. (C: `play`)
+///
+/// In the course mode every chart is played in order until the player fails, and then
+/// the combined result is shown.
+pub fn play(opts: player::Options) -> AngolmoisResult<player::PlayOutcome> {
+    if opts.course.is_empty() {
+        // a chart dropped onto the result screen is played next with the same options, except
+        // for replays which are tied to the original chart
+        let mut opts = opts;
+        loop {
+            let (outcome, _) = try!(play_stage(opts.clone(), None));
+            match util::poll_dropped_file() {
+                Some(path) => {
+                    opts.bmspath = path;
+                    opts.replay = None;
+                    opts.ghost = None;
+                    opts.recordreplay = None;
+                }
+                None => { return Ok(outcome); }
+            }
+        }
+    }
+
+    // the gauge is carried over, so the last chart decides whether the course is cleared
+    let mut paths = vec![opts.bmspath.clone()];
+    paths.push_all(opts.course[]);
+    let mut course = player::CourseState::new(paths.len());
+    let mut outcome = player::Aborted;
+    for path in paths.into_iter() {
+        let mut stageopts = opts.clone();
+        stageopts.bmspath = path;
+        let (stageoutcome, proceed) = try!(play_stage(stageopts, Some(&mut course)));
+        outcome = stageoutcome;
+        if !proceed { break; }
+    }
+    Ok(outcome)
+}
+
+/// Plays a single chart. In the course mode the gauge and combo are carried over from `course`,
+/// which receives the result instead of the display. Returns the outcome of the chart, which is
+/// always `Cleared` for the export and the preview, and whether the course should proceed to
+/// the next chart.
+fn play_stage(mut opts: player::Options, course: Option<&mut player::CourseState>)
+              -> AngolmoisResult<(player::PlayOutcome, bool)> {
+    use std::collections::HashMap;
+    use sdl::get_ticks;
+    use sdl::video::Surface;
+
+    // reads the replays if any. the replay being played back should be recorded from the same
+    // chart, and determines the random seed, modifier and compatibility profile.
+    let playback = try!(read_playback(&opts));
+    for replay in playback.iter() { opts.compat = replay.compat; }
+    let mut ghost = None;
+    for path in opts.ghost.iter() {
+        ghost = Some(try!(replay::read_replay_file(path[])));
+    }
+
+    // adds the per-chart offset, which is saved first if given.
+    let hash = if opts.exportbmson.is_none() {
+        Some(try!(replay::chart_hash(opts.bmspath[])))
+    } else {
+        None
+    };
+    for hash in hash.iter() {
+        for &offset in opts.chartoffset.iter() { try!(player::write_chart_offset(hash, offset)); }
+        opts.offset += opts.chartoffset.or_else(|| player::read_chart_offset(hash)).unwrap_or(0.0);
+    }
+
+    // restores the play speed and cover last used for the chart unless given explicitly.
+    let settings = hash.as_ref().and_then(|hash| player::read_chart_settings(hash, opts.compat));
+    for &(playspeed, cover) in settings.iter() {
+        if opts.restorespeed { opts.playspeed = playspeed; }
+        if opts.restorecover { opts.cover = cover; }
+    }
+    let record = hash.as_ref().and_then(|hash| player::read_score_record(hash, opts.compat));
+
+    // parses the file and sanitizes it. the seed is kept for the replay.
+    let seed = match playback {
+        Some(ref replay) => { opts.modf = replay.modf; opts.flip = replay.flip; replay.seed }
+        None => random_seed(&opts)
+    };
+    let mut r = seeded_rng(seed);
+    let mut bms = try!(parse_chart(opts.bmspath[], opts.compat, &mut r));
+
+    // exports the chart instead of playing if requested
+    match opts.exportbmson {
+        Some(ref outpath) => {
+            use std::ascii::AsciiExt;
+            if !opts.sanitize {
+                // exported as is
+            } else if opts.sanitizepolicy == parser::ReportOnly {
+                parser::sanitize_bms(&mut bms, parser::ReportOnly);
+                for diag in bms.diagnostics.iter().filter(|diag| diag.line == 0) {
+                    warn!("{}: {}", opts.bmspath, diag);
+                }
+            } else {
+                try!(sanitize_chart(&mut bms, &opts));
+            }
+            let pms = opts.bmspath[].to_ascii_lower()[].ends_with(".pms");
+            try!(bmson::write_bmson_file(&bms, pms, outpath[]));
+            return Ok((player::Cleared, true));
+        }
+        None => {}
+    }
+
+    // duplicates the chart to both sides if requested, which requires further sanitization
+    if opts.battle && !parser::apply_battle_modf(&mut bms) {
+        warn!("the battle mode requires a single play chart, ignored");
+    }
+
+    try!(sanitize_chart(&mut bms, &opts));
+
+    // slows down or speeds up the chart if requested. sounds are stretched while loading.
+    if opts.rate != 1.0 { parser::apply_rate(&mut bms, opts.rate); }
+
+    // parses the key specification and further sanitizes `bms` with it
+    let keyspec = try!(player::key_spec(&bms, &opts));
+    parser::compact_bms(&mut bms, &keyspec);
+    let infos = parser::analyze_bms(&bms);
+
+    // swaps both sides of the double play chart if requested, prior to other modifiers
+    if opts.flip {
+        if bms.player != parser::DOUBLE_PLAY ||
+                !parser::apply_flip_modf(&mut bms, &keyspec) {
+            warn!("the flip modifier requires a double play chart with matching sides, ignored");
+        }
+    }
+
+    // applies the modifier if any
+    for &modf in opts.modf.iter() {
+        player::apply_modf(&mut bms, modf, &mut r, &keyspec, 0, keyspec.split);
+        if keyspec.split < keyspec.order.len() {
+            player::apply_modf(&mut bms, modf, &mut r, &keyspec,
+                               keyspec.split, keyspec.order.len());
+        }
+    }
+
+    // initialize SDL
+    try!(player::init_audio(opts.samplerate, opts.audiobuffer));
+    mixer::set_polyphony(opts.polyphony, opts.voicesteal.clone());
+    // joysticks are closed when dropped, so they are kept until the end of the game play
+    let mut joysticks = Vec::new();
+    for &joyidx in opts.joystick.iter() { joysticks.push(try!(player::init_joystick(joyidx))); }
+
+    // uncompress and populate the bitmap font.
+    let mut font = gfx::Font::new();
+    font.create_zoomed_font(1);
+    font.create_zoomed_font(2);
+    let font = font;
+
+    // initialize the screen if required
+    let mut screen = None;
+    let mut presenter = None;
+    let keymap;
+    if opts.has_screen() {
+        let (screen_, presenter_) = try!(player::init_video(opts.is_exclusive(), opts.fullscreen,
+                                                            opts.opengl));
+        screen = Some(screen_);
+        presenter = presenter_;
+        // read the input mapping (dependent to the SDL initialization)
+        let config = try!(config::read_config());
+        keymap = try!(player::read_keymap(&keyspec, |name| config.getenv(name)));
+    } else {
+        keymap = HashMap::new();
+    }
+
+    // XXX we don't really need the environment here
+    fn update_line() { player::update_line("") }
+    fn noop() {}
+    let atexit = if opts.is_exclusive() {update_line} else {noop};
+
+    // the preview clip, if any, replaces the entire game play
+    if opts.preview && try!(player::play_preview_clip(&bms, &opts, || atexit())) {
+        sdl_mixer::allocate_channels(0);
+        atexit();
+        return Ok((player::Cleared, true));
+    }
+
+    // the suggested play speed is accepted with the return key during the loading screen
+    let suggested = if opts.is_exclusive() {None}
+                    else {player::suggested_playspeed(&bms, &infos, &opts)};
+    let mut accepted = false;
+
+    let (sndres, imgres, background, missing) = {
+        // render the loading screen
+        let ticker = std::cell::RefCell::new(player::Ticker::new());
+        let mut saved_screen = None; // XXX should be in a trait actually
+        let _ = saved_screen; // Rust: avoids incorrect warning. (#3796)
+        let update_status;
+        if !opts.is_exclusive() {
+            let screen_: &Surface = screen.as_ref().unwrap();
+            player::show_stagefile_screen(&bms, &infos, &keyspec, &opts, record.as_ref(),
+                                          suggested, screen_, presenter.as_ref(), &font);
+            if opts.showinfo {
+                saved_screen = Some(try!(player::save_screen_for_loading(screen_)));
+                update_status = |path, progress| {
+                    let screen: &Surface = screen.as_ref().unwrap();
+                    let saved_screen: &Surface = saved_screen.as_ref().unwrap();
+                    player::graphic_update_status(path, progress, screen, presenter.as_ref(),
+                                                  saved_screen, &font,
+                                                  ticker.borrow_mut().deref_mut(), || atexit())
+                };
+            } else {
+                update_status = |_path, _progress| player::check_exit_or_enter(|| atexit());
+            }
+        } else if opts.showinfo {
+            player::show_stagefile_noscreen(&bms, &infos, &keyspec, &opts, record.as_ref());
+            update_status = |path, _progress| {
+                let ret = player::text_update_status(path, ticker.borrow_mut().deref_mut(),
+                                                     || atexit());
+                ret.map(|_| false)
+            };
+        } else {
+            update_status = |_path, _progress| Ok(false);
+        }
+
+        // wait for resources (the preview starts immediately)
+        let start = get_ticks() + if opts.preview {0} else {3000};
+        let loaded = try!(player::load_resource(&bms, &opts, |path, (done, total)| {
+            let path = path.map(|path| format!("[{}/{}] {}", done, total, path));
+            if try!(update_status(path, Some((done, total)))) { accepted = true; }
+            Ok(())
+        }));
+        if opts.showinfo {
+            ticker.borrow_mut().reset(); // force update
+            if try!(update_status(None, None)) { accepted = true; }
+        }
+        while get_ticks() < start {
+            if try!(player::check_exit_or_enter(|| atexit())) { accepted = true; }
+        }
+
+        loaded
+    };
+    if accepted {
+        for &speed in suggested.iter() { opts.playspeed = speed; }
+    }
+
+    // create the player and transfer ownership of other resources to it
+    let duration = parser::bms_duration(&bms, infos.originoffset,
+                                        |sref| sndres[**sref as uint].duration());
+    let previewend = infos.originoffset + player::PREVIEW_MEASURES as f64;
+    let mut player = player::Player::new(opts, bms, infos, duration, keyspec, keymap, sndres,
+                                         box player::SdlClock, box player::SdlEventSource);
+    player.playback = playback;
+    player.background = background;
+    player.nmissing = missing.len();
+    player.ghost = ghost;
+    player.record = record;
+    for course in course.iter() { course.start(&mut player); }
+
+    // create the display and runs the actual game play loop
+    let mut display = match screen {
+        Some(screen) => {
+            if player.opts.is_exclusive() {
+                box player::BGAOnlyDisplay::new(screen, presenter, imgres) as Box<player::Display>
+            } else {
+                let display = try!(player::GraphicDisplay::new(&player.opts, &*player.bms,
+                                                               &player.keyspec, screen,
+                                                               presenter, font, imgres));
+                box display as Box<player::Display>
+            }
+        },
+        None if player.opts.terminal => box player::TerminalDisplay::new() as Box<player::Display>,
+        None => box player::TextDisplay::new() as Box<player::Display>
+    };
+
+    // the game play is updated every `UPDATE_INTERVAL` and a frame is rendered only when it is
+    // due, so that the inputs are graded promptly even when the rendering is slow or the frame
+    // rate is low. the next update or frame is rescheduled from now when lagging behind.
+    // XXX SDL 1.2 events have no timestamps, so inputs arriving during the rendering itself are
+    // still graded at the next update.
+    let frametime = 1000 / player.opts.fps.unwrap_or(player::DEFAULT_FPS);
+    let mut nextupdate = get_ticks();
+    let mut nextframe = nextupdate;
+    loop {
+        let now = get_ticks();
+        if now < nextupdate {
+            util::delay(nextupdate - now);
+            continue;
+        }
+        if !player.update() { break; }
+        if player.opts.preview && player.bottom >= previewend { break; }
+        nextupdate += player::UPDATE_INTERVAL;
+        if nextupdate < now { nextupdate = now; }
+
+        if get_ticks() >= nextframe {
+            player.prepare_frame();
+            display.render(&player);
+            player.screenshot = false;
+
+            nextframe += frametime;
+            let now = get_ticks();
+            if nextframe < now { nextframe = now; }
+        }
+    }
+    // saves the replay if requested right after the game play, before the score record and
+    // other outputs which may fail
+    for path in player.opts.recordreplay.iter() {
+        let replay = replay::Replay {
+            hash: try!(replay::chart_hash(player.opts.bmspath[])).md5, seed: seed,
+            modf: player.opts.modf, flip: player.opts.flip, compat: player.opts.compat,
+            events: player.replay.clone(),
+            score: player.score, gradecounts: player.gradecounts,
+        };
+        try!(replay::write_replay_file(&replay, path[]));
+    }
+
+    // plays at the different rate are for the practice and never recorded. the instant death
+    // also counts as finished.
+    let recordable = player.is_finished() && !player.opts.is_autoplay() &&
+                     player.playback.is_none() && player.opts.rate == 1.0;
+
+    // submits the result to the internet ranking if configured, in the background while
+    // the result is shown. the failure is not fatal.
+    let mut submissions = Vec::new();
+    if recordable {
+        for url in player.opts.ir.iter() {
+            for hash in hash.iter() {
+                let submission = ir::Submission::new(hash, &player);
+                submissions.push(ir::submit_in_background(url.clone(), submission));
+            }
+        }
+    }
+
+    let mut proceed = player.is_finished();
+    match course {
+        Some(course) => {
+            // the combined result is shown after the last chart or the failed chart
+            proceed = course.finish(&player);
+            if !proceed || course.is_finished() {
+                display.show_course_result(&player, &*course);
+            }
+        }
+        None => { display.show_result(&player); }
+    }
+    let outcome = player.outcome();
+
+    // updates the local score database
+    if recordable {
+        for hash in hash.iter() { try!(player::update_score_record(hash, &player)); }
+    }
+
+    // saves the play speed and cover for the next play of the same chart. the play speed
+    // derived from the green number is not saved, as it changes with BPM. the failure is
+    // not fatal, as they are only for the convenience.
+    if !player.opts.is_autoplay() && player.playback.is_none() && !player.opts.preview {
+        let playspeed = if player.greennumber.is_some() {player.opts.playspeed}
+                        else {player.nominal_playspeed()};
+        for hash in hash.iter() {
+            match player::write_chart_settings(hash, player.opts.compat, playspeed,
+                                               player.cover) {
+                Ok(()) => {}
+                Err(err) => warn!("failed to save the chart settings: {}", err)
+            }
+        }
+    }
+
+    // waits for the IR submissions, which are usually done while the result is shown
+    for rx in submissions.iter() {
+        match rx.recv_opt() {
+            Ok(Err(err)) => warn!("failed to submit the result to the IR: {}", err),
+            Ok(Ok(())) | Err(()) => {}
+        }
+    }
+
+    // renders the audio for the exported video, as the game play does not record the sound
+    for dir in player.opts.exportvideo.iter() {
+        let path = Path::new(dir[]).join("audio.wav");
+        let ret = std::io::File::create(&path).and_then(|f| {
+            let mut f = std::io::BufferedWriter::new(f);
+            player::render_wav(&*player.bms, &player.infos, player.sndres[], &mut f)
+        });
+        try!(ret.map_err(|err| IoFailure(path.display().to_string(), err)));
+    }
+
+    // saves the score and gauge graph if requested
+    for path in player.opts.historycsv.iter() {
+        try!(player.write_history_csv(path[]));
+    }
+
+    // saves the result for scripts if requested. the course overwrites it for each chart.
+    for path in player.opts.resultjson.iter() {
+        try!(player.write_result_json(path[]));
+    }
+
+    // it's done!
+    atexit();
+    Ok((outcome, proceed))
+}
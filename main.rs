@@ -0,0 +1,1216 @@
+/*
+ * Angolmois -- the simple BMS player
+ * Copyright (c) 2005, 2007, 2009, 2012, 2013, 2014, Kang Seonghoon.
+ * Project Angolmois is copyright (c) 2003-2007, Choi Kaya (CHKY).
+ * 
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ * 
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ * 
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 59 Temple Place - Suite 330, Boston, MA  02111-1307, USA.
+ */
+
+//! The command-line interface of Angolmois. Everything but the option parsing is in the library
+//! crate (`lib.rs`); see its documentation for the overview.
+
+#![feature(phase)]
+#![feature(macro_rules)]
+
+// XXX temporarily cope with the nightly
+#![allow(unknown_features)]
+#![feature(slicing_syntax)]
+
+#[phase(plugin, link)]
+extern crate angolmois;
+
+// `die!` and `warn!` refer to `::util`.
+use angolmois::{util, parser, player, mixer, config, zip, version, exename, play, lint_chart,
+                info_json, render_chart, dump_resolved_chart, analyze_random_branches, benchmark,
+                select_chart, read_course_file, calibrate, calibrate_axes, Interrupted};
+
+/// Prints the usage. Prints to the standard output and exits successfully if the usage was
+/// requested by `--help`, or prints to the standard error and fails otherwise. (C: `usage`)
+pub fn usage(requested: bool) -> ! {
+    let mut out = if requested {std::io::stdout()} else {std::io::stderr()};
+    let _ = write!(&mut out, "\
+{} -- the simple BMS player
+http://mearie.org/projects/angolmois/
+https://github.com/lifthrasiir/angolmois-rust/
+
+Usage: {} [<command>] <options> <path> [<path> ...]
+  Accepts any BMS, BME, BML or PMS file, osu!mania beatmap (OSU) or O2Jam chart (OJN).
+  Resources should be in the same directory as the BMS file.
+  Charts can be read from ZIP archives as well, e.g. `foo.zip` (the first chart
+  in the archive) or `foo.zip/bar/baz.bms`.
+  Multiple paths are played in order as a course, carrying over the gauge.
+  Shows a song selection screen for the song library (see --library) or a file
+  dialog if the path is missing.
+  In Windows, a chart dropped onto the window is played after the current one
+  (dropping files is not supported in other systems).
+
+Commands:
+  play <path> [<path> ...]
+                          Plays the chart(s) (default)
+  info <path>             Prints the song information as JSON, same as -I
+  lint <path>             Reports problems in the chart, same as -L
+  render <path> <wav>     Renders the sounds of the chart to the WAV file, same as
+                          --render
+  export <path> <bmson>   Exports the chart as bmson, same as -E
+  dump <path> <bms>       Writes the chart with #RANDOM resolved to the BMS file,
+                          same as --dump-resolved
+  branches <path>         Reports the notes of every #RANDOM branch, same as
+                          --analyze-random
+  benchmark <path>        Measures the parsing and game play speed, same as
+                          --benchmark
+  Options not used by the command are rejected, except for those from
+  the configuration file.
+
+Options:
+  -h, --help              This help
+  -V, --version           Shows the version
+  --OPTION=ARG            Same as '--OPTION ARG' for any option with an argument
+  -a X.X, --speed X.X     Sets the initial play speed (default: the last one used
+                          for the chart, or 1.0x)
+  -1, .., -9              Same as '-a 1.0', .., '-a 9.0'
+  --rate X.X              Plays the chart and sounds X.X times as fast without
+                          changing the pitch, between 0.5 and 2.0 (default: 1.0);
+                          such plays are not recorded
+  -n MS, --green MS       Derives the play speed from the current BPM so that notes
+                          stay on the screen for MS milliseconds
+  --suggest-speed MS      Suggests the play speed where notes stay on the screen for
+                          MS milliseconds with the main BPM of the chart, which can
+                          be accepted with Enter during the loading screen
+  -W PX, --cover PX       Covers the top of the note area by PX pixels (default:
+                          the last one used for the chart, or 0)
+  -H PX, --lift PX        Raises the judgement line by PX pixels
+  -u N, --sudden N        Fades out objects in the top N% of the note area
+  -i N, --hidden N        Fades out objects in the bottom N% of the note area
+  -Q, --constant          Scrolls objects at a constant rate ignoring BPM changes
+  -v, --autoplay          Enables AUTO PLAY (viewer) mode
+  -x, --exclusive         Enables exclusive (BGA and sound only) mode
+  -X, --sound-only        Enables sound only mode, equivalent to -xB
+  --terminal              Same as above but draws lanes and notes in the terminal
+  --fullscreen            Enables the fullscreen mode (default)
+  -w, --no-fullscreen     Disables the fullscreen mode
+  --opengl                Shows the screen through OpenGL 1.2 with the vertical
+                          sync, drawing notes and BGAs with the GPU
+  --fps N                 Limits the frame rate to N frames per second
+                          (default: 120)
+  --show-fps              Shows the frame rate and the frame time
+  --sample-rate HZ        Mixes sounds at HZ samples per second (default: 44100)
+  --audio-buffer N        Uses the audio buffer of N samples, a power of two (default:
+                          2048); smaller buffers reduce the latency
+  --audio-device DEV      Plays sounds to the output device DEV (passed to SDL as
+                          AUDIODEV, e.g. 'hw:1,0' for ALSA)
+  --key-volume N          Plays key sounds at the volume N out of 128 (default: 128)
+  --bgm-volume N          Plays BGMs at the volume N out of 128 (default: 96)
+  --movie-volume N        Plays audio tracks of BGA movies at the volume N out of
+                          128 (default: not played)
+  --polyphony N           Plays at most N BGMs and key sounds at once (default: 64)
+  --voice-steal POLICY    Stops the 'oldest' (default) or 'quietest' sound when
+                          the polyphony limit is reached
+  --info                  Shows a brief information about the song (default)
+  -q, --no-info           Do not show an information about the song
+  -m, --mirror            Uses a mirror modifier
+  -s, --shuffle           Uses a shuffle modifier
+  -S, --shuffle-ex        Uses a shuffle modifier, even for scratches
+  -r, --random            Uses a random modifier
+  -R, --random-ex         Uses a random modifier, even for scratches
+  -e, --s-random          Uses a random modifier moving each note independently
+  -y, --h-random          Same as -e but avoids consecutive notes in the same lane
+  -f, --flip              Swaps the left and right sides of the double play chart
+  -b, --battle            Duplicates the single play chart to both sides for two players
+  -A WHEN, --assist-clap WHEN
+                          Plays a clap at each note (WHEN=note) or each hit (WHEN=hit)
+  -t, --metronome         Plays a click at each beat, louder at each measure
+  -J, --metronome-flash   Same as -t but also flashes measure bars at each measure
+  -k NAME, --preset NAME  Forces a use of given key preset (default: bms)
+  -K LEFT RIGHT, --key-spec LEFT RIGHT
+                          Sets a custom key specification (see the manual)
+  --bga                   Loads and shows the BGA (default)
+  -B, --no-bga            Do not load and show the BGA
+  -M, --no-movie          Do not load and show the BGA movie
+  --lazy-sounds           Loads sounds just before they are played, for huge charts
+  --early-start SECS      Starts playing once resources used in the first SECS seconds
+                          are loaded, and loads remaining sounds while playing
+  -d DIR, --search-dir DIR
+                          Also searches DIR for resources not found in the directory
+                          of the chart; can be repeated
+  --placeholder-sound MS  Replaces sounds failed to load with a tick of MS
+                          milliseconds (e.g. 30)
+  --bga-stretch MODE      Scales the BGA to the area beside the lanes (MODE=beside) or
+                          to the whole backdrop at the reduced opacity (MODE=backdrop)
+  --poor-bga MODE         Shows the POOR BGA after MISS in place of (MODE=replace,
+                          default) or over (MODE=overlay) the BGA, or never (MODE=off)
+  --poor-duration MS      Shows the POOR BGA for MS milliseconds (default: 600)
+  --lane-style SPEC       Overrides the color and width of lanes, e.g. 'b=#4080ff,30'
+                          for blue black keys 30 pixels wide (10 to 60); the key kind
+                          is same as in -K, and multiple SPECs can be given at once
+                          like 'a=#ffffff b=#4080ff' or by repeating the option
+  --beat-color            Colors notes by their positions in the measure: red for
+                          beats, blue for 8th, yellow for 16th and gray for others
+  -j N, --joystick N      Enable the joystick with index N (normally 0); can be
+                          repeated or given as a list like '0,1' for more devices
+  --deadzone N            Ignores joystick axes moved less than N (default: 3200)
+  --deadzone AXIS=N       Same as above but only for the axis AXIS, like 'axis 2' or
+                          'joy 1 axis 2'; overrides the calibration
+  --calibrate-axes        Measures the center and deadzone of every joystick axis
+                          (of devices given with -j) left at rest, and saves them
+  -P, --preview           Plays the #PREVIEW clip (or the first 8 measures) only
+  -E PATH, --export-bmson PATH
+                          Exports the chart as bmson to PATH instead of playing
+  --compat PROFILE        Parses BMS files like other players: 'canonical' (default),
+                          'lr2' or 'nanasi'
+  -N, --no-sanitize       Do not sanitize the chart before exporting
+  --sanitize POLICY       Treats conflicting objects by POLICY: 'fix' (default),
+                          'keep-duplicates', 'report' (exporting only) or 'strict'
+                          (fails if any)
+  --export-video DIR      Plays in AUTO PLAY mode and exports frames as PNG files and
+                          the sound as a WAV file to DIR
+  -L, --lint              Reports problems in the chart instead of playing; exits with
+                          status 1 for warnings and 2 for errors
+  -I, --info-json         Prints the song information as JSON instead of playing
+  --render PATH           Renders the sounds of the chart to the WAV file PATH instead
+                          of playing
+  --dump-resolved PATH    Writes the chart as generated for the seed (given by -z or
+                          the replay from -Y) to the BMS file PATH instead of playing
+  --analyze-random        Reports the number and density of notes for every
+                          combination of #RANDOM values instead of playing; exits
+                          with status 1 if any combination has no notes
+  --branch-samples N      Same as above but only tries N random combinations
+  --benchmark             Runs the automatic play as fast as possible without the
+                          screen and sounds, and reports the time taken, the
+                          allocations and the peak memory usage in a single line
+                          instead of playing
+  -O PATH, --record PATH  Records the game play to the replay file PATH
+  -D PATH, --graph-csv PATH
+                          Writes the score and gauge over the time to the CSV file PATH
+  --result-json PATH      Writes the grades, score, max combo and gauge over the time
+                          to the JSON file PATH
+  -Y PATH, --replay PATH  Plays back the replay file PATH instead of the actual input
+  -G PATH, --ghost PATH   Compares the score and combo against the replay file PATH
+  -p SPEC, --pacemaker SPEC
+                          Shows the EX score difference against the target SPEC: N% of
+                          the maximum, 'best' for the best record or 'ghost' for the -G
+                          replay
+  -z N, --seed N          Uses the random seed N for #RANDOM and modifiers
+  -F N, --start-measure N Starts the game play from the measure N
+  -U N, --end-measure N   Ends the game play at the measure N
+  -l, --loop-section      Rewinds to the start measure at the end measure
+  -C PATH, --course PATH  Plays charts listed in the course file PATH in order
+  --library DIR           Chooses the chart from the song library in DIR instead of
+                          the file dialog when the path is missing
+  -g KIND, --gauge KIND   Uses the gauge type KIND: easy, normal (default), hard,
+                          exhard or hazard
+  -c, --calibrate         Measures and saves the input offset with a metronome
+  -o MS, --offset MS      Uses the input offset of MS milliseconds instead of
+                          the calibrated one (positive if the input is late)
+  -T MS, --chart-offset MS
+                          Saves and uses the additional offset for this chart
+  --keysound-window MS    Plays the key sound of the closest note only when it is
+                          within MS milliseconds from the key press, or always plays
+                          it if MS is 'none' (default)
+  --ln-release-window MS  Completes LN only when released within MS milliseconds
+                          from its end (default: 144)
+  --lenient-ln            Completes LN held for the most of its duration, even when
+                          released early or held past its end
+  --ir URL                Submits the result to the internet ranking at URL
+  --no-ir                 Do not submit the result to the internet ranking
+
+Environment Variables:
+  ANGOLMOIS_1P_KEYS=<scratch>|<key 1>|<2>|<3>|<4>|<5>|<6>|<7>|<pedal>
+  ANGOLMOIS_2P_KEYS=<pedal>|<key 1>|<2>|<3>|<4>|<5>|<6>|<7>|<scratch>
+  ANGOLMOIS_PMS_KEYS=<key 1>|<2>|<3>|<4>|<5>|<6>|<7>|<8>|<9>
+  ANGOLMOIS_O2JAM_KEYS=<key 1>|<2>|<3>|<4>|<5>|<6>|<7>
+  ANGOLMOIS_SPEED_KEYS=<speed down>|<speed up>
+  ANGOLMOIS_LOOP_KEYS=<loop start>|<loop end>
+  ANGOLMOIS_RETRY_KEY=<retry>
+  ANGOLMOIS_COVER_KEYS=<cover up>|<cover down>|<lift up>|<lift down>
+  ANGOLMOIS_SCREENSHOT_KEY=<screenshot>
+  ANGOLMOIS_XXy_KEY=<keys for channel XX and channel kind y>
+    Sets keys used for game play. Use either SDL key names or joystick names
+    like 'button N' or 'axis N' can be used; 'turntable N' is an axis of
+    the analog turntable, which responds to the direction of spin. Joystick
+    names refer to any opened device unless prefixed like 'joy 1 button N'.
+    Separate multiple keys by '%'.
+    See the manual for more information.
+  ANGOLMOIS_IR_URL=<url>
+    Sets the internet ranking endpoint used when --ir is not given.
+  ANGOLMOIS_FONT=<path>
+    Sets the TrueType font used for non-ASCII titles and messages. Some
+    well-known system fonts are used if not set. Only used when built with
+    the `ttf` feature.
+
+Exit status:
+  The game play exits with 0 if the chart is cleared, 2 if failed, 3 if aborted
+  by the user, and 1 on errors.
+
+Configuration:
+  ~/.config/angolmois/config.toml sets default options in the [options] section
+  (e.g. 'speed = 2.0' for '--speed 2.0', 'fullscreen = false' for
+  '--no-fullscreen') and keys in the [keys] section (e.g. '1p_keys = \"...\"' for
+  ANGOLMOIS_1P_KEYS). Command line options override the configuration, and
+  environment variables are used for keys missing in the configuration. Options
+  in the configuration never select the command.
+
+", version(), exename());
+    util::exit(if requested {0} else {1})
+}
+
+/// Exits with an error message about the wrong command line, which also points to the usage.
+macro_rules! usage_error(
+    ($($e:expr),+) => (
+        die!("{}\nTry `{} --help` for more information.", format!($($e),+), exename())
+    )
+)
+
+/// A command given as the first argument. The command is optional and defaults to `PlayCommand`,
+/// so the legacy invocation with paths only keeps working.
+#[deriving(PartialEq,Eq,Clone)]
+enum Command {
+    /// Plays the chart(s), possibly as a course.
+    PlayCommand,
+    /// Prints the song information as JSON. Same as `--info-json`.
+    InfoCommand,
+    /// Reports problems in the chart. Same as `--lint`.
+    LintCommand,
+    /// Renders the sounds of the chart to the WAV file. Same as `--render`.
+    RenderCommand,
+    /// Exports the chart as bmson. Same as `--export-bmson`.
+    ExportCommand,
+    /// Writes the chart with #RANDOM blocks resolved. Same as `--dump-resolved`.
+    DumpCommand,
+    /// Reports every #RANDOM branch of the chart. Same as `--analyze-random`.
+    BranchesCommand,
+    /// Measures the parsing and game play speed. Same as `--benchmark`.
+    BenchmarkCommand,
+}
+
+impl Command {
+    /// Returns a command with given name if any.
+    fn from_name(name: &str) -> Option<Command> {
+        match name {
+            "play" => Some(PlayCommand),
+            "info" => Some(InfoCommand),
+            "lint" => Some(LintCommand),
+            "render" => Some(RenderCommand),
+            "export" => Some(ExportCommand),
+            "dump" => Some(DumpCommand),
+            "branches" => Some(BranchesCommand),
+            "benchmark" => Some(BenchmarkCommand),
+            _ => None
+        }
+    }
+
+    /// Returns the name of the command.
+    fn name(&self) -> &'static str {
+        match *self {
+            PlayCommand => "play",
+            InfoCommand => "info",
+            LintCommand => "lint",
+            RenderCommand => "render",
+            ExportCommand => "export",
+            DumpCommand => "dump",
+            BranchesCommand => "branches",
+            BenchmarkCommand => "benchmark",
+        }
+    }
+
+    /// Returns the bit for the command in the set of commands accepting an option.
+    fn bit(&self) -> uint {
+        match *self {
+            PlayCommand => PLAY,
+            InfoCommand => INFO,
+            LintCommand => LINT,
+            RenderCommand => RENDER,
+            ExportCommand => EXPORT,
+            DumpCommand => DUMP,
+            BranchesCommand => BRANCHES,
+            BenchmarkCommand => BENCHMARK,
+        }
+    }
+}
+
+// sets of commands accepting the option, as a bit set of `Command::bit`
+const PLAY: uint = 1 << 0;
+const INFO: uint = 1 << 1;
+const LINT: uint = 1 << 2;
+const RENDER: uint = 1 << 3;
+const EXPORT: uint = 1 << 4;
+const DUMP: uint = 1 << 5;
+const BRANCHES: uint = 1 << 6;
+const BENCHMARK: uint = 1 << 7;
+const ANY: uint = PLAY | INFO | LINT | RENDER | EXPORT | DUMP | BRANCHES | BENCHMARK;
+// commands running the game play, including the simulated one
+const GAME: uint = PLAY | BENCHMARK;
+// commands loading sounds
+const SOUNDED: uint = PLAY | RENDER;
+// commands sanitizing the chart
+const SANITIZED: uint = PLAY | INFO | RENDER | EXPORT | BENCHMARK;
+// commands deriving the key specification from the chart
+const KEYED: uint = PLAY | INFO | RENDER | BRANCHES | BENCHMARK;
+
+/// A command line option. The long name is also used as a key in the configuration file.
+struct OptionSpec {
+    /// The long name without leading `--`.
+    long: &'static str,
+    /// The short name without leading `-` if any.
+    short: Option<char>,
+    /// True if the option takes an argument.
+    arg: bool,
+    /// The set of commands accepting the option. Other commands reject the option.
+    commands: uint,
+}
+
+/// Every option recognized in the command line.
+static OPTIONS: &'static [OptionSpec] = &[
+    OptionSpec { long: "help", short: Some('h'), arg: false, commands: ANY },
+    OptionSpec { long: "version", short: Some('V'), arg: false, commands: ANY },
+    OptionSpec { long: "speed", short: Some('a'), arg: true, commands: PLAY },
+    OptionSpec { long: "rate", short: None, arg: true, commands: PLAY | RENDER | BENCHMARK },
+    OptionSpec { long: "autoplay", short: Some('v'), arg: false, commands: PLAY },
+    OptionSpec { long: "exclusive", short: Some('x'), arg: false, commands: PLAY },
+    OptionSpec { long: "sound-only", short: Some('X'), arg: false, commands: PLAY },
+    OptionSpec { long: "terminal", short: None, arg: false, commands: PLAY },
+    OptionSpec { long: "windowed", short: Some('w'), arg: false, commands: PLAY },
+    OptionSpec { long: "no-fullscreen", short: None, arg: false, commands: PLAY },
+    OptionSpec { long: "opengl", short: None, arg: false, commands: PLAY },
+    OptionSpec { long: "fps", short: None, arg: true, commands: PLAY },
+    OptionSpec { long: "show-fps", short: None, arg: false, commands: PLAY },
+    OptionSpec { long: "sample-rate", short: None, arg: true, commands: SOUNDED },
+    OptionSpec { long: "audio-buffer", short: None, arg: true, commands: SOUNDED },
+    OptionSpec { long: "audio-device", short: None, arg: true, commands: PLAY },
+    OptionSpec { long: "polyphony", short: None, arg: true, commands: PLAY },
+    OptionSpec { long: "voice-steal", short: None, arg: true, commands: PLAY },
+    OptionSpec { long: "key-volume", short: None, arg: true, commands: PLAY },
+    OptionSpec { long: "bgm-volume", short: None, arg: true, commands: PLAY },
+    OptionSpec { long: "movie-volume", short: None, arg: true, commands: PLAY },
+    OptionSpec { long: "fullscreen", short: None, arg: false, commands: PLAY },
+    OptionSpec { long: "info", short: None, arg: false, commands: PLAY },
+    OptionSpec { long: "no-info", short: Some('q'), arg: false, commands: PLAY },
+    OptionSpec { long: "mirror", short: Some('m'), arg: false, commands: GAME },
+    OptionSpec { long: "shuffle", short: Some('s'), arg: false, commands: GAME },
+    OptionSpec { long: "shuffle-ex", short: Some('S'), arg: false, commands: GAME },
+    OptionSpec { long: "random", short: Some('r'), arg: false, commands: GAME },
+    OptionSpec { long: "random-ex", short: Some('R'), arg: false, commands: GAME },
+    OptionSpec { long: "s-random", short: Some('e'), arg: false, commands: GAME },
+    OptionSpec { long: "h-random", short: Some('y'), arg: false, commands: GAME },
+    OptionSpec { long: "flip", short: Some('f'), arg: false, commands: GAME },
+    OptionSpec { long: "battle", short: Some('b'), arg: false, commands: GAME },
+    OptionSpec { long: "assist-clap", short: Some('A'), arg: true, commands: PLAY },
+    OptionSpec { long: "metronome", short: Some('t'), arg: false, commands: PLAY },
+    OptionSpec { long: "metronome-flash", short: Some('J'), arg: false, commands: PLAY },
+    OptionSpec { long: "preset", short: Some('k'), arg: true, commands: KEYED },
+    OptionSpec { long: "key-spec", short: Some('K'), arg: true, commands: KEYED },
+    OptionSpec { long: "bga", short: None, arg: false, commands: PLAY },
+    OptionSpec { long: "no-bga", short: Some('B'), arg: false, commands: PLAY },
+    OptionSpec { long: "movie", short: None, arg: false, commands: PLAY },
+    OptionSpec { long: "no-movie", short: Some('M'), arg: false, commands: PLAY },
+    OptionSpec { long: "bga-stretch", short: None, arg: true, commands: PLAY },
+    OptionSpec { long: "poor-bga", short: None, arg: true, commands: PLAY },
+    OptionSpec { long: "poor-duration", short: None, arg: true, commands: PLAY },
+    OptionSpec { long: "lane-style", short: None, arg: true, commands: PLAY },
+    OptionSpec { long: "beat-color", short: None, arg: false, commands: PLAY },
+    OptionSpec { long: "lazy-sounds", short: None, arg: false, commands: PLAY },
+    OptionSpec { long: "early-start", short: None, arg: true, commands: PLAY },
+    OptionSpec { long: "search-dir", short: Some('d'), arg: true, commands: SOUNDED | LINT },
+    OptionSpec { long: "placeholder-sound", short: None, arg: true, commands: SOUNDED },
+    OptionSpec { long: "joystick", short: Some('j'), arg: true, commands: PLAY },
+    OptionSpec { long: "deadzone", short: None, arg: true, commands: PLAY },
+    OptionSpec { long: "calibrate-axes", short: None, arg: false, commands: PLAY },
+    OptionSpec { long: "preview", short: Some('P'), arg: false, commands: PLAY },
+    OptionSpec { long: "export-bmson", short: Some('E'), arg: true, commands: EXPORT },
+    OptionSpec { long: "no-sanitize", short: Some('N'), arg: false, commands: EXPORT },
+    OptionSpec { long: "sanitize", short: None, arg: true, commands: SANITIZED },
+    OptionSpec { long: "compat", short: None, arg: true, commands: ANY },
+    OptionSpec { long: "export-video", short: None, arg: true, commands: PLAY },
+    OptionSpec { long: "lint", short: Some('L'), arg: false, commands: LINT },
+    OptionSpec { long: "info-json", short: Some('I'), arg: false, commands: INFO },
+    OptionSpec { long: "render", short: None, arg: true, commands: RENDER },
+    OptionSpec { long: "dump-resolved", short: None, arg: true, commands: DUMP },
+    OptionSpec { long: "analyze-random", short: None, arg: false, commands: BRANCHES },
+    OptionSpec { long: "benchmark", short: None, arg: false, commands: BENCHMARK },
+    OptionSpec { long: "branch-samples", short: None, arg: true, commands: BRANCHES },
+    OptionSpec { long: "record", short: Some('O'), arg: true, commands: PLAY },
+    OptionSpec { long: "graph-csv", short: Some('D'), arg: true, commands: PLAY },
+    OptionSpec { long: "result-json", short: None, arg: true, commands: PLAY },
+    OptionSpec { long: "replay", short: Some('Y'), arg: true, commands: PLAY | EXPORT | DUMP },
+    OptionSpec { long: "ghost", short: Some('G'), arg: true, commands: PLAY },
+    OptionSpec { long: "pacemaker", short: Some('p'), arg: true, commands: PLAY },
+    OptionSpec { long: "seed", short: Some('z'), arg: true, commands: ANY },
+    OptionSpec { long: "start-measure", short: Some('F'), arg: true, commands: GAME },
+    OptionSpec { long: "end-measure", short: Some('U'), arg: true, commands: GAME },
+    OptionSpec { long: "loop-section", short: Some('l'), arg: false, commands: PLAY },
+    OptionSpec { long: "course", short: Some('C'), arg: true, commands: PLAY },
+    OptionSpec { long: "library", short: None, arg: true, commands: PLAY },
+    OptionSpec { long: "gauge", short: Some('g'), arg: true, commands: GAME },
+    OptionSpec { long: "calibrate", short: Some('c'), arg: false, commands: PLAY },
+    OptionSpec { long: "offset", short: Some('o'), arg: true, commands: PLAY },
+    OptionSpec { long: "chart-offset", short: Some('T'), arg: true, commands: PLAY },
+    OptionSpec { long: "keysound-window", short: None, arg: true, commands: PLAY },
+    OptionSpec { long: "ln-release-window", short: None, arg: true, commands: GAME },
+    OptionSpec { long: "lenient-ln", short: None, arg: false, commands: GAME },
+    OptionSpec { long: "green", short: Some('n'), arg: true, commands: PLAY },
+    OptionSpec { long: "suggest-speed", short: None, arg: true, commands: PLAY },
+    OptionSpec { long: "cover", short: Some('W'), arg: true, commands: PLAY },
+    OptionSpec { long: "lift", short: Some('H'), arg: true, commands: PLAY },
+    OptionSpec { long: "sudden", short: Some('u'), arg: true, commands: PLAY },
+    OptionSpec { long: "hidden", short: Some('i'), arg: true, commands: PLAY },
+    OptionSpec { long: "constant", short: Some('Q'), arg: false, commands: PLAY },
+    OptionSpec { long: "ir", short: None, arg: true, commands: PLAY },
+    OptionSpec { long: "no-ir", short: None, arg: false, commands: PLAY },
+];
+
+impl OptionSpec {
+    /// Returns an option with given long name if any.
+    fn find_long(name: &str) -> Option<&'static OptionSpec> {
+        OPTIONS.iter().find(|spec| spec.long == name)
+    }
+
+    /// Returns an option with given short name if any.
+    fn find_short(c: char) -> Option<&'static OptionSpec> {
+        OPTIONS.iter().find(|spec| spec.short == Some(c))
+    }
+}
+
+/// The entry point. Parses the command line options and delegates other things to `play`.
+/// (C: `main`)
+pub fn main() {
+
+    // options from the configuration file precede the command line ones, which override them
+    let config = match config::read_config() {
+        Ok(config) => config,
+        Err(err) => die!("{}", err)
+    };
+    let configargs = match config.to_args(|option| {
+        option.starts_with("--") && OptionSpec::find_long(option[2..]).is_some()
+    }) {
+        Ok(args) => args,
+        Err(err) => die!("{}", err)
+    };
+    // the command should be the first argument, so it cannot be given in the configuration.
+    // a chart named like the command can be played with an explicit `play` command.
+    let cmdargs = std::os::args();
+    let explicit = cmdargs[].get(1).and_then(|arg| Command::from_name(arg[]));
+    let cmdstart = if explicit.is_some() {2} else {1};
+    let mut args = cmdargs[..1].to_vec();
+    args.push_all(configargs[]);
+    let nconfigargs = configargs.len();
+    args.push_all(cmdargs[cmdstart..]);
+    let nargs = args.len();
+
+    let mut bmspath = None;
+    let mut mode = player::PlayMode;
+    let mut modf = None;
+    let mut bga = player::BgaAndMovie;
+    let mut bgastretch = None;
+    let mut poorbga = player::PoorReplaces;
+    let mut poorduration = player::DEFAULT_POOR_DURATION;
+    let mut lanestyles = Vec::new();
+    let mut beatcolor = false;
+    let mut lazysounds = false;
+    let mut earlystart = None;
+    let mut searchdirs = Vec::new();
+    let mut placeholdersound = None;
+    let mut showinfo = true;
+    let mut terminal = false;
+    let mut fullscreen = true;
+    let mut opengl = false;
+    let mut fps = None;
+    let mut showfps = false;
+    let mut samplerate = player::DEFAULT_SAMPLERATE;
+    let mut audiobuffer = player::DEFAULT_AUDIOBUFFER;
+    let mut audiodevice = None;
+    let mut keyvolume = 128;
+    let mut bgmvolume = 96;
+    let mut movievolume = None;
+    let mut polyphony = mixer::DEFAULT_POLYPHONY;
+    let mut voicesteal = mixer::StealOldest;
+    let mut joystick = Vec::new();
+    let mut deadzone = player::DEFAULT_DEADZONE;
+    let mut axes = player::read_axis_calibration();
+    let mut calibratingaxes = false;
+    let mut preset = None;
+    let mut leftkeys = None;
+    let mut rightkeys = None;
+    let mut playspeed = 1.0;
+    let mut restorespeed = true;
+    let mut rate = 1.0;
+    let mut preview = false;
+    let mut exportbmson = None;
+    let mut exportvideo = None;
+    let mut sanitize = true;
+    let mut sanitizepolicy = parser::FixConflicts;
+    let mut compat = parser::Canonical;
+    let mut render = None;
+    let mut dumpresolved = None;
+    let mut branchsamples = None;
+    let mut recordreplay = None;
+    let mut replay = None;
+    let mut ghost = None;
+    let mut seed = None;
+    let mut startmeasure = None;
+    let mut endmeasure = None;
+    let mut loopsection = false;
+    let mut course = Vec::new();
+    let mut library = None;
+    let mut gauge = player::NormalGauge;
+    let mut calibrating = false;
+    let mut offset = None;
+    let mut chartoffset = None;
+    let mut keysoundwindow = None;
+    let mut lnreleasewindow = player::DEFAULT_LN_RELEASE_WINDOW;
+    let mut lenientln = false;
+    let mut greennumber = None;
+    let mut suggestgreen = None;
+    let mut cover = 0;
+    let mut restorecover = true;
+    let mut lift = 0;
+    let mut sudden = None;
+    let mut hidden = None;
+    let mut constant = false;
+    let mut flip = false;
+    let mut battle = false;
+    let mut assistclap = None;
+    let mut metronome = false;
+    let mut metronomeflash = false;
+    let mut historycsv = None;
+    let mut resultjson = None;
+    let mut pacemaker = None;
+    let mut ir = None;
+    let mut noir = false;
+
+    // options given in the command line, which should be accepted by the command. options from
+    // the configuration file may be meant for other commands and do not affect this command.
+    let mut given = Vec::new();
+
+    let mut i = 1;
+    while i < nargs {
+        let arg = args[i][];
+        if !arg.starts_with("-") {
+            if bmspath.is_none() {
+                bmspath = Some(arg.to_string());
+            } else {
+                course.push(arg.to_string());
+            }
+        } else if arg == "--" {
+            // every remaining argument is a path
+            for arg in args[i+1..].iter() {
+                if bmspath.is_none() {
+                    bmspath = Some(arg.to_string());
+                } else {
+                    course.push(arg.to_string());
+                }
+            }
+            break;
+        } else {
+            // `--option=arg` is same as `--option arg`. short options can be grouped, and
+            // the remaining portion of the group after the option with an argument is its argument.
+            let mut found = Vec::new();
+            if arg.starts_with("--") {
+                let (name, inlinearg) = match arg.find('=') {
+                    Some(idx) => (arg[..idx], Some(arg[idx+1..])),
+                    None => (arg, None)
+                };
+                match OptionSpec::find_long(name[2..]) {
+                    Some(spec) if !spec.arg && inlinearg.is_some() => {
+                        usage_error!("Option {} does not take an argument", name);
+                    }
+                    Some(spec) => { found.push((spec, inlinearg)); }
+                    None => usage_error!("Invalid option: {}", name)
+                }
+            } else {
+                let shortargs = arg[1..];
+                for (j, c) in shortargs.char_indices() {
+                    let next = shortargs.char_range_at(j).next;
+                    if '1' <= c && c <= '9' {
+                        // `-N` is same as `--speed N`
+                        found.push((OptionSpec::find_long("speed").unwrap(),
+                                    Some(shortargs[j..next])));
+                        continue;
+                    }
+                    match OptionSpec::find_short(c) {
+                        Some(spec) if spec.arg => {
+                            let inlinearg = if next < shortargs.len() {
+                                Some(shortargs[next..])
+                            } else {
+                                None
+                            };
+                            found.push((spec, inlinearg));
+                            break;
+                        }
+                        Some(spec) => { found.push((spec, None)); }
+                        None => usage_error!("Invalid option: -{}", c)
+                    }
+                }
+            }
+
+            let fromconfig = i <= nconfigargs;
+            for &(spec, inlinearg) in found.iter() {
+                if !fromconfig { given.push(spec); }
+
+                // Reads the next argument as a whole.
+                macro_rules! next_arg(
+                    () => ({
+                        i += 1;
+                        if i < nargs {
+                            args[i][]
+                        } else {
+                            usage_error!("No argument to the option --{}", spec.long);
+                        }
+                    })
+                )
+
+                let optarg = match inlinearg {
+                    Some(optarg) => optarg,
+                    None if spec.arg => next_arg!(),
+                    None => ""
+                };
+                match spec.long {
+                    "help" => { usage(true); }
+                    "version" => { println!("{}", version()); return; }
+                    "autoplay" => { mode = player::AutoPlayMode; }
+                    "exclusive" => { mode = player::ExclusiveMode; }
+                    "sound-only" => { mode = player::ExclusiveMode; bga = player::NoBga; }
+                    "terminal" => {
+                        mode = player::ExclusiveMode;
+                        bga = player::NoBga;
+                        terminal = true;
+                    }
+                    "windowed" | "no-fullscreen" => { fullscreen = false; }
+                    "fullscreen" => { fullscreen = true; }
+                    "opengl" => { opengl = true; }
+                    "fps" => {
+                        match from_str::<uint>(optarg) {
+                            Some(n) if n > 0 => { fps = Some(n); }
+                            _ => usage_error!("Invalid argument to option --fps")
+                        }
+                    }
+                    "show-fps" => { showfps = true; }
+                    "sample-rate" => {
+                        match from_str::<uint>(optarg) {
+                            Some(n) if 8000 <= n && n <= 192000 => { samplerate = n; }
+                            _ => usage_error!("Invalid argument to option --sample-rate")
+                        }
+                    }
+                    "audio-buffer" => {
+                        match from_str::<uint>(optarg) {
+                            Some(n) if 64 <= n && n <= 32768 && n & (n - 1) == 0 => {
+                                audiobuffer = n;
+                            }
+                            _ => usage_error!("Invalid argument to option --audio-buffer")
+                        }
+                    }
+                    "audio-device" => {
+                        audiodevice = Some(optarg.to_string());
+                    }
+                    "key-volume" => {
+                        match from_str::<uint>(optarg) {
+                            Some(n) if n <= 128 => { keyvolume = n; }
+                            _ => usage_error!("Invalid argument to option --key-volume")
+                        }
+                    }
+                    "bgm-volume" => {
+                        match from_str::<uint>(optarg) {
+                            Some(n) if n <= 128 => { bgmvolume = n; }
+                            _ => usage_error!("Invalid argument to option --bgm-volume")
+                        }
+                    }
+                    "movie-volume" => {
+                        match from_str::<uint>(optarg) {
+                            Some(n) if n <= 128 => { movievolume = Some(n); }
+                            _ => usage_error!("Invalid argument to option --movie-volume")
+                        }
+                    }
+                    "polyphony" => {
+                        match from_str::<uint>(optarg) {
+                            Some(n) if n > 0 => { polyphony = n; }
+                            _ => usage_error!("Invalid argument to option --polyphony")
+                        }
+                    }
+                    "voice-steal" => {
+                        match mixer::StealPolicy::from_name(optarg) {
+                            Some(policy) => { voicesteal = policy; }
+                            None => usage_error!("Invalid argument to option --voice-steal")
+                        }
+                    }
+                    "no-info" => { showinfo = false; }
+                    "info" => { showinfo = true; }
+                    "mirror" => { modf = Some(player::MirrorModf); }
+                    "shuffle" => { modf = Some(player::ShuffleModf); }
+                    "shuffle-ex" => { modf = Some(player::ShuffleExModf); }
+                    "random" => { modf = Some(player::RandomModf); }
+                    "random-ex" => { modf = Some(player::RandomExModf); }
+                    "s-random" => { modf = Some(player::SRandomModf); }
+                    "h-random" => { modf = Some(player::HRandomModf); }
+                    "flip" => { flip = true; }
+                    "battle" => { battle = true; }
+                    "metronome" => { metronome = true; }
+                    "metronome-flash" => { metronome = true; metronomeflash = true; }
+                    "assist-clap" => {
+                        match player::AssistClap::from_name(optarg) {
+                            Some(when) => { assistclap = Some(when); }
+                            None => usage_error!("Invalid argument to option -A")
+                        }
+                    }
+                    "preset" => { preset = Some(optarg.to_string()); }
+                    "key-spec" => {
+                        leftkeys = Some(optarg.to_string());
+                        rightkeys = Some(next_arg!().to_string());
+                    }
+                    "speed" => {
+                        match from_str::<f64>(optarg) {
+                            Some(speed) if speed > 0.0 => {
+                                playspeed = if speed < 0.1 {0.1}
+                                            else if speed > 99.0 {99.0}
+                                            else {speed};
+                                restorespeed = false;
+                            }
+                            _ => usage_error!("Invalid argument to option -a")
+                        }
+                    }
+                    "rate" => {
+                        match from_str::<f64>(optarg) {
+                            Some(r) if 0.5 <= r && r <= 2.0 => { rate = r; }
+                            _ => usage_error!("Invalid argument to option --rate")
+                        }
+                    }
+                    "no-bga" => { bga = player::NoBga; }
+                    "no-movie" => { bga = player::BgaButNoMovie; }
+                    "bga" => { if bga == player::NoBga { bga = player::BgaAndMovie; } }
+                    "movie" => { if bga == player::BgaButNoMovie { bga = player::BgaAndMovie; } }
+                    "lazy-sounds" => { lazysounds = true; }
+                    "early-start" => {
+                        match from_str::<f64>(optarg) {
+                            Some(secs) if secs >= 0.0 => { earlystart = Some(secs); }
+                            _ => usage_error!("Invalid argument to option --early-start")
+                        }
+                    }
+                    "search-dir" => { searchdirs.push(optarg.to_string()); }
+                    "placeholder-sound" => {
+                        match from_str::<uint>(optarg) {
+                            Some(msec) if msec > 0 => { placeholdersound = Some(msec); }
+                            _ => usage_error!("Invalid argument to option --placeholder-sound")
+                        }
+                    }
+                    "beat-color" => { beatcolor = true; }
+                    "lane-style" => {
+                        match player::LaneCustomStyle::parse_list(optarg) {
+                            Some(styles) => { lanestyles.extend(styles.into_iter()); }
+                            None => usage_error!("Invalid argument to option --lane-style")
+                        }
+                    }
+                    "bga-stretch" => {
+                        match player::BgaStretch::from_name(optarg) {
+                            Some(stretch) => { bgastretch = Some(stretch); }
+                            None => usage_error!("Invalid argument to option --bga-stretch")
+                        }
+                    }
+                    "poor-bga" => {
+                        match player::PoorBga::from_name(optarg) {
+                            Some(mode) => { poorbga = mode; }
+                            None => usage_error!("Invalid argument to option --poor-bga")
+                        }
+                    }
+                    "poor-duration" => {
+                        match from_str::<uint>(optarg) {
+                            Some(msec) => { poorduration = msec; }
+                            None => usage_error!("Invalid argument to option --poor-duration")
+                        }
+                    }
+                    "preview" => { preview = true; }
+                    "export-bmson" => { exportbmson = Some(optarg.to_string()); }
+                    "no-sanitize" => { sanitize = false; }
+                    "compat" => {
+                        match parser::CompatProfile::from_name(optarg) {
+                            Some(profile) => { compat = profile; }
+                            None => usage_error!("Invalid argument to option --compat")
+                        }
+                    }
+                    "sanitize" => {
+                        match parser::SanitizePolicy::from_name(optarg) {
+                            Some(policy) => { sanitizepolicy = policy; }
+                            None => usage_error!("Invalid argument to option --sanitize")
+                        }
+                    }
+                    "export-video" => {
+                        exportvideo = Some(optarg.to_string());
+                    }
+                    // they only select the command (see below)
+                    "lint" | "info-json" | "analyze-random" | "benchmark" => {}
+                    "render" => { render = Some(optarg.to_string()); }
+                    "dump-resolved" => {
+                        dumpresolved = Some(optarg.to_string());
+                    }
+                    "branch-samples" => {
+                        match from_str::<uint>(optarg) {
+                            Some(n) if n > 0 => { branchsamples = Some(n); }
+                            _ => usage_error!("Invalid argument to option --branch-samples")
+                        }
+                    }
+                    "record" => { recordreplay = Some(optarg.to_string()); }
+                    "graph-csv" => { historycsv = Some(optarg.to_string()); }
+                    "result-json" => {
+                        resultjson = Some(optarg.to_string());
+                    }
+                    "replay" => { replay = Some(optarg.to_string()); }
+                    "ghost" => { ghost = Some(optarg.to_string()); }
+                    "pacemaker" => {
+                        match player::Pacemaker::from_spec(optarg) {
+                            Some(p) => { pacemaker = Some(p); }
+                            None => usage_error!("Invalid argument to option -p")
+                        }
+                    }
+                    "seed" => {
+                        match from_str::<u64>(optarg) {
+                            Some(n) => { seed = Some(n); }
+                            _ => usage_error!("Invalid argument to option -z")
+                        }
+                    }
+                    "start-measure" => {
+                        match from_str::<uint>(optarg) {
+                            Some(n) => { startmeasure = Some(n); }
+                            _ => usage_error!("Invalid argument to option -F")
+                        }
+                    }
+                    "end-measure" => {
+                        match from_str::<uint>(optarg) {
+                            Some(n) => { endmeasure = Some(n); }
+                            _ => usage_error!("Invalid argument to option -U")
+                        }
+                    }
+                    "loop-section" => { loopsection = true; }
+                    "gauge" => {
+                        match player::GaugeKind::from_name(optarg) {
+                            Some(kind) => { gauge = kind; }
+                            None => usage_error!("Invalid argument to option -g")
+                        }
+                    }
+                    "calibrate" => { calibrating = true; }
+                    "constant" => { constant = true; }
+                    "cover" => {
+                        match from_str::<uint>(optarg) {
+                            Some(px) => { cover = px; restorecover = false; }
+                            _ => usage_error!("Invalid argument to option -W")
+                        }
+                    }
+                    "lift" => {
+                        match from_str::<uint>(optarg) {
+                            Some(px) => { lift = px; }
+                            _ => usage_error!("Invalid argument to option -H")
+                        }
+                    }
+                    "sudden" => {
+                        match from_str::<uint>(optarg) {
+                            Some(pct) if pct <= 100 => { sudden = Some(pct as f64 / 100.0); }
+                            _ => usage_error!("Invalid argument to option -u")
+                        }
+                    }
+                    "hidden" => {
+                        match from_str::<uint>(optarg) {
+                            Some(pct) if pct <= 100 => { hidden = Some(pct as f64 / 100.0); }
+                            _ => usage_error!("Invalid argument to option -i")
+                        }
+                    }
+                    "green" => {
+                        match from_str::<f64>(optarg) {
+                            Some(ms) if ms > 0.0 => { greennumber = Some(ms); }
+                            _ => usage_error!("Invalid argument to option -n")
+                        }
+                    }
+                    "suggest-speed" => {
+                        match from_str::<f64>(optarg) {
+                            Some(ms) if ms > 0.0 => { suggestgreen = Some(ms); }
+                            _ => usage_error!("Invalid argument to option --suggest-speed")
+                        }
+                    }
+                    "offset" => {
+                        match from_str::<f64>(optarg) {
+                            Some(ms) => { offset = Some(ms); }
+                            _ => usage_error!("Invalid argument to option -o")
+                        }
+                    }
+                    "chart-offset" => {
+                        match from_str::<f64>(optarg) {
+                            Some(ms) => { chartoffset = Some(ms); }
+                            _ => usage_error!("Invalid argument to option -T")
+                        }
+                    }
+                    "keysound-window" => {
+                        match optarg {
+                            "none" => { keysoundwindow = None; }
+                            arg => match from_str::<f64>(arg) {
+                                Some(ms) if ms >= 0.0 => { keysoundwindow = Some(ms); }
+                                _ => usage_error!("Invalid argument to option --keysound-window")
+                            }
+                        }
+                    }
+                    "ln-release-window" => {
+                        match from_str::<f64>(optarg) {
+                            Some(ms) if ms >= 0.0 => { lnreleasewindow = ms; }
+                            _ => usage_error!("Invalid argument to option --ln-release-window")
+                        }
+                    }
+                    "lenient-ln" => { lenientln = true; }
+                    "library" => { library = Some(optarg.to_string()); }
+                    "course" => {
+                        match read_course_file(optarg) {
+                            Ok(paths) => { course.extend(paths.into_iter()); }
+                            Err(err) => die!("{}", err)
+                        }
+                    }
+                    "joystick" => {
+                        for n in optarg.split(',') {
+                            match from_str::<uint>(n.trim()) {
+                                Some(n) => { if !joystick.contains(&n) { joystick.push(n); } }
+                                _ => usage_error!("Invalid argument to option -j")
+                            }
+                        }
+                    }
+                    "deadzone" => {
+                        let arg = optarg;
+                        match from_str::<uint>(arg) {
+                            Some(n) if n < 32768 => { deadzone = n; }
+                            Some(_) => usage_error!("Invalid argument to option --deadzone"),
+                            None => match player::AxisCalibration::from_spec(arg) {
+                                Some(ref calib) if calib.deadzone < 32768 => {
+                                    axes.push(calib.clone());
+                                }
+                                _ => usage_error!("Invalid argument to option --deadzone")
+                            }
+                        }
+                    }
+                    "calibrate-axes" => { calibratingaxes = true; }
+                    "ir" => { ir = Some(optarg.to_string()); }
+                    "no-ir" => { noir = true; }
+                    _ => unreachable!()
+                }
+            }
+        }
+        i += 1;
+    }
+
+    // options for other commands select them as well when given in the command line, but
+    // cannot be mixed with each other. those in the configuration file never select the command.
+    let mut command = explicit;
+    let selected = [("lint", LintCommand), ("info-json", InfoCommand),
+                    ("render", RenderCommand), ("export-bmson", ExportCommand),
+                    ("dump-resolved", DumpCommand), ("analyze-random", BranchesCommand),
+                    ("branch-samples", BranchesCommand), ("benchmark", BenchmarkCommand)];
+    for spec in given.iter() {
+        for &(option, ref cmd) in selected.iter() {
+            if option != spec.long { continue; }
+            match command.clone() {
+                Some(ref prev) if *prev != *cmd => {
+                    usage_error!("Option --{} cannot be used with the `{}` command",
+                                 option, prev.name());
+                }
+                Some(_) => {}
+                None => { command = Some(cmd.clone()); }
+            }
+        }
+    }
+    let command = command.unwrap_or(PlayCommand);
+    for spec in given.iter() {
+        if spec.commands & command.bit() == 0 {
+            usage_error!("Option --{} cannot be used with the `{}` command",
+                         spec.long, command.name());
+        }
+    }
+
+    // other commands work on a single chart given explicitly. `render`, `export` and `dump` also
+    // accept the output path as the second path.
+    if command != PlayCommand {
+        if command == RenderCommand && render.is_none() && !course.is_empty() {
+            render = Some(course.remove(0).unwrap());
+        }
+        if command == ExportCommand && exportbmson.is_none() && !course.is_empty() {
+            exportbmson = Some(course.remove(0).unwrap());
+        }
+        if command == DumpCommand && dumpresolved.is_none() && !course.is_empty() {
+            dumpresolved = Some(course.remove(0).unwrap());
+        }
+        if bmspath.is_none() {
+            usage_error!("The `{}` command requires a path to the chart", command.name());
+        }
+        if !course.is_empty() {
+            usage_error!("The `{}` command accepts only one chart", command.name());
+        }
+        if command == RenderCommand && render.is_none() {
+            usage_error!("The `render` command requires a path to the WAV file");
+        }
+        if command == ExportCommand && exportbmson.is_none() {
+            usage_error!("The `export` command requires a path to the bmson file");
+        }
+        if command == DumpCommand && dumpresolved.is_none() {
+            usage_error!("The `dump` command requires a path to the BMS file");
+        }
+    }
+
+    // SDL 1.2 only accepts the output device from the environment
+    for device in audiodevice.iter() {
+        std::os::setenv("AUDIODEV", device[]);
+    }
+
+    if calibrating {
+        match calibrate(fullscreen, samplerate, audiobuffer) {
+            Ok(()) | Err(Interrupted) => {}
+            Err(err) => die!("{}", err)
+        }
+        return;
+    }
+
+    if calibratingaxes {
+        match calibrate_axes(joystick[]) {
+            Ok(()) | Err(Interrupted) => {}
+            Err(err) => die!("{}", err)
+        }
+        return;
+    }
+
+    // shows the song selection screen for the library if the path to the BMS file is missing.
+    // without the library, a file dialog is shown if the system supports it, and otherwise
+    // the song selection screen for the current directory, unless the screen is unavailable.
+    if bmspath.is_none() && !course.is_empty() {
+        bmspath = Some(course.remove(0).unwrap());
+    }
+    if bmspath.is_none() && library.is_none() {
+        bmspath = util::get_path_from_dialog();
+    }
+    if bmspath.is_none() && (library.is_some() || !cfg!(target_os = "windows")) {
+        let dir = library.as_ref().map_or(std::os::getcwd(), |dir| Path::new(dir[]));
+        match select_chart(fullscreen, dir) {
+            Ok(Some(path)) => { bmspath = Some(path); }
+            Ok(None) => { return; }
+            Err(_) => {}
+        }
+    }
+
+    // the path to the ZIP archive itself refers to the first chart in the archive.
+    fn resolve_archive(path: String) -> String {
+        match zip::split_archive_path(path[]) {
+            Some((ref archive, ref inner)) if inner.is_empty() => {
+                match zip::find_chart(archive, player::CHART_EXTS) {
+                    Ok(Some(chart)) => chart,
+                    Ok(None) => die!("No chart found in {}", path),
+                    Err(err) => die!("{}: {}", path, err)
+                }
+            }
+            _ => path
+        }
+    }
+    let bmspath = bmspath.map(resolve_archive);
+    let course: Vec<String> = course.into_iter().map(resolve_archive).collect();
+
+    if !course.is_empty() && (replay.is_some() || recordreplay.is_some() || ghost.is_some()) {
+        die!("Replays cannot be used in the course mode");
+    }
+    if pacemaker == Some(player::PaceGhost) && ghost.is_none() {
+        die!("The ghost pacemaker requires the ghost replay given by -G");
+    }
+
+    match (startmeasure, endmeasure) {
+        (Some(start), Some(end)) if start >= end => {
+            die!("The start measure should precede the end measure");
+        }
+        (_, _) => {}
+    }
+
+    // the video is exported from the AUTO PLAY mode
+    if exportvideo.is_some() {
+        if mode == player::ExclusiveMode {
+            die!("The video cannot be exported without the screen");
+        }
+        if lazysounds || earlystart.is_some() {
+            die!("The video cannot be exported with sounds loaded on demand");
+        }
+        mode = player::AutoPlayMode;
+    }
+
+    // the preview is always played without the screen
+    if preview {
+        mode = player::ExclusiveMode;
+        bga = player::NoBga;
+    }
+
+    match bmspath {
+        None => { usage(false); }
+        Some(bmspath) => {
+            let opts = player::Options {
+                bmspath: bmspath, mode: mode, modf: modf, bga: bga, bgastretch: bgastretch,
+                poorbga: poorbga, poorduration: poorduration,
+                lanestyles: lanestyles, beatcolor: beatcolor,
+                showinfo: showinfo, terminal: terminal, fullscreen: fullscreen, joystick: joystick,
+                deadzone: deadzone, axes: axes,
+                preset: preset, leftkeys: leftkeys, rightkeys: rightkeys, playspeed: playspeed,
+                restorespeed: restorespeed, restorecover: restorecover,
+                preview: preview, exportbmson: exportbmson, sanitize: sanitize,
+                compat: compat, sanitizepolicy: sanitizepolicy,
+                lazysounds: lazysounds, earlystart: earlystart,
+                searchdirs: searchdirs, placeholdersound: placeholdersound,
+                recordreplay: recordreplay, replay: replay, ghost: ghost,
+                seed: seed, startmeasure: startmeasure, endmeasure: endmeasure,
+                loopsection: loopsection, course: course, gauge: gauge,
+                offset: offset.or_else(player::read_global_offset).unwrap_or(0.0),
+                chartoffset: chartoffset, keysoundwindow: keysoundwindow,
+                lnreleasewindow: lnreleasewindow, lenientln: lenientln,
+                greennumber: greennumber, suggestgreen: suggestgreen,
+                cover: cover, lift: lift, sudden: sudden, hidden: hidden,
+                constant: constant, flip: flip, battle: battle, assistclap: assistclap,
+                metronome: metronome, metronomeflash: metronomeflash, historycsv: historycsv,
+                resultjson: resultjson,
+                pacemaker: pacemaker,
+                opengl: opengl, fps: fps, showfps: showfps, exportvideo: exportvideo,
+                samplerate: samplerate, audiobuffer: audiobuffer,
+                rate: rate, keyvolume: keyvolume, bgmvolume: bgmvolume,
+                movievolume: movievolume,
+                polyphony: polyphony, voicesteal: voicesteal,
+                ir: if noir {None} else {ir.or_else(|| std::os::getenv("ANGOLMOIS_IR_URL"))}
+            };
+            match command {
+                LintCommand => match lint_chart(&opts) {
+                    Ok(Some(parser::Error)) => util::exit(2),
+                    Ok(Some(parser::Warning)) => util::exit(1),
+                    Ok(_) => {}
+                    Err(err) => die!("{}", err)
+                },
+                InfoCommand => match info_json(&opts) {
+                    Ok(json) => println!("{}", json),
+                    Err(err) => die!("{}", err)
+                },
+                RenderCommand => match render_chart(&opts, render.unwrap()[]) {
+                    Ok(()) => {}
+                    Err(err) => die!("{}", err)
+                },
+                DumpCommand => match dump_resolved_chart(&opts, dumpresolved.unwrap()[]) {
+                    Ok(()) => {}
+                    Err(err) => die!("{}", err)
+                },
+                BranchesCommand => match analyze_random_branches(&opts, branchsamples) {
+                    Ok(0) => {}
+                    Ok(_) => util::exit(1),
+                    Err(err) => die!("{}", err)
+                },
+                BenchmarkCommand => match benchmark(&opts) {
+                    Ok(()) => {}
+                    Err(err) => die!("{}", err)
+                },
+                // the export is done in place of the game play
+                PlayCommand | ExportCommand => match play(opts) {
+                    Ok(player::Cleared) => {}
+                    Ok(player::Failed) => util::exit(2),
+                    Ok(player::Aborted) | Err(Interrupted) => util::exit(3),
+                    Err(err) => die!("{}", err)
+                }
+            }
+        }
+    }
+}